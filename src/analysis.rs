@@ -0,0 +1,709 @@
+use crate::ast::Regex;
+use crate::lexer::{LexicalError, Tokens};
+use crate::parser::{self, parse_program, Error as ParseError};
+use crate::tokens::Token;
+
+/*
+ * 面向编辑器的静态分析，供LSP服务器使用
+ * 编辑过程中的脚本往往无法完整解析，因此除诊断信息外，
+ * 其余功能(声明、符号、补全)都直接基于token流，不依赖语法树。
+ * 所有位置均为源码中的字节偏移量。
+ */
+
+pub const KEYWORDS: [&str; 31] = [
+    "global",
+    "local",
+    "speak",
+    "input",
+    "if",
+    "exit",
+    "loop",
+    "match",
+    "intent",
+    "default",
+    "threshold",
+    "regex",
+    "as",
+    "prompt",
+    "retry",
+    "else",
+    "form",
+    "slot",
+    "confirm",
+    "state",
+    "on",
+    "enter",
+    "goto",
+    "for",
+    "in",
+    "one",
+    "of",
+    "store",
+    "import",
+    "locale",
+    "transfer",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub start: usize,
+    pub end: usize,
+    pub severity: Severity,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DeclarationKind {
+    /* global x = ...; */
+    Global,
+    /* local x = ...; */
+    Local,
+    /* input x; */
+    Input,
+    /* x = ...; */
+    Assign,
+    /* text ~ "(?P<x>...)"，位置为整个正则字符串 */
+    Capture,
+    /* form中的 slot x ...; */
+    Slot,
+    /* for x in ... {} */
+    For,
+}
+
+/* 变量在源码中出现的一次声明 */
+#[derive(Debug, Clone, PartialEq)]
+pub struct Declaration {
+    pub name: String,
+    pub kind: DeclarationKind,
+    /* 变量名所在的位置 */
+    pub start: usize,
+    pub end: usize,
+    /* 声明所在的整行代码，用于悬停提示 */
+    pub line: String,
+}
+
+/* 文档大纲中的代码块 */
+#[derive(Debug, Clone, PartialEq)]
+pub struct BlockSymbol {
+    /* 例如 loop、for item、if (str == "b")、match、intent "balance"、{} */
+    pub name: String,
+    /* 整个代码块的范围 */
+    pub start: usize,
+    pub end: usize,
+    /* 代码块开头关键字的范围 */
+    pub name_start: usize,
+    pub name_end: usize,
+    pub children: Vec<BlockSymbol>,
+}
+
+pub struct Document {
+    source: String,
+    /* 无法识别的字符会被跳过，对应的错误在diagnostics中报告 */
+    tokens: Vec<(usize, Token, usize)>,
+}
+
+impl Document {
+    pub fn new(source: &str) -> Self {
+        let tokens = Tokens::new(source)
+            .map(|(token, span)| (span.start, token, span.end))
+            .collect();
+        Self {
+            source: source.to_string(),
+            tokens,
+        }
+    }
+
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    /* 词法错误、语法错误、重复或不存在的对话状态以及从未声明过的变量 */
+    pub fn diagnostics(&self) -> Vec<Diagnostic> {
+        let mut diagnostics = Vec::new();
+        let invalid: Vec<&(usize, Token, usize)> = self
+            .tokens
+            .iter()
+            .filter(|(_, token, _)| *token == Token::Error)
+            .collect();
+        for (start, _, end) in &invalid {
+            diagnostics.push(Diagnostic {
+                start: *start,
+                end: *end,
+                severity: Severity::Error,
+                message: LexicalError::InvalidToken.to_string(),
+            });
+        }
+        // 词法错误已经单独报告，这里只报告语法错误
+        if let Err(error) = parse_program(&self.source) {
+            if !matches!(
+                error,
+                ParseError::User {
+                    error: LexicalError::InvalidToken
+                }
+            ) {
+                let (start, end) = parser::span(&self.source, &error);
+                diagnostics.push(Diagnostic {
+                    start,
+                    end,
+                    severity: Severity::Error,
+                    message: error.to_string(),
+                });
+            }
+        }
+        let states = self.names_after(Token::KeywordState);
+        for (index, (start, name, end)) in states.iter().enumerate() {
+            if states[..index].iter().any(|(_, other, _)| other == name) {
+                diagnostics.push(Diagnostic {
+                    start: *start,
+                    end: *end,
+                    severity: Severity::Error,
+                    message: format!("State '{}' is declared twice.", name),
+                });
+            }
+        }
+        for (start, name, end) in self.names_after(Token::KeywordGoto) {
+            if !states.iter().any(|(_, state, _)| *state == name) {
+                diagnostics.push(Diagnostic {
+                    start,
+                    end,
+                    severity: Severity::Error,
+                    message: format!("State '{}' is not declared.", name),
+                });
+            }
+        }
+        let declarations = self.declarations();
+        for (start, name, end) in self.identifiers() {
+            if !declarations.iter().any(|decl| decl.name == name) {
+                diagnostics.push(Diagnostic {
+                    start,
+                    end,
+                    severity: Severity::Warning,
+                    message: format!("Variable '{}' is never declared.", name),
+                });
+            }
+        }
+        diagnostics
+    }
+
+    /*
+     * 紧跟在关键字之后的名字，例如 state 与 goto 后的状态名
+     * 不包括 goto 模块名.状态 中的名字，这些状态在导入的文件中声明
+     */
+    fn names_after(&self, keyword: Token) -> Vec<(usize, &str, usize)> {
+        self.tokens
+            .windows(3)
+            .filter_map(|window| match window {
+                [(_, previous, _), (start, Token::Identifier(name), end), (_, next, _)]
+                    if *previous == keyword && *next != Token::Dot =>
+                {
+                    Some((*start, name.as_str(), *end))
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    /* 变量名，不包括表单名、状态名、函数名、store中的键、导入的模块中的名字以及input语句中的类型名与 max */
+    fn identifiers(&self) -> impl Iterator<Item = (usize, &str, usize)> {
+        self.tokens
+            .iter()
+            .enumerate()
+            .filter_map(|(index, (start, token, end))| match token {
+                Token::Identifier(name) if !self.is_not_variable(index) => {
+                    Some((*start, name.as_str(), *end))
+                }
+                _ => None,
+            })
+    }
+
+    /*
+     * 表单名、状态名、函数名、store中的键、模块名.名字、on enter 中的 enter、for x in 中的 in、
+     * speak one of 中的 one 与 of、input x as number ... max 3 中的 as、number 与 max、
+     * threshold 0.8 与 regex "..." 中的词以及 @消息名 与消息的参数名 在词法上是普通的标识符
+     */
+    fn is_not_variable(&self, index: usize) -> bool {
+        let previous = index.checked_sub(1).map(|i| &self.tokens[i].1);
+        let next = self.tokens.get(index + 1).map(|(_, token, _)| token);
+        if self.is_as(index) || index.checked_sub(1).is_some_and(|i| self.is_as(i)) {
+            return true;
+        }
+        if self.is_one_of(index) || index.checked_sub(1).is_some_and(|i| self.is_one_of(i)) {
+            return true;
+        }
+        // for x in xs 中的 in
+        if index.checked_sub(2).map(|i| &self.tokens[i].1) == Some(&Token::KeywordFor) {
+            return true;
+        }
+        matches!(
+            previous,
+            Some(
+                Token::KeywordForm
+                    | Token::KeywordOn
+                    | Token::KeywordState
+                    | Token::KeywordGoto
+                    | Token::KeywordStore
+                    | Token::Dot
+                    | Token::At
+            )
+        ) || matches!(
+            next,
+            Some(
+                Token::Number(_)
+                    | Token::StringContent(_)
+                    | Token::LParen
+                    | Token::Dot
+                    | Token::Colon
+            )
+        )
+    }
+
+    /* index处是否为 input x as number、slot x as number 与 import "..." as m 中的 as */
+    fn is_as(&self, index: usize) -> bool {
+        let previous = index.checked_sub(1).map(|i| &self.tokens[i].1);
+        matches!(&self.tokens[index].1, Token::Identifier(word) if word.eq_ignore_ascii_case("as"))
+            && matches!(
+                previous,
+                Some(Token::Identifier(_) | Token::StringContent(_))
+            )
+            && matches!(
+                self.tokens.get(index + 1),
+                Some((_, Token::Identifier(_), _))
+            )
+    }
+
+    /* index处是否为 speak one of 中的 one */
+    fn is_one_of(&self, index: usize) -> bool {
+        let word = |token: Option<&(usize, Token, usize)>, expected: &str| matches!(token, Some((_, Token::Identifier(word), _)) if word.eq_ignore_ascii_case(expected));
+        index.checked_sub(1).map(|i| &self.tokens[i].1) == Some(&Token::KeywordSpeak)
+            && word(self.tokens.get(index), "one")
+            && word(self.tokens.get(index + 1), "of")
+    }
+
+    /* 脚本中用到的所有消息的键及其位置，@menu.title 的键为 menu.title */
+    pub fn message_keys(&self) -> Vec<(usize, String, usize)> {
+        let mut keys = Vec::new();
+        for (index, (_, token, _)) in self.tokens.iter().enumerate() {
+            if *token != Token::At {
+                continue;
+            }
+            let (start, mut key, mut end) = match self.tokens.get(index + 1) {
+                Some((start, Token::Identifier(name), end)) => (*start, name.clone(), *end),
+                _ => continue,
+            };
+            if let [(_, Token::Dot, _), (_, Token::Identifier(name), name_end), ..] =
+                &self.tokens[index + 2..]
+            {
+                key = format!("{}.{}", key, name);
+                end = *name_end;
+            }
+            keys.push((start, key, end));
+        }
+        keys
+    }
+
+    /* 按出现顺序列出所有变量声明 */
+    pub fn declarations(&self) -> Vec<Declaration> {
+        let mut declarations = Vec::new();
+        for (index, (start, token, end)) in self.tokens.iter().enumerate() {
+            let previous = index.checked_sub(1).map(|i| &self.tokens[i].1);
+            let name = match token {
+                Token::Identifier(name) => name,
+                Token::StringContent(source) if previous == Some(&Token::Tilde) => {
+                    // 无法编译的正则已经作为语法错误报告
+                    if let Ok(regex) = Regex::new(source) {
+                        for name in regex.capture_names().flatten() {
+                            declarations.push(Declaration {
+                                name: name.to_string(),
+                                kind: DeclarationKind::Capture,
+                                start: *start,
+                                end: *end,
+                                line: self.line_at(*start).trim().to_string(),
+                            });
+                        }
+                    }
+                    continue;
+                }
+                _ => continue,
+            };
+            let kind = match previous {
+                Some(Token::KeywordGlobal) => DeclarationKind::Global,
+                Some(Token::KeywordLocal) => DeclarationKind::Local,
+                Some(Token::KeywordInput) => DeclarationKind::Input,
+                Some(Token::KeywordSlot) => DeclarationKind::Slot,
+                Some(Token::KeywordFor) => DeclarationKind::For,
+                // store中的键不是变量
+                Some(Token::KeywordStore | Token::Dot) => continue,
+                _ if matches!(self.tokens.get(index + 1), Some((_, Token::Assign, _))) => {
+                    DeclarationKind::Assign
+                }
+                _ => continue,
+            };
+            declarations.push(Declaration {
+                name: name.clone(),
+                kind,
+                start: *start,
+                end: *end,
+                line: self.line_at(*start).trim().to_string(),
+            });
+        }
+        declarations
+    }
+
+    fn line_at(&self, offset: usize) -> &str {
+        let line_start = self.source[..offset].rfind('\n').map_or(0, |pos| pos + 1);
+        let line_end = self.source[offset..]
+            .find('\n')
+            .map_or(self.source.len(), |len| offset + len);
+        &self.source[line_start..line_end]
+    }
+
+    /* offset处的变量名 */
+    pub fn identifier_at(&self, offset: usize) -> Option<(usize, &str, usize)> {
+        self.identifiers()
+            .find(|(start, _, end)| *start <= offset && offset <= *end)
+    }
+
+    /* 变量的定义：优先取global声明，否则取第一次赋值或输入 */
+    pub fn definition(&self, offset: usize) -> Option<Declaration> {
+        let (_, name, _) = self.identifier_at(offset)?;
+        let declarations = self.declarations();
+        let mut candidates = declarations.into_iter().filter(|decl| decl.name == name);
+        let first = candidates.next()?;
+        if first.kind == DeclarationKind::Global {
+            return Some(first);
+        }
+        Some(
+            candidates
+                .find(|decl| decl.kind == DeclarationKind::Global)
+                .unwrap_or(first),
+        )
+    }
+
+    /* 悬停提示：显示变量的声明 */
+    pub fn hover(&self, offset: usize) -> Option<String> {
+        let decl = self.definition(offset)?;
+        Some(decl.line)
+    }
+
+    /* 补全候选：所有关键字以及文档中声明过的变量 */
+    pub fn completions(&self) -> (Vec<&'static str>, Vec<String>) {
+        let mut variables: Vec<String> = Vec::new();
+        for decl in self.declarations() {
+            if !variables.contains(&decl.name) {
+                variables.push(decl.name);
+            }
+        }
+        (KEYWORDS.to_vec(), variables)
+    }
+
+    /* 文档大纲：loop、for、if、match及其分支、表单、对话状态以及独立代码块，按嵌套关系组织 */
+    pub fn symbols(&self) -> Vec<BlockSymbol> {
+        let mut roots = Vec::new();
+        let mut stack: Vec<BlockSymbol> = Vec::new();
+        // 尚未闭合的映射字面量的层数，映射的花括号不是代码块
+        let mut maps = 0;
+        // 尚未遇到 '{' 的代码块开头：(名称, 起始位置, 关键字结束位置)
+        let mut header: Option<(String, usize, usize)> = None;
+        for (index, (start, token, end)) in self.tokens.iter().enumerate() {
+            match token {
+                Token::KeywordLoop => header = Some(("loop".to_string(), *start, *end)),
+                Token::KeywordFor => {
+                    let name = match self.tokens.get(index + 1) {
+                        Some((_, Token::Identifier(name), _)) => format!("for {}", name),
+                        _ => "for".to_string(),
+                    };
+                    header = Some((name, *start, *end));
+                }
+                Token::LBracket if maps > 0 || self.is_map(index) => maps += 1,
+                Token::RBracket if maps > 0 => maps -= 1,
+                Token::KeywordMatch => header = Some(("match".to_string(), *start, *end)),
+                Token::KeywordDefault => header = Some(("default".to_string(), *start, *end)),
+                Token::KeywordElse => header = Some(("else".to_string(), *start, *end)),
+                Token::KeywordForm => {
+                    let name = match self.tokens.get(index + 1) {
+                        Some((_, Token::Identifier(name), _)) => format!("form {}", name),
+                        _ => "form".to_string(),
+                    };
+                    header = Some((name, *start, *end));
+                }
+                Token::KeywordState => {
+                    let name = match self.tokens.get(index + 1) {
+                        Some((_, Token::Identifier(name), _)) => format!("state {}", name),
+                        _ => "state".to_string(),
+                    };
+                    header = Some((name, *start, *end));
+                }
+                Token::KeywordOn => {
+                    let name = match (self.tokens.get(index + 1), self.tokens.get(index + 2)) {
+                        (Some((_, Token::Identifier(word), _)), _)
+                            if word.eq_ignore_ascii_case("enter") =>
+                        {
+                            "on enter".to_string()
+                        }
+                        (
+                            Some((_, Token::KeywordInput, _)),
+                            Some((_, Token::Identifier(name), _)),
+                        ) => {
+                            format!("on input {}", name)
+                        }
+                        _ => "on".to_string(),
+                    };
+                    header = Some((name, *start, *end));
+                }
+                Token::KeywordIntent => {
+                    let name = match self.tokens.get(index + 1) {
+                        Some((_, Token::StringContent(name), _)) => format!("intent \"{}\"", name),
+                        _ => "intent".to_string(),
+                    };
+                    header = Some((name, *start, *end));
+                }
+                Token::KeywordIf => {
+                    let name = match self.condition_after(index) {
+                        Some(condition) => format!("if ({})", condition),
+                        None => "if".to_string(),
+                    };
+                    header = Some((name, *start, *end));
+                }
+                Token::LBracket => {
+                    let (name, block_start, name_end) =
+                        header.take().unwrap_or(("{}".to_string(), *start, *end));
+                    stack.push(BlockSymbol {
+                        name,
+                        start: block_start,
+                        end: *end,
+                        name_start: block_start,
+                        name_end,
+                        children: Vec::new(),
+                    });
+                }
+                Token::RBracket => {
+                    if let Some(mut symbol) = stack.pop() {
+                        symbol.end = *end;
+                        match stack.last_mut() {
+                            Some(parent) => parent.children.push(symbol),
+                            None => roots.push(symbol),
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        // 未闭合的代码块延伸到文件末尾
+        while let Some(mut symbol) = stack.pop() {
+            symbol.end = self.source.len();
+            match stack.last_mut() {
+                Some(parent) => parent.children.push(symbol),
+                None => roots.push(symbol),
+            }
+        }
+        roots
+    }
+
+    /* index处的 '{' 是否为映射字面量的开始，映射至少有一项且以 "键": 开头 */
+    fn is_map(&self, index: usize) -> bool {
+        matches!(
+            (self.tokens.get(index + 1), self.tokens.get(index + 2)),
+            (
+                Some((_, Token::StringContent(_), _)),
+                Some((_, Token::Colon, _))
+            )
+        )
+    }
+
+    /* if 关键字后面括号中的条件在源码中的写法 */
+    fn condition_after(&self, index: usize) -> Option<&str> {
+        let (_, open, open_end) = self.tokens.get(index + 1)?;
+        if *open != Token::LParen {
+            return None;
+        }
+        let mut depth = 0;
+        for (start, token, _) in &self.tokens[index + 1..] {
+            match token {
+                Token::LParen => depth += 1,
+                Token::RParen => {
+                    depth -= 1;
+                    if depth == 0 {
+                        return Some(self.source[*open_end..*start].trim());
+                    }
+                }
+                _ => {}
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SOURCE: &str = "global bill = 0;\nloop {\n    input str;\n    if (str == \"r\") {\n        bill = bill + x;\n    };\n}\n";
+
+    #[test]
+    fn test_diagnostics() {
+        let document = Document::new(SOURCE);
+        let diagnostics = document.diagnostics();
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].severity, Severity::Warning);
+        assert_eq!(&SOURCE[diagnostics[0].start..diagnostics[0].end], "x");
+
+        let document = Document::new("speak \"a\"\nexit;");
+        let diagnostics = document.diagnostics();
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].severity, Severity::Error);
+        assert_eq!(
+            &document.source()[diagnostics[0].start..diagnostics[0].end],
+            "exit"
+        );
+
+        // 无法编译的正则表达式在解析时报告
+        let source = "match input { intent \"x\": [regex \"(\"] => {} }";
+        let diagnostics = Document::new(source).diagnostics();
+        assert_eq!(diagnostics.len(), 1);
+        assert!(diagnostics[0].message.starts_with("Invalid regex"));
+        assert_eq!(&source[diagnostics[0].start..diagnostics[0].end], "\"(\"");
+
+        // 正则中的命名分组视为变量声明
+        let source = "input s; if (s ~ \"^(?P<id>[0-9]+)$\") { speak id; };";
+        let document = Document::new(source);
+        assert!(document.diagnostics().is_empty());
+        let decl = document.definition(source.rfind("id").unwrap()).unwrap();
+        assert_eq!(decl.kind, DeclarationKind::Capture);
+
+        // input语句中的类型名与max、表单名、函数名都不是变量，槽位是变量声明
+        let source = "input n as number max 3; form f { slot s prompt upper(n); } speak s;";
+        let document = Document::new(source);
+        assert!(document.diagnostics().is_empty());
+        let decl = document.definition(source.rfind('s').unwrap()).unwrap();
+        assert_eq!(decl.kind, DeclarationKind::Slot);
+        assert_eq!(document.symbols()[0].name, "form f");
+        // transfer 只在语句开头是关键字，可以用作表单名
+        let document = Document::new("form transfer { slot s prompt \"?\"; } transfer s;");
+        assert!(document.diagnostics().is_empty());
+        assert_eq!(document.symbols()[0].name, "form transfer");
+
+        // store中的键不是变量
+        let source = "store bill = 0; store.bill = store.bill + 1; speak store.total;";
+        let document = Document::new(source);
+        assert!(document.diagnostics().is_empty());
+        assert!(document.declarations().is_empty());
+
+        // 消息的键与参数名不是变量，参数的值与简写的参数是变量
+        let source = "input name; speak @greeting(name, count: total); speak @menu.title;";
+        let document = Document::new(source);
+        let diagnostics = document.diagnostics();
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(&source[diagnostics[0].start..diagnostics[0].end], "total");
+        let keys: Vec<(&str, String)> = document
+            .message_keys()
+            .into_iter()
+            .map(|(start, key, end)| (&source[start..end], key))
+            .collect();
+        assert_eq!(
+            keys,
+            vec![
+                ("greeting", "greeting".to_string()),
+                ("menu.title", "menu.title".to_string())
+            ]
+        );
+
+        // 导入的模块中的名字在另一个文件中声明
+        let source = std::fs::read_to_string("examples/shop.dsl").unwrap();
+        assert!(Document::new(&source).diagnostics().is_empty());
+        for (source, message) in [
+            ("input n as money;", "Unknown input type `money`"),
+            (
+                "input n as number max 0;",
+                "Attempts 0 must be a positive integer",
+            ),
+            ("input n as number most 3;", "Unexpected `most`"),
+            (
+                "form f { slot a prompt \"?\"; slot a prompt \"!\"; }",
+                "Duplicate slot `a`",
+            ),
+            ("speak length(\"a\");", "Unknown function `length`"),
+            ("speak len();", "Function `len` takes 1 argument, got 0"),
+        ] {
+            let diagnostics = Document::new(source).diagnostics();
+            assert_eq!(diagnostics.len(), 1, "{}", source);
+            assert!(diagnostics[0].message.starts_with(message), "{}", source);
+        }
+    }
+
+    #[test]
+    fn test_definition_and_hover() {
+        let document = Document::new(SOURCE);
+        let usage = SOURCE.rfind("bill").unwrap();
+        let decl = document.definition(usage).unwrap();
+        assert_eq!(decl.kind, DeclarationKind::Global);
+        assert_eq!(decl.start, SOURCE.find("bill").unwrap());
+        assert_eq!(document.hover(usage).unwrap(), "global bill = 0;");
+
+        let usage = SOURCE.find("str ==").unwrap();
+        assert_eq!(
+            document.definition(usage).unwrap().kind,
+            DeclarationKind::Input
+        );
+    }
+
+    #[test]
+    fn test_symbols_and_completions() {
+        let document = Document::new(SOURCE);
+        let symbols = document.symbols();
+        assert_eq!(symbols.len(), 1);
+        assert_eq!(symbols[0].name, "loop");
+        assert_eq!(symbols[0].children[0].name, "if (str == \"r\")");
+        assert_eq!(
+            &SOURCE[symbols[0].start..symbols[0].end],
+            &SOURCE[17..SOURCE.len() - 1]
+        );
+
+        let (keywords, variables) = document.completions();
+        assert!(keywords.contains(&"speak"));
+        assert_eq!(variables, vec!["bill", "str"]);
+    }
+
+    #[test]
+    fn test_states() {
+        let source = "goto menu; state menu { on enter { speak \"?\"; } on input text { goto Menu; } }\nstate menu {}";
+        let document = Document::new(source);
+        let diagnostics = document.diagnostics();
+        let messages: Vec<&str> = diagnostics.iter().map(|d| d.message.as_str()).collect();
+        assert_eq!(
+            messages,
+            vec![
+                "State 'menu' is declared twice.",
+                "State 'Menu' is not declared."
+            ]
+        );
+        assert_eq!(&source[diagnostics[1].start..diagnostics[1].end], "Menu");
+        // on input 的变量是声明
+        let decl = document.definition(source.find("text").unwrap()).unwrap();
+        assert_eq!(decl.kind, DeclarationKind::Input);
+
+        let symbols = document.symbols();
+        assert_eq!(symbols[0].name, "state menu");
+        let children: Vec<&str> = symbols[0]
+            .children
+            .iter()
+            .map(|s| s.name.as_str())
+            .collect();
+        assert_eq!(children, vec!["on enter", "on input text"]);
+
+        // for的循环变量是声明，映射字面量的括号不是代码块
+        let source = "global m = {\"a\": 1};\nfor key in m {\n    speak m[key];\n}";
+        let document = Document::new(source);
+        assert!(document.diagnostics().is_empty());
+        let decl = document.definition(source.rfind("key").unwrap()).unwrap();
+        assert_eq!(decl.kind, DeclarationKind::For);
+        let symbols = document.symbols();
+        assert_eq!(symbols.len(), 1);
+        assert_eq!(symbols[0].name, "for key");
+    }
+}
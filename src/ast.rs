@@ -1,4 +1,7 @@
 use crate::tokens::Token;
+use crate::value::{Map, Value};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
 use std::fmt;
 /*
  * 语法树中表达式的枚举类型，方便递归下降分析
@@ -7,9 +10,16 @@ use std::fmt;
  * - 二元表达式
  * - 字面量
  * - 赋值语句
+ * - 正则匹配
+ * - 内置函数调用
+ * - 列表与映射字面量
+ * - 下标访问
+ * - 读写持久化存储
+ * - 多语言消息
  */
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
 pub enum Expr {
     /* 赋值表达式 example: a=b+c */
     Assign {
@@ -19,6 +29,7 @@ pub enum Expr {
     /* 二元表达式 example: b+c */
     Binary {
         left: Box<Expr>,
+        #[serde(with = "operator_serde")]
         operator: Token,
         right: Box<Expr>,
     },
@@ -30,26 +41,99 @@ pub enum Expr {
     Variable {
         name: String,
     },
+    /* 正则匹配 example: phone ~ "^1[0-9]{10}$"
+     * 匹配成功时，正则中的命名分组 (?P<name>...) 写入同名变量 */
+    RegexMatch {
+        subject: Box<Expr>,
+        regex: Regex,
+    },
+    /* 内置函数调用 example: len(name)，可用的函数见builtins.rs */
+    Call {
+        name: String,
+        args: Vec<Box<Expr>>,
+    },
+    /* 列表字面量 example: ["a", "b"] */
+    List {
+        items: Vec<Box<Expr>>,
+    },
+    /* 映射字面量 example: {"a": "账户", "e": "退出"}，键只能是字符串 */
+    Map {
+        entries: Vec<(String, Box<Expr>)>,
+    },
+    /* 下标访问 example: items[0]、menu["a"] */
+    Index {
+        target: Box<Expr>,
+        index: Box<Expr>,
+    },
+    /* 读取当前用户保存的值 example: store.balance */
+    Store {
+        key: String,
+    },
+    /* 写入当前用户的存储 example: store.balance = store.balance + 100 */
+    StoreAssign {
+        key: String,
+        value: Box<Expr>,
+    },
+    /* 按当前语言在消息目录中查找的文本 example: @greeting(name)、@balance(amount: bill)
+     * 参数替换消息中的 {名字}，见catalog.rs */
+    Message {
+        key: String,
+        args: Vec<(String, Box<Expr>)>,
+    },
 }
 
 /*
  * 字面量枚举类型，作为表达式中的字面量类型使用
  * 共有如下类型：
- * - 数字
+ * - 数字，以十进制精确表示，0.1 + 0.2 的结果为 0.3
  * - 字符串
+ * - 列表与映射，只作为表达式求值的结果出现，源码中的字面量见Expr::List与Expr::Map
  */
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", content = "value", rename_all = "snake_case")]
 pub enum LiteralValue {
-    Number(f64),
+    /* 序列化为JSON中的字符串，例如 "0.1"，避免经过浮点数丢失精度 */
+    Number(#[serde(with = "rust_decimal::serde::str")] Decimal),
     String(String),
+    List(Vec<Value>),
+    Map(Map),
+}
+
+/* 数字转为字符串时去掉末尾多余的0，例如 1.50 * 2 输出 3 */
+pub fn number_to_string(n: Decimal) -> String {
+    n.normalize().to_string()
 }
 
 impl LiteralValue {
     pub fn trans(&self) -> String {
         match self {
-            LiteralValue::Number(n) => n.to_string(),
+            LiteralValue::Number(n) => number_to_string(*n),
             LiteralValue::String(s) => s.clone(),
+            LiteralValue::List(items) => Value::List(items.clone()).to_string(),
+            LiteralValue::Map(entries) => Value::Map(entries.clone()).to_string(),
+        }
+    }
+}
+
+/* 写入变量时数字转为字符串 */
+impl From<LiteralValue> for Value {
+    fn from(value: LiteralValue) -> Self {
+        match value {
+            LiteralValue::Number(n) => Value::String(number_to_string(n)),
+            LiteralValue::String(s) => Value::String(s),
+            LiteralValue::List(items) => Value::List(items),
+            LiteralValue::Map(entries) => Value::Map(entries),
+        }
+    }
+}
+
+impl From<Value> for LiteralValue {
+    fn from(value: Value) -> Self {
+        match value {
+            Value::String(s) => LiteralValue::String(s),
+            Value::List(items) => LiteralValue::List(items),
+            Value::Map(entries) => LiteralValue::Map(entries),
         }
     }
 }
@@ -59,13 +143,165 @@ impl fmt::Display for LiteralValue {
         match self {
             LiteralValue::Number(n) => write!(f, "{}", n),
             LiteralValue::String(s) => write!(f, "{}", s),
+            LiteralValue::List(_) | LiteralValue::Map(_) => write!(f, "{}", self.trans()),
         }
     }
 }
 
+impl Expr {
+    /* 表达式的优先级，与grammar.lalrpop中的precedence保持一致，数字越小结合越紧 */
+    fn precedence(&self) -> u8 {
+        match self {
+            Expr::Literal { .. }
+            | Expr::Variable { .. }
+            | Expr::Call { .. }
+            | Expr::List { .. }
+            | Expr::Map { .. }
+            | Expr::Index { .. }
+            | Expr::Store { .. }
+            | Expr::Message { .. } => 1,
+            Expr::Binary { operator, .. } => match operator {
+                Token::OperatorPow => 2,
+                Token::OperatorMul | Token::OperatorDiv | Token::OperatorMod => 3,
+                Token::OperatorAdd | Token::OperatorSub => 4,
+                _ => 5,
+            },
+            Expr::RegexMatch { .. } => 5,
+            Expr::Assign { .. } | Expr::StoreAssign { .. } => 6,
+        }
+    }
+}
+
+/* 运算符在源码中的写法 */
+pub fn operator_symbol(operator: &Token) -> &'static str {
+    match operator {
+        Token::OperatorAdd => "+",
+        Token::OperatorSub => "-",
+        Token::OperatorMul => "*",
+        Token::OperatorDiv => "/",
+        Token::OperatorMod => "%",
+        Token::OperatorPow => "^",
+        Token::WEqual => "==",
+        Token::BangEqual => "!=",
+        Token::Assign => "=",
+        _ => "?",
+    }
+}
+
+/* operator_symbol的逆运算 */
+pub fn operator_from_symbol(symbol: &str) -> Option<Token> {
+    match symbol {
+        "+" => Some(Token::OperatorAdd),
+        "-" => Some(Token::OperatorSub),
+        "*" => Some(Token::OperatorMul),
+        "/" => Some(Token::OperatorDiv),
+        "%" => Some(Token::OperatorMod),
+        "^" => Some(Token::OperatorPow),
+        "==" => Some(Token::WEqual),
+        "!=" => Some(Token::BangEqual),
+        "=" => Some(Token::Assign),
+        _ => None,
+    }
+}
+
+/* 序列化时运算符以源码写法保存，例如 "+"、"==" */
+mod operator_serde {
+    use super::{operator_from_symbol, operator_symbol};
+    use crate::tokens::Token;
+    use serde::{de, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(operator: &Token, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(operator_symbol(operator))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Token, D::Error> {
+        let symbol = String::deserialize(deserializer)?;
+        operator_from_symbol(&symbol)
+            .ok_or_else(|| de::Error::custom(format!("unknown operator `{}`", symbol)))
+    }
+}
+
+/*
+ * 将表达式还原为源码形式，例如 str=="b" 还原为 str == "b"
+ * 只在必要时添加括号
+ */
 impl fmt::Display for Expr {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{:?}", self)
+        match self {
+            Expr::Assign { name, value } => write!(f, "{} = {}", name, value),
+            Expr::StoreAssign { key, value } => write!(f, "store.{} = {}", key, value),
+            Expr::Binary {
+                left,
+                operator,
+                right,
+            } => {
+                let level = self.precedence();
+                // 左结合：左侧同级不需要括号，右侧同级需要；乘方为右结合，正好相反
+                let right_assoc = *operator == Token::OperatorPow;
+                if left.precedence() > level || (right_assoc && left.precedence() == level) {
+                    write!(f, "({})", left)?;
+                } else {
+                    write!(f, "{}", left)?;
+                }
+                write!(f, " {} ", operator_symbol(operator))?;
+                if right.precedence() > level || (!right_assoc && right.precedence() == level) {
+                    write!(f, "({})", right)
+                } else {
+                    write!(f, "{}", right)
+                }
+            }
+            Expr::Literal {
+                value: LiteralValue::String(s),
+            } => write!(f, "\"{}\"", s),
+            Expr::Literal {
+                value: LiteralValue::Number(n),
+            } => write!(f, "{}", n),
+            Expr::Literal { value } => write!(f, "{}", value),
+            Expr::Variable { name } => write!(f, "{}", name),
+            Expr::RegexMatch { subject, regex } => {
+                if subject.precedence() > self.precedence() {
+                    write!(f, "({})", subject)?;
+                } else {
+                    write!(f, "{}", subject)?;
+                }
+                write!(f, " ~ \"{}\"", regex.as_str())
+            }
+            Expr::Call { name, args } => {
+                let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+                write!(f, "{}({})", name, args.join(", "))
+            }
+            Expr::List { items } => {
+                let items: Vec<String> = items.iter().map(|item| item.to_string()).collect();
+                write!(f, "[{}]", items.join(", "))
+            }
+            Expr::Map { entries } => {
+                let entries: Vec<String> = entries
+                    .iter()
+                    .map(|(key, value)| format!("\"{}\": {}", key, value))
+                    .collect();
+                write!(f, "{{{}}}", entries.join(", "))
+            }
+            Expr::Index { target, index } => {
+                if target.precedence() > self.precedence() {
+                    write!(f, "({})[{}]", target, index)
+                } else {
+                    write!(f, "{}[{}]", target, index)
+                }
+            }
+            Expr::Store { key } => write!(f, "store.{}", key),
+            Expr::Message { key, args } if args.is_empty() => write!(f, "@{}", key),
+            Expr::Message { key, args } => {
+                // 与参数同名的变量只写变量名
+                let args: Vec<String> = args
+                    .iter()
+                    .map(|(name, value)| match &**value {
+                        Expr::Variable { name: variable } if variable == name => name.clone(),
+                        _ => format!("{}: {}", name, value),
+                    })
+                    .collect();
+                write!(f, "@{}({})", key, args.join(", "))
+            }
+        }
     }
 }
 
@@ -80,9 +316,23 @@ impl fmt::Display for Expr {
  * - 条件语句(if (expr) func();)
  * - 函数声明语句(fn id(){code})
  * - 退出语句(exit;)
+ * - 意图匹配语句(match input { intent "x": [...] => {...} default => {...} })
+ * - 带类型校验的输入语句(input x as number prompt "..." retry "..." max 3 else {...};)
+ * - 表单语句(form name { slot x as number prompt "..."; confirm "..."; })
+ * - 对话状态声明(state name { on enter {...} on input x {...} })，只能出现在顶层
+ * - 状态转移语句(goto name;)
+ * - 遍历语句(for item in list { code })，遍历映射时依次给出键
+ * - 存储声明语句(store key = value;)
+ * - 导入语句(import "common/greetings.dsl";)，只能出现在顶层，由module.rs在执行前展开
+ * - 切换语言语句(locale "en";)
+ * - 转人工语句(transfer "reason";)
  */
 
-#[derive(Debug, Clone, PartialEq)]
+/* 整个程序，即顶层语句的列表，parser、模块加载器与JSON加载都产出这种形式 */
+pub type Program = Vec<Box<Statement>>;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
 pub enum Statement {
     /* 块语句 */
     Block {
@@ -107,6 +357,18 @@ pub enum Statement {
     Speak {
         expression: Box<Expr>,
     },
+    /* 从列表中随机选出一项输出，例如 speak one of ["您好", "欢迎"]; */
+    SpeakOneOf {
+        options: Box<Expr>,
+    },
+    /* 切换之后 @消息 使用的语言，例如 locale "en"; */
+    Locale {
+        locale: Box<Expr>,
+    },
+    /* 暂停机器人并转给人工客服，例如 transfer "用户要求人工服务"; */
+    Transfer {
+        reason: Box<Expr>,
+    },
     /* 输入字符串语句 */
     Input {
         /* 输入字符串语句中的变量名 */
@@ -119,6 +381,241 @@ pub enum Statement {
         /* 变量声明语句中的变量值 */
         init: Box<Expr>,
     },
+    /* 局部变量声明语句，变量只在当前代码块内可见 */
+    Local {
+        name: String,
+        init: Box<Expr>,
+    },
+    /* 持久化存储的声明，当前用户还没有保存过该键时写入初始值 */
+    Store {
+        key: String,
+        init: Box<Expr>,
+    },
     /// 退出语句
     Exit,
+    /* 意图匹配语句，subject为None时读取一行新的输入进行匹配 */
+    Match {
+        subject: Option<Box<Expr>>,
+        /* 置信度阈值，缺省时使用intent::DEFAULT_THRESHOLD */
+        threshold: Option<f64>,
+        arms: Vec<IntentArm>,
+        /* 没有意图达到阈值时执行 */
+        default: Option<Box<Statement>>,
+    },
+    /* 带类型校验的输入语句，输入不合法时重新提示，直到合法或尝试次数用完 */
+    TypedInput {
+        name: String,
+        input_type: InputType,
+        /* 开始输入前输出的提示 */
+        prompt: Option<Box<Expr>>,
+        /* 输入不合法时输出的提示，缺省时重复prompt */
+        retry: Option<Box<Expr>>,
+        /* 最多尝试的次数，缺省时不限次数 */
+        max: Option<u32>,
+        /* 尝试次数用完时执行，此时变量不会被赋值 */
+        fallback: Option<Box<Statement>>,
+    },
+    /* 表单：依次询问尚未填写的槽位，全部填写后可以请用户确认 */
+    Form {
+        name: String,
+        slots: Vec<Slot>,
+        /* 全部填写后输出的确认提示，用户回答是/否 */
+        confirm: Option<Box<Expr>>,
+    },
+    /* 对话状态，通过goto进入：先执行enter，之后每收到一条消息执行一次handler */
+    State {
+        name: String,
+        enter: Option<Box<Statement>>,
+        handler: Option<InputHandler>,
+    },
+    /* 离开当前正在执行的语句，进入另一个对话状态 */
+    Goto {
+        state: String,
+    },
+    /* 导入另一个脚本，path相对于当前文件；
+     * 模块中的全局变量与对话状态以 alias(缺省为文件名).名字 的形式访问 */
+    Import {
+        path: String,
+        alias: Option<String>,
+    },
+    /* 对列表或映射中的每一项执行一次循环体，循环开始时求出iterable的值 */
+    For {
+        variable: String,
+        iterable: Box<Expr>,
+        body: Box<Statement>,
+    },
+}
+
+impl Statement {
+    /* 直接包含的语句：代码块中的语句、循环体、分支、输入语句的else、match的分支与对话状态的代码块 */
+    pub fn children(&self) -> Vec<&Statement> {
+        match self {
+            Statement::Block { statements } => statements.iter().map(|s| &**s).collect(),
+            Statement::Loop { body } | Statement::For { body, .. } => vec![body],
+            Statement::Branch { then, .. } => vec![then],
+            Statement::TypedInput { fallback, .. } => fallback.iter().map(|s| &**s).collect(),
+            Statement::Match { arms, default, .. } => arms
+                .iter()
+                .map(|arm| &*arm.body)
+                .chain(default.as_deref())
+                .collect(),
+            Statement::State { enter, handler, .. } => enter
+                .as_deref()
+                .into_iter()
+                .chain(handler.as_ref().map(|handler| &*handler.body))
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    /* 这条语句本身或者其中任意一层的语句是否满足条件 */
+    pub fn contains(&self, predicate: fn(&Statement) -> bool) -> bool {
+        predicate(self)
+            || self
+                .children()
+                .into_iter()
+                .any(|child| child.contains(predicate))
+    }
+}
+
+/* 对话状态的 on input x {...}，收到的消息写入代码块中的局部变量x */
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct InputHandler {
+    pub variable: String,
+    pub body: Box<Statement>,
+}
+
+/* 表单中的一个槽位，填写后写入同名变量 */
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Slot {
+    /* 用户修改槽位时写的名字，例如 "账号=123" */
+    pub name: String,
+    /* 写入的变量，缺省与name相同；导入的模块中为 模块名.名字 */
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub variable: Option<String>,
+    /* 缺省时接受任意非空输入 */
+    pub input_type: Option<InputType>,
+    /* 按类型规范化之后的值还需要匹配的正则 */
+    pub regex: Option<Regex>,
+    pub prompt: Box<Expr>,
+    /* 输入不合法时输出的提示，缺省时重复prompt */
+    pub retry: Option<Box<Expr>>,
+}
+
+impl Slot {
+    pub fn variable(&self) -> &str {
+        self.variable.as_deref().unwrap_or(&self.name)
+    }
+}
+
+impl fmt::Display for Slot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "slot {}", self.name)?;
+        if let Some(input_type) = &self.input_type {
+            write!(f, " as {}", input_type)?;
+        }
+        if let Some(regex) = &self.regex {
+            write!(f, " regex \"{}\"", regex.as_str())?;
+        }
+        write!(f, " prompt {}", self.prompt)?;
+        if let Some(retry) = &self.retry {
+            write!(f, " retry {}", retry)?;
+        }
+        Ok(())
+    }
+}
+
+/* 输入语句可以要求的类型，校验规则见validation.rs */
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum InputType {
+    Number,
+    Integer,
+    /* 是/否，写入变量的值为 True 或 False */
+    YesNo,
+    /* 从给定的选项中选择一个 */
+    Choice(Vec<String>),
+    Date,
+    Phone,
+}
+
+impl fmt::Display for InputType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InputType::Number => write!(f, "number"),
+            InputType::Integer => write!(f, "integer"),
+            InputType::YesNo => write!(f, "yesno"),
+            InputType::Choice(choices) => {
+                let choices: Vec<String> = choices.iter().map(|c| format!("\"{}\"", c)).collect();
+                write!(f, "choice [{}]", choices.join(", "))
+            }
+            InputType::Date => write!(f, "date"),
+            InputType::Phone => write!(f, "phone"),
+        }
+    }
+}
+
+/* match语句中的一个意图分支 */
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct IntentArm {
+    pub name: String,
+    pub patterns: Vec<Pattern>,
+    pub body: Box<Statement>,
+}
+
+/* 意图的匹配规则：关键词(支持模糊匹配)或正则表达式 */
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", content = "value", rename_all = "snake_case")]
+pub enum Pattern {
+    Keyword(String),
+    Regex(Regex),
+}
+
+impl fmt::Display for Pattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Pattern::Keyword(keyword) => write!(f, "\"{}\"", keyword),
+            Pattern::Regex(regex) => write!(f, "regex \"{}\"", regex.as_str()),
+        }
+    }
+}
+
+/*
+ * 在解析时编译好的正则表达式，执行时不再重复编译
+ * 序列化为源码字符串，反序列化时重新编译
+ */
+#[derive(Debug, Clone)]
+pub struct Regex(regex::Regex);
+
+impl Regex {
+    pub fn new(source: &str) -> Result<Self, regex::Error> {
+        regex::Regex::new(source).map(Regex)
+    }
+}
+
+impl std::ops::Deref for Regex {
+    type Target = regex::Regex;
+
+    fn deref(&self) -> &regex::Regex {
+        &self.0
+    }
+}
+
+impl PartialEq for Regex {
+    fn eq(&self, other: &Self) -> bool {
+        self.as_str() == other.as_str()
+    }
+}
+
+impl Serialize for Regex {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for Regex {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let source = String::deserialize(deserializer)?;
+        Regex::new(&source).map_err(serde::de::Error::custom)
+    }
 }
@@ -0,0 +1,721 @@
+use crate::ast::number_to_string;
+use crate::clock::{self, Clock};
+use crate::interpreter::{parse_number, Operand, Outcome};
+use crate::random::Rng;
+use crate::value::Value;
+use chrono::{Datelike, NaiveDate, NaiveDateTime, NaiveTime, TimeDelta, Timelike};
+use rust_decimal::prelude::ToPrimitive;
+use rust_decimal::{Decimal, RoundingStrategy};
+use std::borrow::Cow;
+use std::fmt::Write;
+
+/*
+ * 表达式中可以调用的内置函数，解释器与虚拟机共用
+ * 字符串函数都按字符(而不是字节)处理，中文与英文一样每个字占一位：
+ * - len(s)                     字符数，参数为列表或映射时为元素个数
+ * - upper(s) / lower(s)        转大写/小写
+ * - trim(s)                    去掉首尾空白
+ * - contains(s, sub)           是否包含子串，返回 True/False
+ * - starts_with(s, prefix)     是否以prefix开头，返回 True/False
+ * - replace(s, from, to)       替换所有的from
+ * - split(s, sep[, i])         以sep分割后的第i段(从0开始)，不存在时为空字符串，
+ *                              省略i时返回所有段组成的列表
+ * - substr(s, start[, count])  从第start个字符开始的count个字符，省略count时取到末尾
+ * - to_number(s)               转为数字，无法转换时报错
+ * - to_string(x)               转为字符串
+ * 数值函数的参数必须是数字或可以解析为数字的字符串：
+ * - round(x[, digits])         四舍五入到digits位小数，省略时取整
+ * - floor(x) / ceil(x)         向下/向上取整
+ * - abs(x)                     绝对值
+ * - min(x, y, ...) / max(x, y, ...)  最小值/最大值
+ * - random()                   [0, 1) 之间的随机小数
+ * - random(n) / random(a, b)   [0, n) 或 [a, b) 之间的随机整数，随机数取自会话的发生器
+ * 列表与映射函数：
+ * - push(list, x)              在列表末尾添加x后的新列表，原列表不变
+ * - keys(map)                  映射的所有键组成的列表，按插入的顺序
+ * - has(map, key) / has(list, x)  映射中是否有该键/列表中是否有该项，返回 True/False
+ * 日期与时间函数，时间的写法见clock.rs，now()读取解释器的时钟：
+ * - now()                      当前时间，例如 2024-03-05 14:30:00
+ * - format_date(t, fmt)        按strftime格式输出，例如 "%m月%d日 %H:%M"
+ * - parse_date(s[, fmt])       按fmt解析后写回统一的形式，省略fmt时按默认的写法解析
+ * - weekday(t)                 星期几，星期一为1，星期日为7
+ * - hour(t)                    小时，0到23
+ * - time_between(t, from, to)  t当天的时刻是否在 [from, to) 之间，from与to写作 HH:MM，
+ *                              from晚于to时表示跨过午夜，例如 "22:00" 到 "06:00"
+ * - date_add(t, n, unit)       加上n个单位之后的时间，n可以为负数
+ * - date_diff(a, b, unit)      从a到b经过的整单位数，b早于a时为负数
+ *   单位为 seconds、minutes、hours、days 或 weeks，也可以写单数
+ * 函数名与参数个数在解析时检查。
+ */
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Builtin {
+    Len,
+    Upper,
+    Lower,
+    Trim,
+    Contains,
+    StartsWith,
+    Replace,
+    Split,
+    Substr,
+    ToNumber,
+    ToString,
+    Round,
+    Floor,
+    Ceil,
+    Abs,
+    Min,
+    Max,
+    Random,
+    Push,
+    Keys,
+    Has,
+    Now,
+    FormatDate,
+    ParseDate,
+    Weekday,
+    Hour,
+    TimeBetween,
+    DateAdd,
+    DateDiff,
+}
+
+impl Builtin {
+    pub const ALL: [Builtin; 29] = [
+        Builtin::Len,
+        Builtin::Upper,
+        Builtin::Lower,
+        Builtin::Trim,
+        Builtin::Contains,
+        Builtin::StartsWith,
+        Builtin::Replace,
+        Builtin::Split,
+        Builtin::Substr,
+        Builtin::ToNumber,
+        Builtin::ToString,
+        Builtin::Round,
+        Builtin::Floor,
+        Builtin::Ceil,
+        Builtin::Abs,
+        Builtin::Min,
+        Builtin::Max,
+        Builtin::Random,
+        Builtin::Push,
+        Builtin::Keys,
+        Builtin::Has,
+        Builtin::Now,
+        Builtin::FormatDate,
+        Builtin::ParseDate,
+        Builtin::Weekday,
+        Builtin::Hour,
+        Builtin::TimeBetween,
+        Builtin::DateAdd,
+        Builtin::DateDiff,
+    ];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|builtin| builtin.name() == name)
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Builtin::Len => "len",
+            Builtin::Upper => "upper",
+            Builtin::Lower => "lower",
+            Builtin::Trim => "trim",
+            Builtin::Contains => "contains",
+            Builtin::StartsWith => "starts_with",
+            Builtin::Replace => "replace",
+            Builtin::Split => "split",
+            Builtin::Substr => "substr",
+            Builtin::ToNumber => "to_number",
+            Builtin::ToString => "to_string",
+            Builtin::Round => "round",
+            Builtin::Floor => "floor",
+            Builtin::Ceil => "ceil",
+            Builtin::Abs => "abs",
+            Builtin::Min => "min",
+            Builtin::Max => "max",
+            Builtin::Random => "random",
+            Builtin::Push => "push",
+            Builtin::Keys => "keys",
+            Builtin::Has => "has",
+            Builtin::Now => "now",
+            Builtin::FormatDate => "format_date",
+            Builtin::ParseDate => "parse_date",
+            Builtin::Weekday => "weekday",
+            Builtin::Hour => "hour",
+            Builtin::TimeBetween => "time_between",
+            Builtin::DateAdd => "date_add",
+            Builtin::DateDiff => "date_diff",
+        }
+    }
+
+    /* 最少与最多的参数个数，min与max不限制个数 */
+    pub fn arity(&self) -> (usize, usize) {
+        match self {
+            Builtin::Len
+            | Builtin::Upper
+            | Builtin::Lower
+            | Builtin::Trim
+            | Builtin::ToNumber
+            | Builtin::ToString
+            | Builtin::Floor
+            | Builtin::Ceil
+            | Builtin::Abs
+            | Builtin::Keys
+            | Builtin::Weekday
+            | Builtin::Hour => (1, 1),
+            Builtin::Now => (0, 0),
+            Builtin::Contains
+            | Builtin::StartsWith
+            | Builtin::Push
+            | Builtin::Has
+            | Builtin::FormatDate => (2, 2),
+            Builtin::Replace | Builtin::TimeBetween | Builtin::DateAdd | Builtin::DateDiff => {
+                (3, 3)
+            }
+            Builtin::Split | Builtin::Substr => (2, 3),
+            Builtin::Round | Builtin::ParseDate => (1, 2),
+            Builtin::Random => (0, 2),
+            Builtin::Min | Builtin::Max => (2, usize::MAX),
+        }
+    }
+
+    /* 参数个数不对时的错误信息 */
+    pub fn check_arity(&self, count: usize) -> Result<(), String> {
+        let (min, max) = self.arity();
+        if (min..=max).contains(&count) {
+            return Ok(());
+        }
+        let expected = if min == max {
+            format!("{}", min)
+        } else if max == usize::MAX {
+            format!("at least {}", min)
+        } else {
+            format!("{} to {}", min, max)
+        };
+        Err(format!(
+            "Function `{}` takes {} argument{}, got {}.",
+            self.name(),
+            expected,
+            if min == 1 && max == 1 { "" } else { "s" },
+            count
+        ))
+    }
+
+    /* 按名字查找函数并检查参数个数，供直接加载语法树的场合使用 */
+    pub fn resolve(name: &str, count: usize) -> Result<Self, String> {
+        let builtin =
+            Self::from_name(name).ok_or_else(|| format!("Unknown function `{}`.", name))?;
+        builtin.check_arity(count)?;
+        Ok(builtin)
+    }
+
+    /* 调用函数，参数个数已经在解析时检查 */
+    pub fn call(
+        &self,
+        args: &[Operand],
+        clock: &dyn Clock,
+        rng: &mut Rng,
+    ) -> Result<Outcome, String> {
+        let text = |index: usize| text(args[index]);
+        Ok(match self {
+            Builtin::Len => Outcome::Number(Decimal::from(match args[0] {
+                Operand::List(items) => items.len(),
+                Operand::Map(entries) => entries.len(),
+                _ => text(0).chars().count(),
+            })),
+            Builtin::Upper => Outcome::String(text(0).to_uppercase()),
+            Builtin::Lower => Outcome::String(text(0).to_lowercase()),
+            Builtin::Trim => Outcome::String(text(0).trim().to_string()),
+            Builtin::Contains => Outcome::Bool(text(0).contains(&*text(1))),
+            Builtin::StartsWith => Outcome::Bool(text(0).starts_with(&*text(1))),
+            Builtin::Replace => Outcome::String(text(0).replace(&*text(1), &text(2))),
+            Builtin::Split => {
+                let separator = text(1);
+                if separator.is_empty() {
+                    return Err("The separator of `split` cannot be empty.".to_string());
+                }
+                let source = text(0);
+                let mut parts = source.split(&*separator);
+                match args.get(2) {
+                    Some(index) => {
+                        let part = parts.nth(count(self, *index)?).map(str::to_string);
+                        Outcome::String(part.unwrap_or_default())
+                    }
+                    None => Outcome::List(parts.map(Value::from).collect()),
+                }
+            }
+            Builtin::Substr => {
+                let start = count(self, args[1])?;
+                let source = text(0);
+                let chars = source.chars().skip(start);
+                Outcome::String(match args.get(2) {
+                    Some(length) => chars.take(count(self, *length)?).collect(),
+                    None => chars.collect(),
+                })
+            }
+            Builtin::ToNumber => {
+                let source = text(0);
+                match parse_number(source.trim()) {
+                    Some(n) => Outcome::Number(n),
+                    None => return Err(format!("Cannot convert '{}' to a number.", source)),
+                }
+            }
+            Builtin::ToString => Outcome::String(text(0).into_owned()),
+            Builtin::Round => {
+                let digits = match args.get(1) {
+                    Some(digits) => count(self, *digits)?,
+                    None => 0,
+                };
+                let digits = u32::try_from(digits).map_err(|_| overflow())?;
+                let n = number(self, args[0])?;
+                // 金额按商业习惯四舍五入，而不是银行家舍入
+                Outcome::Number(
+                    n.round_dp_with_strategy(digits, RoundingStrategy::MidpointAwayFromZero),
+                )
+            }
+            Builtin::Floor => Outcome::Number(number(self, args[0])?.floor()),
+            Builtin::Ceil => Outcome::Number(number(self, args[0])?.ceil()),
+            Builtin::Abs => Outcome::Number(number(self, args[0])?.abs()),
+            Builtin::Min | Builtin::Max => {
+                let mut best = number(self, args[0])?;
+                for arg in &args[1..] {
+                    let n = number(self, *arg)?;
+                    if (*self == Builtin::Min && n < best) || (*self == Builtin::Max && n > best) {
+                        best = n;
+                    }
+                }
+                Outcome::Number(best)
+            }
+            Builtin::Random => match *args {
+                [] => Outcome::Number(rng.unit()),
+                [n] => Outcome::Number(Decimal::from(between(self, rng, 0, integer(self, n)?)?)),
+                [low, high] => {
+                    let (low, high) = (integer(self, low)?, integer(self, high)?);
+                    Outcome::Number(Decimal::from(between(self, rng, low, high)?))
+                }
+                _ => unreachable!("参数个数已经检查"),
+            },
+            Builtin::Push => match args[0] {
+                Operand::List(items) => {
+                    let mut items = items.to_vec();
+                    items.push(Value::from(args[1]));
+                    Outcome::List(items)
+                }
+                other => return Err(expected(self, "a list", other)),
+            },
+            Builtin::Keys => match args[0] {
+                Operand::Map(entries) => Outcome::List(
+                    entries
+                        .keys()
+                        .map(|key| Value::from(key.as_str()))
+                        .collect(),
+                ),
+                other => return Err(expected(self, "a map", other)),
+            },
+            Builtin::Has => match args[0] {
+                Operand::Map(entries) => Outcome::Bool(entries.contains_key(&*text(1))),
+                Operand::List(items) => Outcome::Bool(items.contains(&Value::from(args[1]))),
+                other => return Err(expected(self, "a list or a map", other)),
+            },
+            Builtin::Now => Outcome::String(clock::format(clock.now())),
+            Builtin::FormatDate => {
+                let time = time(self, args[0])?;
+                let format = text(1);
+                let mut result = String::new();
+                // 格式中有无法识别或者用不到的占位符(例如时区)时报错
+                write!(result, "{}", time.format(&format))
+                    .map_err(|_| format!("Invalid date format '{}'.", format))?;
+                Outcome::String(result)
+            }
+            Builtin::ParseDate => {
+                let source = text(0);
+                let time = match args.get(1) {
+                    Some(_) => {
+                        let format = text(1);
+                        NaiveDateTime::parse_from_str(source.trim(), &format)
+                            .or_else(|_| {
+                                NaiveDate::parse_from_str(source.trim(), &format)
+                                    .map(|date| date.and_time(NaiveTime::MIN))
+                            })
+                            .ok()
+                    }
+                    None => clock::parse(&source),
+                };
+                match time {
+                    Some(time) => Outcome::String(clock::format(time)),
+                    None => return Err(format!("Cannot convert '{}' to a date.", source)),
+                }
+            }
+            Builtin::Weekday => Outcome::Number(Decimal::from(
+                time(self, args[0])?.weekday().number_from_monday(),
+            )),
+            Builtin::Hour => Outcome::Number(Decimal::from(time(self, args[0])?.hour())),
+            Builtin::TimeBetween => {
+                let time = time(self, args[0])?.time();
+                let (from, to) = (time_of_day(self, args[1])?, time_of_day(self, args[2])?);
+                Outcome::Bool(if from <= to {
+                    from <= time && time < to
+                } else {
+                    from <= time || time < to
+                })
+            }
+            Builtin::DateAdd => {
+                let seconds = integer(self, args[1])?
+                    .checked_mul(unit(self, args[2])?)
+                    .and_then(TimeDelta::try_seconds)
+                    .ok_or_else(overflow)?;
+                let time = time(self, args[0])?
+                    .checked_add_signed(seconds)
+                    .ok_or_else(overflow)?;
+                Outcome::String(clock::format(time))
+            }
+            Builtin::DateDiff => {
+                let elapsed = time(self, args[1])? - time(self, args[0])?;
+                Outcome::Number(Decimal::from(elapsed.num_seconds() / unit(self, args[2])?))
+            }
+        })
+    }
+}
+
+/* 参数的字符串形式，变量中保存的值本身就是字符串，列表与映射取输出时的形式 */
+fn text(operand: Operand) -> Cow<str> {
+    match operand {
+        Operand::Number(n) => Cow::Owned(number_to_string(n)),
+        Operand::String(s) => Cow::Borrowed(s),
+        other => Cow::Owned(other.to_string()),
+    }
+}
+
+/* 参数类型不对时的错误信息 */
+fn expected(builtin: &Builtin, kind: &str, operand: Operand) -> String {
+    format!(
+        "Function `{}` expects {}, got '{}'.",
+        builtin.name(),
+        kind,
+        operand
+    )
+}
+
+/* 作为数字的参数 */
+fn number(builtin: &Builtin, operand: Operand) -> Result<Decimal, String> {
+    let value = match operand {
+        Operand::Number(n) => Some(n),
+        Operand::String(s) => parse_number(s.trim()),
+        _ => None,
+    };
+    value.ok_or_else(|| {
+        format!(
+            "Function `{}` expects a number, got '{}'.",
+            builtin.name(),
+            text(operand)
+        )
+    })
+}
+
+/* 作为下标或长度的参数，必须是非负整数 */
+fn count(builtin: &Builtin, operand: Operand) -> Result<usize, String> {
+    let value = number(builtin, operand).ok();
+    match value {
+        Some(n) if !n.is_sign_negative() && n.fract().is_zero() => {
+            n.to_usize().ok_or_else(overflow)
+        }
+        _ => Err(format!(
+            "Function `{}` expects a non-negative integer, got '{}'.",
+            builtin.name(),
+            text(operand)
+        )),
+    }
+}
+
+/* 作为整数的参数，可以为负数 */
+fn integer(builtin: &Builtin, operand: Operand) -> Result<i64, String> {
+    match number(builtin, operand) {
+        Ok(n) if n.fract().is_zero() => n.to_i64().ok_or_else(overflow),
+        _ => Err(expected(builtin, "an integer", operand)),
+    }
+}
+
+/* [low, high) 之间的随机整数 */
+fn between(builtin: &Builtin, rng: &mut Rng, low: i64, high: i64) -> Result<i64, String> {
+    if low >= high {
+        return Err(format!(
+            "Function `{}` expects a non-empty range, got [{}, {}).",
+            builtin.name(),
+            low,
+            high
+        ));
+    }
+    let span = (high as i128 - low as i128) as u64;
+    Ok((low as i128 + rng.below(span) as i128) as i64)
+}
+
+/* 作为时间的参数 */
+fn time(builtin: &Builtin, operand: Operand) -> Result<NaiveDateTime, String> {
+    clock::parse(&text(operand)).ok_or_else(|| expected(builtin, "a date", operand))
+}
+
+/* 作为一天中时刻的参数 */
+fn time_of_day(builtin: &Builtin, operand: Operand) -> Result<NaiveTime, String> {
+    clock::parse_time(&text(operand)).ok_or_else(|| expected(builtin, "a time of day", operand))
+}
+
+/* 时间单位对应的秒数 */
+fn unit(builtin: &Builtin, operand: Operand) -> Result<i64, String> {
+    let unit = text(operand);
+    Ok(match unit.trim().trim_end_matches('s') {
+        "second" => 1,
+        "minute" => 60,
+        "hour" => 60 * 60,
+        "day" => 24 * 60 * 60,
+        "week" => 7 * 24 * 60 * 60,
+        _ => {
+            return Err(format!(
+                "Function `{}` expects a time unit, got '{}'.",
+                builtin.name(),
+                unit
+            ))
+        }
+    })
+}
+
+fn overflow() -> String {
+    "The result is out of range!".to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::{FixedClock, SystemClock};
+    use crate::value::Map;
+
+    fn call(name: &str, args: &[&str]) -> Result<Outcome, String> {
+        let args: Vec<Operand> = args.iter().map(|arg| Operand::String(arg)).collect();
+        Builtin::from_name(name)
+            .unwrap()
+            .call(&args, &SystemClock, &mut Rng::seeded(0))
+    }
+
+    fn string(value: &str) -> Result<Outcome, String> {
+        Ok(Outcome::String(value.to_string()))
+    }
+
+    #[test]
+    fn test_unicode_strings() {
+        assert_eq!(
+            call("len", &["你好Tom"]),
+            Ok(Outcome::Number(Decimal::from(5)))
+        );
+        assert_eq!(call("upper", &["straße"]), string("STRASSE"));
+        assert_eq!(call("lower", &["ÀB"]), string("àb"));
+        assert_eq!(call("trim", &["\u{3000} 余额 \n"]), string("余额"));
+        assert_eq!(
+            call("contains", &["查询余额", "余额"]),
+            Ok(Outcome::Bool(true))
+        );
+        assert_eq!(
+            call("starts_with", &["查询余额", "余额"]),
+            Ok(Outcome::Bool(false))
+        );
+        assert_eq!(call("replace", &["一二一", "一", "三"]), string("三二三"));
+        assert_eq!(call("substr", &["你好世界", "1", "2"]), string("好世"));
+        assert_eq!(call("substr", &["你好世界", "2"]), string("世界"));
+        assert_eq!(call("substr", &["你好", "5"]), string(""));
+        assert_eq!(call("split", &["张三,李四", ",", "1"]), string("李四"));
+        assert_eq!(call("split", &["张三,李四", ",", "2"]), string(""));
+    }
+
+    #[test]
+    fn test_conversions_and_errors() {
+        assert_eq!(
+            call("to_number", &[" 12.5 "]),
+            Ok(Outcome::Number(Decimal::new(125, 1)))
+        );
+        assert!(call("to_number", &["十二"]).is_err());
+        assert!(call("to_number", &["inf"]).is_err());
+        assert_eq!(
+            Builtin::ToString.call(
+                &[Operand::Number(Decimal::new(300, 2))],
+                &SystemClock,
+                &mut Rng::seeded(0)
+            ),
+            string("3")
+        );
+        assert!(call("substr", &["abc", "-1"]).is_err());
+        assert!(call("split", &["abc", "", "0"]).is_err());
+        assert_eq!(Builtin::from_name("length"), None);
+        assert_eq!(
+            Builtin::Substr.check_arity(1),
+            Err("Function `substr` takes 2 to 3 arguments, got 1.".to_string())
+        );
+        assert!(Builtin::Len.check_arity(1).is_ok());
+    }
+
+    #[test]
+    fn test_collections() {
+        let list = [Value::from("a"), Value::from("b")];
+        let mut map = Map::new();
+        map.insert("k".to_string(), Value::from("v"));
+        let (list, map) = (Operand::List(&list), Operand::Map(&map));
+        assert_eq!(
+            Builtin::Len.call(&[list], &SystemClock, &mut Rng::seeded(0)),
+            Ok(Outcome::Number(Decimal::from(2)))
+        );
+        assert_eq!(
+            Builtin::Push.call(
+                &[list, Operand::String("c")],
+                &SystemClock,
+                &mut Rng::seeded(0)
+            ),
+            Ok(Outcome::List(vec!["a".into(), "b".into(), "c".into()]))
+        );
+        assert_eq!(
+            Builtin::Keys.call(&[map], &SystemClock, &mut Rng::seeded(0)),
+            Ok(Outcome::List(vec!["k".into()]))
+        );
+        assert_eq!(
+            Builtin::Has.call(
+                &[map, Operand::String("v")],
+                &SystemClock,
+                &mut Rng::seeded(0)
+            ),
+            Ok(Outcome::Bool(false))
+        );
+        assert_eq!(
+            Builtin::Has.call(
+                &[list, Operand::String("b")],
+                &SystemClock,
+                &mut Rng::seeded(0)
+            ),
+            Ok(Outcome::Bool(true))
+        );
+        assert_eq!(
+            call("split", &["张三,李四", ","]),
+            Ok(Outcome::List(vec!["张三".into(), "李四".into()]))
+        );
+        assert_eq!(
+            Builtin::Keys.call(&[list], &SystemClock, &mut Rng::seeded(0)),
+            Err("Function `keys` expects a map, got '[\"a\", \"b\"]'.".to_string())
+        );
+        assert!(Builtin::Push
+            .call(&[map, list], &SystemClock, &mut Rng::seeded(0))
+            .is_err());
+        assert!(Builtin::Upper
+            .call(&[list], &SystemClock, &mut Rng::seeded(0))
+            .is_ok());
+    }
+
+    #[test]
+    fn test_random() {
+        let rng = &mut Rng::seeded(1);
+        let random = |args: &[&str], rng: &mut Rng| {
+            let args: Vec<Operand> = args.iter().map(|arg| Operand::String(arg)).collect();
+            match Builtin::Random.call(&args, &SystemClock, rng) {
+                Ok(Outcome::Number(n)) => n,
+                other => panic!("{:?}", other),
+            }
+        };
+        for _ in 0..100 {
+            let n = random(&[], rng);
+            assert!(n >= Decimal::ZERO && n < Decimal::ONE);
+            let n = random(&["3"], rng);
+            assert!(n.is_integer() && n >= Decimal::ZERO && n < Decimal::from(3));
+            let n = random(&["-2", "0"], rng);
+            assert!(n == Decimal::from(-2) || n == Decimal::from(-1));
+        }
+        assert_eq!(
+            call("random", &["5", "5"]),
+            Err("Function `random` expects a non-empty range, got [5, 5).".to_string())
+        );
+        assert!(call("random", &["0.5"]).is_err());
+        assert!(Builtin::Random.check_arity(3).is_err());
+    }
+
+    #[test]
+    fn test_dates() {
+        // 2024-03-05 是星期二
+        let clock = FixedClock::parse("2024-03-05 14:30").unwrap();
+        assert_eq!(
+            Builtin::Now.call(&[], &clock, &mut Rng::seeded(0)),
+            string("2024-03-05 14:30:00")
+        );
+        assert_eq!(
+            call("format_date", &["2024-03-05 14:30", "%m月%d日 %H:%M"]),
+            string("03月05日 14:30")
+        );
+        assert!(call("format_date", &["2024-03-05", "%Z"]).is_err());
+        assert_eq!(
+            call("parse_date", &["05/03/2024 9:30", "%d/%m/%Y %H:%M"]),
+            string("2024-03-05 09:30:00")
+        );
+        assert_eq!(
+            call("parse_date", &["2024年3月5日"]),
+            string("2024-03-05 00:00:00")
+        );
+        assert!(call("parse_date", &["明天"]).is_err());
+        let number = |n: i64| Ok(Outcome::Number(Decimal::from(n)));
+        assert_eq!(call("weekday", &["2024-03-05"]), number(2));
+        assert_eq!(call("weekday", &["2024-03-10"]), number(7));
+        assert_eq!(call("hour", &["2024-03-05 14:30"]), number(14));
+        assert_eq!(
+            call("time_between", &["2024-03-05 14:30", "09:00", "18:00"]),
+            Ok(Outcome::Bool(true))
+        );
+        assert_eq!(
+            call("time_between", &["2024-03-05 18:00", "09:00", "18:00"]),
+            Ok(Outcome::Bool(false))
+        );
+        // 跨过午夜的时段
+        assert_eq!(
+            call("time_between", &["2024-03-05 05:59", "22:00", "06:00"]),
+            Ok(Outcome::Bool(true))
+        );
+        assert_eq!(
+            call("date_add", &["2024-02-28 12:00", "1", "day"]),
+            string("2024-02-29 12:00:00")
+        );
+        assert_eq!(
+            call("date_add", &["2024-02-28 12:00", "-90", "minutes"]),
+            string("2024-02-28 10:30:00")
+        );
+        assert_eq!(
+            call("date_diff", &["2024-03-01", "2024-03-05 14:30", "days"]),
+            number(4)
+        );
+        assert_eq!(
+            call("date_diff", &["2024-03-05 14:30", "2024-03-01", "hours"]),
+            number(-110)
+        );
+        assert!(call("date_add", &["2024-03-05", "1.5", "days"]).is_err());
+        assert_eq!(
+            call("date_add", &["2024-03-05", "1", "month"]),
+            Err("Function `date_add` expects a time unit, got 'month'.".to_string())
+        );
+        assert_eq!(
+            call("hour", &["14:30"]),
+            Err("Function `hour` expects a date, got '14:30'.".to_string())
+        );
+    }
+
+    #[test]
+    fn test_rounding() {
+        let number = |text: &str| Ok(Outcome::Number(text.parse().unwrap()));
+        assert_eq!(call("round", &["2.5"]), number("3"));
+        assert_eq!(call("round", &["-2.5"]), number("-3"));
+        assert_eq!(call("round", &["3.14159", "2"]), number("3.14"));
+        assert_eq!(call("floor", &["-1.5"]), number("-2"));
+        assert_eq!(call("ceil", &["1.01"]), number("2"));
+        assert_eq!(call("abs", &["-0.3"]), number("0.3"));
+        assert_eq!(call("min", &["3", "1.5", "2"]), number("1.5"));
+        assert_eq!(call("max", &["3", "10"]), number("10"));
+        assert!(call("abs", &["abc"]).is_err());
+        assert!(call("round", &["1", "0.5"]).is_err());
+        assert_eq!(
+            Builtin::Max.check_arity(1),
+            Err("Function `max` takes at least 2 arguments, got 1.".to_string())
+        );
+    }
+}
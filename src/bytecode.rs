@@ -0,0 +1,942 @@
+use crate::ast::{Expr, InputType, LiteralValue, Pattern, Regex, Slot, Statement};
+use crate::builtins::Builtin;
+use crate::intent;
+use crate::tokens::Token;
+use crate::value::{Map, Value};
+use rust_decimal::Decimal;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::sync::Arc;
+
+/*
+ * 字节码编译器
+ * 将语法树编译为线性的指令序列，供vm.rs中的栈式虚拟机执行：
+ * - 变量在编译期解析为槽位编号，运行时不再查找HashMap
+ * - 字面量放入常量池，运行时只需复制引用计数指针
+ * - loop、for与if编译为跳转指令，执行时不需要复制语法树
+ * 作用域规则与environment.rs一致：
+ * 全局作用域以及每个代码块中可能创建的变量各自占用一个槽位，
+ * 进入代码块时清空该块的槽位。赋值与input在运行时才能确定写入哪一层作用域，
+ * 因此变量引用在编译期解析为由内向外的候选槽位列表：
+ * 若某一层作用域在此处必然已经定义了该变量，列表到此为止；
+ * 只有一个候选时使用Load/Store，否则使用LoadAny/StoreAny。
+ */
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Instruction {
+    /* 将常量池中的值压栈 */
+    Constant(usize),
+    /* 将变量的值(字符串)压栈 */
+    Load(usize),
+    /* 弹出栈顶并转为字符串存入变量 */
+    Store(usize),
+    /* 弹出两个操作数，压入运算结果 */
+    Binary(BinaryOp),
+    /* 丢弃栈顶 */
+    Pop,
+    /* 弹出栈顶并输出 */
+    Speak,
+    /* 弹出栈顶的列表，随机输出其中的一项 */
+    SpeakOneOf,
+    /* 读取一行输入存入变量 */
+    Input(usize),
+    /* 以下三条指令的参数为Chunk::lookups中的候选槽位列表：
+     * 读取第一个已定义的候选；
+     * 写入第一个已定义的候选，都未定义时写入第一个候选(当前作用域) */
+    LoadAny(usize),
+    StoreAny(usize),
+    InputAny(usize),
+    /* 进入代码块，清空Chunk::scopes中对应的槽位 */
+    EnterScope(usize),
+    /* 读取一行输入压栈，用于 match input */
+    Read,
+    /* 弹出待匹配的文本，按Chunk::matches中的意图识别结果跳转 */
+    Match(usize),
+    /* 弹出待匹配的文本，按Chunk::regexes中的正则匹配，压入True/False并写入命名分组 */
+    RegexMatch(usize),
+    /* 带类型的input：弹出已尝试的次数，读取一行输入并按Chunk::asks中的类型校验，
+     * 合法时写入变量并跳到语句末尾，次数用完时跳到else分支，
+     * 否则压入新的次数，继续执行其后的重新提示 */
+    Ask(usize),
+    /* 以下三条指令的参数为Chunk::forms中的表单：
+     * 跳到第一个尚未填写的槽位的提示，都已填写时跳到确认提示或表单之后；
+     * 弹出正在询问的槽位序号，读取一行输入并填写；
+     * 读取用户对确认提示的回答 */
+    FormNext(usize),
+    FormInput(usize),
+    FormConfirm(usize),
+    /* 进入Chunk::states中的对话状态 */
+    Goto(usize),
+    /* 弹出给定个数的参数，调用内置函数并压入结果 */
+    Call(Builtin, usize),
+    /* 弹出给定个数的元素，压入由它们组成的列表 */
+    List(usize),
+    /* 弹出给定个数的键值对(先压入键再压入值)，压入由它们组成的映射 */
+    Map(usize),
+    /* 弹出下标与被访问的列表或映射，压入对应的项 */
+    Index,
+    /* for循环：栈顶为下一项的序号，其下为遍历的列表或映射，
+     * 还有下一项时压入新的序号与该项，否则弹出两者并跳转 */
+    Next(usize),
+    /* 以下三条指令的第一个参数为常量池中store的键：
+     * 压入当前用户保存的值；弹出栈顶写入存储；
+     * 当前用户已经保存过该键时跳转，用于跳过store声明的初始值 */
+    StoreGet(usize),
+    StoreSet(usize),
+    JumpIfStored(usize, usize),
+    /* 弹出Chunk::messages中的消息所需的参数，按当前语言格式化后压入文本 */
+    Message(usize),
+    /* 弹出栈顶作为之后使用的语言 */
+    Locale,
+    /* 弹出转接的原因，转给人工客服并等待交还控制 */
+    Transfer,
+    /* 弹出条件，为False时跳转 */
+    JumpIfFalse(usize),
+    Jump(usize),
+    Exit,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    Pow,
+    Equal,
+}
+
+impl BinaryOp {
+    fn from_token(token: &Token) -> Option<Self> {
+        match token {
+            Token::OperatorAdd => Some(BinaryOp::Add),
+            Token::OperatorSub => Some(BinaryOp::Sub),
+            Token::OperatorMul => Some(BinaryOp::Mul),
+            Token::OperatorDiv => Some(BinaryOp::Div),
+            Token::OperatorMod => Some(BinaryOp::Mod),
+            Token::OperatorPow => Some(BinaryOp::Pow),
+            Token::WEqual => Some(BinaryOp::Equal),
+            _ => None,
+        }
+    }
+
+    pub fn token(&self) -> Token {
+        match self {
+            BinaryOp::Add => Token::OperatorAdd,
+            BinaryOp::Sub => Token::OperatorSub,
+            BinaryOp::Mul => Token::OperatorMul,
+            BinaryOp::Div => Token::OperatorDiv,
+            BinaryOp::Mod => Token::OperatorMod,
+            BinaryOp::Pow => Token::OperatorPow,
+            BinaryOp::Equal => Token::WEqual,
+        }
+    }
+}
+
+/* 常量池中的值，字符串共享存储 */
+#[derive(Debug, Clone, PartialEq)]
+pub enum Constant {
+    Number(Decimal),
+    String(Arc<str>),
+    /* 只出现在直接加载的语法树中 */
+    List(Arc<Vec<Value>>),
+    Map(Arc<Map>),
+}
+
+/* 编译产物 */
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Chunk {
+    pub code: Vec<Instruction>,
+    pub constants: Vec<Constant>,
+    /* 槽位编号对应的变量名，用于报错 */
+    pub names: Vec<String>,
+    /* 全局变量对应的槽位 */
+    pub globals: HashMap<String, usize>,
+    pub lookups: Vec<Vec<usize>>,
+    pub scopes: Vec<Vec<usize>>,
+    pub matches: Vec<MatchTable>,
+    pub regexes: Vec<RegexTable>,
+    pub asks: Vec<AskTable>,
+    pub forms: Vec<FormTable>,
+    pub states: Vec<StateTable>,
+    pub messages: Vec<MessageTable>,
+    /* 每条顶层语句(对话状态为其代码)的第一条指令与语句的序号，按指令位置排列，用于报告出错的语句 */
+    pub origins: Vec<(usize, usize)>,
+}
+
+impl Chunk {
+    /* 第pc条指令所在的顶层语句的序号 */
+    pub fn origin(&self, pc: usize) -> Option<usize> {
+        let (_, index) = self.origins.iter().rev().find(|(start, _)| *start <= pc)?;
+        Some(*index)
+    }
+}
+
+/* 一条match语句：各意图的规则，以及各分支代码的起始位置 */
+#[derive(Debug, Clone, PartialEq)]
+pub struct MatchTable {
+    pub intents: Vec<Vec<Pattern>>,
+    pub threshold: f64,
+    /* 第i个意图的分支位置，最后一项为default分支或match语句之后的位置 */
+    pub targets: Vec<usize>,
+}
+
+/* 一个正则匹配表达式：编译好的正则，以及各命名分组写入的变量 */
+#[derive(Debug, Clone, PartialEq)]
+pub struct RegexTable {
+    pub regex: Regex,
+    /* 分组名与Chunk::lookups中的候选槽位列表 */
+    pub bindings: Vec<(String, usize)>,
+}
+
+/* 一条带类型的input语句 */
+#[derive(Debug, Clone, PartialEq)]
+pub struct AskTable {
+    pub input_type: InputType,
+    /* 写入的变量，为Chunk::lookups中的候选槽位列表 */
+    pub target: usize,
+    pub max: Option<u32>,
+    /* else分支的位置，没有else分支时与end相同 */
+    pub fallback: usize,
+    /* 语句之后的位置 */
+    pub end: usize,
+}
+
+/* 一条form语句，各项位置均为代码中的下标 */
+#[derive(Debug, Clone, PartialEq)]
+pub struct FormTable {
+    pub slots: Vec<Slot>,
+    /* 各槽位写入的变量，为Chunk::lookups中的候选槽位列表 */
+    pub targets: Vec<usize>,
+    /* 各槽位的提示与重新提示 */
+    pub prompts: Vec<usize>,
+    pub retries: Vec<usize>,
+    pub confirm: Option<usize>,
+    /* FormNext指令的位置 */
+    pub next: usize,
+    pub end: usize,
+}
+
+/* 一个对话状态：on enter之后执行 on input 的循环，没有 on input 时执行完 on enter 即结束 */
+#[derive(Debug, Clone, PartialEq)]
+pub struct StateTable {
+    pub name: String,
+    pub entry: usize,
+}
+
+/* 一个@消息：消息的键与各参数的名字，参数的值按顺序压栈 */
+#[derive(Debug, Clone, PartialEq)]
+pub struct MessageTable {
+    pub key: String,
+    pub names: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CompileError {
+    /* 赋值只能作为独立的表达式语句出现，parser已经保证这一点，只有从JSON加载的语法树会遇到 */
+    NestedAssign(String),
+    UnknownOperator(Token),
+    DuplicateState(String),
+    UnknownState(String),
+    /* 未知的函数或参数个数不对，只可能出现在直接加载的语法树中 */
+    InvalidCall(String),
+    /* 导入语句应当在编译之前由module::load展开 */
+    UnresolvedImport(String),
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CompileError::NestedAssign(name) => write!(
+                f,
+                "Assignment to '{}' must be a statement of its own.",
+                name
+            ),
+            CompileError::UnknownOperator(token) => write!(f, "Unknown operator {}.", token),
+            CompileError::DuplicateState(name) => write!(f, "State '{}' is declared twice.", name),
+            CompileError::UnknownState(name) => write!(f, "State '{}' is not declared.", name),
+            CompileError::InvalidCall(message) => write!(f, "{}", message),
+            CompileError::UnresolvedImport(path) => write!(
+                f,
+                "Import of \"{}\" must be resolved by loading the script from a file.",
+                path
+            ),
+        }
+    }
+}
+
+impl std::error::Error for CompileError {}
+
+/* 编译整个程序，对话状态的代码放在顶层代码之后 */
+pub fn compile(ast: &[Box<Statement>]) -> Result<Chunk, CompileError> {
+    let mut compiler = Compiler::default();
+    let mut states = Vec::new();
+    for (index, statement) in ast.iter().enumerate() {
+        if let Statement::State {
+            name,
+            enter,
+            handler,
+        } = &**statement
+        {
+            if compiler
+                .chunk
+                .states
+                .iter()
+                .any(|state| state.name == *name)
+            {
+                return Err(CompileError::DuplicateState(name.clone()));
+            }
+            compiler.chunk.states.push(StateTable {
+                name: name.clone(),
+                entry: 0,
+            });
+            states.push((index, enter, handler));
+        }
+    }
+    for (index, statement) in ast.iter().enumerate() {
+        let start = compiler.chunk.code.len();
+        compiler.chunk.origins.push((start, index));
+        compiler.statement(statement)?;
+    }
+    if states.is_empty() {
+        return Ok(compiler.chunk);
+    }
+    compiler.emit(Instruction::Exit);
+    // goto可能发生在顶层代码的任何位置，此时全局变量不一定已经定义
+    compiler.scopes[0].defined.clear();
+    for (index, (origin, enter, handler)) in states.into_iter().enumerate() {
+        let entry = compiler.chunk.code.len();
+        compiler.chunk.states[index].entry = entry;
+        compiler.chunk.origins.push((entry, origin));
+        if let Some(enter) = enter {
+            compiler.statement(enter)?;
+        }
+        match handler {
+            Some(handler) => {
+                let listen = compiler.emit(Instruction::Read);
+                match &*handler.body {
+                    Statement::Block { statements } => {
+                        compiler.block(statements, Some(&handler.variable))?
+                    }
+                    _ => unreachable!("on input 的处理代码是代码块"),
+                }
+                compiler.emit(Instruction::Jump(listen));
+            }
+            None => {
+                compiler.emit(Instruction::Exit);
+            }
+        }
+    }
+    Ok(compiler.chunk)
+}
+
+/* 编译期的一层作用域 */
+#[derive(Default)]
+struct Scope {
+    /* 该作用域中可能存在的变量 */
+    slots: HashMap<String, usize>,
+    /* 执行到当前位置时必然已经存在的变量 */
+    defined: HashSet<String>,
+}
+
+struct Compiler {
+    chunk: Chunk,
+    scopes: Vec<Scope>,
+    /* 当前作用域中处于条件执行位置(非代码块形式的分支或循环体)的层数 */
+    conditional: usize,
+    strings: HashMap<String, usize>,
+}
+
+impl Default for Compiler {
+    fn default() -> Self {
+        Self {
+            chunk: Chunk::default(),
+            scopes: vec![Scope::default()],
+            conditional: 0,
+            strings: HashMap::new(),
+        }
+    }
+}
+
+impl Compiler {
+    fn emit(&mut self, instruction: Instruction) -> usize {
+        self.chunk.code.push(instruction);
+        self.chunk.code.len() - 1
+    }
+
+    fn new_slot(&mut self, name: &str) -> usize {
+        self.chunk.names.push(name.to_string());
+        self.chunk.names.len() - 1
+    }
+
+    /* 变量在某一层作用域中的槽位，不存在时分配 */
+    fn slot_in(&mut self, depth: usize, name: &str) -> usize {
+        if let Some(slot) = self.scopes[depth].slots.get(name) {
+            return *slot;
+        }
+        let slot = self.new_slot(name);
+        self.scopes[depth].slots.insert(name.to_string(), slot);
+        if depth == 0 {
+            self.chunk.globals.insert(name.to_string(), slot);
+        }
+        slot
+    }
+
+    /* 由内向外的候选槽位，以及是否能确定变量此处已经存在 */
+    fn candidates(&mut self, name: &str) -> (Vec<usize>, bool) {
+        let mut candidates = Vec::new();
+        for scope in self.scopes.iter().rev() {
+            if let Some(slot) = scope.slots.get(name) {
+                candidates.push(*slot);
+                if scope.defined.contains(name) {
+                    return (candidates, true);
+                }
+            }
+        }
+        // 全局变量可能在循环的上一轮中才被定义，始终作为最后的候选
+        let global = self.slot_in(0, name);
+        if !candidates.contains(&global) {
+            candidates.push(global);
+        }
+        (candidates, false)
+    }
+
+    fn lookup(&mut self, candidates: Vec<usize>) -> usize {
+        self.chunk.lookups.push(candidates);
+        self.chunk.lookups.len() - 1
+    }
+
+    fn define(&mut self, depth: usize, name: &str) {
+        if depth == self.scopes.len() - 1 && self.conditional == 0 {
+            self.scopes[depth].defined.insert(name.to_string());
+        }
+    }
+
+    /* 赋值与input的目标：最近一个包含该变量的作用域，否则为当前作用域 */
+    fn assign_target(&mut self, name: &str) -> Vec<usize> {
+        let depth = self.scopes.len() - 1;
+        let (mut candidates, found) = self.candidates(name);
+        if !found && !self.scopes[depth].slots.contains_key(name) {
+            let slot = self.slot_in(depth, name);
+            if !candidates.contains(&slot) {
+                candidates.insert(0, slot);
+            }
+        }
+        if candidates.len() == 1 {
+            self.define(depth, name);
+        }
+        candidates
+    }
+
+    fn store(&mut self, name: &str) {
+        let candidates = self.assign_target(name);
+        match candidates[..] {
+            [slot] => self.emit(Instruction::Store(slot)),
+            _ => {
+                let index = self.lookup(candidates);
+                self.emit(Instruction::StoreAny(index))
+            }
+        };
+    }
+
+    /* 代码块形式以外的分支与循环体在当前作用域中条件执行 */
+    fn nested(&mut self, statement: &Statement) -> Result<(), CompileError> {
+        if matches!(statement, Statement::Block { .. }) {
+            return self.statement(statement);
+        }
+        self.conditional += 1;
+        let result = self.statement(statement);
+        self.conditional -= 1;
+        result
+    }
+
+    /* 删除位于removed处的指令后，修正其后跳转指令的目标 */
+    fn shift_jumps(&mut self, removed: usize) {
+        for instruction in &mut self.chunk.code[removed..] {
+            match instruction {
+                Instruction::Jump(target)
+                | Instruction::JumpIfFalse(target)
+                | Instruction::JumpIfStored(_, target)
+                | Instruction::Next(target)
+                    if *target > removed =>
+                {
+                    *target -= 1
+                }
+                _ => {}
+            }
+        }
+        for table in &mut self.chunk.matches {
+            for target in &mut table.targets {
+                if *target > removed {
+                    *target -= 1;
+                }
+            }
+        }
+        for table in &mut self.chunk.asks {
+            for target in [&mut table.fallback, &mut table.end] {
+                if *target > removed {
+                    *target -= 1;
+                }
+            }
+        }
+        for table in &mut self.chunk.forms {
+            let targets = table.prompts.iter_mut().chain(&mut table.retries);
+            let targets = targets
+                .chain(&mut table.confirm)
+                .chain([&mut table.next, &mut table.end]);
+            for target in targets {
+                if *target > removed {
+                    *target -= 1;
+                }
+            }
+        }
+        for table in &mut self.chunk.states {
+            if table.entry > removed {
+                table.entry -= 1;
+            }
+        }
+    }
+
+    fn constant(&mut self, value: &LiteralValue) -> usize {
+        match value {
+            LiteralValue::Number(n) => {
+                self.chunk.constants.push(Constant::Number(*n));
+                self.chunk.constants.len() - 1
+            }
+            // 相同的字符串只保存一份
+            LiteralValue::String(s) => {
+                if let Some(index) = self.strings.get(s) {
+                    return *index;
+                }
+                self.chunk
+                    .constants
+                    .push(Constant::String(Arc::from(s.as_str())));
+                let index = self.chunk.constants.len() - 1;
+                self.strings.insert(s.clone(), index);
+                index
+            }
+            LiteralValue::List(items) => {
+                self.chunk
+                    .constants
+                    .push(Constant::List(Arc::new(items.clone())));
+                self.chunk.constants.len() - 1
+            }
+            LiteralValue::Map(entries) => {
+                self.chunk
+                    .constants
+                    .push(Constant::Map(Arc::new(entries.clone())));
+                self.chunk.constants.len() - 1
+            }
+        }
+    }
+
+    /* 编译代码块，local为进入时从栈顶取值定义的局部变量 */
+    fn block(
+        &mut self,
+        statements: &[Box<Statement>],
+        local: Option<&str>,
+    ) -> Result<(), CompileError> {
+        let enter = self.emit(Instruction::EnterScope(0));
+        self.scopes.push(Scope::default());
+        if let Some(name) = local {
+            let depth = self.scopes.len() - 1;
+            let slot = self.slot_in(depth, name);
+            self.emit(Instruction::Store(slot));
+            self.define(depth, name);
+        }
+        let conditional = std::mem::replace(&mut self.conditional, 0);
+        let result = statements
+            .iter()
+            .try_for_each(|statement| self.statement(statement));
+        self.conditional = conditional;
+        let scope = self.scopes.pop().expect("代码块的作用域");
+        result?;
+        // 代码块中没有创建变量时不需要清空槽位
+        if scope.slots.is_empty() {
+            self.chunk.code.remove(enter);
+            self.shift_jumps(enter);
+        } else {
+            let mut slots: Vec<usize> = scope.slots.into_values().collect();
+            slots.sort_unstable();
+            self.chunk.scopes.push(slots);
+            self.chunk.code[enter] = Instruction::EnterScope(self.chunk.scopes.len() - 1);
+        }
+        Ok(())
+    }
+
+    fn statement(&mut self, statement: &Statement) -> Result<(), CompileError> {
+        match statement {
+            Statement::Block { statements } => self.block(statements, None)?,
+            Statement::Expression { expression } => match &**expression {
+                Expr::Assign { name, value } => {
+                    self.expression(value)?;
+                    self.store(name);
+                }
+                Expr::StoreAssign { key, value } => {
+                    self.expression(value)?;
+                    let key = self.constant(&LiteralValue::String(key.clone()));
+                    self.emit(Instruction::StoreSet(key));
+                }
+                _ => {
+                    self.expression(expression)?;
+                    self.emit(Instruction::Pop);
+                }
+            },
+            Statement::Branch { condition, then } => {
+                self.expression(condition)?;
+                let jump = self.emit(Instruction::JumpIfFalse(0));
+                self.nested(then)?;
+                self.chunk.code[jump] = Instruction::JumpIfFalse(self.chunk.code.len());
+            }
+            Statement::Loop { body } => {
+                let start = self.chunk.code.len();
+                self.nested(body)?;
+                self.emit(Instruction::Jump(start));
+            }
+            Statement::For {
+                variable,
+                iterable,
+                body,
+            } => {
+                self.expression(iterable)?;
+                let first = self.constant(&LiteralValue::Number(Decimal::ZERO));
+                self.emit(Instruction::Constant(first));
+                let next = self.emit(Instruction::Next(0));
+                match &**body {
+                    Statement::Block { statements } => self.block(statements, Some(variable))?,
+                    _ => unreachable!("for的循环体是代码块"),
+                }
+                self.emit(Instruction::Jump(next));
+                self.chunk.code[next] = Instruction::Next(self.chunk.code.len());
+            }
+            Statement::Speak { expression } => {
+                self.expression(expression)?;
+                self.emit(Instruction::Speak);
+            }
+            Statement::SpeakOneOf { options } => {
+                self.expression(options)?;
+                self.emit(Instruction::SpeakOneOf);
+            }
+            Statement::Input { input } => {
+                let candidates = self.assign_target(input);
+                match candidates[..] {
+                    [slot] => self.emit(Instruction::Input(slot)),
+                    _ => {
+                        let index = self.lookup(candidates);
+                        self.emit(Instruction::InputAny(index))
+                    }
+                };
+            }
+            Statement::TypedInput {
+                name,
+                input_type,
+                prompt,
+                retry,
+                max,
+                fallback,
+            } => {
+                if let Some(prompt) = prompt {
+                    self.expression(prompt)?;
+                    self.emit(Instruction::Speak);
+                }
+                let attempts = self.constant(&LiteralValue::Number(Decimal::ZERO));
+                self.emit(Instruction::Constant(attempts));
+                // 有次数限制时变量可能不会被赋值
+                if max.is_some() {
+                    self.conditional += 1;
+                }
+                let candidates = self.assign_target(name);
+                if max.is_some() {
+                    self.conditional -= 1;
+                }
+                let target = self.lookup(candidates);
+                let index = self.chunk.asks.len();
+                self.chunk.asks.push(AskTable {
+                    input_type: input_type.clone(),
+                    target,
+                    max: *max,
+                    fallback: 0,
+                    end: 0,
+                });
+                let ask = self.emit(Instruction::Ask(index));
+                if let Some(message) = retry.as_ref().or(prompt.as_ref()) {
+                    self.expression(message)?;
+                    self.emit(Instruction::Speak);
+                }
+                self.emit(Instruction::Jump(ask));
+                self.chunk.asks[index].fallback = self.chunk.code.len();
+                if let Some(fallback) = fallback {
+                    self.nested(fallback)?;
+                }
+                self.chunk.asks[index].end = self.chunk.code.len();
+            }
+            Statement::Form { slots, confirm, .. } => {
+                let targets = slots
+                    .iter()
+                    .map(|slot| {
+                        let candidates = self.assign_target(slot.variable());
+                        self.lookup(candidates)
+                    })
+                    .collect();
+                let index = self.chunk.forms.len();
+                let next = self.emit(Instruction::FormNext(index));
+                let mut table = FormTable {
+                    slots: slots.clone(),
+                    targets,
+                    prompts: Vec::new(),
+                    retries: Vec::new(),
+                    confirm: None,
+                    next,
+                    end: 0,
+                };
+                // 每个槽位：提示、等待输入、重新提示后回到等待输入
+                for (position, slot) in slots.iter().enumerate() {
+                    table.prompts.push(self.chunk.code.len());
+                    self.expression(&slot.prompt)?;
+                    self.emit(Instruction::Speak);
+                    let position = self.constant(&LiteralValue::Number(Decimal::from(position)));
+                    let wait = self.emit(Instruction::Constant(position));
+                    self.emit(Instruction::FormInput(index));
+                    table.retries.push(self.chunk.code.len());
+                    self.expression(slot.retry.as_ref().unwrap_or(&slot.prompt))?;
+                    self.emit(Instruction::Speak);
+                    self.emit(Instruction::Jump(wait));
+                }
+                if let Some(confirm) = confirm {
+                    table.confirm = Some(self.chunk.code.len());
+                    self.expression(confirm)?;
+                    self.emit(Instruction::Speak);
+                    self.emit(Instruction::FormConfirm(index));
+                }
+                table.end = self.chunk.code.len();
+                self.chunk.forms.push(table);
+            }
+            Statement::Var { name, init } => {
+                self.expression(init)?;
+                let slot = self.slot_in(0, name);
+                self.emit(Instruction::Store(slot));
+                self.define(0, name);
+            }
+            Statement::Local { name, init } => {
+                self.expression(init)?;
+                let depth = self.scopes.len() - 1;
+                let slot = self.slot_in(depth, name);
+                self.emit(Instruction::Store(slot));
+                self.define(depth, name);
+            }
+            Statement::Store { key, init } => {
+                let key = self.constant(&LiteralValue::String(key.clone()));
+                let jump = self.emit(Instruction::JumpIfStored(key, 0));
+                self.expression(init)?;
+                self.emit(Instruction::StoreSet(key));
+                self.chunk.code[jump] = Instruction::JumpIfStored(key, self.chunk.code.len());
+            }
+            Statement::Locale { locale } => {
+                self.expression(locale)?;
+                self.emit(Instruction::Locale);
+            }
+            Statement::Transfer { reason } => {
+                self.expression(reason)?;
+                self.emit(Instruction::Transfer);
+            }
+            Statement::Exit => {
+                self.emit(Instruction::Exit);
+            }
+            Statement::Match {
+                subject,
+                threshold,
+                arms,
+                default,
+            } => {
+                match subject {
+                    Some(subject) => self.expression(subject)?,
+                    None => {
+                        self.emit(Instruction::Read);
+                    }
+                }
+                let index = self.chunk.matches.len();
+                self.chunk.matches.push(MatchTable {
+                    intents: arms.iter().map(|arm| arm.patterns.clone()).collect(),
+                    threshold: threshold.unwrap_or(intent::DEFAULT_THRESHOLD),
+                    targets: Vec::new(),
+                });
+                self.emit(Instruction::Match(index));
+                let mut ends = Vec::new();
+                for arm in arms {
+                    let start = self.chunk.code.len();
+                    self.chunk.matches[index].targets.push(start);
+                    self.nested(&arm.body)?;
+                    ends.push(self.emit(Instruction::Jump(0)));
+                }
+                let start = self.chunk.code.len();
+                self.chunk.matches[index].targets.push(start);
+                if let Some(default) = default {
+                    self.nested(default)?;
+                }
+                let end = self.chunk.code.len();
+                for jump in ends {
+                    self.chunk.code[jump] = Instruction::Jump(end);
+                }
+            }
+            // 状态的代码由compile放在顶层代码之后
+            Statement::State { .. } => {}
+            Statement::Import { path, .. } => {
+                return Err(CompileError::UnresolvedImport(path.clone()));
+            }
+            Statement::Goto { state } => {
+                let index = self
+                    .chunk
+                    .states
+                    .iter()
+                    .position(|table| table.name == *state)
+                    .ok_or_else(|| CompileError::UnknownState(state.clone()))?;
+                self.emit(Instruction::Goto(index));
+            }
+        }
+        Ok(())
+    }
+
+    fn expression(&mut self, expression: &Expr) -> Result<(), CompileError> {
+        match expression {
+            Expr::Assign { name, .. } => return Err(CompileError::NestedAssign(name.clone())),
+            Expr::StoreAssign { key, .. } => {
+                return Err(CompileError::NestedAssign(format!("store.{}", key)))
+            }
+            Expr::Binary {
+                left,
+                operator,
+                right,
+            } => {
+                let op = BinaryOp::from_token(operator)
+                    .ok_or_else(|| CompileError::UnknownOperator(operator.clone()))?;
+                self.expression(left)?;
+                self.expression(right)?;
+                self.emit(Instruction::Binary(op));
+            }
+            Expr::Literal { value } => {
+                let index = self.constant(value);
+                self.emit(Instruction::Constant(index));
+            }
+            Expr::RegexMatch { subject, regex } => {
+                self.expression(subject)?;
+                // 只有匹配成功时才写入分组变量，因此不能视为必然已经定义
+                self.conditional += 1;
+                let bindings = regex
+                    .capture_names()
+                    .flatten()
+                    .map(|name| {
+                        let candidates = self.assign_target(name);
+                        (name.to_string(), self.lookup(candidates))
+                    })
+                    .collect();
+                self.conditional -= 1;
+                self.chunk.regexes.push(RegexTable {
+                    regex: regex.clone(),
+                    bindings,
+                });
+                self.emit(Instruction::RegexMatch(self.chunk.regexes.len() - 1));
+            }
+            Expr::Call { name, args } => {
+                let builtin =
+                    Builtin::resolve(name, args.len()).map_err(CompileError::InvalidCall)?;
+                for arg in args {
+                    self.expression(arg)?;
+                }
+                self.emit(Instruction::Call(builtin, args.len()));
+            }
+            Expr::List { items } => {
+                for item in items {
+                    self.expression(item)?;
+                }
+                self.emit(Instruction::List(items.len()));
+            }
+            Expr::Map { entries } => {
+                for (key, value) in entries {
+                    let key = self.constant(&LiteralValue::String(key.clone()));
+                    self.emit(Instruction::Constant(key));
+                    self.expression(value)?;
+                }
+                self.emit(Instruction::Map(entries.len()));
+            }
+            Expr::Index { target, index } => {
+                self.expression(target)?;
+                self.expression(index)?;
+                self.emit(Instruction::Index);
+            }
+            Expr::Store { key } => {
+                let key = self.constant(&LiteralValue::String(key.clone()));
+                self.emit(Instruction::StoreGet(key));
+            }
+            Expr::Message { key, args } => {
+                for (_, value) in args {
+                    self.expression(value)?;
+                }
+                self.chunk.messages.push(MessageTable {
+                    key: key.clone(),
+                    names: args.iter().map(|(name, _)| name.clone()).collect(),
+                });
+                self.emit(Instruction::Message(self.chunk.messages.len() - 1));
+            }
+            Expr::Variable { name } => {
+                let (candidates, _) = self.candidates(name);
+                match candidates[..] {
+                    [slot] => self.emit(Instruction::Load(slot)),
+                    _ => {
+                        let index = self.lookup(candidates);
+                        self.emit(Instruction::LoadAny(index))
+                    }
+                };
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_program;
+
+    #[test]
+    fn test_compile_loop_and_branch() {
+        let ast =
+            parse_program("global n = 0; loop { input n; if (n == \"e\") { exit; }; }").unwrap();
+        let chunk = compile(&ast).unwrap();
+        assert_eq!(chunk.names, vec!["n"]);
+        assert_eq!(
+            chunk.code,
+            vec![
+                Instruction::Constant(0),
+                Instruction::Store(0),
+                Instruction::Input(0),
+                Instruction::Load(0),
+                Instruction::Constant(1),
+                Instruction::Binary(BinaryOp::Equal),
+                Instruction::JumpIfFalse(8),
+                Instruction::Exit,
+                Instruction::Jump(2),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_reject_nested_assign() {
+        // 两种引擎共用的parser不接受表达式中的赋值
+        assert!(parse_program("speak x = 1;").is_err());
+        assert!(parse_program("speak (x = 1) + 1;").is_err());
+        // 从JSON加载的语法树不经过parser，由编译器报告
+        let ast = vec![Box::new(Statement::Speak {
+            expression: Box::new(Expr::Assign {
+                name: "x".to_string(),
+                value: Box::new(Expr::Literal {
+                    value: LiteralValue::Number(1.into()),
+                }),
+            }),
+        })];
+        assert_eq!(
+            compile(&ast),
+            Err(CompileError::NestedAssign("x".to_string()))
+        );
+    }
+}
@@ -0,0 +1,286 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/*
+ * 多语言的消息目录
+ * 脚本中的 @greeting(name) 不直接写出文本，而是按会话当前的语言在目录中查找，
+ * 每种语言一个TOML文件，文件名即语言名，例如 locales/zh.toml、locales/en.toml：
+ *   greeting = "你好，{name}！"
+ *   [menu]
+ *   title = "请选择："        # 嵌套的表以点连接，写作 @menu.title
+ * 消息中的 {name} 替换为同名参数的值。
+ * 会话开始时使用默认语言，locale "en"; 语句可以在对话中途切换；
+ * 当前语言中缺少的消息取默认语言中的文本。
+ */
+
+#[derive(Debug)]
+pub enum Error {
+    Io {
+        path: PathBuf,
+        error: io::Error,
+    },
+    Toml {
+        path: PathBuf,
+        error: toml::de::Error,
+    },
+    /* 消息的值不是字符串 */
+    NotAString {
+        path: PathBuf,
+        key: String,
+    },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io { path, error } => write!(f, "{}: {}", path.display(), error),
+            Error::Toml { path, error } => write!(f, "{}: {}", path.display(), error),
+            Error::NotAString { path, key } => {
+                write!(f, "{}: Message `{}` must be a string.", path.display(), key)
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Catalog {
+    /* 语言名到该语言全部消息的映射 */
+    locales: BTreeMap<String, BTreeMap<String, String>>,
+    default: Option<String>,
+}
+
+/* 某种语言中缺少的消息 */
+#[derive(Debug, Clone, PartialEq)]
+pub struct Missing {
+    pub locale: String,
+    pub key: String,
+}
+
+impl Catalog {
+    /* 读取目录中所有的 .toml 文件，默认语言为按名字排序的第一种 */
+    pub fn load_dir(directory: &Path) -> Result<Self, Error> {
+        let io_error = |error| Error::Io {
+            path: directory.to_path_buf(),
+            error,
+        };
+        let mut catalog = Catalog::default();
+        for entry in fs::read_dir(directory).map_err(io_error)? {
+            let path = entry.map_err(io_error)?.path();
+            if path.extension().and_then(|extension| extension.to_str()) != Some("toml") {
+                continue;
+            }
+            let locale = path.file_stem().unwrap_or_default().to_string_lossy();
+            let source = fs::read_to_string(&path).map_err(|error| Error::Io {
+                path: path.clone(),
+                error,
+            })?;
+            catalog.add(&locale, &source).map_err(|error| match error {
+                Error::Toml { error, .. } => Error::Toml { path, error },
+                Error::NotAString { key, .. } => Error::NotAString { path, key },
+                other => other,
+            })?;
+        }
+        Ok(catalog)
+    }
+
+    /* 加入一种语言的TOML源码，同名的语言会被合并 */
+    pub fn add(&mut self, locale: &str, source: &str) -> Result<(), Error> {
+        let path = PathBuf::from(format!("{}.toml", locale));
+        let table: toml::Table = toml::from_str(source).map_err(|error| Error::Toml {
+            path: path.clone(),
+            error,
+        })?;
+        let messages = self.locales.entry(locale.to_string()).or_default();
+        flatten("", table, messages).map_err(|key| Error::NotAString { path, key })
+    }
+
+    /* 指定默认语言 */
+    pub fn with_default(mut self, locale: &str) -> Self {
+        self.default = Some(locale.to_string());
+        self
+    }
+
+    pub fn default_locale(&self) -> Option<&str> {
+        self.default
+            .as_deref()
+            .or_else(|| self.locales.keys().next().map(String::as_str))
+    }
+
+    /* locale语句切换到的语言必须有对应的目录 */
+    pub fn check_locale(&self, locale: &str) -> Result<(), String> {
+        if self.locales.contains_key(locale) {
+            return Ok(());
+        }
+        let available: Vec<&str> = self.locales().collect();
+        Err(format!(
+            "Unknown locale '{}' (available: {})",
+            locale,
+            available.join(", ")
+        ))
+    }
+
+    pub fn locales(&self) -> impl Iterator<Item = &str> {
+        self.locales.keys().map(String::as_str)
+    }
+
+    /*
+     * 按给定的语言(None时为默认语言)格式化消息，该语言中没有时取默认语言中的文本
+     * 消息不存在或者缺少参数时返回错误
+     */
+    pub fn format(
+        &self,
+        locale: Option<&str>,
+        key: &str,
+        args: &[(&str, String)],
+    ) -> Result<String, String> {
+        let default = self.default_locale();
+        let template = [locale, default]
+            .into_iter()
+            .flatten()
+            .find_map(|locale| self.locales.get(locale)?.get(key))
+            .ok_or_else(|| {
+                format!(
+                    "Message '{}' is not defined in locale '{}'",
+                    key,
+                    locale.or(default).unwrap_or("")
+                )
+            })?;
+        let mut text = String::new();
+        let mut rest = template.as_str();
+        while let Some(start) = rest.find('{') {
+            let end = match rest[start..].find('}') {
+                Some(end) => start + end,
+                None => break,
+            };
+            text.push_str(&rest[..start]);
+            let name = rest[start + 1..end].trim();
+            match args.iter().find(|(arg, _)| *arg == name) {
+                Some((_, value)) => text.push_str(value),
+                None => return Err(format!("Message '{}' expects an argument '{}'", key, name)),
+            }
+            rest = &rest[end + 1..];
+        }
+        text.push_str(rest);
+        Ok(text)
+    }
+
+    /* 在某些语言中有、另一些语言中没有的消息 */
+    pub fn missing(&self) -> Vec<Missing> {
+        let keys: BTreeSet<&String> = self.locales.values().flat_map(|m| m.keys()).collect();
+        self.missing_keys(keys.into_iter().map(String::as_str))
+    }
+
+    /* 给定的消息中，每种语言各缺少哪些 */
+    pub fn missing_keys<'a>(&self, keys: impl IntoIterator<Item = &'a str>) -> Vec<Missing> {
+        let keys: Vec<&str> = keys.into_iter().collect();
+        let mut missing = Vec::new();
+        for (locale, messages) in &self.locales {
+            for key in &keys {
+                if !messages.contains_key(*key) {
+                    missing.push(Missing {
+                        locale: locale.clone(),
+                        key: key.to_string(),
+                    });
+                }
+            }
+        }
+        missing
+    }
+}
+
+/* 将嵌套的表展开为以点连接的键，遇到不是字符串的值时返回它的键 */
+fn flatten(
+    prefix: &str,
+    table: toml::Table,
+    messages: &mut BTreeMap<String, String>,
+) -> Result<(), String> {
+    for (name, value) in table {
+        let key = match prefix {
+            "" => name,
+            _ => format!("{}.{}", prefix, name),
+        };
+        match value {
+            toml::Value::String(text) => {
+                messages.insert(key, text);
+            }
+            toml::Value::Table(table) => flatten(&key, table, messages)?,
+            _ => return Err(key),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn catalog() -> Catalog {
+        let mut catalog = Catalog::default();
+        catalog
+            .add(
+                "zh",
+                "greeting = \"你好，{name}！\"\n[menu]\ntitle = \"菜单\"\nhelp = \"帮助\"",
+            )
+            .unwrap();
+        catalog
+            .add(
+                "en",
+                "greeting = \"Hello, {name}!\"\n[menu]\ntitle = \"Menu\"",
+            )
+            .unwrap();
+        catalog.with_default("zh")
+    }
+
+    #[test]
+    fn test_format_and_fallback() {
+        let catalog = catalog();
+        let args = [("name", "Tom".to_string())];
+        let format = |locale, key: &str| catalog.format(locale, key, &args);
+        assert_eq!(format(None, "greeting"), Ok("你好，Tom！".to_string()));
+        assert_eq!(
+            format(Some("en"), "greeting"),
+            Ok("Hello, Tom!".to_string())
+        );
+        assert_eq!(format(Some("en"), "menu.title"), Ok("Menu".to_string()));
+        // 英文中缺少的消息取默认语言
+        assert_eq!(format(Some("en"), "menu.help"), Ok("帮助".to_string()));
+        assert_eq!(
+            format(Some("en"), "bye"),
+            Err("Message 'bye' is not defined in locale 'en'".to_string())
+        );
+        assert_eq!(
+            catalog.format(None, "greeting", &[]),
+            Err("Message 'greeting' expects an argument 'name'".to_string())
+        );
+    }
+
+    #[test]
+    fn test_missing_keys() {
+        let catalog = catalog();
+        assert_eq!(
+            catalog.missing(),
+            vec![Missing {
+                locale: "en".to_string(),
+                key: "menu.help".to_string()
+            }]
+        );
+        let missing = catalog.missing_keys(["greeting", "bye"]);
+        let locales: Vec<&str> = missing.iter().map(|m| m.locale.as_str()).collect();
+        assert_eq!(locales, ["en", "zh"]);
+        assert!(missing.iter().all(|m| m.key == "bye"));
+        assert_eq!(catalog.check_locale("en"), Ok(()));
+        assert_eq!(
+            catalog.check_locale("fr"),
+            Err("Unknown locale 'fr' (available: en, zh)".to_string())
+        );
+        assert!(matches!(
+            Catalog::default().add("en", "count = 3"),
+            Err(Error::NotAString { key, .. }) if key == "count"
+        ));
+    }
+}
@@ -0,0 +1,98 @@
+use chrono::{Local, NaiveDate, NaiveDateTime, NaiveTime};
+
+/*
+ * 日期与时间函数使用的时钟
+ * 解释器与虚拟机默认读取系统的本地时间，测试或回放对话时可以换成固定的时钟，
+ * 这样 now() 以及由它算出的问候语、营业时间等不会随运行的时刻变化。
+ * 语言中的时间同样以字符串保存，统一写作 2024-03-05 14:30:00；
+ * 作为参数时也可以省略秒或者只写日期(当天0点)，日期部分的写法与带类型的输入相同，
+ * 因此 input d as date 得到的值可以直接传给日期函数。
+ */
+
+pub const FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+
+pub trait Clock: Send + Sync {
+    /* 当前的本地时间 */
+    fn now(&self) -> NaiveDateTime;
+}
+
+/* 系统时钟 */
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> NaiveDateTime {
+        Local::now().naive_local()
+    }
+}
+
+/* 停在某一时刻的时钟 */
+pub struct FixedClock(pub NaiveDateTime);
+
+impl FixedClock {
+    /* 由语言中的时间字符串创建，无法解析时返回None */
+    pub fn parse(text: &str) -> Option<Self> {
+        parse(text).map(FixedClock)
+    }
+}
+
+impl Clock for FixedClock {
+    fn now(&self) -> NaiveDateTime {
+        self.0
+    }
+}
+
+/*
+ * 解析语言中的时间：日期之后可以用空格或T隔开 HH:MM 或 HH:MM:SS
+ * 例如 2024-03-05、2024/3/5 9:30、2024年3月5日 14:30:00
+ */
+pub fn parse(text: &str) -> Option<NaiveDateTime> {
+    let text = text.trim();
+    let (date, time) = match text.split_once([' ', 'T']) {
+        Some((date, time)) => (date, parse_time(time)?),
+        None => (text, NaiveTime::MIN),
+    };
+    let date = crate::validation::validate(&crate::ast::InputType::Date, date)?;
+    let date = NaiveDate::parse_from_str(&date, "%Y-%m-%d").ok()?;
+    Some(date.and_time(time))
+}
+
+/* 一天中的时刻，写作 HH:MM 或 HH:MM:SS */
+pub fn parse_time(text: &str) -> Option<NaiveTime> {
+    let text = text.trim();
+    NaiveTime::parse_from_str(text, "%H:%M:%S")
+        .or_else(|_| NaiveTime::parse_from_str(text, "%H:%M"))
+        .ok()
+}
+
+/* 时间写回语言中的形式 */
+pub fn format(time: NaiveDateTime) -> String {
+    time.format(FORMAT).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_and_format() {
+        let time = |text| parse(text).map(format);
+        assert_eq!(
+            time("2024-03-05 14:30:00"),
+            Some("2024-03-05 14:30:00".to_string())
+        );
+        assert_eq!(time("2024/3/5"), Some("2024-03-05 00:00:00".to_string()));
+        assert_eq!(
+            time("2024年3月5日 9:05"),
+            Some("2024-03-05 09:05:00".to_string())
+        );
+        assert_eq!(
+            time("2024-03-05T23:59:59"),
+            Some("2024-03-05 23:59:59".to_string())
+        );
+        assert_eq!(time("2024-02-30"), None);
+        assert_eq!(time("2024-03-05 25:00"), None);
+        assert_eq!(time("明天"), None);
+        let clock = FixedClock::parse("2024-03-05 08:00").unwrap();
+        assert_eq!(format(clock.now()), "2024-03-05 08:00:00");
+    }
+}
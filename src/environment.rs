@@ -0,0 +1,135 @@
+use crate::value::{Map, Value};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+
+/*
+ * 解释器的作用域链
+ * scopes[0]为全局作用域，之后每进入一个代码块压入一个新的作用域，
+ * 离开代码块时直接弹出，块内创建的变量不会泄漏到外层。
+ * 变量的规则如下：
+ * - global x = ...; 总是写入全局作用域
+ * - local x = ...;  总是在当前作用域中创建变量，可以遮蔽外层同名变量
+ * - 赋值与input写入最近一个包含该变量的作用域，
+ *   若所有作用域中都没有该变量，则在当前作用域中创建
+ * - 读取变量时从内向外查找
+ */
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Environment {
+    scopes: Vec<HashMap<String, Value>>,
+}
+
+impl Default for Environment {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Environment {
+    /* 只包含全局作用域的环境 */
+    pub fn new() -> Self {
+        Self {
+            scopes: vec![HashMap::new()],
+        }
+    }
+
+    /* 进入代码块 */
+    pub fn push_scope(&mut self) {
+        self.scopes.push(HashMap::new());
+    }
+
+    /* 离开代码块，全局作用域不会被弹出 */
+    pub fn pop_scope(&mut self) {
+        if self.scopes.len() > 1 {
+            self.scopes.pop();
+        }
+    }
+
+    /* 当前作用域的嵌套深度，全局作用域为0 */
+    pub fn depth(&self) -> usize {
+        self.scopes.len() - 1
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Value> {
+        self.scopes.iter().rev().find_map(|scope| scope.get(name))
+    }
+
+    /* 赋值：写入最近一个包含该变量的作用域，不存在时在当前作用域中创建 */
+    pub fn assign(&mut self, name: &str, value: Value) {
+        match self
+            .scopes
+            .iter_mut()
+            .rev()
+            .find(|scope| scope.contains_key(name))
+        {
+            Some(scope) => {
+                scope.insert(name.to_string(), value);
+            }
+            None => self.define_local(name, value),
+        }
+    }
+
+    /* 删除最近一个作用域中的变量，外层的同名变量随之重新可见 */
+    pub fn remove(&mut self, name: &str) -> Option<Value> {
+        self.scopes
+            .iter_mut()
+            .rev()
+            .find_map(|scope| scope.remove(name))
+    }
+
+    pub fn define_local(&mut self, name: &str, value: Value) {
+        let scope = self.scopes.last_mut().expect("至少存在全局作用域");
+        scope.insert(name.to_string(), value);
+    }
+
+    pub fn define_global(&mut self, name: &str, value: Value) {
+        self.scopes[0].insert(name.to_string(), value);
+    }
+
+    /* 当前可见的所有变量，按名字排序，内层作用域的同名变量遮蔽外层 */
+    pub fn variables(&self) -> Map {
+        let mut variables = BTreeMap::new();
+        for scope in &self.scopes {
+            variables.extend(scope.iter());
+        }
+        variables
+            .into_iter()
+            .map(|(name, value)| (name.clone(), value.clone()))
+            .collect()
+    }
+
+    /* 全局作用域中的所有变量 */
+    pub fn globals(&self) -> &HashMap<String, Value> {
+        &self.scopes[0]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shadowing_and_assign() {
+        let mut env = Environment::new();
+        env.define_global("x", Value::from("1"));
+        env.push_scope();
+        env.define_local("x", Value::from("2"));
+        env.push_scope();
+        // 赋值写入最近的同名变量，即被遮蔽的局部变量
+        env.assign("x", Value::from("3"));
+        env.assign("y", Value::from("4"));
+        env.define_global("z", Value::from("5"));
+        assert_eq!(env.get("x"), Some(&Value::from("3")));
+        assert_eq!(env.depth(), 2);
+        let variables = env.variables();
+        let names: Vec<&String> = variables.keys().collect();
+        assert_eq!(names, ["x", "y", "z"]);
+        assert_eq!(variables["x"], Value::from("3"));
+        env.pop_scope();
+        assert_eq!(env.get("y"), None);
+        assert_eq!(env.get("z"), Some(&Value::from("5")));
+        env.pop_scope();
+        env.pop_scope();
+        assert_eq!(env.get("x"), Some(&Value::from("1")));
+        assert_eq!(env.globals().len(), 2);
+    }
+}
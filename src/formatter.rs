@@ -0,0 +1,520 @@
+use crate::ast::Statement;
+use crate::lexer::Lexer;
+use crate::parser::{self, parse_program};
+use crate::tokens::Token;
+
+/*
+ * 脚本格式化工具
+ * 先用ProgramParser解析出语法树，再将语法树按统一的风格输出：
+ * - 每层缩进4个空格
+ * - 运算符两侧各一个空格，关键字统一为小写
+ * - 语句之间最多保留一个空行
+ * 由于lexer会丢弃注释，格式化时同时遍历token流，
+ * 根据注释在源码中的位置把注释放回到对应的语句前后。
+ */
+
+const INDENT: &str = "    ";
+
+/* 源码中的一条注释 */
+struct Comment {
+    start: usize,
+    text: String,
+    /* 注释独占一行，否则为某行代码的行尾注释 */
+    own_line: bool,
+}
+
+struct Formatter<'a> {
+    source: &'a str,
+    tokens: Vec<(usize, Token, usize)>,
+    /* 下一个未处理的token */
+    pos: usize,
+    comments: Vec<Comment>,
+    /* 下一个未输出的注释 */
+    next_comment: usize,
+    indent: usize,
+    /* 刚输出完 '{' 或位于文件开头，此时不需要空行 */
+    at_block_start: bool,
+    out: String,
+}
+
+/* 将脚本格式化为统一风格，源码无法解析时返回解析错误 */
+pub fn format_source(source: &str) -> Result<String, parser::Error> {
+    let ast = parse_program(source)?;
+    let mut formatter = Formatter::new(source);
+    for statement in &ast {
+        formatter.statement(statement);
+    }
+    formatter.flush_comments(source.len());
+    Ok(formatter.out)
+}
+
+/* 判断脚本是否已经是格式化后的样子 */
+pub fn is_formatted(source: &str) -> Result<bool, parser::Error> {
+    Ok(format_source(source)? == source)
+}
+
+impl<'a> Formatter<'a> {
+    fn new(source: &'a str) -> Self {
+        let tokens: Vec<(usize, Token, usize)> =
+            Lexer::new(source).filter_map(Result::ok).collect();
+        let comments = collect_comments(source, &tokens);
+        Self {
+            source,
+            tokens,
+            pos: 0,
+            comments,
+            next_comment: 0,
+            indent: 0,
+            at_block_start: true,
+            out: String::new(),
+        }
+    }
+
+    fn statement(&mut self, statement: &Statement) {
+        let first = self.pos;
+        match statement {
+            Statement::Block { .. } => self.block(String::new(), first, statement, ""),
+            Statement::Loop { body } => {
+                self.pos += 1; // loop
+                self.block("loop".to_string(), first, body, "");
+            }
+            Statement::For {
+                variable,
+                iterable,
+                body,
+            } => {
+                self.skip_to_brace();
+                let header = format!("for {} in {}", variable, iterable);
+                self.block(header, first, body, "");
+            }
+            Statement::Branch { condition, then } => {
+                self.pos += 1; // if
+                self.skip_parens();
+                self.block(format!("if ({})", condition), first, then, ";");
+            }
+            Statement::Speak { expression } => self.simple(format!("speak {};", expression)),
+            Statement::SpeakOneOf { options } => self.simple(format!("speak one of {};", options)),
+            Statement::Input { input } => self.simple(format!("input {};", input)),
+            Statement::TypedInput {
+                name,
+                input_type,
+                prompt,
+                retry,
+                max,
+                fallback,
+            } => {
+                let mut header = format!("input {} as {}", name, input_type);
+                if let Some(prompt) = prompt {
+                    header.push_str(&format!(" prompt {}", prompt));
+                }
+                if let Some(retry) = retry {
+                    header.push_str(&format!(" retry {}", retry));
+                }
+                if let Some(max) = max {
+                    header.push_str(&format!(" max {}", max));
+                }
+                match fallback {
+                    Some(fallback) => {
+                        self.skip_to_brace();
+                        self.block(format!("{} else", header), first, fallback, ";");
+                    }
+                    None => self.simple(format!("{};", header)),
+                }
+            }
+            Statement::Var { name, init } => self.simple(format!("global {} = {};", name, init)),
+            Statement::Local { name, init } => self.simple(format!("local {} = {};", name, init)),
+            Statement::Store { key, init } => self.simple(format!("store {} = {};", key, init)),
+            Statement::Locale { locale } => self.simple(format!("locale {};", locale)),
+            Statement::Transfer { reason } => self.simple(format!("transfer {};", reason)),
+            Statement::Expression { expression } => self.simple(format!("{};", expression)),
+            Statement::Exit => self.simple("exit;".to_string()),
+            Statement::Form {
+                name,
+                slots,
+                confirm,
+            } => {
+                self.skip_to_brace();
+                self.line(&format!("form {} {{", name), first, self.pos, true);
+                self.pos += 1;
+                self.indent += 1;
+                self.at_block_start = true;
+                for slot in slots {
+                    self.simple(format!("{};", slot));
+                }
+                if let Some(confirm) = confirm {
+                    self.simple(format!("confirm {};", confirm));
+                }
+                let close = self.pos;
+                self.flush_comments(self.tokens[close].0);
+                self.indent -= 1;
+                self.line("}", close, close, false);
+                self.pos = close + 1;
+            }
+            Statement::State {
+                name,
+                enter,
+                handler,
+            } => {
+                self.skip_to_brace();
+                let close = self.pos + 1;
+                if enter.is_none()
+                    && handler.is_none()
+                    && !self.has_comment_before(self.tokens[close].0)
+                {
+                    self.line(&format!("state {} {{}}", name), first, close, true);
+                    self.pos = close + 1;
+                    return;
+                }
+                self.line(&format!("state {} {{", name), first, self.pos, true);
+                self.pos += 1;
+                self.indent += 1;
+                self.at_block_start = true;
+                if let Some(enter) = enter {
+                    let first = self.pos;
+                    self.skip_to_brace();
+                    self.block("on enter".to_string(), first, enter, "");
+                }
+                if let Some(handler) = handler {
+                    let first = self.pos;
+                    self.skip_to_brace();
+                    let header = format!("on input {}", handler.variable);
+                    self.block(header, first, &handler.body, "");
+                }
+                let close = self.pos;
+                self.flush_comments(self.tokens[close].0);
+                self.indent -= 1;
+                self.line("}", close, close, false);
+                self.pos = close + 1;
+            }
+            Statement::Goto { state } => self.simple(format!("goto {};", state)),
+            Statement::Import { path, alias } => match alias {
+                Some(alias) => self.simple(format!("import \"{}\" as {};", path, alias)),
+                None => self.simple(format!("import \"{}\";", path)),
+            },
+            Statement::Match {
+                subject,
+                threshold,
+                arms,
+                default,
+            } => {
+                let mut header = match subject {
+                    Some(subject) => format!("match {}", subject),
+                    None => "match input".to_string(),
+                };
+                if let Some(threshold) = threshold {
+                    header.push_str(&format!(" threshold {}", threshold));
+                }
+                self.skip_to_brace();
+                self.line(&format!("{} {{", header), first, self.pos, true);
+                self.pos += 1;
+                self.indent += 1;
+                self.at_block_start = true;
+                for arm in arms {
+                    let patterns: Vec<String> =
+                        arm.patterns.iter().map(|p| p.to_string()).collect();
+                    let header = format!("intent \"{}\": [{}] =>", arm.name, patterns.join(", "));
+                    let first = self.pos;
+                    self.skip_to_brace();
+                    self.block(header, first, &arm.body, "");
+                }
+                if let Some(default) = default {
+                    let first = self.pos;
+                    self.skip_to_brace();
+                    self.block("default =>".to_string(), first, default, "");
+                }
+                let close = self.pos;
+                self.flush_comments(self.tokens[close].0);
+                self.indent -= 1;
+                self.line("}", close, close, false);
+                self.pos = close + 1;
+            }
+        }
+    }
+
+    /* 跳到下一个代码块的 '{'，跳过其前面的表达式中的映射字面量 */
+    fn skip_to_brace(&mut self) {
+        let mut depth = 0;
+        while let Some((_, token, _)) = self.tokens.get(self.pos) {
+            match token {
+                Token::LBracket if depth == 0 && !self.is_map(self.pos) => return,
+                Token::LBracket => depth += 1,
+                Token::RBracket => depth -= 1,
+                _ => {}
+            }
+            self.pos += 1;
+        }
+    }
+
+    /* pos处的 '{' 是否为映射字面量的开始，映射至少有一项且以 "键": 开头 */
+    fn is_map(&self, pos: usize) -> bool {
+        matches!(
+            (self.tokens.get(pos + 1), self.tokens.get(pos + 2)),
+            (
+                Some((_, Token::StringContent(_), _)),
+                Some((_, Token::Colon, _))
+            )
+        )
+    }
+
+    /* 以 ';' 结尾的单行语句 */
+    fn simple(&mut self, text: String) {
+        let first = self.pos;
+        let mut depth = 0;
+        while let Some((_, token, _)) = self.tokens.get(self.pos) {
+            match token {
+                Token::LParen => depth += 1,
+                Token::RParen => depth -= 1,
+                Token::Semicolon if depth == 0 => break,
+                _ => {}
+            }
+            self.pos += 1;
+        }
+        self.line(&text, first, self.pos, true);
+        self.pos += 1;
+    }
+
+    /* 跳过 if 后面括号中的条件表达式 */
+    fn skip_parens(&mut self) {
+        let mut depth = 0;
+        while let Some((_, token, _)) = self.tokens.get(self.pos) {
+            self.pos += 1;
+            match token {
+                Token::LParen => depth += 1,
+                Token::RParen => {
+                    depth -= 1;
+                    if depth == 0 {
+                        return;
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /*
+     * 输出一个代码块，header为 '{' 之前的内容(loop、if (...))
+     * suffix为 '}' 之后的内容，例如 if 语句末尾的 ';'
+     * 调用时pos指向 '{'
+     */
+    fn block(&mut self, header: String, first: usize, block: &Statement, suffix: &str) {
+        let statements = match block {
+            Statement::Block { statements } => statements,
+            _ => unreachable!("loop与if的语句体总是代码块"),
+        };
+        let open = if header.is_empty() {
+            "{".to_string()
+        } else {
+            format!("{} {{", header)
+        };
+        let close_token = self.pos + 1;
+        // 空代码块且内部没有注释时写在同一行
+        if statements.is_empty() && !self.has_comment_before(self.tokens[close_token].0) {
+            let last = close_token + usize::from(!suffix.is_empty());
+            self.line(&format!("{}}}{}", open, suffix), first, last, true);
+            self.pos = last + 1;
+            return;
+        }
+        self.line(&open, first, self.pos, true);
+        self.pos += 1;
+        self.indent += 1;
+        self.at_block_start = true;
+        for statement in statements {
+            self.statement(statement);
+        }
+        let close = self.pos;
+        self.flush_comments(self.tokens[close].0);
+        self.indent -= 1;
+        let last = close + usize::from(!suffix.is_empty());
+        self.line(&format!("}}{}", suffix), close, last, false);
+        self.pos = last + 1;
+    }
+
+    /* 输出一行代码，该行对应源码中第first到第last个token */
+    fn line(&mut self, text: &str, first: usize, last: usize, allow_blank: bool) {
+        let first_start = self.tokens[first].0;
+        let last_end = self.tokens[last].2;
+        // 该行之前以及夹在该行token之间的注释都放在该行上方
+        self.flush_comments(last_end);
+        if allow_blank && !self.at_block_start && self.blank_line_before(first_start) {
+            self.out.push('\n');
+        }
+        self.push_indent();
+        self.out.push_str(text);
+        if let Some(comment) = self.comments.get(self.next_comment) {
+            let same_line = !self.source[last_end..comment.start].contains('\n');
+            if !comment.own_line && same_line {
+                self.out.push(' ');
+                self.out.push_str(&comment.text);
+                self.next_comment += 1;
+            }
+        }
+        self.out.push('\n');
+        self.at_block_start = false;
+    }
+
+    /* 输出所有位于offset之前且尚未输出的注释 */
+    fn flush_comments(&mut self, offset: usize) {
+        while let Some(comment) = self.comments.get(self.next_comment) {
+            if comment.start >= offset {
+                break;
+            }
+            if !self.at_block_start && self.blank_line_before(comment.start) {
+                self.out.push('\n');
+            }
+            let text = comment.text.clone();
+            self.push_indent();
+            self.out.push_str(&text);
+            self.out.push('\n');
+            self.next_comment += 1;
+            self.at_block_start = false;
+        }
+    }
+
+    fn has_comment_before(&self, offset: usize) -> bool {
+        self.comments
+            .get(self.next_comment)
+            .is_some_and(|comment| comment.start < offset)
+    }
+
+    /* offset之前的空白中是否包含空行 */
+    fn blank_line_before(&self, offset: usize) -> bool {
+        let before = &self.source[..offset];
+        let content = before.trim_end();
+        !content.is_empty() && before[content.len()..].matches('\n').count() >= 2
+    }
+
+    fn push_indent(&mut self) {
+        for _ in 0..self.indent {
+            self.out.push_str(INDENT);
+        }
+    }
+}
+
+/* token之间只可能是空白或注释，在这些间隙中找出所有注释 */
+fn collect_comments(source: &str, tokens: &[(usize, Token, usize)]) -> Vec<Comment> {
+    let mut comments = Vec::new();
+    let mut gap_start = 0;
+    let gap_ends = tokens
+        .iter()
+        .map(|(start, _, end)| (*start, *end))
+        .chain(std::iter::once((source.len(), source.len())));
+    for (start, end) in gap_ends {
+        let mut offset = gap_start;
+        while let Some(found) = source[offset..start].find('#') {
+            let comment_start = offset + found;
+            let comment_end = source[comment_start..start]
+                .find('\n')
+                .map_or(start, |len| comment_start + len);
+            let line_start = source[..comment_start].rfind('\n').map_or(0, |pos| pos + 1);
+            comments.push(Comment {
+                start: comment_start,
+                text: source[comment_start..comment_end].trim_end().to_string(),
+                own_line: source[line_start..comment_start].trim().is_empty(),
+            });
+            offset = comment_end;
+        }
+        gap_start = end;
+    }
+    comments
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_spacing_and_indent() {
+        let source = "global x=5;\nif(x==5) {\nspeak \"five\"+x;\n};\nloop{input str;exit;}\n";
+        let expected = "global x = 5;\nif (x == 5) {\n    speak \"five\" + x;\n};\nloop {\n    input str;\n    exit;\n}\n";
+        assert_eq!(format_source(source).unwrap(), expected);
+        assert!(is_formatted(expected).unwrap());
+
+        let source = "speak substr( trim(x) ,1,len(x)-1 )+\"!\";\n";
+        let expected = "speak substr(trim(x), 1, len(x) - 1) + \"!\";\n";
+        assert_eq!(format_source(source).unwrap(), expected);
+
+        // 乘方为右结合，数字保留源码中的小数位数
+        let source = "x=(2^3)^2+2^(3^2)*1.50%(a%b);\n";
+        let expected = "x = (2 ^ 3) ^ 2 + 2 ^ 3 ^ 2 * 1.50 % (a % b);\n";
+        assert_eq!(format_source(source).unwrap(), expected);
+
+        let source = "if((x+\"1\")~\"^(?P<n>[0-9]+)$\"){speak n;};\n";
+        let expected = "if (x + \"1\" ~ \"^(?P<n>[0-9]+)$\") {\n    speak n;\n};\n";
+        assert_eq!(format_source(source).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_format_preserves_comments() {
+        let source = "# Author: test\n\nglobal bill=0; # 余额\nloop{\n  # 菜单\n  speak \"menu\";\n\n\n  exit;\n  # 结束\n}\n";
+        let expected = "# Author: test\n\nglobal bill = 0; # 余额\nloop {\n    # 菜单\n    speak \"menu\";\n\n    exit;\n    # 结束\n}\n";
+        assert_eq!(format_source(source).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_format_match() {
+        let source = "match input threshold 0.8{\nintent \"balance\":[\"余额\",regex \"^b$\"]=>{\nspeak \"b\";\n} # 查询\ndefault=>{}}\n";
+        let expected = "match input threshold 0.8 {\n    intent \"balance\": [\"余额\", regex \"^b$\"] => {\n        speak \"b\";\n    } # 查询\n    default => {}\n}\n";
+        assert_eq!(format_source(source).unwrap(), expected);
+        assert!(is_formatted(expected).unwrap());
+    }
+
+    #[test]
+    fn test_format_typed_input() {
+        let source = "input n AS integer prompt \"几个?\" max 2 else{\nspeak \"放弃\";};\ninput p as choice[\"a\",\"b\"]retry\"a/b?\";\n";
+        let expected = "input n as integer prompt \"几个?\" max 2 else {\n    speak \"放弃\";\n};\ninput p as choice [\"a\", \"b\"] retry \"a/b?\";\n";
+        assert_eq!(format_source(source).unwrap(), expected);
+        assert!(is_formatted(expected).unwrap());
+    }
+
+    #[test]
+    fn test_format_form() {
+        let source = "form transfer{ # 转账\nslot account AS integer regex \"^[0-9]{8}$\" prompt\"账号?\";\n\n\nslot amount as number prompt \"金额?\" retry \"请输入数字\";\nconfirm \"确认向\"+account+\"转账?\";}\n";
+        let expected = "form transfer { # 转账\n    slot account as integer regex \"^[0-9]{8}$\" prompt \"账号?\";\n\n    slot amount as number prompt \"金额?\" retry \"请输入数字\";\n    confirm \"确认向\" + account + \"转账?\";\n}\n";
+        assert_eq!(format_source(source).unwrap(), expected);
+        assert!(is_formatted(expected).unwrap());
+    }
+
+    #[test]
+    fn test_format_state() {
+        let source = "state Menu{\non enter{speak \"1. 余额\";}\n# 处理输入\non input choice{\nif(choice==\"1\"){GOTO Menu;};}}\nstate done{}\n";
+        let expected = "state Menu {\n    on enter {\n        speak \"1. 余额\";\n    }\n    # 处理输入\n    on input choice {\n        if (choice == \"1\") {\n            goto Menu;\n        };\n    }\n}\nstate done {}\n";
+        assert_eq!(format_source(source).unwrap(), expected);
+        assert!(is_formatted(expected).unwrap());
+    }
+
+    #[test]
+    fn test_format_collections() {
+        let source = "global m={\"a\":[1,2],\"b\":{\"c\":x[0]}};\nfor k in m{speak m[k];}\n";
+        let expected =
+            "global m = {\"a\": [1, 2], \"b\": {\"c\": x[0]}};\nfor k in m {\n    speak m[k];\n}\n";
+        assert_eq!(format_source(source).unwrap(), expected);
+        assert!(is_formatted(expected).unwrap());
+
+        let source = "Speak ONE of[\"您好\",name+\"!\"];\n";
+        let expected = "speak one of [\"您好\", name + \"!\"];\n";
+        assert_eq!(format_source(source).unwrap(), expected);
+
+        let source = "STORE bill=0;\nstore . bill=store.bill+x;\n";
+        let expected = "store bill = 0;\nstore.bill = store.bill + x;\n";
+        assert_eq!(format_source(source).unwrap(), expected);
+
+        let source = "IMPORT \"common/faq.dsl\"AS help;\ngoto help . menu;\n";
+        let expected = "import \"common/faq.dsl\" as help;\ngoto help.menu;\n";
+        assert_eq!(format_source(source).unwrap(), expected);
+
+        let source = "if (x == \"人工\") { TRANSFER \"用户要求\"+x; };\n";
+        let expected = "if (x == \"人工\") {\n    transfer \"用户要求\" + x;\n};\n";
+        assert_eq!(format_source(source).unwrap(), expected);
+
+        let source = "LOCALE \"en\";\nspeak @ greeting(name,count:n+1);speak @menu . title;\n";
+        let expected = "locale \"en\";\nspeak @greeting(name, count: n + 1);\nspeak @menu.title;\n";
+        assert_eq!(format_source(source).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_format_is_idempotent() {
+        let source = std::fs::read_to_string("examples/bank.dsl").unwrap();
+        let formatted = format_source(&source).unwrap();
+        assert_eq!(format_source(&formatted).unwrap(), formatted);
+    }
+}
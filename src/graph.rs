@@ -0,0 +1,428 @@
+use crate::ast::Statement;
+use std::collections::HashMap;
+
+/*
+ * 对话流程图导出
+ * 遍历语法树，生成机器人的控制流/对话图，
+ * 支持导出为Graphviz DOT与Mermaid两种格式。
+ * 图中包含以下节点：
+ * - 开始/结束
+ * - 打印语句(speak)
+ * - 输入语句(input)
+ * - 变量声明与表达式语句
+ * - 分支判断(if)，出边上标注条件
+ * - 意图匹配(match)，出边上标注意图名称
+ * - 循环(loop)，循环体末尾连回循环节点
+ * - 遍历(for)，循环体末尾连回遍历节点，遍历结束的出边标注done
+ * - 退出语句(exit)
+ * - 对话状态(state)，goto连到状态节点，on input 的处理代码末尾连回等待输入的节点
+ */
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NodeKind {
+    Start,
+    End,
+    Speak,
+    Input,
+    Action,
+    Branch,
+    Loop,
+    Exit,
+    State,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Node {
+    pub kind: NodeKind,
+    pub label: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Edge {
+    pub from: usize,
+    pub to: usize,
+    /* 分支条件，普通顺序执行的边没有标注 */
+    pub label: Option<String>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct DialogGraph {
+    pub nodes: Vec<Node>,
+    pub edges: Vec<Edge>,
+    /* 状态名对应的节点，goto可能出现在状态声明之前，因此预先创建 */
+    states: HashMap<String, usize>,
+}
+
+/* 尚未连接到后继节点的出边：(起点, 边上的标注) */
+type Pending = Vec<(usize, Option<String>)>;
+
+impl DialogGraph {
+    /* 根据语法树构建对话图 */
+    pub fn build(ast: &[Box<Statement>]) -> Self {
+        let mut graph = DialogGraph::default();
+        let start = graph.add_node(NodeKind::Start, "start".to_string());
+        for statement in ast {
+            if let Statement::State { name, .. } = &**statement {
+                let node = graph.add_node(NodeKind::State, format!("state {}", name));
+                graph.states.insert(name.clone(), node);
+            }
+        }
+        let mut pending = graph.walk_list(ast, vec![(start, None)]);
+        for statement in ast {
+            if let Statement::State {
+                name,
+                enter,
+                handler,
+            } = &**statement
+            {
+                let node = graph.states[name];
+                let exits = match enter {
+                    Some(enter) => graph.walk(enter, vec![(node, Some("enter".to_string()))]),
+                    None => vec![(node, None)],
+                };
+                match handler {
+                    Some(handler) => {
+                        let label = format!("on input {}", handler.variable);
+                        let listen = graph.add_node(NodeKind::Input, label);
+                        graph.connect(exits, listen);
+                        let exits = graph.walk(&handler.body, vec![(listen, None)]);
+                        graph.connect(exits, listen);
+                    }
+                    // 没有 on input 的状态执行完 on enter 后对话结束
+                    None => pending.extend(exits),
+                }
+            }
+        }
+        // 程序执行完毕但没有遇到exit时，同样会结束对话
+        if !pending.is_empty() {
+            let end = graph.add_node(NodeKind::End, "end".to_string());
+            graph.connect(pending, end);
+        }
+        graph
+    }
+
+    fn add_node(&mut self, kind: NodeKind, label: String) -> usize {
+        self.nodes.push(Node { kind, label });
+        self.nodes.len() - 1
+    }
+
+    fn connect(&mut self, pending: Pending, to: usize) {
+        for (from, label) in pending {
+            self.edges.push(Edge { from, to, label });
+        }
+    }
+
+    fn walk_list(&mut self, statements: &[Box<Statement>], mut pending: Pending) -> Pending {
+        for statement in statements {
+            pending = self.walk(statement, pending);
+        }
+        pending
+    }
+
+    /* 将一条语句接到pending之后，返回该语句执行完后的出边 */
+    fn walk(&mut self, statement: &Statement, pending: Pending) -> Pending {
+        let (kind, label) = match statement {
+            Statement::Block { statements } => return self.walk_list(statements, pending),
+            Statement::Branch { condition, then } => {
+                let node = self.add_node(NodeKind::Branch, condition.to_string());
+                self.connect(pending, node);
+                let mut exits = self.walk(then, vec![(node, Some(condition.to_string()))]);
+                exits.push((node, Some("else".to_string())));
+                return exits;
+            }
+            Statement::Match {
+                subject,
+                arms,
+                default,
+                ..
+            } => {
+                let label = match subject {
+                    Some(subject) => format!("match {}", subject),
+                    None => "match input".to_string(),
+                };
+                let node = self.add_node(NodeKind::Branch, label);
+                self.connect(pending, node);
+                let mut exits = Vec::new();
+                for arm in arms {
+                    exits.extend(self.walk(&arm.body, vec![(node, Some(arm.name.clone()))]));
+                }
+                let otherwise = vec![(node, Some("default".to_string()))];
+                match default {
+                    Some(default) => exits.extend(self.walk(default, otherwise)),
+                    None => exits.extend(otherwise),
+                }
+                return exits;
+            }
+            Statement::Loop { body } => {
+                let node = self.add_node(NodeKind::Loop, "loop".to_string());
+                self.connect(pending, node);
+                let exits = self.walk(body, vec![(node, None)]);
+                self.connect(exits, node);
+                // 语言中没有break，循环只能通过exit离开
+                return Vec::new();
+            }
+            Statement::For {
+                variable,
+                iterable,
+                body,
+            } => {
+                let label = format!("for {} in {}", variable, iterable);
+                let node = self.add_node(NodeKind::Loop, label);
+                self.connect(pending, node);
+                let exits = self.walk(body, vec![(node, None)]);
+                self.connect(exits, node);
+                return vec![(node, Some("done".to_string()))];
+            }
+            Statement::TypedInput {
+                name,
+                input_type,
+                fallback,
+                ..
+            } => {
+                let label = format!("input {} as {}", name, input_type);
+                let node = self.add_node(NodeKind::Input, label);
+                self.connect(pending, node);
+                let mut exits = vec![(node, None)];
+                if let Some(fallback) = fallback {
+                    exits.extend(self.walk(fallback, vec![(node, Some("else".to_string()))]));
+                }
+                return exits;
+            }
+            Statement::Form { name, slots, .. } => {
+                let names: Vec<&str> = slots.iter().map(|slot| slot.name.as_str()).collect();
+                (
+                    NodeKind::Input,
+                    format!("form {} ({})", name, names.join(", ")),
+                )
+            }
+            Statement::Exit => {
+                let node = self.add_node(NodeKind::Exit, "exit".to_string());
+                self.connect(pending, node);
+                return Vec::new();
+            }
+            // 状态的内容由build单独展开
+            Statement::State { .. } | Statement::Import { .. } => return pending,
+            Statement::Goto { state } => {
+                match self.states.get(state) {
+                    Some(node) => self.connect(pending, *node),
+                    None => {
+                        let node = self.add_node(NodeKind::Action, format!("goto {}", state));
+                        self.connect(pending, node);
+                    }
+                }
+                return Vec::new();
+            }
+            Statement::Speak { expression } => (NodeKind::Speak, format!("speak {}", expression)),
+            Statement::SpeakOneOf { options } => {
+                (NodeKind::Speak, format!("speak one of {}", options))
+            }
+            Statement::Input { input } => (NodeKind::Input, format!("input {}", input)),
+            Statement::Var { name, init } => {
+                (NodeKind::Action, format!("global {} = {}", name, init))
+            }
+            Statement::Local { name, init } => {
+                (NodeKind::Action, format!("local {} = {}", name, init))
+            }
+            Statement::Store { key, init } => {
+                (NodeKind::Action, format!("store {} = {}", key, init))
+            }
+            Statement::Locale { locale } => (NodeKind::Action, format!("locale {}", locale)),
+            // 人工客服交还控制后从这里继续，因此与普通语句一样接到下一条语句
+            Statement::Transfer { reason } => (NodeKind::Action, format!("transfer {}", reason)),
+            Statement::Expression { expression } => (NodeKind::Action, expression.to_string()),
+        };
+        let node = self.add_node(kind, label);
+        self.connect(pending, node);
+        vec![(node, None)]
+    }
+
+    /* 导出为Graphviz DOT格式 */
+    pub fn to_dot(&self) -> String {
+        let mut out = String::from("digraph dialog {\n    node [fontname=\"sans-serif\"];\n");
+        for (id, node) in self.nodes.iter().enumerate() {
+            let shape = match node.kind {
+                NodeKind::Start | NodeKind::End => "ellipse",
+                NodeKind::Speak | NodeKind::Action => "box",
+                NodeKind::Input => "parallelogram",
+                NodeKind::Branch => "diamond",
+                NodeKind::Loop => "hexagon",
+                NodeKind::Exit => "doublecircle",
+                NodeKind::State => "box3d",
+            };
+            out.push_str(&format!(
+                "    n{} [shape={}, label=\"{}\"];\n",
+                id,
+                shape,
+                escape_dot(&node.label)
+            ));
+        }
+        for edge in &self.edges {
+            match &edge.label {
+                Some(label) => out.push_str(&format!(
+                    "    n{} -> n{} [label=\"{}\"];\n",
+                    edge.from,
+                    edge.to,
+                    escape_dot(label)
+                )),
+                None => out.push_str(&format!("    n{} -> n{};\n", edge.from, edge.to)),
+            }
+        }
+        out.push_str("}\n");
+        out
+    }
+
+    /* 导出为Mermaid flowchart格式 */
+    pub fn to_mermaid(&self) -> String {
+        let mut out = String::from("flowchart TD\n");
+        for (id, node) in self.nodes.iter().enumerate() {
+            let label = escape_mermaid(&node.label);
+            let shape = match node.kind {
+                NodeKind::Start | NodeKind::End => format!("([\"{}\"])", label),
+                NodeKind::Speak | NodeKind::Action => format!("[\"{}\"]", label),
+                NodeKind::Input => format!("[/\"{}\"/]", label),
+                NodeKind::Branch => format!("{{\"{}\"}}", label),
+                NodeKind::Loop => format!("{{{{\"{}\"}}}}", label),
+                NodeKind::Exit => format!("((\"{}\"))", label),
+                NodeKind::State => format!("[[\"{}\"]]", label),
+            };
+            out.push_str(&format!("    n{}{}\n", id, shape));
+        }
+        for edge in &self.edges {
+            match &edge.label {
+                Some(label) => out.push_str(&format!(
+                    "    n{} -->|\"{}\"| n{}\n",
+                    edge.from,
+                    escape_mermaid(label),
+                    edge.to
+                )),
+                None => out.push_str(&format!("    n{} --> n{}\n", edge.from, edge.to)),
+            }
+        }
+        out
+    }
+}
+
+fn escape_dot(label: &str) -> String {
+    label.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+fn escape_mermaid(label: &str) -> String {
+    label.replace('"', "#quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::{Expr, LiteralValue, Program};
+    use crate::tokens::Token;
+
+    /* loop { input str; if (str == "e") { exit; }; } */
+    fn menu_ast() -> Program {
+        vec![Box::new(Statement::Loop {
+            body: Box::new(Statement::Block {
+                statements: vec![
+                    Box::new(Statement::Input {
+                        input: "str".to_string(),
+                    }),
+                    Box::new(Statement::Branch {
+                        condition: Box::new(Expr::Binary {
+                            left: Box::new(Expr::Variable {
+                                name: "str".to_string(),
+                            }),
+                            operator: Token::WEqual,
+                            right: Box::new(Expr::Literal {
+                                value: LiteralValue::String("e".to_string()),
+                            }),
+                        }),
+                        then: Box::new(Statement::Block {
+                            statements: vec![Box::new(Statement::Exit)],
+                        }),
+                    }),
+                ],
+            }),
+        })]
+    }
+
+    #[test]
+    fn test_build_menu_graph() {
+        let graph = DialogGraph::build(&menu_ast());
+        let kinds: Vec<NodeKind> = graph.nodes.iter().map(|node| node.kind).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                NodeKind::Start,
+                NodeKind::Loop,
+                NodeKind::Input,
+                NodeKind::Branch,
+                NodeKind::Exit
+            ]
+        );
+        let edges: Vec<(usize, usize, Option<&str>)> = graph
+            .edges
+            .iter()
+            .map(|edge| (edge.from, edge.to, edge.label.as_deref()))
+            .collect();
+        assert_eq!(
+            edges,
+            vec![
+                (0, 1, None),
+                (1, 2, None),
+                (2, 3, None),
+                (3, 4, Some("str == \"e\"")),
+                // 条件不成立时回到循环开头
+                (3, 1, Some("else")),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_export_formats() {
+        let graph = DialogGraph::build(&menu_ast());
+        let dot = graph.to_dot();
+        assert!(dot.starts_with("digraph dialog {"));
+        assert!(dot.contains("n3 [shape=diamond, label=\"str == \\\"e\\\"\"];"));
+        assert!(dot.contains("n3 -> n4 [label=\"str == \\\"e\\\"\"];"));
+
+        let mermaid = graph.to_mermaid();
+        assert!(mermaid.starts_with("flowchart TD"));
+        assert!(mermaid.contains("n2[/\"input str\"/]"));
+        assert!(mermaid.contains("n3 -->|\"str == #quot;e#quot;\"| n4"));
+    }
+
+    #[test]
+    fn test_states() {
+        let ast = crate::parser::parse_program(
+            "goto main; state main { on enter { speak \"hi\"; } on input text { goto done; } } state done { }",
+        )
+        .unwrap();
+        let graph = DialogGraph::build(&ast);
+        let labels: Vec<&str> = graph.nodes.iter().map(|node| node.label.as_str()).collect();
+        assert_eq!(
+            labels,
+            vec![
+                "start",
+                "state main",
+                "state done",
+                "speak \"hi\"",
+                "on input text",
+                "end"
+            ]
+        );
+        let edges: Vec<(usize, usize, Option<&str>)> = graph
+            .edges
+            .iter()
+            .map(|edge| (edge.from, edge.to, edge.label.as_deref()))
+            .collect();
+        assert_eq!(
+            edges,
+            vec![
+                (0, 1, None),
+                (1, 3, Some("enter")),
+                (3, 4, None),
+                (4, 2, None),
+                (2, 5, None),
+            ]
+        );
+        assert!(graph.to_mermaid().contains("n1[[\"state main\"]]"));
+    }
+}
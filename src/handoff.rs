@@ -0,0 +1,102 @@
+use crate::value::Map;
+use serde::{Deserialize, Serialize};
+
+/*
+ * 转人工
+ * 脚本执行到 transfer "原因"; 时机器人暂停，向宿主发出一个转接事件：
+ * 转接的原因、到目前为止的对话记录以及会话中已经收集到的变量，
+ * 人工客服据此接手对话。客服处理完后由宿主交还控制：
+ * - Handback::End     对话由人工结束，会话随之结束
+ * - Handback::Resume  脚本从transfer之后继续执行，variables中的值先写入会话，
+ *                     例如客服确认了的订单号
+ * 转接期间用户发来的消息不再交给脚本处理。
+ */
+
+/* 对话记录中的一条 */
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Line {
+    pub role: Role,
+    pub text: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    /* 机器人的输出 */
+    Bot,
+    /* 用户的消息 */
+    User,
+}
+
+impl Line {
+    pub fn bot(text: &str) -> Self {
+        Self {
+            role: Role::Bot,
+            text: text.to_string(),
+        }
+    }
+
+    pub fn user(text: &str) -> Self {
+        Self {
+            role: Role::User,
+            text: text.to_string(),
+        }
+    }
+}
+
+/* 转接事件 */
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Handoff {
+    pub reason: String,
+    pub transcript: Vec<Line>,
+    /* 转接时可见的所有变量，内层作用域的同名变量优先 */
+    pub variables: Map,
+}
+
+/* 宿主交还控制的方式，JSON形式为 {"action": "resume", "variables": {...}} 或 {"action": "end"} */
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum Handback {
+    Resume {
+        #[serde(default)]
+        variables: Map,
+    },
+    End,
+}
+
+impl Handback {
+    /* 不写入任何变量，直接继续执行脚本 */
+    pub fn resume() -> Self {
+        Handback::Resume {
+            variables: Map::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::value::Value;
+
+    #[test]
+    fn test_handback_json() {
+        let resume: Handback =
+            serde_json::from_str(r#"{"action": "resume", "variables": {"order": "A12"}}"#).unwrap();
+        let mut variables = Map::new();
+        variables.insert("order".to_string(), Value::from("A12"));
+        assert_eq!(resume, Handback::Resume { variables });
+        assert_eq!(
+            serde_json::from_str::<Handback>(r#"{"action": "resume"}"#).unwrap(),
+            Handback::resume()
+        );
+        assert_eq!(
+            serde_json::from_str::<Handback>(r#"{"action": "end"}"#).unwrap(),
+            Handback::End
+        );
+        assert!(serde_json::from_str::<Handback>(r#"{"action": "wait"}"#).is_err());
+        assert_eq!(
+            serde_json::to_string(&Line::user("hi")).unwrap(),
+            r#"{"role":"user","text":"hi"}"#
+        );
+    }
+}
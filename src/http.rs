@@ -0,0 +1,508 @@
+use crate::handoff::Handback;
+use crate::interpreter::{Interpreter, Session, State, Turn};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hasher};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/*
+ * 面向网页组件的HTTP JSON接口
+ * POST /sessions                 开始新的对话，返回会话id与开场的输出；
+ *                                store中的值缺省以会话id作为用户；可信模式下请求体可以为
+ *                                {"user": "..."}，store中的值按该用户保存
+ * POST /sessions/{id}/messages   请求体为 {"text": "..."}，返回机器人的回复以及对话是否结束；
+ *                                脚本执行到transfer时回复中带有转接事件 "transfer"，
+ *                                之后发来的消息返回409，直到人工客服交还控制
+ * POST /sessions/{id}/handback   人工客服交还控制，请求体为 {"action": "resume", "variables": {...}}
+ *                                或 {"action": "end"}，返回与发送消息相同的回复
+ * GET  /sessions/{id}            查询会话状态、当前的对话状态、全局变量与转接事件
+ * 会话保存在内存中，由解释器的resume推进，不经过标准输入输出。
+ * 脚本运行出错时该会话被丢弃并返回500，不影响其他会话；
+ * 结束的会话保留一段时间供查询，没有结束但长时间没有收到消息的会话同样过期，
+ * 过期的会话在开始新的对话时被清理。
+ * 脚本运行期间只锁住当前会话，多个会话的请求可以在不同线程上同时处理。
+ * 任何能访问接口的客户端都能冒充其他用户，只有只允许可信的后端服务访问时才应当开启可信模式。
+ */
+
+/* 结束的会话默认保留的时间 */
+pub const FINISHED_TTL: Duration = Duration::from_secs(300);
+
+/* 没有结束的会话在最后一条消息之后默认保留的时间 */
+pub const IDLE_TTL: Duration = Duration::from_secs(30 * 60);
+
+pub struct Api {
+    interpreter: Arc<Interpreter>,
+    sessions: Mutex<HashMap<String, Arc<Mutex<Entry>>>>,
+    counter: AtomicU64,
+    /* 会话id中的随机部分，避免id被猜出 */
+    random: RandomState,
+    ttl: Duration,
+    idle_ttl: Duration,
+    /* 是否允许创建会话时指定store的用户 */
+    trusted: bool,
+}
+
+struct Entry {
+    session: Session,
+    /* 最后一次推进会话的时间，会话结束时即为结束的时间 */
+    active: Instant,
+    /* 脚本运行出错，会话已从表中移除，仍持有该会话的请求按会话不存在处理 */
+    failed: bool,
+}
+
+#[derive(Deserialize)]
+struct Message {
+    text: String,
+}
+
+#[derive(Deserialize, Default)]
+struct NewSession {
+    user: Option<String>,
+}
+
+impl Api {
+    pub fn new(interpreter: Arc<Interpreter>) -> Self {
+        Self {
+            interpreter,
+            sessions: Mutex::new(HashMap::new()),
+            counter: AtomicU64::new(0),
+            random: RandomState::new(),
+            ttl: FINISHED_TTL,
+            idle_ttl: IDLE_TTL,
+            trusted: false,
+        }
+    }
+
+    /* 修改结束的会话保留的时间 */
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    /* 修改没有结束的会话在最后一条消息之后保留的时间 */
+    pub fn with_idle_ttl(mut self, ttl: Duration) -> Self {
+        self.idle_ttl = ttl;
+        self
+    }
+
+    /* 可信模式：创建会话的请求可以指定store的用户 */
+    pub fn with_trusted(mut self, trusted: bool) -> Self {
+        self.trusted = trusted;
+        self
+    }
+
+    fn new_id(&self) -> String {
+        let count = self.counter.fetch_add(1, Ordering::Relaxed);
+        let mut hasher = self.random.build_hasher();
+        hasher.write_u64(count);
+        format!("{:x}{:016x}", count, hasher.finish())
+    }
+
+    /* 处理一个请求，返回状态码与JSON响应体 */
+    pub fn handle(&self, method: &str, url: &str, body: &str) -> (u16, Value) {
+        let path = url.split('?').next().unwrap_or("");
+        let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+        match (method, segments.as_slice()) {
+            ("POST", ["sessions"]) => self.create(body),
+            ("POST", ["sessions", id, "messages"]) => self.message(id, body),
+            ("POST", ["sessions", id, "handback"]) => self.hand_back(id, body),
+            ("GET", ["sessions", id]) => self.state(id),
+            (_, ["sessions", ..]) => error(405, "Method not allowed."),
+            _ => error(404, "Not found."),
+        }
+    }
+
+    fn create(&self, body: &str) -> (u16, Value) {
+        // 请求体可以为空
+        let request: NewSession = match body.trim() {
+            "" => NewSession::default(),
+            body => match serde_json::from_str(body) {
+                Ok(request) => request,
+                Err(e) => return error(400, &format!("Invalid request: {}", e)),
+            },
+        };
+        if request.user.is_some() && !self.trusted {
+            return error(403, "Choosing the user requires a trusted server.");
+        }
+        let id = self.new_id();
+        let user = request.user.as_deref().unwrap_or(&id);
+        let interpreter = &self.interpreter;
+        let started = panic::catch_unwind(AssertUnwindSafe(|| interpreter.start_for(user)));
+        let (session, turn) = match started {
+            Ok(started) => started,
+            Err(_) => return error(500, "The script failed to run."),
+        };
+        let mut response = reply(&turn, &session);
+        response["id"] = json!(id);
+        let entry = Entry {
+            active: Instant::now(),
+            session,
+            failed: false,
+        };
+        let mut sessions = self.sessions.lock().unwrap();
+        self.sweep(&mut sessions);
+        sessions.insert(id, Arc::new(Mutex::new(entry)));
+        (201, response)
+    }
+
+    /* 清理超过保留时间的会话，正在处理请求的会话不会被清理 */
+    fn sweep(&self, sessions: &mut HashMap<String, Arc<Mutex<Entry>>>) {
+        sessions.retain(|_, entry| match entry.try_lock() {
+            Ok(entry) => {
+                let ttl = match entry.session.state() {
+                    State::Finished => self.ttl,
+                    State::NeedsInput | State::Transferred => self.idle_ttl,
+                };
+                entry.active.elapsed() < ttl
+            }
+            Err(_) => true,
+        });
+    }
+
+    /* 锁住会话后处理请求，处理期间不持有会话表的锁，会话不存在时返回404 */
+    fn with_entry(&self, id: &str, f: impl FnOnce(&mut Entry) -> (u16, Value)) -> (u16, Value) {
+        let entry = self.sessions.lock().unwrap().get(id).cloned();
+        match entry {
+            Some(entry) => {
+                let mut entry = entry.lock().unwrap();
+                match entry.failed {
+                    true => error(404, "Unknown session."),
+                    false => f(&mut entry),
+                }
+            }
+            None => error(404, "Unknown session."),
+        }
+    }
+
+    fn message(&self, id: &str, body: &str) -> (u16, Value) {
+        let message: Message = match serde_json::from_str(body) {
+            Ok(message) => message,
+            Err(e) => return error(400, &format!("Invalid message: {}", e)),
+        };
+        self.with_entry(id, |entry| {
+            match entry.session.state() {
+                State::Finished => return error(409, "The session has ended."),
+                State::Transferred => {
+                    return error(409, "The session has been transferred to a human agent.")
+                }
+                State::NeedsInput => {}
+            }
+            let interpreter = &self.interpreter;
+            let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                interpreter.resume(&mut entry.session, &message.text)
+            }));
+            self.respond(entry, id, result)
+        })
+    }
+
+    fn hand_back(&self, id: &str, body: &str) -> (u16, Value) {
+        let handback: Handback = match serde_json::from_str(body) {
+            Ok(handback) => handback,
+            Err(e) => return error(400, &format!("Invalid handback: {}", e)),
+        };
+        self.with_entry(id, |entry| {
+            if entry.session.state() != State::Transferred {
+                return error(409, "The session has not been transferred.");
+            }
+            let interpreter = &self.interpreter;
+            let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                interpreter.hand_back(&mut entry.session, handback)
+            }));
+            self.respond(entry, id, result)
+        })
+    }
+
+    /* 记录推进会话的时间，脚本运行出错时丢弃该会话 */
+    fn respond(&self, entry: &mut Entry, id: &str, result: thread::Result<Turn>) -> (u16, Value) {
+        match result {
+            Ok(turn) => {
+                entry.active = Instant::now();
+                (200, reply(&turn, &entry.session))
+            }
+            Err(_) => {
+                entry.failed = true;
+                self.sessions.lock().unwrap().remove(id);
+                error(500, "The script failed to run.")
+            }
+        }
+    }
+
+    fn state(&self, id: &str) -> (u16, Value) {
+        self.with_entry(id, |Entry { session, .. }| {
+            (
+                200,
+                json!({
+                    "id": id,
+                    "state": match session.state() {
+                        State::NeedsInput => "needs_input",
+                        State::Finished => "finished",
+                        State::Transferred => "transferred",
+                    },
+                    "dialog_state": session.current_state(),
+                    "user": session.user(),
+                    "variables": session.env.globals(),
+                    "transfer": session.handoff(),
+                }),
+            )
+        })
+    }
+}
+
+/* 一轮的回复，转给人工客服时带上转接事件 */
+fn reply(turn: &Turn, session: &Session) -> Value {
+    let mut reply = json!({
+        "output": turn.output,
+        "ended": turn.state == State::Finished,
+    });
+    if let Some(handoff) = session.handoff() {
+        reply["transfer"] = json!(handoff);
+    }
+    reply
+}
+
+fn error(status: u16, message: &str) -> (u16, Value) {
+    (status, json!({ "error": message }))
+}
+
+/* 在给定的HTTP服务上处理请求，直到服务关闭，每个CPU核心一个工作线程 */
+pub fn serve(api: &Api, server: &tiny_http::Server) {
+    let workers = thread::available_parallelism().map_or(4, |n| n.get());
+    thread::scope(|scope| {
+        for _ in 0..workers {
+            scope.spawn(|| {
+                for request in server.incoming_requests() {
+                    answer(api, request);
+                }
+            });
+        }
+    });
+}
+
+fn answer(api: &Api, mut request: tiny_http::Request) {
+    let mut body = String::new();
+    let (status, value) = match request.as_reader().read_to_string(&mut body) {
+        Ok(_) => api.handle(request.method().as_str(), request.url(), &body),
+        Err(e) => error(400, &format!("Invalid request body: {}", e)),
+    };
+    let header =
+        tiny_http::Header::from_bytes("Content-Type", "application/json").expect("合法的响应头");
+    let response = tiny_http::Response::from_string(value.to_string())
+        .with_status_code(status)
+        .with_header(header);
+    if let Err(e) = request.respond(response) {
+        eprintln!("发送响应失败: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_program;
+
+    fn api(source: &str) -> Api {
+        Api::new(Arc::new(Interpreter::new(parse_program(source).unwrap())))
+    }
+
+    #[test]
+    fn test_conversation() {
+        let api = api("global n = \"\"; speak \"name?\"; input n; speak \"hi \" + n;");
+        let (status, created) = api.handle("POST", "/sessions", "");
+        assert_eq!(status, 201);
+        assert_eq!(created["output"], json!(["name?"]));
+        assert_eq!(created["ended"], json!(false));
+        let id = created["id"].as_str().unwrap();
+
+        let (status, state) = api.handle("GET", &format!("/sessions/{}", id), "");
+        assert_eq!(status, 200);
+        assert_eq!(state["state"], "needs_input");
+        assert_eq!(state["dialog_state"], Value::Null);
+
+        let url = format!("/sessions/{}/messages", id);
+        let (status, reply) = api.handle("POST", &url, r#"{"text": "Tom"}"#);
+        assert_eq!(status, 200);
+        assert_eq!(reply, json!({ "output": ["hi Tom"], "ended": true }));
+
+        let (_, state) = api.handle("GET", &format!("/sessions/{}", id), "");
+        assert_eq!(state["state"], "finished");
+        assert_eq!(state["variables"], json!({ "n": "Tom" }));
+        assert_eq!(api.handle("POST", &url, r#"{"text": "x"}"#).0, 409);
+    }
+
+    #[test]
+    fn test_transfer_and_handback() {
+        let api = api("input issue; transfer \"complaint\"; speak \"back: \" + order;");
+        let (_, created) = api.handle("POST", "/sessions", "");
+        let id = created["id"].as_str().unwrap();
+        let messages = format!("/sessions/{}/messages", id);
+        let handback = format!("/sessions/{}/handback", id);
+        assert_eq!(api.handle("POST", &handback, r#"{"action": "end"}"#).0, 409);
+
+        let (status, reply) = api.handle("POST", &messages, r#"{"text": "broken"}"#);
+        assert_eq!(status, 200);
+        assert_eq!(reply["ended"], json!(false));
+        assert_eq!(reply["transfer"]["reason"], "complaint");
+        assert_eq!(
+            reply["transfer"]["transcript"],
+            json!([{ "role": "user", "text": "broken" }])
+        );
+        assert_eq!(reply["transfer"]["variables"], json!({ "issue": "broken" }));
+        let (_, state) = api.handle("GET", &format!("/sessions/{}", id), "");
+        assert_eq!(state["state"], "transferred");
+        // 转接期间用户的消息不再交给脚本
+        assert_eq!(
+            api.handle("POST", &messages, r#"{"text": "hello?"}"#).0,
+            409
+        );
+
+        assert_eq!(
+            api.handle("POST", &handback, r#"{"action": "wait"}"#).0,
+            400
+        );
+        let body = r#"{"action": "resume", "variables": {"order": "A12"}}"#;
+        let (status, reply) = api.handle("POST", &handback, body);
+        assert_eq!(status, 200);
+        assert_eq!(reply, json!({ "output": ["back: A12"], "ended": true }));
+    }
+
+    #[test]
+    fn test_store_per_user() {
+        let api = api(
+            "store visits = 0; store.visits = to_number(store.visits) + 1; speak store.visits;",
+        );
+        // 只有可信模式下才能指定用户
+        assert_eq!(
+            api.handle("POST", "/sessions", r#"{"user": "alice"}"#).0,
+            403
+        );
+        let api = api.with_trusted(true);
+        let start = |body| api.handle("POST", "/sessions", body).1;
+        assert_eq!(start(r#"{"user": "alice"}"#)["output"], json!(["1"]));
+        assert_eq!(start(r#"{"user": "alice"}"#)["output"], json!(["2"]));
+        assert_eq!(start(r#"{"user": "bob"}"#)["output"], json!(["1"]));
+        // 未指定用户时每个会话各自一份
+        let created = start("");
+        assert_eq!(created["output"], json!(["1"]));
+        let url = format!("/sessions/{}", created["id"].as_str().unwrap());
+        let (_, state) = api.handle("GET", &url, "");
+        assert_eq!(state["user"], created["id"]);
+        assert_eq!(api.handle("POST", "/sessions", "[1]").0, 400);
+    }
+
+    #[test]
+    fn test_errors() {
+        let api = api("input x; speak x - 1;");
+        assert_eq!(api.handle("GET", "/sessions/missing", "").0, 404);
+        assert_eq!(api.handle("GET", "/other", "").0, 404);
+        assert_eq!(api.handle("DELETE", "/sessions", "").0, 405);
+
+        let (_, created) = api.handle("POST", "/sessions", "");
+        let url = format!("/sessions/{}/messages", created["id"].as_str().unwrap());
+        assert_eq!(api.handle("POST", &url, "not json").0, 400);
+        // 运行时错误只影响当前会话
+        assert_eq!(api.handle("POST", &url, r#"{"text": "abc"}"#).0, 500);
+        assert_eq!(api.handle("POST", &url, r#"{"text": "abc"}"#).0, 404);
+    }
+
+    #[test]
+    fn test_finished_sessions_expire() {
+        let api = api("speak \"bye\";").with_ttl(Duration::ZERO);
+        let (_, created) = api.handle("POST", "/sessions", "");
+        let url = format!("/sessions/{}", created["id"].as_str().unwrap());
+        assert_eq!(api.handle("GET", &url, "").1["state"], "finished");
+        // 开始新的对话时清理
+        api.handle("POST", "/sessions", "");
+        assert_eq!(api.handle("GET", &url, "").0, 404);
+    }
+
+    #[test]
+    fn test_idle_sessions_expire() {
+        let source = "loop { input x; speak x; }";
+        let api = api(source).with_idle_ttl(Duration::from_millis(400));
+        let (_, idle) = api.handle("POST", "/sessions", "");
+        let (_, active) = api.handle("POST", "/sessions", "");
+        let idle = format!("/sessions/{}", idle["id"].as_str().unwrap());
+        let active = format!("/sessions/{}", active["id"].as_str().unwrap());
+        thread::sleep(Duration::from_millis(300));
+        let messages = format!("{}/messages", active);
+        assert_eq!(api.handle("POST", &messages, r#"{"text": "hi"}"#).0, 200);
+        thread::sleep(Duration::from_millis(200));
+        // 收到消息的会话重新计时，没有结束的会话同样过期
+        api.handle("POST", "/sessions", "");
+        assert_eq!(api.handle("GET", &idle, "").0, 404);
+        assert_eq!(api.handle("GET", &active, "").1["state"], "needs_input");
+    }
+
+    #[test]
+    fn test_sessions_run_concurrently() {
+        use crate::store::{Error, Storage};
+        use crate::value;
+        use std::sync::mpsc;
+
+        /* 读取时通知测试，并等待测试放行 */
+        struct Gate {
+            entered: Mutex<mpsc::Sender<()>>,
+            release: Mutex<mpsc::Receiver<()>>,
+        }
+        impl Storage for Gate {
+            fn get(&self, _user: &str, _key: &str) -> Result<Option<value::Value>, Error> {
+                self.entered.lock().unwrap().send(()).unwrap();
+                self.release.lock().unwrap().recv().unwrap();
+                Ok(None)
+            }
+            fn set(&self, _user: &str, _key: &str, _value: &value::Value) -> Result<(), Error> {
+                Ok(())
+            }
+        }
+
+        let (entered, wait_entered) = mpsc::channel();
+        let (release, wait_release) = mpsc::channel();
+        let gate = Gate {
+            entered: Mutex::new(entered),
+            release: Mutex::new(wait_release),
+        };
+        let source = "loop { input x; if (x == \"slow\") { store k = 0; }; speak x; }";
+        let interpreter =
+            Interpreter::new(parse_program(source).unwrap()).with_store(Arc::new(gate));
+        let api = Api::new(Arc::new(interpreter));
+        let url =
+            |created: Value| format!("/sessions/{}/messages", created["id"].as_str().unwrap());
+        let slow = url(api.handle("POST", "/sessions", "").1);
+        let fast = url(api.handle("POST", "/sessions", "").1);
+        thread::scope(|scope| {
+            let blocked = scope.spawn(|| api.handle("POST", &slow, r#"{"text": "slow"}"#));
+            wait_entered.recv().unwrap();
+            // 一个会话的脚本还在运行时，其他会话的请求照常处理
+            let (status, reply) = api.handle("POST", &fast, r#"{"text": "fast"}"#);
+            assert_eq!(status, 200);
+            assert_eq!(reply["output"], json!(["fast"]));
+            release.send(()).unwrap();
+            assert_eq!(blocked.join().unwrap().1["output"], json!(["slow"]));
+        });
+    }
+
+    #[test]
+    fn test_http_roundtrip() {
+        use std::io::{Read, Write};
+        let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+        let addr = server.server_addr().to_ip().unwrap();
+        std::thread::spawn(move || serve(&api("speak \"hello\";"), &server));
+
+        let mut stream = std::net::TcpStream::connect(addr).unwrap();
+        write!(
+            stream,
+            "POST /sessions HTTP/1.1\r\nHost: localhost\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+        )
+        .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 201"));
+        assert!(response.contains("application/json"));
+        assert!(response.contains(r#""output":["hello"]"#));
+    }
+}
@@ -0,0 +1,121 @@
+use crate::ast::Pattern;
+
+/*
+ * match语句的意图识别
+ * 每条规则对输入给出[0, 1]之间的置信度，意图的置信度取其规则中的最大值：
+ * - 关键词：输入与关键词都先归一化(转小写、去掉空白与标点)，
+ *   输入中包含关键词时置信度为1，否则在输入中寻找与关键词最相近的片段，
+ *   以编辑距离换算相似度，例如 "balanse" 与 "balance" 的置信度约为0.86
+ * - 正则表达式：在原始输入上匹配，匹配成功时置信度为1，否则为0
+ * 置信度最高且不低于阈值的意图胜出，相同时取先声明的意图。
+ */
+
+/* match语句没有给出threshold时使用的阈值 */
+pub const DEFAULT_THRESHOLD: f64 = 0.75;
+
+/* 选出输入对应的意图，没有意图达到阈值时返回None */
+pub fn classify(intents: &[&[Pattern]], threshold: f64, text: &str) -> Option<usize> {
+    let normalized = normalize(text);
+    let mut best: Option<(usize, f64)> = None;
+    for (index, patterns) in intents.iter().enumerate() {
+        let score = patterns
+            .iter()
+            .map(|pattern| confidence(pattern, text, &normalized))
+            .fold(0.0, f64::max);
+        if score >= threshold && best.is_none_or(|(_, best)| score > best) {
+            best = Some((index, score));
+        }
+    }
+    best.map(|(index, _)| index)
+}
+
+/* 单条规则对输入的置信度，normalized为归一化后的输入 */
+pub fn confidence(pattern: &Pattern, text: &str, normalized: &[char]) -> f64 {
+    match pattern {
+        Pattern::Regex(regex) => f64::from(u8::from(regex.is_match(text))),
+        Pattern::Keyword(keyword) => fuzzy(&normalize(keyword), normalized),
+    }
+}
+
+/* 转小写并去掉空白与标点，只保留字母、数字与汉字 */
+pub fn normalize(text: &str) -> Vec<char> {
+    text.chars()
+        .filter(|c| c.is_alphanumeric())
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+/* 关键词与输入中最相近的片段之间的相似度 */
+fn fuzzy(keyword: &[char], text: &[char]) -> f64 {
+    if keyword.is_empty() || text.is_empty() {
+        return 0.0;
+    }
+    if text.windows(keyword.len()).any(|window| window == keyword) {
+        return 1.0;
+    }
+    // 片段长度允许比关键词多或少一个字符，以容纳漏字与多字
+    let shortest = keyword.len().saturating_sub(1).max(1).min(text.len());
+    let longest = (keyword.len() + 1).min(text.len());
+    let mut best: f64 = 0.0;
+    for len in shortest..=longest {
+        for window in text.windows(len) {
+            let distance = levenshtein(keyword, window);
+            let similarity = 1.0 - distance as f64 / keyword.len().max(len) as f64;
+            best = best.max(similarity);
+        }
+    }
+    best
+}
+
+fn levenshtein(a: &[char], b: &[char]) -> usize {
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    let mut current = vec![0; b.len() + 1];
+    for (i, ca) in a.iter().enumerate() {
+        current[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(ca != cb);
+            current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        std::mem::swap(&mut previous, &mut current);
+    }
+    previous[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::Regex;
+
+    fn keywords(words: &[&str]) -> Vec<Pattern> {
+        words
+            .iter()
+            .map(|word| Pattern::Keyword(word.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_keyword_and_fuzzy() {
+        let balance = keywords(&["余额", "balance", "查看"]);
+        let recharge = keywords(&["充值", "top up"]);
+        let intents = [&balance[..], &recharge[..]];
+        let classify = |text| classify(&intents, DEFAULT_THRESHOLD, text);
+        assert_eq!(classify("I want to check my Balance!"), Some(0));
+        assert_eq!(classify("我想查一下余额"), Some(0));
+        assert_eq!(classify("please TOP-UP my account"), Some(1));
+        // 拼写错误仍能识别
+        assert_eq!(classify("my balanse"), Some(0));
+        assert_eq!(classify("你好"), None);
+    }
+
+    #[test]
+    fn test_regex_and_threshold() {
+        let order = [Pattern::Regex(Regex::new(r"^\d{6}$").unwrap())];
+        let status = keywords(&["status"]);
+        let intents = [&order[..], &status[..]];
+        assert_eq!(classify(&intents, 0.9, "123456"), Some(0));
+        assert_eq!(classify(&intents, 0.9, "order 123456"), None);
+        // "statvs" 的置信度约为0.83，低于0.9的阈值
+        assert_eq!(classify(&intents, 0.9, "statvs"), None);
+        assert_eq!(classify(&intents, 0.8, "statvs"), Some(1));
+    }
+}
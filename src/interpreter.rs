@@ -1,67 +1,430 @@
 use crate::ast::{self, Expr, Statement};
+use crate::builtins::Builtin;
+use crate::catalog::Catalog;
+use crate::clock::{Clock, SystemClock};
 use crate::tokens::Token;
-use std::collections::HashMap;
-use std::io;
+use crate::environment::Environment;
+use crate::handoff::{Handback, Handoff, Line};
+use crate::intent;
+use crate::random::Rng;
+use crate::store::{self, MemoryStore, Storage};
+use crate::validation;
+use crate::value::{Map, Value};
+use rust_decimal::prelude::ToPrimitive;
+use rust_decimal::{Decimal, MathematicalOps};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::{self, BufRead, Write};
+use std::sync::Arc;
+
+/*
+ * 解释器与外界交互的接口
+ * speak语句的输出与input语句的输入都经过这里，
+ * 默认实现为标准输入输出，测试时可以替换为预先写好的对话
+ */
+pub trait Io {
+    /* 输出一行 */
+    fn speak(&mut self, text: &str);
+    /* 读取一行输入，var为输入写入的变量(match input 与 on input 时为None)，输入结束时返回None */
+    fn input(&mut self, var: Option<&str>) -> Option<String>;
+    /* 执行到transfer语句，等待人工客服交还控制，默认没有人工客服，直接结束对话 */
+    fn transfer(&mut self, _handoff: &Handoff) -> Handback {
+        Handback::End
+    }
+}
+
+/* 标准输入输出 */
+pub struct StdIo;
+
+impl Io for StdIo {
+    fn speak(&mut self, text: &str) {
+        let mut stdout = io::stdout().lock();
+        writeln!(stdout, "{}", text).expect("无法写入输出");
+    }
+
+    fn input(&mut self, _var: Option<&str>) -> Option<String> {
+        let mut value = String::new();
+        let read = io::stdin().lock().read_line(&mut value).expect("无法读取输入");
+        if read == 0 {
+            return None;
+        }
+        // 去除回车符
+        Some(value.trim().to_string())
+    }
+
+    /* 在标准错误上输出转接事件，由终端前的人工客服输入 resume 继续，其余输入结束对话 */
+    fn transfer(&mut self, handoff: &Handoff) -> Handback {
+        let event = serde_json::to_string(handoff).expect("转接事件总是可以序列化");
+        eprintln!("{}", event);
+        match self.input(None).as_deref() {
+            Some("resume") => Handback::resume(),
+            _ => Handback::End,
+        }
+    }
+}
+
+/*
+ * 按顺序提供预先写好的输入，并记录所有输出，用于测试
+ * 转接时记录转接事件，下一条输入为 resume 时继续执行，否则结束对话
+ */
+#[derive(Debug, Default)]
+pub struct ScriptedIo {
+    inputs: VecDeque<String>,
+    pub output: Vec<String>,
+    pub handoffs: Vec<Handoff>,
+}
+
+impl ScriptedIo {
+    pub fn new<S: ToString>(inputs: &[S]) -> Self {
+        Self {
+            inputs: inputs.iter().map(|input| input.to_string()).collect(),
+            output: Vec::new(),
+            handoffs: Vec::new(),
+        }
+    }
+}
+
+impl Io for ScriptedIo {
+    fn speak(&mut self, text: &str) {
+        self.output.push(text.to_string());
+    }
+
+    fn input(&mut self, _var: Option<&str>) -> Option<String> {
+        self.inputs.pop_front()
+    }
+
+    fn transfer(&mut self, handoff: &Handoff) -> Handback {
+        self.handoffs.push(handoff.clone());
+        match self.input(None).as_deref() {
+            Some("resume") => Handback::resume(),
+            _ => Handback::End,
+        }
+    }
+}
+
+/* 会话当前所处的状态 */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum State {
+    /* 停在input语句处，等待用户的下一条消息 */
+    NeedsInput,
+    /* 遇到exit语句或程序执行完毕 */
+    Finished,
+    /* 停在transfer语句处，等待人工客服交还控制 */
+    Transferred,
+}
+
+/* 会话执行一轮的结果：这一轮中的所有输出以及执行后的状态 */
+#[derive(Debug, Clone, PartialEq)]
+pub struct Turn {
+    pub output: Vec<String>,
+    pub state: State,
+}
+
+/*
+ * 执行过程中产生的输出
+ * start与resume收集这一轮的输出作为Turn返回，
+ * interpret_with在产生时立即交给Io，出错或者死循环之前的输出也不会丢失
+ */
+enum Output<'io> {
+    Collect(Vec<String>),
+    Stream(&'io mut dyn Io),
+}
+
+impl Output<'_> {
+    fn push(&mut self, text: String) {
+        match self {
+            Output::Collect(lines) => lines.push(text),
+            Output::Stream(io) => io.speak(&text),
+        }
+    }
+
+    /* 收集到的输出，逐行交给Io时为空 */
+    fn into_lines(self) -> Vec<String> {
+        match self {
+            Output::Collect(lines) => lines,
+            Output::Stream(_) => Vec::new(),
+        }
+    }
+}
+
+/*
+ * 执行栈中的一帧
+ * 语句以其在语法树中的路径表示：第一个下标为顶层语句的序号，
+ * 之后每一层对应代码块中的序号，循环体、for的循环体、分支的then与输入语句的else固定为0，
+ * match语句中第i个意图的代码块为i，default为意图的个数。
+ * 帧中只保存路径而不保存引用，会话因此不依赖语法树的生命周期。
+ */
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+enum Frame {
+    /* 正在执行的语句序列，path为空时表示整个程序 */
+    Block { path: Vec<usize>, next: usize },
+    /* 正在执行的循环，每次回到这一帧时重新执行循环体 */
+    Loop { path: Vec<usize> },
+    /* 正在执行的for循环，items为循环开始时求出的各项，next为下一轮的序号 */
+    Each {
+        path: Vec<usize>,
+        items: Vec<Value>,
+        next: usize,
+    },
+}
+
+/* 挂起在带类型的input处时，该语句的路径以及已经尝试的次数 */
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Asking {
+    path: Vec<usize>,
+    attempts: u32,
+}
+
+/* 挂起在表单处时，该语句的路径以及正在询问的槽位，None表示等待用户确认 */
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Filling {
+    path: Vec<usize>,
+    slot: Option<usize>,
+}
+
+/*
+ * 一次对话的全部运行状态
+ * 与语法树分开保存，同一个解释器可以同时推进任意多个会话，
+ * 每个会话在input处挂起，收到下一条消息后通过Interpreter::resume继续执行
+ */
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Session {
+    pub env: Environment,
+    frames: Vec<Frame>,
+    /* 挂起时等待写入的变量 */
+    waiting: Option<String>,
+    /* 挂起在 match input 处时，该match语句的路径 */
+    #[serde(default)]
+    matching: Option<Vec<usize>>,
+    /* 等待写入的变量需要校验时不为None */
+    #[serde(default)]
+    asking: Option<Asking>,
+    /* 正在填写的表单 */
+    #[serde(default)]
+    filling: Option<Filling>,
+    /* 当前所处的对话状态 */
+    #[serde(default)]
+    dialog: Option<String>,
+    /* 当前状态的 on input 正在等待消息 */
+    #[serde(default)]
+    listening: bool,
+    /* speak one of 与 random() 使用的随机数发生器 */
+    #[serde(default)]
+    rng: Rng,
+    /* store中的值按这个用户保存 */
+    #[serde(default = "default_user")]
+    user: String,
+    /* locale语句切换到的语言，None时使用消息目录的默认语言 */
+    #[serde(default)]
+    locale: Option<String>,
+    /* 到目前为止的对话记录，只在程序中有transfer语句时记录，转接时交给人工客服 */
+    #[serde(default)]
+    transcript: Vec<Line>,
+    /* 挂起在transfer处时的转接事件 */
+    #[serde(default)]
+    handoff: Option<Handoff>,
+}
+
+fn default_user() -> String {
+    store::DEFAULT_USER.to_string()
+}
+
+impl Session {
+    /* 从程序开头执行的新会话 */
+    fn new(env: Environment, rng: Rng, user: &str) -> Self {
+        Self {
+            env,
+            frames: vec![Frame::Block {
+                path: Vec::new(),
+                next: 0,
+            }],
+            waiting: None,
+            matching: None,
+            asking: None,
+            filling: None,
+            dialog: None,
+            listening: false,
+            rng,
+            user: user.to_string(),
+            locale: None,
+            transcript: Vec::new(),
+            handoff: None,
+        }
+    }
+
+    /* 挂起时等待写入的变量名，挂起在 match input 处时为None */
+    pub fn waiting(&self) -> Option<&str> {
+        self.waiting.as_deref()
+    }
+
+    /* 会话所属的用户 */
+    pub fn user(&self) -> &str {
+        &self.user
+    }
+
+    /* locale语句切换到的语言，没有切换过时为None */
+    pub fn locale(&self) -> Option<&str> {
+        self.locale.as_deref()
+    }
+
+    /* 到目前为止的对话记录 */
+    pub fn transcript(&self) -> &[Line] {
+        &self.transcript
+    }
+
+    /* 挂起在transfer处时的转接事件 */
+    pub fn handoff(&self) -> Option<&Handoff> {
+        self.handoff.as_ref()
+    }
+
+    /* 当前所处的对话状态，没有执行过goto时为None */
+    pub fn current_state(&self) -> Option<&str> {
+        self.dialog.as_deref()
+    }
+
+    pub fn state(&self) -> State {
+        if self.handoff.is_some() {
+            State::Transferred
+        } else if self.waiting.is_some()
+            || self.matching.is_some()
+            || self.filling.is_some()
+            || self.listening
+        {
+            State::NeedsInput
+        } else {
+            State::Finished
+        }
+    }
+
+    /* 结束会话，退出所有尚未执行完的代码块 */
+    fn finish(&mut self) {
+        self.unwind();
+        self.waiting = None;
+        self.matching = None;
+        self.asking = None;
+        self.filling = None;
+        self.dialog = None;
+        self.listening = false;
+        self.handoff = None;
+    }
+
+    /* 退出所有尚未执行完的代码块，exit与goto共用 */
+    fn unwind(&mut self) {
+        for frame in self.frames.drain(..) {
+            if let Frame::Block { path, .. } = frame {
+                if !path.is_empty() {
+                    self.env.pop_scope();
+                }
+            }
+        }
+    }
+}
 
 pub struct Interpreter {
     /* 解释器运行的环境，实现较为简单。
      * 本解释器唯一需要注意的是variable和value之间的一一对应
      * 因此可以环境内部便是一个map，key=variable，value=value
      * 环境之间管理采用stack结构，
-     * 当进入一个block时可以将父环境拷贝，然后处理新增变量
-     * 执行完该block后可以直接pop掉
+     * 当进入一个block时压入新的作用域，执行完该block后直接pop掉，
+     * 具体的作用域规则见environment.rs
+     * interpret与interpret_with以该环境作为会话的初始环境，执行完毕后写回
      */
-    pub env: Vec<HashMap<String, String>>,
+    pub env: Environment,
     pub ast: Vec<Box<ast::Statement>>,
+    /* now() 等日期函数读取的时钟，所有会话共用 */
+    pub clock: Arc<dyn Clock>,
+    /* 新会话的随机数种子，None时每个会话取不同的种子 */
+    pub seed: Option<u64>,
+    /* store.key 读写的持久化存储，所有会话共用，按会话的用户区分 */
+    pub store: Arc<dyn Storage>,
+    /* start与interpret开始的会话所属的用户 */
+    pub user: String,
+    /* @消息 使用的多语言消息目录 */
+    pub catalog: Arc<Catalog>,
+    /* 程序中有transfer语句时才记录对话记录，转接时交给人工客服 */
+    transcribe: bool,
+}
+
+/*
+ * 表达式求值时可以访问的内容：会话中的变量、随机数发生器、用户与语言，
+ * 以及解释器的时钟、存储与消息目录
+ */
+pub struct Context<'a> {
+    pub env: &'a mut Environment,
+    pub rng: &'a mut Rng,
+    pub clock: &'a dyn Clock,
+    pub store: &'a dyn Storage,
+    pub user: &'a str,
+    pub catalog: &'a Catalog,
+    pub locale: Option<&'a str>,
 }
 
 impl Interpreter {
-    /* 创建解释器对象 */
+    /* 创建解释器对象，默认使用系统时钟与进程内的存储 */
     pub fn new(ast: Vec<Box<ast::Statement>>) -> Self {
-        let env = vec![HashMap::new()];
-        Self { env, ast }
-    }
-    /* 添加新环境 */
-    fn add_new_env(&mut self) {
-        let new_env = self.env.last().cloned().unwrap(); // 克隆上一个环境
-        self.env.push(new_env);
+        let env = Environment::new();
+        Self {
+            env,
+            clock: Arc::new(SystemClock),
+            seed: None,
+            store: Arc::new(MemoryStore::new()),
+            user: store::DEFAULT_USER.to_string(),
+            catalog: Arc::new(Catalog::default()),
+            transcribe: ast
+                .iter()
+                .any(|statement| statement.contains(|s| matches!(s, Statement::Transfer { .. }))),
+            ast,
+        }
     }
-    /* 从栈顶弹出当前环境 */
-    fn rm_now_env(&mut self) {
-        self.update_env();
-        self.env.pop();
+
+    /* 替换时钟，例如在测试中固定 now() 的结果 */
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
     }
-    /* 添加变量到当前环境中 */
-    fn add_new_var(&mut self, name: String, init: Box<ast::Expr>) {
-        if let Some(cur_env) = self.env.last_mut() {
-            cur_env.insert(name, init.trans(cur_env.clone()));
-        }
+
+    /* 固定随机数种子，每个会话的 speak one of 与 random() 都得到同样的序列 */
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = Some(seed);
+        self
     }
 
-    pub fn update_env(&mut self) {
-        if self.env.len() < 2 {
-            return; // 如果环境中的 HashMap 小于 2 个，无法进行比较
-        }
+    /* 替换存储，例如使用store::open打开的文件，进程退出后值仍然保留 */
+    pub fn with_store(mut self, store: Arc<dyn Storage>) -> Self {
+        self.store = store;
+        self
+    }
 
-        let last_idx = self.env.len() - 1;
-        let second_last_idx = last_idx - 1;
+    /* 指定默认的用户，命令行运行时同一个用户的多次对话共享store中的值 */
+    pub fn with_user(mut self, user: &str) -> Self {
+        self.user = user.to_string();
+        self
+    }
 
-        let last_env = self.env.get(last_idx).cloned();
-        let second_last_env = self.env.get_mut(second_last_idx);
+    /* 使用给定的消息目录 */
+    pub fn with_catalog(mut self, catalog: Arc<Catalog>) -> Self {
+        self.catalog = catalog;
+        self
+    }
 
-        if let (Some(last_env), Some(second_last_env)) = (last_env, second_last_env) {
-            // 创建一个克隆的 HashMap，用于存储需要更新的键值对
-            let updates: HashMap<String, String> = last_env
-                .iter()
-                .filter(|(key, value)| {
-                    // 仅保留与倒数第二个 HashMap 不同的项
-                    second_last_env.get(*key) != Some(value)
-                })
-                .map(|(key, value)| (key.clone(), value.clone()))
-                .collect();
+    fn rng(&self) -> Rng {
+        match self.seed {
+            Some(seed) => Rng::seeded(seed),
+            None => Rng::from_entropy(),
+        }
+    }
 
-            // 更新倒数第二个 HashMap
-            second_last_env.extend(updates);
+    fn context<'a>(&'a self, session: &'a mut Session) -> Context<'a> {
+        Context {
+            env: &mut session.env,
+            rng: &mut session.rng,
+            clock: &*self.clock,
+            store: &*self.store,
+            user: &session.user,
+            catalog: &self.catalog,
+            locale: session.locale.as_deref(),
         }
     }
 
@@ -75,50 +438,322 @@ impl Interpreter {
     //     None // 未找到变量
     // }
 
+    /* 使用标准输入输出执行程序 */
     pub fn interpret(&mut self) {
-        let mut new_ast = Vec::new();
-        std::mem::swap(&mut self.ast, &mut new_ast);
-        for statement in new_ast {
-            self.execute(statement);
+        self.interpret_with(&mut StdIo);
+    }
+
+    /* 使用给定的输入输出执行程序，直到exit、输入结束或程序执行完毕 */
+    pub fn interpret_with(&mut self, io: &mut dyn Io) {
+        let mut session = Session::new(std::mem::take(&mut self.env), self.rng(), &self.user);
+        let mut state = self.run(&mut session, &mut Output::Stream(io));
+        loop {
+            match state {
+                State::Finished => break,
+                State::Transferred => {
+                    let handoff = session.handoff().expect("转接中的会话有转接事件");
+                    let handback = io.transfer(handoff);
+                    state = self.hand_back_with(&mut session, handback, &mut Output::Stream(io));
+                    continue;
+                }
+                State::NeedsInput => {}
+            }
+            match io.input(session.waiting()) {
+                Some(text) => {
+                    state = self.resume_with(&mut session, &text, &mut Output::Stream(io));
+                }
+                // 输入结束视为exit
+                None => {
+                    session.finish();
+                    break;
+                }
+            }
         }
+        self.env = session.env;
     }
 
-    fn execute(&mut self, statement: Box<Statement>) {
-        match *statement {
+    /* 开始一次新的对话，执行到第一个input或程序结束 */
+    pub fn start(&self) -> (Session, Turn) {
+        self.start_for(&self.user)
+    }
+
+    /* 为指定的用户开始新的对话，store中的值按该用户读写 */
+    pub fn start_for(&self, user: &str) -> (Session, Turn) {
+        let mut session = Session::new(Environment::new(), self.rng(), user);
+        let mut output = Output::Collect(Vec::new());
+        let state = self.run(&mut session, &mut output);
+        let turn = Turn {
+            output: output.into_lines(),
+            state,
+        };
+        (session, turn)
+    }
+
+    /*
+     * 将用户的消息写入挂起处的变量并继续执行
+     * 会话已结束或者已经转给人工客服时不做任何事，消息也不记入对话记录
+     */
+    pub fn resume(&self, session: &mut Session, text: &str) -> Turn {
+        let mut output = Output::Collect(Vec::new());
+        let state = self.resume_with(session, text, &mut output);
+        Turn {
+            output: output.into_lines(),
+            state,
+        }
+    }
+
+    fn resume_with(&self, session: &mut Session, text: &str, output: &mut Output) -> State {
+        let state = session.state();
+        if state != State::NeedsInput {
+            return state;
+        }
+        if self.transcribe {
+            session.transcript.push(Line::user(text));
+        }
+        self.respond(session, text, output)
+    }
+
+    /* 人工客服交还控制：结束对话，或者写入客服给出的变量后从transfer之后继续执行 */
+    pub fn hand_back(&self, session: &mut Session, handback: Handback) -> Turn {
+        let mut output = Output::Collect(Vec::new());
+        let state = self.hand_back_with(session, handback, &mut output);
+        Turn {
+            output: output.into_lines(),
+            state,
+        }
+    }
+
+    fn hand_back_with(
+        &self,
+        session: &mut Session,
+        handback: Handback,
+        output: &mut Output,
+    ) -> State {
+        if session.handoff.take().is_none() {
+            return session.state();
+        }
+        let variables = match handback {
+            Handback::Resume { variables } => variables,
+            Handback::End => {
+                session.finish();
+                return State::Finished;
+            }
+        };
+        for (name, value) in variables {
+            session.env.assign(&name, value);
+        }
+        self.run(session, output)
+    }
+
+    /* 按会话挂起的位置处理用户的消息 */
+    fn respond(&self, session: &mut Session, text: &str, output: &mut Output) -> State {
+        if let Some(filling) = session.filling.take() {
+            session.waiting = None;
+            self.fill(filling, text, session, output);
+        } else if let Some(name) = session.waiting.take() {
+            match session.asking.take() {
+                Some(asking) => self.answer(asking, name, text, session, output),
+                None => session.env.assign(&name, text.into()),
+            }
+        } else if let Some(path) = session.matching.take() {
+            self.choose(path, text, session, output);
+        } else if session.listening {
+            session.listening = false;
+            self.listen(text, session, output);
+        } else {
+            return State::Finished;
+        }
+        // 仍在等待重新输入时不需要继续执行
+        if session.state() == State::NeedsInput {
+            return State::NeedsInput;
+        }
+        self.run(session, output)
+    }
+
+    /* 路径对应的语句 */
+    fn statement(&self, path: &[usize]) -> &Statement {
+        let mut statement: &Statement = &self.ast[path[0]];
+        for index in &path[1..] {
+            statement = match statement {
+                Statement::Block { statements } => &statements[*index],
+                Statement::Loop { body } | Statement::For { body, .. } => body,
+                Statement::Branch { then, .. } => then,
+                Statement::TypedInput { fallback, .. } => fallback.as_ref().expect("else分支"),
+                Statement::State { enter, handler, .. } => match index {
+                    0 => enter.as_ref().expect("on enter代码块"),
+                    _ => &handler.as_ref().expect("on input代码块").body,
+                },
+                Statement::Match { arms, default, .. } => match arms.get(*index) {
+                    Some(arm) => &arm.body,
+                    None => default.as_ref().expect("default分支"),
+                },
+                _ => unreachable!("路径只经过代码块、循环、分支、输入语句的else、match与对话状态"),
+            };
+        }
+        statement
+    }
+
+    /* 执行到需要用户输入、转接或者程序结束，返回执行后的状态 */
+    fn run(&self, session: &mut Session, output: &mut Output) -> State {
+        while let Some(frame) = session.frames.last_mut() {
+            let mut item = None;
+            let path = match frame {
+                Frame::Block { path, next } => {
+                    let len = if path.is_empty() {
+                        self.ast.len()
+                    } else {
+                        match self.statement(path) {
+                            Statement::Block { statements } => statements.len(),
+                            _ => unreachable!("代码块帧只指向代码块"),
+                        }
+                    };
+                    if *next == len {
+                        if !path.is_empty() {
+                            session.env.pop_scope();
+                        }
+                        session.frames.pop();
+                        continue;
+                    }
+                    *next += 1;
+                    let mut child = path.clone();
+                    child.push(*next - 1);
+                    child
+                }
+                Frame::Loop { path } => {
+                    let mut body = path.clone();
+                    body.push(0);
+                    body
+                }
+                Frame::Each { path, items, next } => {
+                    if *next == items.len() {
+                        session.frames.pop();
+                        continue;
+                    }
+                    item = Some(items[*next].clone());
+                    *next += 1;
+                    let mut body = path.clone();
+                    body.push(0);
+                    body
+                }
+            };
+            match item {
+                Some(item) => self.each(path, item, session, output),
+                None => self.execute(path, session, output),
+            }
+            let state = session.state();
+            if state != State::Finished {
+                return state;
+            }
+        }
+        // 执行完毕后停留在当前状态，等待它的 on input 处理下一条消息
+        if let Some((_, Statement::State { handler: Some(_), .. })) = self.dialog(session) {
+            session.listening = true;
+            return State::NeedsInput;
+        }
+        State::Finished
+    }
+
+    /* 输出一行，程序中有transfer语句时同时记入对话记录 */
+    fn say(&self, text: String, session: &mut Session, output: &mut Output) {
+        if self.transcribe {
+            session.transcript.push(Line::bot(&text));
+        }
+        output.push(text);
+    }
+
+    /* 执行一条语句，代码块与循环只压入新的帧，由run继续推进 */
+    fn execute(&self, path: Vec<usize>, session: &mut Session, output: &mut Output) {
+        match self.statement(&path) {
             ast::Statement::Speak { expression } => {
-                println!("{}", expression.trans(self.env.last().unwrap().clone()));
+                let text = expression.trans(&mut self.context(session));
+                self.say(text, session, output);
+            }
+            ast::Statement::SpeakOneOf { options } => {
+                let ctx = &mut self.context(session);
+                let options = options.value(ctx);
+                let text = pick_one(Operand::from(&options), ctx.rng)
+                    .unwrap_or_else(|e| panic!("{}", e))
+                    .to_string();
+                self.say(text, session, output);
+            }
+            ast::Statement::Transfer { reason } => {
+                let reason = reason.trans(&mut self.context(session));
+                session.handoff = Some(Handoff {
+                    reason,
+                    transcript: session.transcript.clone(),
+                    variables: session.env.variables(),
+                });
+            }
+            ast::Statement::Locale { locale } => {
+                let locale = locale.trans(&mut self.context(session));
+                self.catalog
+                    .check_locale(&locale)
+                    .unwrap_or_else(|e| panic!("{}", e));
+                session.locale = Some(locale);
             }
             ast::Statement::Var { name, init } => {
-                self.add_new_var(name, init);
+                let value = init.value(&mut self.context(session));
+                session.env.define_global(name, value);
+            }
+            ast::Statement::Local { name, init } => {
+                let value = init.value(&mut self.context(session));
+                session.env.define_local(name, value);
+            }
+            ast::Statement::Store { key, init } => {
+                let ctx = &mut self.context(session);
+                let saved = ctx.store.get(ctx.user, key);
+                if saved.unwrap_or_else(|e| panic!("{}", e)).is_none() {
+                    let value = init.value(ctx);
+                    ctx.store
+                        .set(ctx.user, key, &value)
+                        .unwrap_or_else(|e| panic!("{}", e));
+                }
+            }
+            ast::Statement::Loop { .. } => {
+                session.frames.push(Frame::Loop { path });
+            }
+            ast::Statement::For { iterable, .. } => {
+                let items = match iterable.value(&mut self.context(session)) {
+                    Value::List(items) => items,
+                    Value::Map(entries) => entries.into_keys().map(Value::String).collect(),
+                    Value::String(s) => panic!("Only lists and maps can be iterated, got '{}'", s),
+                };
+                session.frames.push(Frame::Each {
+                    path,
+                    items,
+                    next: 0,
+                });
             }
-            ast::Statement::Loop { body } => loop {
-                self.execute(body.clone());
-            },
             ast::Statement::Input { input } => {
-                let mut value = String::new();
-                io::stdin().read_line(&mut value).expect("无法读取输入");
-                // 去除回车符
-                value = value.trim().to_string();
-                self.add_new_var(
-                    input,
-                    Box::new(Expr::Literal {
-                        value: ast::LiteralValue::String(value),
-                    }),
-                );
+                session.waiting = Some(input.clone());
+            }
+            ast::Statement::Form { .. } => {
+                self.advance(path, session, output);
+            }
+            ast::Statement::TypedInput { name, prompt, .. } => {
+                if let Some(prompt) = prompt {
+                    let text = prompt.trans(&mut self.context(session));
+                    self.say(text, session, output);
+                }
+                session.waiting = Some(name.clone());
+                session.asking = Some(Asking { path, attempts: 0 });
             }
             ast::Statement::Expression { expression } => {
-                expression.exec(self.env.last_mut().unwrap());
+                expression.exec(&mut self.context(session));
             }
             ast::Statement::Exit => {
-                std::process::exit(0);
+                session.finish();
             }
-            ast::Statement::Branch { condition, then } => {
-                if let Expr::Literal { value: res } = *condition.exec(self.env.last_mut().unwrap())
+            ast::Statement::Branch { condition, .. } => {
+                if let Expr::Literal { value: res } = *condition.exec(&mut self.context(session))
                 {
+                    #[allow(clippy::collapsible_match)]
                     match res {
                         ast::LiteralValue::String(value) => {
                             if value == "True" {
-                                self.execute(then);
+                                let mut then = path;
+                                then.push(0);
+                                self.execute(then, session, output);
                             } else if value == "False" {
                             } else {
                                 panic!("结果非布尔值！")
@@ -132,12 +767,233 @@ impl Interpreter {
                     panic!("结果非布尔值！")
                 }
             }
-            ast::Statement::Block { statements } => {
-                self.add_new_env();
-                for stmt in statements {
-                    self.execute(stmt);
+            ast::Statement::Block { .. } => {
+                session.env.push_scope();
+                session.frames.push(Frame::Block { path, next: 0 });
+            }
+            ast::Statement::Match { subject, .. } => match subject {
+                Some(subject) => {
+                    let text = subject.trans(&mut self.context(session));
+                    self.choose(path, &text, session, output);
                 }
-                self.rm_now_env();
+                None => session.matching = Some(path),
+            },
+            // 状态只在goto时执行
+            ast::Statement::State { .. } => {}
+            ast::Statement::Import { path, .. } => {
+                panic!("Import of \"{}\" must be resolved by loading the script from a file", path)
+            }
+            ast::Statement::Goto { state } => {
+                session.unwind();
+                session.dialog = Some(state.clone());
+                match self.dialog(session) {
+                    Some((index, Statement::State { enter: Some(_), .. })) => {
+                        self.execute(vec![index, 0], session, output);
+                    }
+                    Some(_) => {}
+                    None => panic!("State '{}' is not declared", state),
+                }
+            }
+        }
+    }
+
+    /* 当前对话状态的序号与声明 */
+    fn dialog(&self, session: &Session) -> Option<(usize, &Statement)> {
+        let name = session.dialog.as_deref()?;
+        self.ast
+            .iter()
+            .enumerate()
+            .find_map(|(index, statement)| match &**statement {
+                Statement::State { name: declared, .. } if declared == name => {
+                    Some((index, &**statement))
+                }
+                _ => None,
+            })
+    }
+
+    /* 当前状态的 on input 收到消息：进入处理代码块，消息写入其中的局部变量 */
+    fn listen(&self, text: &str, session: &mut Session, output: &mut Output) {
+        let (index, variable) = match self.dialog(session) {
+            Some((index, Statement::State { handler: Some(handler), .. })) => {
+                (index, &handler.variable)
+            }
+            _ => unreachable!("等待消息的状态有 on input"),
+        };
+        self.execute(vec![index, 1], session, output);
+        session.env.define_local(variable, text.into());
+    }
+
+    /* for循环的一轮：进入循环体，当前项写入其中的局部变量 */
+    fn each(&self, body: Vec<usize>, item: Value, session: &mut Session, output: &mut Output) {
+        let variable = match self.statement(&body[..body.len() - 1]) {
+            Statement::For { variable, .. } => variable,
+            _ => unreachable!("循环体的上一层是for语句"),
+        };
+        self.execute(body, session, output);
+        session.env.define_local(variable, item);
+    }
+
+    /* 根据意图识别的结果执行match语句中对应的分支 */
+    fn choose(
+        &self,
+        path: Vec<usize>,
+        text: &str,
+        session: &mut Session,
+        output: &mut Output,
+    ) {
+        let (threshold, arms, default) = match self.statement(&path) {
+            Statement::Match {
+                threshold,
+                arms,
+                default,
+                ..
+            } => (threshold, arms, default),
+            _ => unreachable!("路径指向match语句"),
+        };
+        let intents: Vec<&[ast::Pattern]> = arms.iter().map(|arm| &arm.patterns[..]).collect();
+        let threshold = threshold.unwrap_or(intent::DEFAULT_THRESHOLD);
+        let index = match intent::classify(&intents, threshold, text) {
+            Some(index) => index,
+            None if default.is_some() => arms.len(),
+            None => return,
+        };
+        let mut arm = path;
+        arm.push(index);
+        self.execute(arm, session, output);
+    }
+
+    /* 校验带类型的input收到的输入：合法时写入变量，否则重新提示，次数用完时执行else分支 */
+    fn answer(
+        &self,
+        mut asking: Asking,
+        name: String,
+        text: &str,
+        session: &mut Session,
+        output: &mut Output,
+    ) {
+        let (input_type, prompt, retry, max, fallback) = match self.statement(&asking.path) {
+            Statement::TypedInput {
+                input_type,
+                prompt,
+                retry,
+                max,
+                fallback,
+                ..
+            } => (input_type, prompt, retry, max, fallback),
+            _ => unreachable!("路径指向带类型的input语句"),
+        };
+        if let Some(value) = validation::validate(input_type, text) {
+            session.env.assign(&name, value.into());
+            return;
+        }
+        asking.attempts += 1;
+        if max.is_some_and(|max| asking.attempts >= max) {
+            if fallback.is_some() {
+                let mut path = asking.path;
+                path.push(0);
+                self.execute(path, session, output);
+            }
+            return;
+        }
+        if let Some(message) = retry.as_ref().or(prompt.as_ref()) {
+            let text = message.trans(&mut self.context(session));
+            self.say(text, session, output);
+        }
+        session.waiting = Some(name);
+        session.asking = Some(asking);
+    }
+
+    fn form(&self, path: &[usize]) -> (&[ast::Slot], &Option<Box<Expr>>) {
+        match self.statement(path) {
+            Statement::Form { slots, confirm, .. } => (slots, confirm),
+            _ => unreachable!("路径指向form语句"),
+        }
+    }
+
+    /* 询问表单中第一个尚未填写的槽位，全部填写后请用户确认，没有confirm时表单结束 */
+    fn advance(&self, path: Vec<usize>, session: &mut Session, output: &mut Output) {
+        let (slots, confirm) = self.form(&path);
+        match slots
+            .iter()
+            .position(|slot| session.env.get(slot.variable()).is_none())
+        {
+            Some(index) => {
+                let text = slots[index].prompt.trans(&mut self.context(session));
+                self.say(text, session, output);
+                session.waiting = Some(slots[index].variable().to_string());
+                session.filling = Some(Filling {
+                    path,
+                    slot: Some(index),
+                });
+            }
+            None => {
+                if let Some(confirm) = confirm {
+                    let text = confirm.trans(&mut self.context(session));
+                    self.say(text, session, output);
+                    session.filling = Some(Filling { path, slot: None });
+                }
+            }
+        }
+    }
+
+    /* 处理填写表单时收到的消息：修改某个槽位、回答当前槽位或者回答确认 */
+    fn fill(
+        &self,
+        filling: Filling,
+        text: &str,
+        session: &mut Session,
+        output: &mut Output,
+    ) {
+        let (slots, confirm) = self.form(&filling.path);
+        if let Some((index, value)) = validation::correction(slots, text) {
+            return self.answer_slot(filling.path, index, value, session, output);
+        }
+        if let Some(index) = filling.slot {
+            return self.answer_slot(filling.path, index, text, session, output);
+        }
+        match validation::validate(&ast::InputType::YesNo, text).as_deref() {
+            Some("True") => {}
+            // 用户否认时清空所有槽位，从头重新填写
+            Some(_) => {
+                for slot in slots {
+                    session.env.remove(slot.variable());
+                }
+                self.advance(filling.path, session, output);
+            }
+            None => {
+                let confirm = confirm.as_ref().expect("等待确认的表单有confirm");
+                let text = confirm.trans(&mut self.context(session));
+                self.say(text, session, output);
+                session.filling = Some(filling);
+            }
+        }
+    }
+
+    /* 填写一个槽位，不合法时重新提示并继续询问该槽位 */
+    fn answer_slot(
+        &self,
+        path: Vec<usize>,
+        index: usize,
+        text: &str,
+        session: &mut Session,
+        output: &mut Output,
+    ) {
+        let (slots, _) = self.form(&path);
+        let slot = &slots[index];
+        match validation::validate_slot(slot, text) {
+            Some(value) => {
+                session.env.assign(slot.variable(), value.into());
+                self.advance(path, session, output);
+            }
+            None => {
+                let message = slot.retry.as_ref().unwrap_or(&slot.prompt);
+                let text = message.trans(&mut self.context(session));
+                self.say(text, session, output);
+                session.waiting = Some(slot.variable().to_string());
+                session.filling = Some(Filling {
+                    path,
+                    slot: Some(index),
+                });
             }
         }
     }
@@ -149,48 +1005,58 @@ pub trait Utils {
      * 为interpreter.rs服务，因此只需要建立接口
      * 具体实现依靠interpreter.rs中的环境实现
      */
-    fn trans(&self, env: HashMap<String, String>) -> String;
+    fn trans(&self, ctx: &mut Context) -> String;
     /*
      * 用于对表达式语句的执行
      */
-    fn exec(&self, env: &mut HashMap<String, String>) -> Box<Expr>;
+    fn exec(&self, ctx: &mut Context) -> Box<Expr>;
+    /*
+     * 用于求出写入变量的值，列表与映射保持原样，数字转为字符串
+     */
+    fn value(&self, ctx: &mut Context) -> Value;
 }
 
 impl Utils for Box<Expr> {
-    fn trans(&self, env: HashMap<String, String>) -> String {
+    fn trans(&self, ctx: &mut Context) -> String {
         match &**self {
-            Expr::Assign { .. } => {
+            Expr::Assign { .. } | Expr::StoreAssign { .. } => {
                 panic!("本程序不允许采用连等式!");
             }
-            Expr::Binary { .. } => {
-                let mut cur_env = env.clone();
-                if let Expr::Literal { value: res } = *self.exec(&mut cur_env) {
-                    match res {
-                        ast::LiteralValue::String(value) => {
-                            format!("{}", value)
-                        }
-                        ast::LiteralValue::Number(value) => {
-                            format!("{}", value.to_string())
-                        }
-                    }
+            Expr::Binary { .. }
+            | Expr::RegexMatch { .. }
+            | Expr::Call { .. }
+            | Expr::List { .. }
+            | Expr::Map { .. }
+            | Expr::Index { .. }
+            | Expr::Store { .. }
+            | Expr::Message { .. } => {
+                if let Expr::Literal { value: res } = *self.exec(ctx) {
+                    res.trans()
                 } else {
                     panic!("结果应为字符串!")
                 }
             }
             Expr::Literal { value } => value.trans(),
             Expr::Variable { name } => {
-                if let Some(value) = env.get(name) {
-                    value.clone()
+                if let Some(value) = ctx.env.get(name) {
+                    value.to_string()
                 } else {
                     panic!("Variable '{}' cannot be found", name);
                 }
             }
         }
     }
-    fn exec(&self, env: &mut HashMap<String, String>) -> Box<Expr> {
+    fn value(&self, ctx: &mut Context) -> Value {
+        match *self.exec(ctx) {
+            Expr::Literal { value } => value.into(),
+            _ => panic!("结果应为字面量!"),
+        }
+    }
+    fn exec(&self, ctx: &mut Context) -> Box<Expr> {
         match &**self {
             Expr::Assign { name, value } => {
-                env.insert(name.clone(), value.trans(env.clone()));
+                let value = value.value(ctx);
+                ctx.env.assign(name, value);
                 Box::new(Expr::Literal {
                     value: ast::LiteralValue::String("Successd".to_string()),
                 })
@@ -201,356 +1067,415 @@ impl Utils for Box<Expr> {
                 right,
             } => {
                 // 首先计算左表达式和右表达式的值
-                let left_value = left.exec(env);
-                let right_value = right.exec(env);
-                match operator {
-                    Token::OperatorAdd => {
-                        // 先解包 left_value 和 right_value
-                        if let Expr::Literal {
+                let left_value = left.exec(ctx);
+                let right_value = right.exec(ctx);
+                match (*left_value, *right_value) {
+                    (
+                        Expr::Literal {
                             value: left_literal,
-                        } = *left_value
-                        {
-                            if let Expr::Literal {
-                                value: right_literal,
-                            } = *right_value
-                            {
-                                match (left_literal, right_literal) {
-                                    (
-                                        ast::LiteralValue::Number(left_num),
-                                        ast::LiteralValue::Number(right_num),
-                                    ) => {
-                                        //处理两个数字的情况
-                                        let result = left_num + right_num;
-                                        Box::new(Expr::Literal {
-                                            value: (ast::LiteralValue::Number(result)),
-                                        })
-                                    }
-                                    (
-                                        ast::LiteralValue::String(left_str),
-                                        ast::LiteralValue::String(right_str),
-                                    ) => {
-                                        match (left_str.parse::<f64>(), right_str.parse::<f64>()) {
-                                            (Ok(left_num), Ok(right_num)) => {
-                                                // 处理两个数字的情况
-                                                let result = left_num + right_num;
-                                                Box::new(Expr::Literal {
-                                                    value: ast::LiteralValue::Number(result),
-                                                })
-                                            }
-                                            _ => {
-                                                // 处理字符串拼接
-                                                let result = format!("{}{}", left_str, right_str);
-                                                Box::new(Expr::Literal {
-                                                    value: ast::LiteralValue::String(result),
-                                                })
-                                            }
-                                        }
-                                    }
-                                    (
-                                        ast::LiteralValue::String(left_str),
-                                        ast::LiteralValue::Number(right_num),
-                                    ) => {
-                                        // 为字符串和数字
-                                        let result =
-                                            format!("{}{}", left_str, right_num.to_string());
-                                        Box::new(Expr::Literal {
-                                            value: (ast::LiteralValue::String(result)),
-                                        })
-                                    }
-                                    (
-                                        ast::LiteralValue::Number(left_num),
-                                        ast::LiteralValue::String(right_str),
-                                    ) => {
-                                        // 为字符串和数字
-                                        let result =
-                                            format!("{}{}", left_num.to_string(), right_str);
-                                        Box::new(Expr::Literal {
-                                            value: (ast::LiteralValue::String(result)),
-                                        })
-                                    }
-                                }
-                            } else {
-                                panic!("Right value is not a Literal ");
-                            }
-                        } else {
-                            panic!("Left value is not a Literal");
-                        }
-                    }
-                    Token::OperatorSub => {
-                        if let Expr::Literal {
-                            value: left_literal,
-                        } = *left_value
-                        {
-                            if let Expr::Literal {
-                                value: right_literal,
-                            } = *right_value
-                            {
-                                match (left_literal, right_literal) {
-                                    (
-                                        ast::LiteralValue::Number(left_num),
-                                        ast::LiteralValue::Number(right_num),
-                                    ) => {
-                                        //处理两个数字的情况
-                                        let result = left_num - right_num;
-                                        Box::new(Expr::Literal {
-                                            value: ast::LiteralValue::Number(result),
-                                        })
-                                    }
-                                    (
-                                        ast::LiteralValue::String(left_str),
-                                        ast::LiteralValue::String(right_str),
-                                    ) => {
-                                        // 尝试将字符串解析为数字，如果成功，则执行数字减法
-                                        if let Ok(left_num) = left_str.parse::<f64>() {
-                                            if let Ok(right_num) = right_str.parse::<f64>() {
-                                                let result = left_num - right_num;
-                                                Box::new(Expr::Literal {
-                                                    value: ast::LiteralValue::Number(result),
-                                                })
-                                            } else {
-                                                panic!(
-                                                    "Right value and left value must be a number!"
-                                                );
-                                            }
-                                        } else {
-                                            panic!("Right value and left value must be a number!");
-                                        }
-                                    }
-                                    _ => {
-                                        panic!("Right value and left value must be a number!");
-                                    }
-                                }
-                            } else {
-                                panic!("Right value is not a Literal ");
-                            }
-                        } else {
-                            panic!("Left value is not a Literal");
-                        }
-                    }
-                    Token::OperatorMul => {
-                        if let Expr::Literal {
-                            value: left_literal,
-                        } = *left_value
-                        {
-                            if let Expr::Literal {
-                                value: right_literal,
-                            } = *right_value
-                            {
-                                match (left_literal, right_literal) {
-                                    (
-                                        ast::LiteralValue::Number(left_num),
-                                        ast::LiteralValue::Number(right_num),
-                                    ) => {
-                                        //处理两个数字的情况
-                                        let result = left_num * right_num;
-                                        Box::new(Expr::Literal {
-                                            value: ast::LiteralValue::Number(result),
-                                        })
-                                    }
-                                    (
-                                        ast::LiteralValue::String(left_str),
-                                        ast::LiteralValue::String(right_str),
-                                    ) => {
-                                        // 尝试将字符串解析为数字，如果成功，则执行数字乘法
-                                        if let Ok(left_num) = left_str.parse::<f64>() {
-                                            if let Ok(right_num) = right_str.parse::<f64>() {
-                                                let result = left_num * right_num;
-                                                Box::new(Expr::Literal {
-                                                    value: ast::LiteralValue::Number(result),
-                                                })
-                                            } else {
-                                                panic!(
-                                                    "Right value and left value must be a number!"
-                                                );
-                                            }
-                                        } else {
-                                            panic!("Right value and left value must be a number!");
-                                        }
-                                    }
-                                    _ => {
-                                        panic!("Right value and left value must be a number!");
-                                    }
-                                }
-                            } else {
-                                panic!("Right value is not a Literal ");
-                            }
-                        } else {
-                            panic!("Left value is not a Literal");
-                        }
+                        },
+                        Expr::Literal {
+                            value: right_literal,
+                        },
+                    ) => {
+                        let result = eval_binary(
+                            operator,
+                            Operand::from(&left_literal),
+                            Operand::from(&right_literal),
+                        )
+                        .unwrap_or_else(|e| panic!("{}", e));
+                        Box::new(Expr::Literal {
+                            value: result.into(),
+                        })
                     }
-                    Token::OperatorDiv => {
-                        if let Expr::Literal {
-                            value: left_literal,
-                        } = *left_value
-                        {
-                            if let Expr::Literal {
-                                value: right_literal,
-                            } = *right_value
-                            {
-                                match (left_literal, right_literal) {
-                                    (
-                                        ast::LiteralValue::Number(left_num),
-                                        ast::LiteralValue::Number(right_num),
-                                    ) => {
-                                        //处理两个数字的情况
-                                        if right_num != 0.0 {
-                                            let result = left_num / right_num;
-                                            Box::new(Expr::Literal {
-                                                value: ast::LiteralValue::Number(result),
-                                            })
-                                        } else {
-                                            panic!("Division by zero is not allowed!");
-                                        }
-                                    }
-                                    (
-                                        ast::LiteralValue::String(left_str),
-                                        ast::LiteralValue::String(right_str),
-                                    ) => {
-                                        // 尝试将字符串解析为数字，如果成功，则执行数字除法
-                                        if let Ok(left_num) = left_str.parse::<f64>() {
-                                            if let Ok(right_num) = right_str.parse::<f64>() {
-                                                if right_num != 0.0 {
-                                                    let result = left_num / right_num;
-                                                    Box::new(Expr::Literal {
-                                                        value: ast::LiteralValue::Number(result),
-                                                    })
-                                                } else {
-                                                    panic!("Division by zero is not allowed!");
-                                                }
-                                            } else {
-                                                panic!(
-                                                    "Right value and left value must be a number!"
-                                                );
-                                            }
-                                        } else {
-                                            panic!("Right value and left value must be a number!");
-                                        }
-                                    }
-                                    _ => {
-                                        panic!("Right value and left value must be a number!");
-                                    }
-                                }
-                            } else {
-                                panic!("Right value is not a Literal ");
-                            }
-                        } else {
-                            panic!("Left value is not a Literal");
-                        }
-                    }
-                    Token::WEqual => {
-                        if let Expr::Literal {
-                            value: left_literal,
-                        } = *left_value
-                        {
-                            if let Expr::Literal {
-                                value: right_literal,
-                            } = *right_value
-                            {
-                                match (left_literal, right_literal) {
-                                    (
-                                        ast::LiteralValue::String(left_str),
-                                        ast::LiteralValue::String(right_str),
-                                    ) => {
-                                        // 处理两个字符串的情况
-                                        if left_str == right_str {
-                                            Box::new(Expr::Literal {
-                                                value: (ast::LiteralValue::String(
-                                                    "True".to_string(),
-                                                )),
-                                            })
-                                        } else {
-                                            Box::new(Expr::Literal {
-                                                value: (ast::LiteralValue::String(
-                                                    "False".to_string(),
-                                                )),
-                                            })
-                                        }
-                                    }
-                                    (
-                                        ast::LiteralValue::String(left_str),
-                                        ast::LiteralValue::Number(right_num),
-                                    ) => {
-                                        // 为字符串和数字
-                                        if left_str == right_num.to_string() {
-                                            Box::new(Expr::Literal {
-                                                value: (ast::LiteralValue::String(
-                                                    "True".to_string(),
-                                                )),
-                                            })
-                                        } else {
-                                            Box::new(Expr::Literal {
-                                                value: (ast::LiteralValue::String(
-                                                    "False".to_string(),
-                                                )),
-                                            })
-                                        }
-                                    }
-                                    (
-                                        ast::LiteralValue::Number(left_num),
-                                        ast::LiteralValue::String(right_str),
-                                    ) => {
-                                        // 为字符串和数字
-                                        if left_num.to_string() == right_str {
-                                            Box::new(Expr::Literal {
-                                                value: (ast::LiteralValue::String(
-                                                    "True".to_string(),
-                                                )),
-                                            })
-                                        } else {
-                                            Box::new(Expr::Literal {
-                                                value: (ast::LiteralValue::String(
-                                                    "False".to_string(),
-                                                )),
-                                            })
-                                        }
-                                    }
-                                    (
-                                        ast::LiteralValue::Number(left_num),
-                                        ast::LiteralValue::Number(right_num),
-                                    ) => {
-                                        //处理两个数字的情况
-                                        if left_num == right_num {
-                                            Box::new(Expr::Literal {
-                                                value: (ast::LiteralValue::String(
-                                                    "True".to_string(),
-                                                )),
-                                            })
-                                        } else {
-                                            Box::new(Expr::Literal {
-                                                value: (ast::LiteralValue::String(
-                                                    "False".to_string(),
-                                                )),
-                                            })
-                                        }
-                                    }
-                                }
-                            } else {
-                                panic!("Right value is not a Literal ");
-                            }
-                        } else {
-                            panic!("Left value is not a Literal");
+                    (Expr::Literal { .. }, _) => panic!("Right value is not a Literal "),
+                    _ => panic!("Left value is not a Literal"),
+                }
+            }
+            Expr::Literal { value } => Box::new(Expr::Literal {
+                value: value.clone(),
+            }),
+            Expr::Variable { name } => {
+                let res = ctx
+                    .env
+                    .get(name)
+                    .unwrap_or_else(|| panic!("Variable '{}' cannot be found", name));
+                Box::new(Expr::Literal {
+                    value: res.clone().into(),
+                })
+            }
+            Expr::RegexMatch { subject, regex } => {
+                let text = subject.trans(ctx);
+                let matched = match match_regex(regex, &text) {
+                    Some(captures) => {
+                        for (name, value) in captures {
+                            ctx.env.assign(name, value.into());
                         }
+                        true
                     }
-                    _ => {
-                        panic!("Need a operator!");
+                    None => false,
+                };
+                Box::new(Expr::Literal {
+                    value: Outcome::Bool(matched).into(),
+                })
+            }
+            Expr::Call { name, args } => {
+                let builtin =
+                    Builtin::resolve(name, args.len()).unwrap_or_else(|e| panic!("{}", e));
+                let values: Vec<ast::LiteralValue> = args
+                    .iter()
+                    .map(|arg| match *arg.exec(ctx) {
+                        Expr::Literal { value } => value,
+                        _ => panic!("Argument is not a Literal"),
+                    })
+                    .collect();
+                let operands: Vec<Operand> = values.iter().map(Operand::from).collect();
+                let result = builtin
+                    .call(&operands, ctx.clock, ctx.rng)
+                    .unwrap_or_else(|e| panic!("{}", e));
+                Box::new(Expr::Literal {
+                    value: result.into(),
+                })
+            }
+            Expr::List { items } => {
+                let items = items.iter().map(|item| item.value(ctx)).collect();
+                Box::new(Expr::Literal {
+                    value: ast::LiteralValue::List(items),
+                })
+            }
+            Expr::Map { entries } => {
+                let entries = entries
+                    .iter()
+                    .map(|(key, value)| (key.clone(), value.value(ctx)))
+                    .collect();
+                Box::new(Expr::Literal {
+                    value: ast::LiteralValue::Map(entries),
+                })
+            }
+            Expr::Index { target, index } => {
+                let (target, index) = match (*target.exec(ctx), *index.exec(ctx)) {
+                    (Expr::Literal { value: target }, Expr::Literal { value: index }) => {
+                        (target, index)
                     }
-                }
+                    _ => panic!("Index is not a Literal"),
+                };
+                let result = eval_index(Operand::from(&target), Operand::from(&index))
+                    .unwrap_or_else(|e| panic!("{}", e));
+                Box::new(Expr::Literal {
+                    value: result.into(),
+                })
             }
-            Expr::Literal { value } => match value {
-                ast::LiteralValue::Number(num) => Box::new(Expr::Literal {
-                    value: ast::LiteralValue::Number(*num),
-                }),
-                ast::LiteralValue::String(str) => Box::new(Expr::Literal {
-                    value: ast::LiteralValue::String(str.to_string()),
-                }),
-            },
-            Expr::Variable { name } => {
-                let res = env.get(name).unwrap();
+            Expr::Store { key } => {
+                let value = load(ctx.store, ctx.user, key).unwrap_or_else(|e| panic!("{}", e));
                 Box::new(Expr::Literal {
-                    value: ast::LiteralValue::String(res.to_string()),
+                    value: value.into(),
+                })
+            }
+            Expr::StoreAssign { key, value } => {
+                let value = value.value(ctx);
+                ctx.store
+                    .set(ctx.user, key, &value)
+                    .unwrap_or_else(|e| panic!("{}", e));
+                Box::new(Expr::Literal {
+                    value: ast::LiteralValue::String("Successd".to_string()),
+                })
+            }
+            Expr::Message { key, args } => {
+                let args: Vec<(&str, String)> = args
+                    .iter()
+                    .map(|(name, value)| (name.as_str(), value.trans(ctx)))
+                    .collect();
+                let text = ctx
+                    .catalog
+                    .format(ctx.locale, key, &args)
+                    .unwrap_or_else(|e| panic!("{}", e));
+                Box::new(Expr::Literal {
+                    value: ast::LiteralValue::String(text),
+                })
+            }
+        }
+    }
+}
+
+/* 读取store中的值，用户没有保存过该键时报错 */
+pub fn load(store: &dyn Storage, user: &str, key: &str) -> Result<Value, String> {
+    match store.get(user, key) {
+        Ok(Some(value)) => Ok(value),
+        Ok(None) => Err(format!("Key '{}' cannot be found in the store", key)),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/*
+ * 正则匹配，成功时返回所有命名分组的值
+ * 没有参与匹配的可选分组取空字符串，保证匹配成功后这些变量总是存在
+ */
+pub fn match_regex<'r, 't>(
+    regex: &'r ast::Regex,
+    text: &'t str,
+) -> Option<Vec<(&'r str, &'t str)>> {
+    let captures = regex.captures(text)?;
+    Some(
+        regex
+            .capture_names()
+            .flatten()
+            .map(|name| (name, captures.name(name).map_or("", |m| m.as_str())))
+            .collect(),
+    )
+}
+
+/* speak one of 从列表中随机选出的一项，解释器与虚拟机共用 */
+pub fn pick_one<'a>(options: Operand<'a>, rng: &mut Rng) -> Result<&'a Value, String> {
+    match options {
+        Operand::List(items) if !items.is_empty() => {
+            Ok(&items[rng.below(items.len() as u64) as usize])
+        }
+        other => Err(format!(
+            "`speak one of` expects a non-empty list, got '{}'!",
+            other
+        )),
+    }
+}
+
+/* 二元运算的操作数，借用字面量的内容，避免复制字符串 */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Operand<'a> {
+    Number(Decimal),
+    String(&'a str),
+    List(&'a [Value]),
+    Map(&'a Map),
+}
+
+impl Operand<'_> {
+    /* 列表与映射的类型名，用于报错 */
+    pub fn collection(&self) -> Option<&'static str> {
+        match self {
+            Operand::List(_) => Some("list"),
+            Operand::Map(_) => Some("map"),
+            _ => None,
+        }
+    }
+}
+
+impl<'a> From<&'a ast::LiteralValue> for Operand<'a> {
+    fn from(value: &'a ast::LiteralValue) -> Self {
+        match value {
+            ast::LiteralValue::Number(n) => Operand::Number(*n),
+            ast::LiteralValue::String(s) => Operand::String(s),
+            ast::LiteralValue::List(items) => Operand::List(items),
+            ast::LiteralValue::Map(entries) => Operand::Map(entries),
+        }
+    }
+}
+
+impl<'a> From<&'a Value> for Operand<'a> {
+    fn from(value: &'a Value) -> Self {
+        match value {
+            Value::String(s) => Operand::String(s),
+            Value::List(items) => Operand::List(items),
+            Value::Map(entries) => Operand::Map(entries),
+        }
+    }
+}
+
+/* 操作数作为变量或列表中的一项保存时的值 */
+impl From<Operand<'_>> for Value {
+    fn from(operand: Operand) -> Self {
+        match operand {
+            Operand::Number(n) => Value::String(ast::number_to_string(n)),
+            Operand::String(s) => Value::String(s.to_string()),
+            Operand::List(items) => Value::List(items.to_vec()),
+            Operand::Map(entries) => Value::Map(entries.clone()),
+        }
+    }
+}
+
+/* 输出与报错时的形式，与speak一致 */
+impl fmt::Display for Operand<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Operand::Number(n) => write!(f, "{}", ast::number_to_string(*n)),
+            Operand::String(s) => write!(f, "{}", s),
+            Operand::List(items) => write!(f, "{}", Value::List(items.to_vec())),
+            Operand::Map(entries) => write!(f, "{}", Value::Map((*entries).clone())),
+        }
+    }
+}
+
+/* 二元运算的结果，比较运算的结果在语言中表示为字符串"True"/"False" */
+#[derive(Debug, Clone, PartialEq)]
+pub enum Outcome {
+    Number(Decimal),
+    String(String),
+    Bool(bool),
+    List(Vec<Value>),
+    Map(Map),
+}
+
+impl From<Value> for Outcome {
+    fn from(value: Value) -> Self {
+        match value {
+            Value::String(s) => Outcome::String(s),
+            Value::List(items) => Outcome::List(items),
+            Value::Map(entries) => Outcome::Map(entries),
+        }
+    }
+}
+
+impl From<Outcome> for ast::LiteralValue {
+    fn from(outcome: Outcome) -> Self {
+        match outcome {
+            Outcome::Number(n) => ast::LiteralValue::Number(n),
+            Outcome::String(s) => ast::LiteralValue::String(s),
+            Outcome::Bool(true) => ast::LiteralValue::String("True".to_string()),
+            Outcome::Bool(false) => ast::LiteralValue::String("False".to_string()),
+            Outcome::List(items) => ast::LiteralValue::List(items),
+            Outcome::Map(entries) => ast::LiteralValue::Map(entries),
+        }
+    }
+}
+
+/* 变量中保存的字符串作为数字参与运算时的值，例如 "12"、"-0.5" */
+pub fn parse_number(text: &str) -> Option<Decimal> {
+    text.parse().ok()
+}
+
+/*
+ * 二元运算的语义，解释器与虚拟机共用这一份实现
+ * 数字以十进制精确计算，不会出现 0.1 + 0.2 = 0.30000000000000004
+ * - 加法：两个数字相加；字符串能解析为数字时按数字相加，否则拼接
+ * - 减乘除、取余与乘方：操作数必须是数字或可以解析为数字的字符串
+ * - 乘方的指数为整数时结果是精确的，否则为近似值
+ * - 相等：数字与字符串比较时先将数字转为字符串，列表与映射逐项比较
+ * - 列表与映射只能比较是否相等，或者与字符串拼接，拼接时取输出时的形式
+ */
+pub fn eval_binary(operator: &Token, left: Operand, right: Operand) -> Result<Outcome, String> {
+    const NOT_NUMBER: &str = "Right value and left value must be a number!";
+    const OVERFLOW: &str = "The result is out of range!";
+    const DIVISION_BY_ZERO: &str = "Division by zero is not allowed!";
+    if let Some(kind) = left.collection().or(right.collection()) {
+        return match (operator, left, right) {
+            (Token::WEqual, _, _) => Ok(Outcome::Bool(left == right)),
+            (Token::OperatorAdd, Operand::String(_), _)
+            | (Token::OperatorAdd, _, Operand::String(_)) => {
+                Ok(Outcome::String(format!("{}{}", left, right)))
+            }
+            _ => Err(format!(
+                "Operator `{}` cannot be applied to a {}!",
+                ast::operator_symbol(operator),
+                kind
+            )),
+        };
+    }
+    let number = |operand: Operand| match operand {
+        Operand::Number(n) => Some(n),
+        Operand::String(s) => parse_number(s),
+        _ => None,
+    };
+    let numbers = |left: Operand, right: Operand| match (number(left), number(right)) {
+        (Some(l), Some(r)) => Ok((l, r)),
+        _ => Err(NOT_NUMBER.to_string()),
+    };
+    let checked = |result: Option<Decimal>| {
+        result
+            .map(Outcome::Number)
+            .ok_or_else(|| OVERFLOW.to_string())
+    };
+    match operator {
+        Token::OperatorAdd => match (left, right) {
+            (Operand::Number(l), Operand::Number(r)) => checked(l.checked_add(r)),
+            (Operand::String(l), Operand::String(r)) => match (parse_number(l), parse_number(r)) {
+                (Some(l), Some(r)) => checked(l.checked_add(r)),
+                // 处理字符串拼接
+                _ => Ok(Outcome::String(format!("{}{}", l, r))),
+            },
+            // 变量中的数字以字符串保存，能解析为数字时按数字相加
+            (Operand::String(l), Operand::Number(r)) => match parse_number(l) {
+                Some(l) => checked(l.checked_add(r)),
+                None => Ok(Outcome::String(format!("{}{}", l, ast::number_to_string(r)))),
+            },
+            (Operand::Number(l), Operand::String(r)) => match parse_number(r) {
+                Some(r) => checked(l.checked_add(r)),
+                None => Ok(Outcome::String(format!("{}{}", ast::number_to_string(l), r))),
+            },
+            _ => unreachable!("列表与映射已经在前面处理"),
+        },
+        Token::OperatorSub => {
+            let (l, r) = numbers(left, right)?;
+            checked(l.checked_sub(r))
+        }
+        Token::OperatorMul => {
+            let (l, r) = numbers(left, right)?;
+            checked(l.checked_mul(r))
+        }
+        Token::OperatorDiv | Token::OperatorMod => {
+            let (l, r) = numbers(left, right)?;
+            if r.is_zero() {
+                return Err(DIVISION_BY_ZERO.to_string());
+            }
+            match operator {
+                Token::OperatorDiv => checked(l.checked_div(r)),
+                _ => checked(l.checked_rem(r)),
+            }
+        }
+        Token::OperatorPow => {
+            let (l, r) = numbers(left, right)?;
+            if l.is_zero() && r.is_sign_negative() {
+                return Err(DIVISION_BY_ZERO.to_string());
+            }
+            match r.to_i64() {
+                Some(exponent) if r.fract().is_zero() => checked(l.checked_powi(exponent)),
+                _ => checked(l.checked_powd(r)),
+            }
+        }
+        Token::WEqual => Ok(Outcome::Bool(match (left, right) {
+            (Operand::String(l), Operand::String(r)) => l == r,
+            (Operand::String(l), Operand::Number(r)) => l == ast::number_to_string(r),
+            (Operand::Number(l), Operand::String(r)) => ast::number_to_string(l) == r,
+            (Operand::Number(l), Operand::Number(r)) => l == r,
+            _ => unreachable!("列表与映射已经在前面处理"),
+        })),
+        _ => Err("Need a operator!".to_string()),
+    }
+}
+
+/*
+ * 下标访问的语义，解释器与虚拟机共用
+ * - 列表：下标为从0开始的整数，也可以是能解析为整数的字符串，超出范围时报错
+ * - 映射：下标为键，数字先转为字符串，键不存在时报错，可以先用has检查
+ */
+pub fn eval_index(target: Operand, index: Operand) -> Result<Outcome, String> {
+    match target {
+        Operand::List(items) => {
+            let position = match index {
+                Operand::Number(n) => Some(n),
+                Operand::String(s) => parse_number(s),
+                _ => None,
+            };
+            position
+                .filter(|n| n.fract().is_zero())
+                .and_then(|n| n.to_usize())
+                .and_then(|position| items.get(position))
+                .map(|item| Outcome::from(item.clone()))
+                .ok_or_else(|| {
+                    format!(
+                        "Index {} is out of range for a list of length {}!",
+                        index,
+                        items.len()
+                    )
                 })
+        }
+        Operand::Map(entries) => {
+            if let Some(kind) = index.collection() {
+                return Err(format!("A {} cannot be used as a key!", kind));
             }
+            let key = index.to_string();
+            entries
+                .get(&key)
+                .map(|value| Outcome::from(value.clone()))
+                .ok_or_else(|| format!("Key '{}' cannot be found!", key))
         }
+        _ => Err(format!("Only lists and maps can be indexed, got '{}'!", target)),
     }
 }
 
@@ -566,7 +1491,15 @@ mod tests {
     fn test_addition() {
         // 创建一个Interpreter实例
         let mut interpreter = Interpreter::new(vec![]);
-        let mut env = interpreter.env.last_mut().unwrap();
+        let ctx = &mut Context {
+            env: &mut interpreter.env,
+            rng: &mut Rng::seeded(0),
+            clock: &SystemClock,
+            store: &MemoryStore::new(),
+            user: store::DEFAULT_USER,
+            catalog: &Catalog::default(),
+            locale: None,
+        };
 
         // 添加一个变量 name 到环境中
         let name_variable_name = "name".to_string();
@@ -581,7 +1514,7 @@ mod tests {
         });
 
         // 调用 exec 方法来声明变量
-        name_var_assign_expr.exec(&mut env);
+        name_var_assign_expr.exec(ctx);
 
         // 创建一个带变量的字符串连接表达式
         let left_value = Box::new(Expr::Literal {
@@ -603,7 +1536,7 @@ mod tests {
             }),
         });
         // 调用 exec 方法并检查返回值
-        let result = concat_expr.exec(&mut env);
+        let result = concat_expr.exec(ctx);
         // 检查结果是否是预期的 LiteralValue::String("你好，Tom请问有什么需要帮助的？")
         assert_eq!(
             result,
@@ -617,12 +1550,20 @@ mod tests {
     fn test_complex_equality() {
         // 创建一个Interpreter实例
         let mut interpreter = Interpreter::new(vec![]);
-        let mut env = interpreter.env.last_mut().unwrap();
+        let ctx = &mut Context {
+            env: &mut interpreter.env,
+            rng: &mut Rng::seeded(0),
+            clock: &SystemClock,
+            store: &MemoryStore::new(),
+            user: store::DEFAULT_USER,
+            catalog: &Catalog::default(),
+            locale: None,
+        };
 
         // 创建一个变量并赋值
         let variable_name = "x".to_string();
         let variable_value = "Hello".to_string();
-        env.insert(variable_name.clone(), variable_value.clone());
+        ctx.env.define_local(&variable_name, variable_value.clone().into());
 
         // 创建左操作数的表达式
         let left_value = Box::new(Expr::Variable {
@@ -642,7 +1583,7 @@ mod tests {
         });
 
         // 调用 exec 方法并检查返回值
-        let result = eq_expr.exec(&mut env);
+        let result = eq_expr.exec(ctx);
 
         // 检查结果是否是预期的 LiteralValue::String("True")
         assert_eq!(
@@ -652,4 +1593,484 @@ mod tests {
             })
         );
     }
+
+    /* 运行一段脚本，返回所有输出 */
+    fn run(source: &str, inputs: &[&str]) -> (Vec<String>, Interpreter) {
+        let ast = crate::parser::parse_program(source).unwrap();
+        let mut io = ScriptedIo::new(inputs);
+        let mut interpreter = Interpreter::new(ast);
+        interpreter.interpret_with(&mut io);
+        (io.output, interpreter)
+    }
+
+    #[test]
+    fn test_block_scoping() {
+        let source = r#"
+            global x = "outer";
+            {
+                local x = "inner";
+                speak x;
+                x = "changed";
+                speak x;
+            }
+            speak x;
+            if (x == "outer") {
+                input answer;
+                y = "block";
+                x = "assigned";
+                global g = "nested";
+            };
+            speak x;
+            speak g;
+        "#;
+        let (output, interpreter) = run(source, &["yes"]);
+        assert_eq!(output, vec!["inner", "changed", "outer", "assigned", "nested"]);
+        // 代码块中新建的变量不会泄漏到外层
+        assert_eq!(interpreter.env.get("answer"), None);
+        assert_eq!(interpreter.env.get("y"), None);
+        assert_eq!(interpreter.env.depth(), 0);
+    }
+
+    #[test]
+    fn test_loop_scope_is_fresh() {
+        // 每一轮循环都进入新的作用域，上一轮的局部变量不可见
+        let source = r#"
+            global n = 0;
+            loop {
+                n = n + "1";
+                input a;
+                if (n == 1) { first = a; };
+                if (n == 2) { speak n; speak a; exit; };
+            }
+        "#;
+        let (output, interpreter) = run(source, &["1", "2"]);
+        assert_eq!(output, vec!["2", "2"]);
+        assert_eq!(interpreter.env.get("n"), Some(&Value::from("2")));
+        assert_eq!(interpreter.env.get("a"), None);
+    }
+
+    #[test]
+    fn test_output_is_streamed() {
+        // 运行时错误之前的输出已经交给Io
+        let ast = crate::parser::parse_program("speak \"before\"; speak 1 - \"x\";").unwrap();
+        let mut io = ScriptedIo::new(&[] as &[&str]);
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            Interpreter::new(ast).interpret_with(&mut io)
+        }));
+        assert!(result.is_err());
+        assert_eq!(io.output, vec!["before"]);
+
+        // 不需要输入的循环也能逐行输出，这里在第三行时停止
+        struct Stop(usize);
+        impl Io for Stop {
+            fn speak(&mut self, _text: &str) {
+                self.0 += 1;
+                if self.0 == 3 {
+                    panic!("stop");
+                }
+            }
+            fn input(&mut self, _var: Option<&str>) -> Option<String> {
+                None
+            }
+        }
+        let ast = crate::parser::parse_program("loop { speak \"tick\"; }").unwrap();
+        let mut io = Stop(0);
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            Interpreter::new(ast).interpret_with(&mut io)
+        }));
+        assert!(result.is_err());
+        assert_eq!(io.0, 3);
+    }
+
+    #[test]
+    fn test_resume_sessions() {
+        let source = std::fs::read_to_string("examples/bank.dsl").unwrap();
+        let interpreter = Interpreter::new(crate::parser::parse_program(&source).unwrap());
+
+        // 同一个解释器交替推进两个互不影响的会话
+        let (mut alice, turn) = interpreter.start_for("alice");
+        assert_eq!(turn.state, State::NeedsInput);
+        assert_eq!(turn.output[0], "你好Tom，请问有什么需要帮助的？");
+        let (mut bob, _) = interpreter.start_for("bob");
+
+        interpreter.resume(&mut alice, "r");
+        let turn = interpreter.resume(&mut alice, "100");
+        assert_eq!(turn.output[0], "充值成功!");
+        let turn = interpreter.resume(&mut bob, "b");
+        assert_eq!(turn.output[0], "你的账户余额为:0");
+        let turn = interpreter.resume(&mut alice, "b");
+        assert_eq!(turn.output[0], "你的账户余额为:100");
+
+        let turn = interpreter.resume(&mut alice, "e");
+        assert_eq!(turn, Turn { output: vec![], state: State::Finished });
+        assert_eq!(alice.state(), State::Finished);
+        assert_eq!(alice.env.depth(), 0);
+        // 已结束的会话不再响应
+        assert_eq!(interpreter.resume(&mut alice, "b").output, Vec::<String>::new());
+        assert_eq!(bob.state(), State::NeedsInput);
+        // 余额保存在store中，alice的下一次对话仍能看到
+        let (mut alice, _) = interpreter.start_for("alice");
+        let turn = interpreter.resume(&mut alice, "b");
+        assert_eq!(turn.output[0], "你的账户余额为:100");
+    }
+
+    #[test]
+    fn test_match_input_suspends() {
+        let source = std::fs::read_to_string("examples/intent.dsl").unwrap();
+        let interpreter = Interpreter::new(crate::parser::parse_program(&source).unwrap());
+        let (mut session, _) = interpreter.start();
+        // 挂起在 match input 处，没有等待写入的变量
+        assert_eq!(session.state(), State::NeedsInput);
+        assert_eq!(session.waiting(), None);
+        let turn = interpreter.resume(&mut session, "我要充值");
+        assert_eq!(turn.output, vec!["请输入充值的金额:"]);
+        assert_eq!(session.waiting(), Some("amount"));
+        interpreter.resume(&mut session, "30");
+        let turn = interpreter.resume(&mut session, "余额多少");
+        assert_eq!(turn.output, vec!["你的账户余额为:30"]);
+        let turn = interpreter.resume(&mut session, "再见");
+        assert_eq!(turn.state, State::Finished);
+    }
+
+    #[test]
+    fn test_typed_input_retries() {
+        let source = r#"
+            input n as integer prompt "几个?" retry "请输入整数" max 2 else { speak "放弃"; };
+            input ok as yesno prompt "确认?";
+            speak ok;
+        "#;
+        let interpreter = Interpreter::new(crate::parser::parse_program(source).unwrap());
+        let (mut session, turn) = interpreter.start();
+        assert_eq!(turn.output, vec!["几个?"]);
+        assert_eq!(session.waiting(), Some("n"));
+        let turn = interpreter.resume(&mut session, "abc");
+        assert_eq!(turn.output, vec!["请输入整数"]);
+        assert_eq!(session.waiting(), Some("n"));
+        // 第二次仍不合法，执行else分支后继续
+        let turn = interpreter.resume(&mut session, "1.5");
+        assert_eq!(turn.output, vec!["放弃", "确认?"]);
+        assert_eq!(session.env.get("n"), None);
+        let turn = interpreter.resume(&mut session, "好的");
+        assert_eq!(
+            turn,
+            Turn {
+                output: vec!["True".to_string()],
+                state: State::Finished
+            }
+        );
+    }
+
+    #[test]
+    fn test_form_fills_missing_slots() {
+        let source = std::fs::read_to_string("examples/form.dsl").unwrap();
+        let interpreter = Interpreter::new(crate::parser::parse_program(&source).unwrap());
+        // name已经存在，直接询问账号
+        let (mut session, turn) = interpreter.start();
+        assert_eq!(turn.output[1], "请输入收款账号(8位数字):");
+        assert_eq!(session.waiting(), Some("account"));
+        let turn = interpreter.resume(&mut session, "1234");
+        assert_eq!(turn.output, vec!["账号应为8位数字，请重新输入:"]);
+        // 提前填写之后的槽位，仍然询问当前的槽位
+        let turn = interpreter.resume(&mut session, "amount=50");
+        assert_eq!(turn.output, vec!["请输入收款账号(8位数字):"]);
+        let turn = interpreter.resume(&mut session, "12345678");
+        assert_eq!(turn.output, vec!["请选择转账方式: 1.网银 2.柜台"]);
+        let turn = interpreter.resume(&mut session, "2");
+        assert!(turn.output[0].starts_with("向12345678通过柜台转账50元"));
+        assert_eq!(session.waiting(), None);
+        // 确认时修改已经填写的槽位
+        let turn = interpreter.resume(&mut session, "Amount = 80");
+        assert!(turn.output[0].starts_with("向12345678通过柜台转账80元"));
+        let turn = interpreter.resume(&mut session, "好的");
+        assert_eq!(turn.output, vec!["转账已提交，谢谢Tom!"]);
+        assert_eq!(turn.state, State::Finished);
+    }
+
+    #[test]
+    fn test_states_and_goto() {
+        let source = std::fs::read_to_string("examples/states.dsl").unwrap();
+        let interpreter = Interpreter::new(crate::parser::parse_program(&source).unwrap());
+        let (mut session, turn) = interpreter.start();
+        assert_eq!(turn.output[1], "------主菜单-----");
+        assert_eq!(session.current_state(), Some("main"));
+        assert_eq!(session.waiting(), None);
+        assert_eq!(turn.state, State::NeedsInput);
+        let turn = interpreter.resume(&mut session, "a");
+        assert_eq!(turn.output[0], "------账户服务-----");
+        interpreter.resume(&mut session, "r");
+        assert_eq!(session.current_state(), Some("recharge"));
+        let turn = interpreter.resume(&mut session, "abc");
+        assert_eq!(turn.output, vec!["请输入有效的数字金额:"]);
+        interpreter.resume(&mut session, "50");
+        assert_eq!(session.current_state(), Some("account"));
+        assert_eq!(session.env.get("bill").and_then(Value::as_str), Some("50"));
+        // on input 的变量只在处理代码块中存在
+        assert_eq!(session.env.get("amount"), None);
+        interpreter.resume(&mut session, "q");
+        let turn = interpreter.resume(&mut session, "e");
+        assert_eq!(turn.output, vec!["再见!"]);
+        assert_eq!(turn.state, State::Finished);
+        assert_eq!(session.current_state(), None);
+    }
+
+    #[test]
+    fn test_collections_and_for() {
+        let source = r#"
+            global menu = {"b": "余额", "r": "充值"};
+            global seen = [];
+            for key in menu {
+                input answer;
+                seen = push(seen, key + "=" + answer);
+            }
+            speak seen;
+            speak seen[1] + " " + menu["r"] + " " + len(menu);
+            speak [1, [2, "x"]] == [1, [2, "x"]];
+        "#;
+        let interpreter = Interpreter::new(crate::parser::parse_program(source).unwrap());
+        let (mut session, _) = interpreter.start();
+        // 在for循环体中等待输入，恢复后继续剩下的元素
+        assert_eq!(session.waiting(), Some("answer"));
+        interpreter.resume(&mut session, "1");
+        assert_eq!(session.waiting(), Some("answer"));
+        let turn = interpreter.resume(&mut session, "2");
+        assert_eq!(
+            turn.output,
+            vec![r#"["b=1", "r=2"]"#, "r=2 充值 2", "True"]
+        );
+        assert_eq!(
+            session.env.get("seen"),
+            Some(&Value::List(vec!["b=1".into(), "r=2".into()]))
+        );
+        // 循环变量只在循环体中存在
+        assert_eq!(session.env.get("key"), None);
+
+        let list = [Value::from("a")];
+        let list = Operand::List(&list);
+        assert!(eval_index(list, Operand::String("1")).is_err());
+        assert!(eval_binary(&Token::OperatorSub, list, Operand::String("1")).is_err());
+        assert_eq!(
+            eval_binary(&Token::OperatorAdd, Operand::String("x"), list),
+            Ok(Outcome::String(r#"x["a"]"#.to_string()))
+        );
+    }
+
+    #[test]
+    fn test_injected_clock() {
+        let source = std::fs::read_to_string("examples/hours.dsl").unwrap();
+        let ast = crate::parser::parse_program(&source).unwrap();
+        let clock = crate::clock::FixedClock::parse("2024-03-05 09:30").unwrap();
+        let interpreter = Interpreter::new(ast).with_clock(Arc::new(clock));
+        let (mut session, turn) = interpreter.start();
+        assert_eq!(
+            turn.output[..3],
+            ["早上好!", "现在是2024年03月05日 09:30", "人工客服在线"]
+        );
+        assert_eq!(
+            session.env.get("today").and_then(Value::as_str),
+            Some("2024-03-05 09:30:00")
+        );
+        let turn = interpreter.resume(&mut session, "2024-03-05");
+        assert_eq!(turn.output[0], "该笔交易发生在0天前");
+        assert_eq!(turn.output[1], "退款申请的截止时间为03月12日");
+    }
+
+    #[test]
+    fn test_seeded_variants() {
+        let source = r#"
+            loop {
+                speak one of ["您好", "你好呀", "欢迎"];
+                input x;
+                speak random(1, 7);
+            }
+        "#;
+        let ast = crate::parser::parse_program(source).unwrap();
+        let interpreter = Interpreter::new(ast).with_seed(42);
+        let transcript = |session: &mut Session, first: Turn| {
+            let mut output = first.output;
+            for text in ["1", "2", "3"] {
+                output.extend(interpreter.resume(session, text).output);
+            }
+            output
+        };
+        // 同一个种子的两个会话得到同样的对话记录
+        let (mut alice, turn) = interpreter.start();
+        let expected = transcript(&mut alice, turn);
+        let (mut bob, turn) = interpreter.start();
+        assert_eq!(transcript(&mut bob, turn), expected);
+        assert!(["您好", "你好呀", "欢迎"].contains(&expected[0].as_str()));
+        assert!(["1", "2", "3", "4", "5", "6"].contains(&expected[1].as_str()));
+
+        // 发生器随会话一起保存，恢复后接着原来的序列
+        let json = crate::snapshot::to_json(&interpreter.ast, &alice);
+        let mut restored = crate::snapshot::from_json(&interpreter.ast, &json).unwrap();
+        assert_eq!(
+            interpreter.resume(&mut restored, "4"),
+            interpreter.resume(&mut alice, "4")
+        );
+    }
+
+    #[test]
+    fn test_store_persists_across_sessions() {
+        let source = r#"
+            store bill = 0;
+            speak "余额:" + store.bill;
+            input amount;
+            store.bill = store.bill + amount;
+            speak "余额:" + store.bill;
+        "#;
+        let ast = crate::parser::parse_program(source).unwrap();
+        let interpreter = Interpreter::new(ast);
+        let (mut session, turn) = interpreter.start_for("alice");
+        assert_eq!(turn.output, vec!["余额:0"]);
+        assert_eq!(interpreter.resume(&mut session, "100").output, vec!["余额:100"]);
+        // 同一个用户的下一次对话读到上一次写入的值，声明的初始值不再生效
+        let (mut session, turn) = interpreter.start_for("alice");
+        assert_eq!(turn.output, vec!["余额:100"]);
+        assert_eq!(interpreter.resume(&mut session, "50").output, vec!["余额:150"]);
+        let (_, turn) = interpreter.start_for("bob");
+        assert_eq!(turn.output, vec!["余额:0"]);
+        assert_eq!(
+            interpreter.store.get("alice", "bill").unwrap(),
+            Some(Value::from("150"))
+        );
+
+        // 没有声明也没有写入过的键
+        let ast = crate::parser::parse_program("speak store.missing;").unwrap();
+        let result = std::panic::catch_unwind(|| Interpreter::new(ast).start());
+        assert!(result.is_err());
+    }
+
+    fn catalog() -> Arc<Catalog> {
+        let mut catalog = Catalog::default();
+        catalog
+            .add("zh", "greeting = \"你好，{name}！\"\nbye = \"再见\"")
+            .unwrap();
+        catalog
+            .add("en", "greeting = \"Hello, {name}!\"")
+            .unwrap();
+        Arc::new(catalog.with_default("zh"))
+    }
+
+    #[test]
+    fn test_locale_switching() {
+        let source = r#"
+            input name;
+            speak @greeting(name);
+            input language;
+            locale language;
+            speak @greeting(name: upper(name));
+            speak @bye;
+        "#;
+        let ast = crate::parser::parse_program(source).unwrap();
+        let interpreter = Interpreter::new(ast).with_catalog(catalog());
+        let (mut session, _) = interpreter.start();
+        assert_eq!(interpreter.resume(&mut session, "Tom").output, vec!["你好，Tom！"]);
+        assert_eq!(session.locale(), None);
+        // 切换后的语言随会话保存，英文中缺少的消息取默认语言
+        let json = serde_json::to_string(&session).unwrap();
+        let mut session: Session = serde_json::from_str(&json).unwrap();
+        assert_eq!(
+            interpreter.resume(&mut session, "en").output,
+            vec!["Hello, TOM!", "再见"]
+        );
+        assert_eq!(session.locale(), Some("en"));
+
+        let (mut session, _) = interpreter.start();
+        interpreter.resume(&mut session, "Tom");
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            interpreter.resume(&mut session, "fr")
+        }));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_transfer_to_agent() {
+        let source = r#"
+            speak "您好，请问有什么可以帮您？";
+            loop {
+                input issue;
+                if (issue == "人工") {
+                    local attempts = "3";
+                    transfer "用户要求人工服务";
+                    speak "欢迎回来，订单" + order + "已处理";
+                    exit;
+                };
+                speak "请再描述一下问题";
+            }
+        "#;
+        let ast = crate::parser::parse_program(source).unwrap();
+        let interpreter = Interpreter::new(ast);
+        let (mut session, _) = interpreter.start();
+        interpreter.resume(&mut session, "退款");
+        let turn = interpreter.resume(&mut session, "人工");
+        assert_eq!(turn, Turn { output: vec![], state: State::Transferred });
+        let handoff = session.handoff().unwrap().clone();
+        assert_eq!(handoff.reason, "用户要求人工服务");
+        assert_eq!(
+            handoff.transcript,
+            vec![
+                Line::bot("您好，请问有什么可以帮您？"),
+                Line::user("退款"),
+                Line::bot("请再描述一下问题"),
+                Line::user("人工"),
+            ]
+        );
+        let names: Vec<&String> = handoff.variables.keys().collect();
+        assert_eq!(names, ["attempts", "issue"]);
+
+        // 转接期间用户的消息不交给脚本，会话快照中保留转接事件
+        assert_eq!(interpreter.resume(&mut session, "在吗").state, State::Transferred);
+        let json = serde_json::to_string(&session).unwrap();
+        let mut session: Session = serde_json::from_str(&json).unwrap();
+        let mut variables = crate::value::Map::new();
+        variables.insert("order".to_string(), Value::from("A12"));
+        let turn = interpreter.hand_back(&mut session, Handback::Resume { variables });
+        assert_eq!(turn.output, vec!["欢迎回来，订单A12已处理"]);
+        assert_eq!(turn.state, State::Finished);
+        assert_eq!(session.transcript().len(), 5);
+
+        // 人工客服结束对话
+        let (mut session, _) = interpreter.start();
+        interpreter.resume(&mut session, "人工");
+        let turn = interpreter.hand_back(&mut session, Handback::End);
+        assert_eq!(turn.state, State::Finished);
+        assert_eq!(session.state(), State::Finished);
+
+        let mut io = ScriptedIo::new(&["人工", "end"]);
+        Interpreter::new(crate::parser::parse_program(source).unwrap()).interpret_with(&mut io);
+        assert_eq!(io.output, vec!["您好，请问有什么可以帮您？"]);
+        assert_eq!(io.handoffs.len(), 1);
+
+        // 没有transfer语句的程序不保留对话记录
+        let ast = crate::parser::parse_program("loop { input x; speak x; }").unwrap();
+        let interpreter = Interpreter::new(ast);
+        let (mut session, _) = interpreter.start();
+        interpreter.resume(&mut session, "hi");
+        assert!(session.transcript().is_empty());
+    }
+
+    #[test]
+    fn test_exact_decimal_arithmetic() {
+        let number = |text: &str| Operand::Number(text.parse().unwrap());
+        let eval = |operator, left, right| match eval_binary(&operator, left, right) {
+            Ok(Outcome::Number(n)) => ast::number_to_string(n),
+            other => panic!("{:?}", other),
+        };
+        let sum = eval(Token::OperatorAdd, number("0.1"), number("0.2"));
+        assert_eq!(sum, "0.3");
+        // 变量中保存的字符串同样精确相加
+        let sum = eval(Token::OperatorAdd, Operand::String("0.3"), Operand::String("0.2"));
+        assert_eq!(sum, "0.5");
+        assert_eq!(eval(Token::OperatorMul, number("1.50"), number("2")), "3");
+        assert_eq!(eval(Token::OperatorMod, number("7"), number("3")), "1");
+        assert_eq!(eval(Token::OperatorMod, number("5.5"), number("2")), "1.5");
+        assert_eq!(eval(Token::OperatorPow, number("1.1"), number("2")), "1.21");
+        assert_eq!(eval(Token::OperatorPow, number("2"), number("-2")), "0.25");
+        assert!(eval_binary(&Token::OperatorMod, number("1"), number("0")).is_err());
+        assert!(eval_binary(&Token::OperatorPow, number("10"), number("100")).is_err());
+        assert_eq!(
+            eval_binary(&Token::WEqual, Operand::String("3"), number("3.0")),
+            Ok(Outcome::Bool(true))
+        );
+    }
 }
@@ -1,29 +1,167 @@
 use super::tokens::Token;
 use logos::{Logos, SpannedIter};
 use std::fmt; // your enum
+use std::iter::Peekable;
+use std::ops::Range;
 
 pub type Spanned<Tok, Loc, Error> = Result<(Loc, Tok, Loc), Error>;
 
 #[derive(Debug)]
 pub enum LexicalError {
     InvalidToken,
+    /* 字面量本身合法但取值无效，例如无法编译的正则表达式，由parser在归约时报告 */
+    InvalidLiteral {
+        start: usize,
+        end: usize,
+        message: String,
+    },
 }
 impl fmt::Display for LexicalError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             LexicalError::InvalidToken => write!(f, "Invalid token encountered."),
+            LexicalError::InvalidLiteral { message, .. } => write!(f, "{}", message),
         }
     }
 }
+
+/*
+ * 最初的 global、speak、input、if、exit、loop 是保留字，之后加入的关键字都不保留，
+ * 在其他位置仍然可以用作变量名，以免新的语法使已有的脚本无法运行。
+ * 开始一条语句的词只有位于语句开头、并且后面跟着名字或表达式时才是关键字，
+ * 例如 local x = 1; 中的 local，而 local = 1; 与 speak local; 中的 local 是变量，
+ * store 后面跟着 . 时也是关键字。
+ * input与slot语句中的 prompt、retry、else 后面是表达式或代码块，
+ * 只有紧跟在名字、字面量或 ) ] 之后时才是关键字，因为表达式中两个操作数不会相邻。
+ * LR(1)文法无法区分这些位置上的词与变量名，因此由这里根据前后的token判断，
+ * parser、formatter与LSP看到的是同一个token流。
+ * 后面是固定种类token的 as、in、one of、threshold 等词由grammar.lalrpop按标识符的文本识别。
+ */
+pub struct Tokens<'input> {
+    stream: Peekable<SpannedIter<'input, Token>>,
+    /* 上一个token，None表示位于文件开头 */
+    previous: Option<Token>,
+    /* 位于input或slot语句中 */
+    clauses: bool,
+}
+
+impl<'input> Tokens<'input> {
+    pub fn new(input: &'input str) -> Self {
+        // the Token::lexer() method is provided by the Logos trait
+        Self {
+            stream: Token::lexer(input).spanned().peekable(),
+            previous: None,
+            clauses: false,
+        }
+    }
+
+    /* 标识符word在当前位置是否为关键字 */
+    fn keyword(&mut self, word: &str) -> Option<Token> {
+        let keyword = match word.to_lowercase().as_str() {
+            "local" => Token::KeywordLocal,
+            "match" => Token::KeywordMatch,
+            "intent" => Token::KeywordIntent,
+            "default" => Token::KeywordDefault,
+            "prompt" => Token::KeywordPrompt,
+            "retry" => Token::KeywordRetry,
+            "else" => Token::KeywordElse,
+            "form" => Token::KeywordForm,
+            "slot" => Token::KeywordSlot,
+            "confirm" => Token::KeywordConfirm,
+            "state" => Token::KeywordState,
+            "on" => Token::KeywordOn,
+            "goto" => Token::KeywordGoto,
+            "for" => Token::KeywordFor,
+            "store" => Token::KeywordStore,
+            "import" => Token::KeywordImport,
+            "locale" => Token::KeywordLocale,
+            "transfer" => Token::KeywordTransfer,
+            _ => return None,
+        };
+        let recognized = match keyword {
+            Token::KeywordPrompt | Token::KeywordRetry | Token::KeywordElse => {
+                self.clauses
+                    && matches!(
+                        self.previous,
+                        Some(
+                            Token::Identifier(_)
+                                | Token::Number(_)
+                                | Token::StringContent(_)
+                                | Token::RParen
+                                | Token::RSquare
+                        )
+                    )
+            }
+            // store.键 在任何位置都是store中的值
+            Token::KeywordStore if matches!(self.stream.peek(), Some((Token::Dot, _))) => true,
+            _ => {
+                self.statement_start()
+                    && self
+                        .stream
+                        .peek()
+                        .is_some_and(|(next, _)| follows_keyword(next))
+            }
+        };
+        recognized.then_some(keyword)
+    }
+
+    fn statement_start(&self) -> bool {
+        matches!(
+            self.previous,
+            None | Some(Token::Semicolon | Token::LBracket | Token::RBracket)
+        )
+    }
+}
+
+/*
+ * 可以跟在语句开头的关键字之后、却不能跟在变量名之后的token：
+ * 名字、字面量、表达式的开始、match input 与 on input 中的 input 以及 default => 中的 =>
+ */
+fn follows_keyword(token: &Token) -> bool {
+    matches!(
+        token,
+        Token::Identifier(_)
+            | Token::Number(_)
+            | Token::StringContent(_)
+            | Token::LParen
+            | Token::LSquare
+            | Token::LBracket
+            | Token::At
+            | Token::KeywordInput
+            | Token::FatArrow
+    )
+}
+
+impl<'input> Iterator for Tokens<'input> {
+    type Item = (Token, Range<usize>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (mut token, span) = self.stream.next()?;
+        if let Token::Identifier(word) = &token {
+            if let Some(keyword) = self.keyword(word) {
+                token = keyword;
+            }
+        }
+        match token {
+            Token::KeywordInput | Token::KeywordSlot if self.statement_start() => {
+                self.clauses = true
+            }
+            Token::Semicolon => self.clauses = false,
+            _ => {}
+        }
+        self.previous = Some(token.clone());
+        Some((token, span))
+    }
+}
+
 pub struct Lexer<'input> {
     // instead of an iterator over characters, we have a token iterator
-    token_stream: SpannedIter<'input, Token>,
+    token_stream: Tokens<'input>,
 }
 impl<'input> Lexer<'input> {
     pub fn new(input: &'input str) -> Self {
-        // the Token::lexer() method is provided by the Logos trait
         Self {
-            token_stream: Token::lexer(input).spanned(),
+            token_stream: Tokens::new(input),
         }
     }
 }
@@ -45,6 +183,7 @@ impl<'input> Iterator for Lexer<'input> {
 mod tests {
     use crate::lexer::Lexer;
     use crate::tokens::Token;
+    use rust_decimal::Decimal;
 
     // 编写测试函数来测试解析规则
     fn test_token(input: &str, expected_token: Token) {
@@ -64,6 +203,217 @@ mod tests {
         test_token("loop", Token::KeywordLoop);
     }
 
+    fn tokens(input: &str) -> Vec<Token> {
+        Lexer::new(input).map(|result| result.unwrap().1).collect()
+    }
+
+    /* 脚本中的word都是变量名，并且可以正常解析 */
+    fn assert_identifier(word: &str) {
+        let script = format!("{{ {0} = 1; speak {0} + {0}; }} global g = {0};", word);
+        let identifier = Token::Identifier(word.to_string());
+        let count = tokens(&script)
+            .into_iter()
+            .filter(|token| *token == identifier)
+            .count();
+        assert_eq!(count, 4, "{}", script);
+        assert!(crate::parser::parse_program(&script).is_ok(), "{}", script);
+    }
+
+    #[test]
+    fn test_local_keyword() {
+        let local = || Token::Identifier("local".to_string());
+        assert_eq!(
+            tokens("local x = 1; LOCAL y = local;"),
+            vec![
+                Token::KeywordLocal,
+                Token::Identifier("x".to_string()),
+                Token::Assign,
+                Token::Number(1.into()),
+                Token::Semicolon,
+                Token::KeywordLocal,
+                Token::Identifier("y".to_string()),
+                Token::Assign,
+                local(),
+                Token::Semicolon,
+            ]
+        );
+        // 不在语句开头或者后面不是名字与表达式时是普通的变量名
+        assert_identifier("local");
+        assert_eq!(tokens("local"), vec![local()]);
+    }
+
+    #[test]
+    fn test_match_keywords() {
+        // 位于语句开头并且后面跟着名字或表达式时是关键字
+        let keywords = [
+            ("match input {}", Token::KeywordMatch),
+            ("match x {}", Token::KeywordMatch),
+            ("{ intent \"a\": [] => {} }", Token::KeywordIntent),
+            ("{} default => {}", Token::KeywordDefault),
+        ];
+        for (input, keyword) in keywords {
+            assert!(tokens(input).contains(&keyword), "{}", input);
+        }
+        // 其他位置是普通的变量名，threshold 与 regex 只由文法按位置识别
+        for word in ["match", "intent", "default", "threshold", "regex"] {
+            assert_identifier(word);
+        }
+        let source = r#"
+            match regex threshold 0.5 {
+                intent "r": [regex "^r$"] => { speak intent; }
+                default => { speak default; }
+            }
+        "#;
+        assert!(crate::parser::parse_program(source).is_ok());
+    }
+
+    #[test]
+    fn test_input_keywords() {
+        // input语句中紧跟在名字、字面量或 ) ] 之后时是关键字
+        let keywords = [
+            ("input x as number PROMPT \"?\";", Token::KeywordPrompt),
+            ("input x as t prompt (p) retry p;", Token::KeywordRetry),
+            ("input x as t max 3 else {};", Token::KeywordElse),
+        ];
+        for (input, keyword) in keywords {
+            assert!(tokens(input).contains(&keyword), "{}", input);
+        }
+        // 紧跟在关键字之后的是变量名
+        assert_eq!(
+            tokens("input x as n prompt prompt retry retry;")[4..8],
+            [
+                Token::KeywordPrompt,
+                Token::Identifier("prompt".to_string()),
+                Token::KeywordRetry,
+                Token::Identifier("retry".to_string()),
+            ]
+        );
+        // 其他位置是普通的变量名，as 与 max 只由文法按位置识别
+        for word in ["prompt", "retry", "else", "as", "max"] {
+            assert_identifier(word);
+        }
+        let source = "input else as integer prompt prompt retry retry max 2 else { speak max; };";
+        assert!(crate::parser::parse_program(source).is_ok());
+    }
+
+    #[test]
+    fn test_form_keywords() {
+        let keywords = [
+            ("Form f {", Token::KeywordForm),
+            ("{ slot s prompt \"?\"; }", Token::KeywordSlot),
+            ("slot s as t retry r;", Token::KeywordRetry),
+            ("; confirm \"ok?\";", Token::KeywordConfirm),
+        ];
+        for (input, keyword) in keywords {
+            assert!(tokens(input).contains(&keyword), "{}", input);
+        }
+        for word in ["form", "slot", "confirm"] {
+            assert_identifier(word);
+        }
+        let source = r#"
+            form form {
+                slot slot as number prompt form + confirm retry slot;
+                confirm confirm + slot;
+            }
+        "#;
+        assert!(crate::parser::parse_program(source).is_ok());
+    }
+
+    #[test]
+    fn test_state_keywords() {
+        let keywords = [
+            ("state s { on enter {} }", Token::KeywordState),
+            ("state s { on enter {} }", Token::KeywordOn),
+            ("{} on input x {}", Token::KeywordOn),
+            ("goto s;", Token::KeywordGoto),
+        ];
+        for (input, keyword) in keywords {
+            assert!(tokens(input).contains(&keyword), "{}", input);
+        }
+        // enter 只由文法按位置识别
+        for word in ["state", "on", "goto", "enter"] {
+            assert_identifier(word);
+        }
+        let source = r#"
+            goto state;
+            state state {
+                on enter { speak enter + on; }
+                on input on { goto state; }
+            }
+        "#;
+        assert!(crate::parser::parse_program(source).is_ok());
+    }
+
+    #[test]
+    fn test_for_keyword() {
+        assert!(tokens("For x in xs {}").contains(&Token::KeywordFor));
+        assert!(tokens("for [1] {}").contains(&Token::KeywordFor));
+        // in 只由文法按位置识别
+        for word in ["for", "in"] {
+            assert_identifier(word);
+        }
+        let source = "global for = 0; for in in [1, 2] { for = for + in; } speak for;";
+        assert!(crate::parser::parse_program(source).is_ok());
+    }
+
+    #[test]
+    fn test_one_of_words() {
+        // one 与 of 只由文法在 speak 之后识别
+        for word in ["one", "of"] {
+            assert_identifier(word);
+        }
+        let source = "speak one; speak one + of; speak one of [one, of]; speak ONE OF of;";
+        assert!(crate::parser::parse_program(source).is_ok());
+    }
+
+    #[test]
+    fn test_store_keyword() {
+        let keywords = [
+            ("Store k = 1;", Token::KeywordStore),
+            ("speak 1 + store.k;", Token::KeywordStore),
+        ];
+        for (input, keyword) in keywords {
+            assert!(tokens(input).contains(&keyword), "{}", input);
+        }
+        assert_identifier("store");
+        let source = "store = 1; store count = store; store.count = store.count + store;";
+        assert!(crate::parser::parse_program(source).is_ok());
+    }
+
+    #[test]
+    fn test_import_keyword() {
+        assert!(tokens("import \"m.dsl\" as m;").contains(&Token::KeywordImport));
+        assert_identifier("import");
+        let source = "import \"m.dsl\" as import; import = 1; speak import.x + import;";
+        assert!(crate::parser::parse_program(source).is_ok());
+    }
+
+    #[test]
+    fn test_locale_keyword() {
+        let keywords = [
+            ("Locale language;", Token::KeywordLocale),
+            ("{ locale (\"en\"); }", Token::KeywordLocale),
+        ];
+        for (input, keyword) in keywords {
+            assert!(tokens(input).contains(&keyword), "{}", input);
+        }
+        assert_identifier("locale");
+        let source = "locale = \"en\"; locale locale; speak @hello(locale: locale);";
+        assert!(crate::parser::parse_program(source).is_ok());
+    }
+
+    #[test]
+    fn test_transfer_keyword() {
+        assert!(tokens("TRANSFER \"投诉\";").contains(&Token::KeywordTransfer));
+        assert_identifier("transfer");
+        // 转账表单的名字 transfer 不在语句开头
+        let source = r#"
+            form transfer { slot transfer prompt "?"; }
+            transfer transfer;
+        "#;
+        assert!(crate::parser::parse_program(source).is_ok());
+    }
+
     #[test]
     fn test_single_lexing() {
         test_token("(", Token::LParen);
@@ -71,6 +421,14 @@ mod tests {
         test_token(";", Token::Semicolon);
         test_token("{", Token::LBracket);
         test_token("}", Token::RBracket);
+        test_token("[", Token::LSquare);
+        test_token("]", Token::RSquare);
+        test_token(",", Token::Comma);
+        test_token(":", Token::Colon);
+        test_token("=>", Token::FatArrow);
+        test_token(".", Token::Dot);
+        test_token("@", Token::At);
+        test_token("~", Token::Tilde);
     }
 
     #[test]
@@ -108,11 +466,14 @@ mod tests {
 
     #[test]
     fn test_number_lexing() {
-        test_token("123.456", Token::Number(123.456));
-        test_token("0.5", Token::Number(0.5));
-        test_token("1.0", Token::Number(1.0));
-        test_token("2.5", Token::Number(2.5));
-        test_token("15", Token::Number(15.0));
+        test_token("123.456", Token::Number(Decimal::new(123456, 3)));
+        test_token("0.5", Token::Number(Decimal::new(5, 1)));
+        // 保留源码中的小数位数
+        test_token("1.0", Token::Number(Decimal::new(10, 1)));
+        test_token("2.5", Token::Number(Decimal::new(25, 1)));
+        test_token("15", Token::Number(Decimal::from(15)));
+        test_token("%", Token::OperatorMod);
+        test_token("^", Token::OperatorPow);
     }
 
     #[test]
@@ -2,8 +2,52 @@ pub mod ast;
 
 pub mod interpreter;
 
+pub mod environment;
+
+pub mod value;
+
+pub mod intent;
+
+pub mod validation;
+
+pub mod builtins;
+
+pub mod clock;
+
+pub mod random;
+
+pub mod store;
+
+pub mod catalog;
+
+pub mod handoff;
+
+pub mod snapshot;
+
+pub mod server;
+
+pub mod http;
+
+pub mod protocol;
+
+pub mod bytecode;
+
+pub mod vm;
+
 pub mod tokens;
 
 pub mod lexer;
 
-//pub mod parser;
\ No newline at end of file
+pub mod graph;
+
+pub mod formatter;
+
+pub mod serialize;
+
+pub mod analysis;
+
+pub mod lsp;
+
+pub mod module;
+
+pub mod parser;
\ No newline at end of file
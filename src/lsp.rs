@@ -0,0 +1,617 @@
+use crate::analysis::{self, BlockSymbol, Document};
+use crate::builtins::Builtin;
+use lsp_server::{Connection, Message, Notification, Request, RequestId, Response};
+use lsp_types::notification::{
+    DidChangeTextDocument, DidCloseTextDocument, DidOpenTextDocument, Notification as _,
+    PublishDiagnostics,
+};
+use lsp_types::request::{
+    Completion, DocumentSymbolRequest, GotoDefinition, HoverRequest, Request as _,
+};
+use lsp_types::{
+    CompletionItem, CompletionItemKind, CompletionResponse, DiagnosticSeverity, DocumentSymbol,
+    DocumentSymbolResponse, GotoDefinitionResponse, Hover, HoverContents, HoverProviderCapability,
+    Location, MarkupContent, MarkupKind, OneOf, Position, PublishDiagnosticsParams, Range,
+    ServerCapabilities, SymbolKind, TextDocumentSyncCapability, TextDocumentSyncKind, Url,
+};
+use std::collections::HashMap;
+use std::error::Error;
+
+/*
+ * .dsl脚本的Language Server，通过stdin/stdout与编辑器通信
+ * 具体的分析逻辑位于analysis.rs，这里只负责协议的收发与位置换算
+ * 支持的功能：
+ * - 诊断信息(词法/语法错误、未声明的变量)
+ * - 关键字与变量补全
+ * - 悬停显示变量的声明
+ * - 跳转到变量定义
+ * - 文档大纲(代码块)
+ */
+
+pub fn run_stdio() -> Result<(), Box<dyn Error + Sync + Send>> {
+    let (connection, io_threads) = Connection::stdio();
+    let capabilities = ServerCapabilities {
+        text_document_sync: Some(TextDocumentSyncCapability::Kind(TextDocumentSyncKind::FULL)),
+        completion_provider: Some(Default::default()),
+        hover_provider: Some(HoverProviderCapability::Simple(true)),
+        definition_provider: Some(OneOf::Left(true)),
+        document_symbol_provider: Some(OneOf::Left(true)),
+        ..Default::default()
+    };
+    connection.initialize(serde_json::to_value(capabilities)?)?;
+    Server::default().main_loop(&connection)?;
+    // 先关闭连接，写线程才会退出
+    drop(connection);
+    io_threads.join()?;
+    Ok(())
+}
+
+#[derive(Default)]
+struct Server {
+    documents: HashMap<Url, Document>,
+}
+
+impl Server {
+    fn main_loop(&mut self, connection: &Connection) -> Result<(), Box<dyn Error + Sync + Send>> {
+        for message in &connection.receiver {
+            match message {
+                Message::Request(request) => {
+                    if connection.handle_shutdown(&request)? {
+                        return Ok(());
+                    }
+                    let response = self.handle_request(request);
+                    connection.sender.send(Message::Response(response))?;
+                }
+                Message::Notification(notification) => {
+                    if let Some(uri) = self.handle_notification(notification)? {
+                        connection
+                            .sender
+                            .send(Message::Notification(self.diagnostics(uri)))?;
+                    }
+                }
+                Message::Response(_) => {}
+            }
+        }
+        Ok(())
+    }
+
+    /* 处理文档的打开、修改与关闭，返回需要重新发布诊断信息的文档 */
+    fn handle_notification(
+        &mut self,
+        notification: Notification,
+    ) -> Result<Option<Url>, serde_json::Error> {
+        match notification.method.as_str() {
+            DidOpenTextDocument::METHOD => {
+                let params: lsp_types::DidOpenTextDocumentParams =
+                    serde_json::from_value(notification.params)?;
+                let uri = params.text_document.uri;
+                self.documents
+                    .insert(uri.clone(), Document::new(&params.text_document.text));
+                Ok(Some(uri))
+            }
+            DidChangeTextDocument::METHOD => {
+                let params: lsp_types::DidChangeTextDocumentParams =
+                    serde_json::from_value(notification.params)?;
+                // 采用全量同步，最后一次修改即为完整的文档内容
+                match params.content_changes.into_iter().last() {
+                    Some(change) => {
+                        let uri = params.text_document.uri;
+                        self.documents
+                            .insert(uri.clone(), Document::new(&change.text));
+                        Ok(Some(uri))
+                    }
+                    None => Ok(None),
+                }
+            }
+            DidCloseTextDocument::METHOD => {
+                let params: lsp_types::DidCloseTextDocumentParams =
+                    serde_json::from_value(notification.params)?;
+                self.documents.remove(&params.text_document.uri);
+                Ok(None)
+            }
+            _ => Ok(None),
+        }
+    }
+
+    fn diagnostics(&self, uri: Url) -> Notification {
+        let diagnostics = match self.documents.get(&uri) {
+            Some(document) => document
+                .diagnostics()
+                .into_iter()
+                .map(|diagnostic| lsp_types::Diagnostic {
+                    range: range(document.source(), diagnostic.start, diagnostic.end),
+                    severity: Some(match diagnostic.severity {
+                        analysis::Severity::Error => DiagnosticSeverity::ERROR,
+                        analysis::Severity::Warning => DiagnosticSeverity::WARNING,
+                    }),
+                    source: Some("robot_dsl".to_string()),
+                    message: diagnostic.message,
+                    ..Default::default()
+                })
+                .collect(),
+            None => Vec::new(),
+        };
+        Notification::new(
+            PublishDiagnostics::METHOD.to_string(),
+            PublishDiagnosticsParams {
+                uri,
+                diagnostics,
+                version: None,
+            },
+        )
+    }
+
+    fn handle_request(&self, request: Request) -> Response {
+        let id = request.id.clone();
+        let result = match request.method.as_str() {
+            Completion::METHOD => self.completion(request),
+            HoverRequest::METHOD => self.hover(request),
+            GotoDefinition::METHOD => self.definition(request),
+            DocumentSymbolRequest::METHOD => self.symbols(request),
+            _ => {
+                return Response::new_err(
+                    id,
+                    lsp_server::ErrorCode::MethodNotFound as i32,
+                    format!("unsupported request {}", request.method),
+                )
+            }
+        };
+        match result {
+            Ok(value) => Response::new_ok(id, value),
+            Err(e) => invalid_params(id, e),
+        }
+    }
+
+    fn completion(&self, request: Request) -> Result<serde_json::Value, serde_json::Error> {
+        let params: lsp_types::CompletionParams = serde_json::from_value(request.params)?;
+        let uri = params.text_document_position.text_document.uri;
+        let mut items = Vec::new();
+        if let Some(document) = self.documents.get(&uri) {
+            let (keywords, variables) = document.completions();
+            items.extend(keywords.into_iter().map(|keyword| CompletionItem {
+                label: keyword.to_string(),
+                kind: Some(CompletionItemKind::KEYWORD),
+                ..Default::default()
+            }));
+            items.extend(Builtin::ALL.iter().map(|builtin| CompletionItem {
+                label: builtin.name().to_string(),
+                kind: Some(CompletionItemKind::FUNCTION),
+                ..Default::default()
+            }));
+            items.extend(variables.into_iter().map(|variable| CompletionItem {
+                label: variable,
+                kind: Some(CompletionItemKind::VARIABLE),
+                ..Default::default()
+            }));
+        }
+        serde_json::to_value(CompletionResponse::Array(items))
+    }
+
+    fn hover(&self, request: Request) -> Result<serde_json::Value, serde_json::Error> {
+        let params: lsp_types::HoverParams = serde_json::from_value(request.params)?;
+        let position = params.text_document_position_params;
+        let hover = self
+            .documents
+            .get(&position.text_document.uri)
+            .and_then(|document| {
+                let offset = offset(document.source(), position.position);
+                document.hover(offset)
+            })
+            .map(|line| Hover {
+                contents: HoverContents::Markup(MarkupContent {
+                    kind: MarkupKind::Markdown,
+                    value: format!("```robot_dsl\n{}\n```", line),
+                }),
+                range: None,
+            });
+        serde_json::to_value(hover)
+    }
+
+    fn definition(&self, request: Request) -> Result<serde_json::Value, serde_json::Error> {
+        let params: lsp_types::GotoDefinitionParams = serde_json::from_value(request.params)?;
+        let position = params.text_document_position_params;
+        let uri = position.text_document.uri;
+        let location = self.documents.get(&uri).and_then(|document| {
+            let offset = offset(document.source(), position.position);
+            document.definition(offset).map(|decl| {
+                GotoDefinitionResponse::Scalar(Location {
+                    uri: uri.clone(),
+                    range: range(document.source(), decl.start, decl.end),
+                })
+            })
+        });
+        serde_json::to_value(location)
+    }
+
+    fn symbols(&self, request: Request) -> Result<serde_json::Value, serde_json::Error> {
+        let params: lsp_types::DocumentSymbolParams = serde_json::from_value(request.params)?;
+        let symbols = match self.documents.get(&params.text_document.uri) {
+            Some(document) => {
+                let mut symbols: Vec<DocumentSymbol> = document
+                    .declarations()
+                    .into_iter()
+                    .filter(|decl| decl.kind == analysis::DeclarationKind::Global)
+                    .map(|decl| {
+                        let range = range(document.source(), decl.start, decl.end);
+                        new_symbol(decl.name, SymbolKind::VARIABLE, range, range, None)
+                    })
+                    .collect();
+                symbols.extend(
+                    document
+                        .symbols()
+                        .iter()
+                        .map(|symbol| block_symbol(document.source(), symbol)),
+                );
+                symbols.sort_by_key(|symbol| symbol.range.start);
+                symbols
+            }
+            None => Vec::new(),
+        };
+        serde_json::to_value(DocumentSymbolResponse::Nested(symbols))
+    }
+}
+
+fn invalid_params(id: RequestId, e: serde_json::Error) -> Response {
+    Response::new_err(
+        id,
+        lsp_server::ErrorCode::InvalidParams as i32,
+        e.to_string(),
+    )
+}
+
+fn block_symbol(source: &str, symbol: &BlockSymbol) -> DocumentSymbol {
+    let children = symbol
+        .children
+        .iter()
+        .map(|child| block_symbol(source, child))
+        .collect();
+    new_symbol(
+        symbol.name.clone(),
+        SymbolKind::NAMESPACE,
+        range(source, symbol.start, symbol.end),
+        range(source, symbol.name_start, symbol.name_end),
+        Some(children),
+    )
+}
+
+#[allow(deprecated)]
+fn new_symbol(
+    name: String,
+    kind: SymbolKind,
+    range: Range,
+    selection_range: Range,
+    children: Option<Vec<DocumentSymbol>>,
+) -> DocumentSymbol {
+    DocumentSymbol {
+        name,
+        detail: None,
+        kind,
+        tags: None,
+        deprecated: None,
+        range,
+        selection_range,
+        children,
+    }
+}
+
+/* 字节偏移量转换为LSP位置，列号以UTF-16编码单元计 */
+pub fn position(source: &str, offset: usize) -> Position {
+    let before = &source[..offset];
+    let line_start = before.rfind('\n').map_or(0, |pos| pos + 1);
+    Position {
+        line: before.matches('\n').count() as u32,
+        character: before[line_start..].encode_utf16().count() as u32,
+    }
+}
+
+/* LSP位置转换为字节偏移量，超出范围时取行尾或文件末尾 */
+pub fn offset(source: &str, position: Position) -> usize {
+    let mut line_start = 0;
+    for _ in 0..position.line {
+        match source[line_start..].find('\n') {
+            Some(len) => line_start += len + 1,
+            None => return source.len(),
+        }
+    }
+    let mut units = 0;
+    for (index, ch) in source[line_start..].char_indices() {
+        if units >= position.character as usize || ch == '\n' {
+            return line_start + index;
+        }
+        units += ch.len_utf16();
+    }
+    source.len()
+}
+
+fn range(source: &str, start: usize, end: usize) -> Range {
+    Range {
+        start: position(source, start),
+        end: position(source, end),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lsp_types::notification::Exit;
+    use lsp_types::request::Shutdown;
+    use lsp_types::{
+        DidChangeTextDocumentParams, DidOpenTextDocumentParams, TextDocumentContentChangeEvent,
+        TextDocumentIdentifier, TextDocumentItem, TextDocumentPositionParams,
+        VersionedTextDocumentIdentifier,
+    };
+    use std::thread;
+
+    const SOURCE: &str = "global name = \"Tom\";\nloop {\n    speak name;\n}\n";
+
+    /* 通过内存中的连接驱动服务器，模拟编辑器一侧 */
+    struct Client {
+        connection: Connection,
+        server: thread::JoinHandle<()>,
+        next_id: i32,
+    }
+
+    impl Client {
+        fn start() -> Self {
+            let (server, connection) = Connection::memory();
+            let server = thread::spawn(move || Server::default().main_loop(&server).unwrap());
+            Self {
+                connection,
+                server,
+                next_id: 0,
+            }
+        }
+
+        fn uri() -> Url {
+            Url::parse("file:///tmp/test.dsl").unwrap()
+        }
+
+        fn notify<N: lsp_types::notification::Notification>(&self, params: N::Params) {
+            let notification = Notification::new(N::METHOD.to_string(), params);
+            self.connection
+                .sender
+                .send(Message::Notification(notification))
+                .unwrap();
+        }
+
+        /* 等待服务器发布的诊断信息 */
+        fn published(&self) -> PublishDiagnosticsParams {
+            match self.connection.receiver.recv().unwrap() {
+                Message::Notification(notification) => {
+                    assert_eq!(notification.method, PublishDiagnostics::METHOD);
+                    serde_json::from_value(notification.params).unwrap()
+                }
+                message => panic!("unexpected message {:?}", message),
+            }
+        }
+
+        fn open(&self, text: &str) -> PublishDiagnosticsParams {
+            self.notify::<DidOpenTextDocument>(DidOpenTextDocumentParams {
+                text_document: TextDocumentItem::new(
+                    Self::uri(),
+                    "robot_dsl".to_string(),
+                    1,
+                    text.to_string(),
+                ),
+            });
+            self.published()
+        }
+
+        fn send(&mut self, method: &str, params: serde_json::Value) -> Response {
+            self.next_id += 1;
+            let request = Request::new(self.next_id.into(), method.to_string(), params);
+            self.connection
+                .sender
+                .send(Message::Request(request))
+                .unwrap();
+            match self.connection.receiver.recv().unwrap() {
+                Message::Response(response) => {
+                    assert_eq!(response.id, self.next_id.into());
+                    response
+                }
+                message => panic!("unexpected message {:?}", message),
+            }
+        }
+
+        fn request<R: lsp_types::request::Request>(&mut self, params: R::Params) -> R::Result {
+            let response = self.send(R::METHOD, serde_json::to_value(params).unwrap());
+            assert!(response.error.is_none(), "{:?}", response.error);
+            serde_json::from_value(response.result.unwrap()).unwrap()
+        }
+
+        fn at(line: u32, character: u32) -> TextDocumentPositionParams {
+            TextDocumentPositionParams::new(
+                TextDocumentIdentifier::new(Self::uri()),
+                Position::new(line, character),
+            )
+        }
+
+        /* 按协议先发送shutdown请求，再发送exit通知，服务器随之退出 */
+        fn shutdown(mut self) {
+            self.request::<Shutdown>(());
+            self.notify::<Exit>(());
+            self.server.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_diagnostics() {
+        let client = Client::start();
+        let published = client.open("speak name;");
+        assert_eq!(published.uri, Client::uri());
+        assert_eq!(published.diagnostics.len(), 1);
+        let diagnostic = &published.diagnostics[0];
+        assert_eq!(diagnostic.severity, Some(DiagnosticSeverity::WARNING));
+        assert_eq!(diagnostic.message, "Variable 'name' is never declared.");
+        assert_eq!(
+            diagnostic.range,
+            Range::new(Position::new(0, 6), Position::new(0, 10))
+        );
+
+        // 修改后重新发布，声明变量后警告消失
+        client.notify::<DidChangeTextDocument>(DidChangeTextDocumentParams {
+            text_document: VersionedTextDocumentIdentifier::new(Client::uri(), 2),
+            content_changes: vec![TextDocumentContentChangeEvent {
+                range: None,
+                range_length: None,
+                text: SOURCE.to_string(),
+            }],
+        });
+        assert_eq!(client.published().diagnostics, vec![]);
+
+        client.notify::<DidChangeTextDocument>(DidChangeTextDocumentParams {
+            text_document: VersionedTextDocumentIdentifier::new(Client::uri(), 3),
+            content_changes: vec![TextDocumentContentChangeEvent {
+                range: None,
+                range_length: None,
+                text: "speak \"a\"".to_string(),
+            }],
+        });
+        let diagnostics = client.published().diagnostics;
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].severity, Some(DiagnosticSeverity::ERROR));
+        client.shutdown();
+    }
+
+    #[test]
+    fn test_completion() {
+        let mut client = Client::start();
+        client.open(SOURCE);
+        let items = match client.request::<Completion>(lsp_types::CompletionParams {
+            text_document_position: Client::at(2, 10),
+            work_done_progress_params: Default::default(),
+            partial_result_params: Default::default(),
+            context: None,
+        }) {
+            Some(CompletionResponse::Array(items)) => items,
+            response => panic!("unexpected completion {:?}", response),
+        };
+        let kind = |label: &str| {
+            items
+                .iter()
+                .find(|item| item.label == label)
+                .and_then(|item| item.kind)
+        };
+        assert_eq!(kind("speak"), Some(CompletionItemKind::KEYWORD));
+        assert_eq!(
+            kind(Builtin::ALL[0].name()),
+            Some(CompletionItemKind::FUNCTION)
+        );
+        assert_eq!(kind("name"), Some(CompletionItemKind::VARIABLE));
+        client.shutdown();
+    }
+
+    #[test]
+    fn test_hover_and_definition() {
+        let mut client = Client::start();
+        client.open(SOURCE);
+        let hover = client
+            .request::<HoverRequest>(lsp_types::HoverParams {
+                text_document_position_params: Client::at(2, 12),
+                work_done_progress_params: Default::default(),
+            })
+            .unwrap();
+        assert_eq!(
+            hover.contents,
+            HoverContents::Markup(MarkupContent {
+                kind: MarkupKind::Markdown,
+                value: "```robot_dsl\nglobal name = \"Tom\";\n```".to_string(),
+            })
+        );
+
+        let definition = client.request::<GotoDefinition>(lsp_types::GotoDefinitionParams {
+            text_document_position_params: Client::at(2, 12),
+            work_done_progress_params: Default::default(),
+            partial_result_params: Default::default(),
+        });
+        assert_eq!(
+            definition,
+            Some(GotoDefinitionResponse::Scalar(Location::new(
+                Client::uri(),
+                Range::new(Position::new(0, 7), Position::new(0, 11)),
+            )))
+        );
+
+        // 不在变量上时没有结果
+        let hover = client.request::<HoverRequest>(lsp_types::HoverParams {
+            text_document_position_params: Client::at(1, 1),
+            work_done_progress_params: Default::default(),
+        });
+        assert_eq!(hover, None);
+        client.shutdown();
+    }
+
+    #[test]
+    fn test_symbols() {
+        let mut client = Client::start();
+        client.open(SOURCE);
+        let symbols =
+            match client.request::<DocumentSymbolRequest>(lsp_types::DocumentSymbolParams {
+                text_document: TextDocumentIdentifier::new(Client::uri()),
+                work_done_progress_params: Default::default(),
+                partial_result_params: Default::default(),
+            }) {
+                Some(DocumentSymbolResponse::Nested(symbols)) => symbols,
+                response => panic!("unexpected symbols {:?}", response),
+            };
+        let outline: Vec<(&str, SymbolKind, Range)> = symbols
+            .iter()
+            .map(|symbol| (symbol.name.as_str(), symbol.kind, symbol.range))
+            .collect();
+        assert_eq!(
+            outline,
+            vec![
+                (
+                    "name",
+                    SymbolKind::VARIABLE,
+                    Range::new(Position::new(0, 7), Position::new(0, 11))
+                ),
+                (
+                    "loop",
+                    SymbolKind::NAMESPACE,
+                    Range::new(Position::new(1, 0), Position::new(3, 1))
+                ),
+            ]
+        );
+        assert_eq!(symbols[1].children, Some(vec![]));
+        client.shutdown();
+    }
+
+    #[test]
+    fn test_invalid_requests() {
+        let mut client = Client::start();
+        let response = client.send("textDocument/rename", serde_json::Value::Null);
+        assert_eq!(
+            response.error.unwrap().code,
+            lsp_server::ErrorCode::MethodNotFound as i32
+        );
+        let response = client.send(HoverRequest::METHOD, serde_json::json!({}));
+        assert_eq!(
+            response.error.unwrap().code,
+            lsp_server::ErrorCode::InvalidParams as i32
+        );
+        // 未打开的文档没有补全项
+        let items = client.request::<Completion>(lsp_types::CompletionParams {
+            text_document_position: Client::at(0, 0),
+            work_done_progress_params: Default::default(),
+            partial_result_params: Default::default(),
+            context: None,
+        });
+        assert_eq!(items, Some(CompletionResponse::Array(vec![])));
+        client.shutdown();
+    }
+
+    #[test]
+    fn test_position_conversion() {
+        let source = "speak \"你好\";\nglobal 𝑥 = 1;";
+        let offset_of_semicolon = source.find(';').unwrap();
+        let pos = position(source, offset_of_semicolon);
+        assert_eq!(pos, Position::new(0, 10));
+        assert_eq!(offset(source, pos), offset_of_semicolon);
+
+        let pos = position(source, source.find('=').unwrap());
+        // 𝑥 在UTF-16中占两个编码单元
+        assert_eq!(pos, Position::new(1, 10));
+        assert_eq!(offset(source, pos), source.find('=').unwrap());
+    }
+}
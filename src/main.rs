@@ -1,51 +1,356 @@
-use lalrpop_util::lalrpop_mod;
-use robot_dsl::interpreter::Interpreter;
-use robot_dsl::lexer::Lexer;
-lalrpop_mod!(pub grammar);
+use robot_dsl::analysis::Document;
+use robot_dsl::ast;
+use robot_dsl::catalog::Catalog;
+use robot_dsl::formatter;
+use robot_dsl::graph::DialogGraph;
+use robot_dsl::bytecode;
+use robot_dsl::clock::{Clock, FixedClock, SystemClock};
+use robot_dsl::interpreter::{Interpreter, StdIo};
+use robot_dsl::vm::Vm;
+use robot_dsl::module;
+use robot_dsl::serialize;
+use robot_dsl::store::{self, MemoryStore, Storage};
+use robot_dsl::server;
+use robot_dsl::http;
+use robot_dsl::protocol;
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+const USAGE: &str = "用法:
+    robot_dsl [--vm] [--protocol text|jsonl] [--now 时间] [--seed 种子] [--store 文件] [--user 用户]
+              [--locales 目录] [--locale 语言] [脚本路径]
+                                                      运行脚本(.dsl或.json)，默认为 examples/bank.dsl
+                                                      --vm 使用字节码虚拟机执行
+                                                      --protocol jsonl 以JSON Lines与父进程交互
+                                                      --now 固定now()返回的时间，例如 \"2024-03-05 09:00\"
+                                                      --seed 固定随机数种子，speak one of 与 random() 的结果可以复现
+                                                      --store store中的值保存到文件(.json或.db)，下次运行时仍然保留
+                                                      --user store中的值按该用户保存，默认为default
+                                                      --locales @消息使用的目录，每种语言一个.toml文件
+                                                      --locale 会话开始时的语言，默认为目录中按名字排序的第一种
+    robot_dsl graph [--format dot|mermaid] <脚本路径>   导出对话流程图
+    robot_dsl fmt [--check] <脚本路径>...              格式化脚本，--check 时只检查不写回
+    robot_dsl compile <脚本路径>                        将语法树输出为JSON
+    robot_dsl serve [--http] [--port 端口] [--store 文件] [--user 用户] [--trusted]
+                    [--locales 目录] [--locale 语言] <脚本路径>
+                                                      在本地端口上提供多会话聊天服务，默认端口7878
+                                                      --http 提供HTTP JSON接口而不是按行收发的TCP服务
+                                                      --trusted 由客户端指定store的用户：TCP连接发送的第一行为用户id，
+                                                                HTTP创建会话时的\"user\"字段，只应当允许可信的后端服务连接
+                                                      --store --user --locales --locale 同 robot_dsl
+    robot_dsl i18n <消息目录> [脚本路径]...              检查各语言中缺少的消息，包括脚本中用到的消息
+    robot_dsl lsp                                     启动Language Server(stdio)";
 
 //#[cfg(not(test))]
-fn main() -> Result<(), Box<dyn std::error::Error>>{
-    let source_code = std::fs::read_to_string("examples/bank.dsl")?;
-    let lexer = Lexer::new(&source_code[..]);
-    // // 打印 token 流
-    // for token in lexer {
-    //     println!("{:?}", token);
-    // }
-    let parser = grammar::ProgramParser::new();
-    let ast = parser.parse(lexer)?;
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        Some("graph") => graph(&args[1..]),
+        Some("fmt") => fmt(&args[1..]),
+        Some("compile") => compile(&args[1..]),
+        Some("serve") => serve(&args[1..]),
+        Some("i18n") => i18n(&args[1..]),
+        Some("lsp") => robot_dsl::lsp::run_stdio().map_err(|e| e as Box<dyn std::error::Error>),
+        Some("-h") | Some("--help") => {
+            println!("{}", USAGE);
+            Ok(())
+        }
+        _ => run(&args),
+    }
+}
+
+/* 读取并解析脚本文件，同时加载其中导入的模块 */
+fn parse_file(path: &str) -> Result<ast::Program, Box<dyn std::error::Error>> {
+    Ok(module::load(Path::new(path))?)
+}
+
+/* 打开--store指定的存储文件，未指定时使用进程内的存储 */
+fn open_store(path: Option<&str>) -> Result<Arc<dyn Storage>, Box<dyn std::error::Error>> {
+    match path {
+        Some(path) => Ok(store::open(Path::new(path)).map_err(|e| format!("{}: {}", path, e))?),
+        None => Ok(Arc::new(MemoryStore::new())),
+    }
+}
+
+/* 读取--locales指定的消息目录，--locale指定默认语言 */
+fn open_catalog(
+    directory: Option<&str>,
+    locale: Option<&str>,
+) -> Result<Arc<Catalog>, Box<dyn std::error::Error>> {
+    let mut catalog = match directory {
+        Some(directory) => Catalog::load_dir(Path::new(directory))?,
+        None => Catalog::default(),
+    };
+    if let Some(locale) = locale {
+        catalog.check_locale(locale)?;
+        catalog = catalog.with_default(locale);
+    }
+    Ok(Arc::new(catalog))
+}
+
+/* 加载程序，.json文件视为序列化后的语法树，其余视为脚本源码 */
+fn load_program(path: &str) -> Result<ast::Program, Box<dyn std::error::Error>> {
+    Ok(load_sources(path)?.0)
+}
+
+/* 语法树，以及每条顶层语句所在的文件 */
+type Sources = (ast::Program, Vec<PathBuf>);
+
+/* 加载程序，同时给出每条顶层语句所在的文件 */
+fn load_sources(path: &str) -> Result<Sources, Box<dyn std::error::Error>> {
+    if path.ends_with(".json") {
+        let json = std::fs::read_to_string(path)?;
+        let ast = serialize::from_json(&json).map_err(|e| format!("{}: {}", path, e))?;
+        let sources = vec![PathBuf::from(path); ast.len()];
+        Ok((ast, sources))
+    } else {
+        Ok(module::load_sources(Path::new(path))?)
+    }
+}
+
+fn run(args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let mut use_vm = false;
+    let mut protocol = "text";
+    let mut now = None;
+    let mut seed = None;
+    let mut store = None;
+    let mut user = store::DEFAULT_USER;
+    let mut locales = None;
+    let mut locale = None;
+    let mut path = "examples/bank.dsl";
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--vm" => use_vm = true,
+            "--protocol" => protocol = iter.next().map(String::as_str).unwrap_or(""),
+            "--now" => now = Some(iter.next().map(String::as_str).unwrap_or("")),
+            "--seed" => seed = Some(iter.next().map(String::as_str).unwrap_or("")),
+            "--store" => store = Some(iter.next().map(String::as_str).unwrap_or("")),
+            "--user" => user = iter.next().map(String::as_str).unwrap_or(user),
+            "--locales" => locales = Some(iter.next().map(String::as_str).unwrap_or("")),
+            "--locale" => locale = Some(iter.next().map(String::as_str).unwrap_or("")),
+            _ => path = arg.as_str(),
+        }
+    }
+    let clock: Arc<dyn Clock> = match now {
+        Some(now) => Arc::new(FixedClock::parse(now).ok_or(format!("无效的时间: {}", now))?),
+        None => Arc::new(SystemClock),
+    };
+    let seed = match seed {
+        Some(seed) => Some(
+            seed.parse::<u64>()
+                .map_err(|_| format!("无效的随机数种子: {}", seed))?,
+        ),
+        None => None,
+    };
+    let store = open_store(store)?;
+    let catalog = open_catalog(locales, locale)?;
+    let interpreter = |ast| {
+        let mut interpreter = Interpreter::new(ast)
+            .with_clock(clock.clone())
+            .with_store(store.clone())
+            .with_user(user)
+            .with_catalog(catalog.clone());
+        interpreter.seed = seed;
+        interpreter
+    };
+    let (ast, sources) = load_sources(path)?;
+    let jsonl = match protocol {
+        "text" => false,
+        "jsonl" => true,
+        _ => return Err(format!("未知的协议: {}\n{}", protocol, USAGE).into()),
+    };
+    if use_vm {
+        let chunk = bytecode::compile(&ast).map_err(|e| format!("{}: {}", path, e))?;
+        let mut vm = Vm::new(&chunk)
+            .with_clock(clock)
+            .with_store(store, user)
+            .with_catalog(catalog);
+        if let Some(seed) = seed {
+            vm = vm.with_seed(seed);
+        }
+        if jsonl {
+            let stdin = std::io::stdin().lock();
+            protocol::run_jsonl_vm(&mut vm, stdin, std::io::stdout().lock())?;
+            return Ok(());
+        }
+        // 报告出错的指令来自哪个文件
+        vm.run(&mut StdIo).map_err(|e| match chunk.origin(e.pc) {
+            Some(index) => format!("{}: {}", sources[index].display(), e),
+            None => e.to_string(),
+        })?;
+        return Ok(());
+    }
+    if jsonl {
+        let stdin = std::io::stdin().lock();
+        protocol::run_jsonl(&interpreter(ast), stdin, std::io::stdout().lock())?;
+        return Ok(());
+    }
     // for statement in ast {
     //     let statement_ref: &ast::Statement = statement.as_ref();
     //     println!("{:?}",statement_ref);
 
     // }
-    let mut interpreter= Interpreter::new(ast);
-    interpreter.interpret();
+    interpreter(ast).interpret();
+    Ok(())
+}
+
+/* graph子命令：将脚本的对话流程导出为DOT或Mermaid */
+fn graph(args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let mut format = "dot";
+    let mut path = None;
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--format" => format = iter.next().map(String::as_str).unwrap_or(""),
+            _ => path = Some(arg.as_str()),
+        }
+    }
+    let path = path.ok_or(USAGE)?;
+    let graph = DialogGraph::build(&load_program(path)?);
+    match format {
+        "dot" => print!("{}", graph.to_dot()),
+        "mermaid" => print!("{}", graph.to_mermaid()),
+        _ => return Err(format!("未知的图格式: {}\n{}", format, USAGE).into()),
+    }
+    Ok(())
+}
+
+/* fmt子命令：格式化脚本，--check模式下遇到未格式化的文件时返回错误 */
+fn fmt(args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let check = args.iter().any(|arg| arg == "--check");
+    let paths: Vec<&String> = args.iter().filter(|arg| *arg != "--check").collect();
+    if paths.is_empty() {
+        return Err(USAGE.into());
+    }
+    let mut unformatted = Vec::new();
+    for path in paths {
+        let source_code = std::fs::read_to_string(path)?;
+        let formatted =
+            formatter::format_source(&source_code).map_err(|e| format!("{}: {}", path, e))?;
+        if formatted == source_code {
+            continue;
+        }
+        if check {
+            println!("需要格式化: {}", path);
+            unformatted.push(path.as_str());
+        } else {
+            std::fs::write(path, formatted)?;
+        }
+    }
+    if !unformatted.is_empty() {
+        return Err(format!("{} 个文件未格式化", unformatted.len()).into());
+    }
+    Ok(())
+}
+
+/* compile子命令：解析脚本并输出JSON格式的语法树 */
+fn compile(args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let path = args.first().ok_or(USAGE)?;
+    println!("{}", serialize::to_json(&parse_file(path)?));
+    Ok(())
+}
+
+/* serve子命令：每个TCP连接或HTTP会话对应一个独立的对话 */
+fn serve(args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let mut port = "7878";
+    let mut use_http = false;
+    let mut store = None;
+    let mut user = store::DEFAULT_USER;
+    let mut trusted = false;
+    let mut locales = None;
+    let mut locale = None;
+    let mut path = None;
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--port" => port = iter.next().map(String::as_str).unwrap_or(""),
+            "--http" => use_http = true,
+            "--store" => store = Some(iter.next().map(String::as_str).unwrap_or("")),
+            "--user" => user = iter.next().map(String::as_str).unwrap_or(user),
+            "--trusted" => trusted = true,
+            "--locales" => locales = Some(iter.next().map(String::as_str).unwrap_or("")),
+            "--locale" => locale = Some(iter.next().map(String::as_str).unwrap_or("")),
+            _ => path = Some(arg.as_str()),
+        }
+    }
+    let path = path.ok_or(USAGE)?;
+    let port: u16 = port.parse().map_err(|_| format!("无效的端口: {}", port))?;
+    let interpreter = Interpreter::new(load_program(path)?)
+        .with_store(open_store(store)?)
+        .with_user(user)
+        .with_catalog(open_catalog(locales, locale)?);
+    let interpreter = Arc::new(interpreter);
+    if use_http {
+        let server = tiny_http::Server::http(("127.0.0.1", port)).map_err(|e| e.to_string())?;
+        eprintln!("正在监听 http://{}", server.server_addr());
+        http::serve(&http::Api::new(interpreter).with_trusted(trusted), &server);
+        return Ok(());
+    }
+    let listener = TcpListener::bind(("127.0.0.1", port))?;
+    eprintln!("正在监听 {}", listener.local_addr()?);
+    server::serve(interpreter, listener, trusted)?;
     Ok(())
 }
 
+/*
+ * i18n子命令：列出各语言中缺少的消息
+ * 脚本中用到的消息按出现的位置报告，其余的只在某些语言中定义的消息按语言文件报告
+ */
+fn i18n(args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let directory = args.first().ok_or(USAGE)?;
+    let catalog = Catalog::load_dir(Path::new(directory))?;
+    let mut used = std::collections::HashSet::new();
+    let mut count = 0;
+    for path in &args[1..] {
+        let source_code = std::fs::read_to_string(path)?;
+        for (start, key, _) in Document::new(&source_code).message_keys() {
+            let before = &source_code[..start];
+            let line = before.matches('\n').count() + 1;
+            let line_start = before.rfind('\n').map_or(0, |pos| pos + 1);
+            let column = before[line_start..].chars().count() + 1;
+            for missing in catalog.missing_keys([key.as_str()]) {
+                println!(
+                    "{}:{}:{}: Message '{}' is missing in locale '{}'",
+                    path, line, column, missing.key, missing.locale
+                );
+                count += 1;
+            }
+            used.insert(key);
+        }
+    }
+    for missing in catalog.missing() {
+        if !used.contains(&missing.key) {
+            let file = Path::new(directory).join(format!("{}.toml", missing.locale));
+            println!("{}: Message '{}' is missing", file.display(), missing.key);
+            count += 1;
+        }
+    }
+    if count > 0 {
+        return Err(format!("{} 条消息缺少翻译", count).into());
+    }
+    Ok(())
+}
 
 /*
  * 由于使用了lalrpop生成parser，因此不独立出文件对parser进行单元测试
- * 测试内容位于main.rs之下
+ * 测试内容位于main.rs之下，parser本身位于robot_dsl::parser
  */
 
 
 #[cfg(test)]
 mod tests {
 
-    use robot_dsl::{ast, lexer::Lexer, lexer::LexicalError,tokens::Token};
-    use lalrpop_util::{lalrpop_mod, ParseError};
-    lalrpop_mod!(pub grammar);
+    use robot_dsl::{ast, lexer::Lexer, lexer::LexicalError, parser::grammar, tokens::Token};
+    use lalrpop_util::ParseError;
 
     pub fn parse_program(
         input: &str,
-    ) -> Result<Vec<Box<ast::Statement>>, ParseError<usize, Token, LexicalError>> {
+    ) -> Result<ast::Program, ParseError<usize, Token, LexicalError>> {
         let lexer = Lexer::new(input);
         let parser = grammar::ProgramParser::new();
-        match parser.parse(lexer) {
-            Ok(ast) => Ok(ast),
-            Err(e) => Err(e),
-        }
+        parser.parse(lexer)
     }
 
     #[test]
@@ -58,7 +363,7 @@ mod tests {
             let expected_ast = vec![Box::new(ast::Statement::Var {
                 name: String::from("my_var"),
                 init: Box::new(ast::Expr::Literal {
-                    value: ast::LiteralValue::Number(42.0),
+                    value: ast::LiteralValue::Number(42.into()),
                 }),
             })];
             assert_eq!(ast, expected_ast);
@@ -127,7 +432,7 @@ mod tests {
                     }),
                     operator: Token::WEqual,
                     right: Box::new(ast::Expr::Literal {
-                        value: ast::LiteralValue::Number(42.0),
+                        value: ast::LiteralValue::Number(42.into()),
                     }),
                 }),
                 then: Box::new(ast::Statement::Block {
@@ -159,7 +464,7 @@ mod tests {
                         }),
                         operator: Token::OperatorAdd,
                         right: Box::new(ast::Expr::Literal {
-                            value: ast::LiteralValue::Number(3.0),
+                            value: ast::LiteralValue::Number(3.into()),
                         }),
                     }),
                     operator: Token::OperatorMul,
@@ -169,7 +474,7 @@ mod tests {
                         }),
                         operator: Token::OperatorSub,
                         right: Box::new(ast::Expr::Literal {
-                            value: ast::LiteralValue::Number(5.0),
+                            value: ast::LiteralValue::Number(5.into()),
                         }),
                     }),
                 }),
@@ -190,7 +495,7 @@ mod tests {
                 body: Box::new(ast::Statement::Block {
                     statements: vec![Box::new(ast::Statement::Speak {
                         expression: Box::new(ast::Expr::Literal {
-                            value: ast::LiteralValue::Number(42.0),
+                            value: ast::LiteralValue::Number(42.into()),
                         }),
                     })],
                 }),
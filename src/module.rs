@@ -0,0 +1,644 @@
+use crate::ast::{Expr, Regex, Statement};
+use crate::parser::{self, parse_program};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/*
+ * 模块加载器
+ * import "common/greetings.dsl"; 导入另一个脚本，路径相对于写有import的文件。
+ * 加载时把导入语句原地替换为模块的顶层语句，得到一个不含import的完整程序，
+ * 解释器、虚拟机与其他工具都不需要知道模块的存在：
+ * - 模块中的顶层语句在导入处依次执行，例如声明全局变量或者输出欢迎语
+ * - 模块中声明的全局变量与对话状态改名为 模块名.名字，导入者写作 greetings.shop、
+ *   goto greetings.welcome，不同模块中的同名变量与状态互不冲突；
+ *   模块名缺省为文件名，也可以用 import "..." as name; 指定
+ * - 同一个文件只加载一次，以同一个模块名再次导入时忽略，以另一个模块名导入时报错；
+ *   相互导入的文件报告为循环导入
+ * 出错时报告出错的文件以及行号与列号；
+ * load_sources同时给出每条顶层语句来自的文件，运行时出错时据此报告出错的模块。
+ */
+
+#[derive(Debug)]
+pub enum Error {
+    Io {
+        path: PathBuf,
+        error: io::Error,
+    },
+    /* 某个文件中的语法错误，行号与列号从1开始 */
+    Parse {
+        path: PathBuf,
+        line: usize,
+        column: usize,
+        message: String,
+    },
+    /* 导入链，最后一项为再次出现的文件 */
+    Cycle(Vec<PathBuf>),
+    /* 两个不同的文件使用了同一个模块名 */
+    Namespace {
+        name: String,
+        first: PathBuf,
+        second: PathBuf,
+    },
+    /* 同一个文件以两个不同的模块名导入 */
+    Alias {
+        path: PathBuf,
+        first: String,
+        second: String,
+    },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io { path, error } => write!(f, "{}: {}", path.display(), error),
+            Error::Parse {
+                path,
+                line,
+                column,
+                message,
+            } => write!(f, "{}:{}:{}: {}", path.display(), line, column, message),
+            Error::Cycle(chain) => {
+                let chain: Vec<String> = chain
+                    .iter()
+                    .map(|path| path.display().to_string())
+                    .collect();
+                write!(f, "Circular import: {}", chain.join(" -> "))
+            }
+            Error::Namespace {
+                name,
+                first,
+                second,
+            } => write!(
+                f,
+                "Module name `{}` is used by both {} and {}.",
+                name,
+                first.display(),
+                second.display()
+            ),
+            Error::Alias {
+                path,
+                first,
+                second,
+            } => write!(
+                f,
+                "{} is already imported as `{}` and cannot be imported again as `{}`.",
+                path.display(),
+                first,
+                second
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/* 加载脚本及其导入的所有模块 */
+pub fn load(path: &Path) -> Result<Vec<Box<Statement>>, Error> {
+    load_sources(path).map(|(program, _)| program)
+}
+
+/* 加载脚本，同时返回每条顶层语句所在的文件 */
+pub fn load_sources(path: &Path) -> Result<(Vec<Box<Statement>>, Vec<PathBuf>), Error> {
+    let program = Loader::default().file(path, None)?;
+    Ok(program.into_iter().unzip())
+}
+
+#[derive(Default)]
+struct Loader {
+    /* 正在加载的文件(规范化后的路径，以及报错时显示的路径)，用于发现循环导入 */
+    loading: Vec<(PathBuf, PathBuf)>,
+    /* 已经加载过的文件及其模块名，程序的入口没有模块名 */
+    loaded: HashMap<PathBuf, Option<String>>,
+    /* 模块名对应的文件 */
+    names: HashMap<String, PathBuf>,
+}
+
+impl Loader {
+    /* 加载一个文件，name为None时是程序的入口，其中的名字保持不变 */
+    fn file(
+        &mut self,
+        path: &Path,
+        name: Option<&str>,
+    ) -> Result<Vec<(Box<Statement>, PathBuf)>, Error> {
+        let io_error = |error| Error::Io {
+            path: path.to_path_buf(),
+            error,
+        };
+        let canonical = fs::canonicalize(path).map_err(io_error)?;
+        if let Some(index) = self.loading.iter().position(|(file, _)| *file == canonical) {
+            let mut chain: Vec<PathBuf> = self.loading[index..]
+                .iter()
+                .map(|(_, shown)| shown.clone())
+                .collect();
+            chain.push(path.to_path_buf());
+            return Err(Error::Cycle(chain));
+        }
+        let source = fs::read_to_string(path).map_err(io_error)?;
+        let mut statements = parse_program(&source).map_err(|error| {
+            let (start, _) = parser::span(&source, &error);
+            let before = &source[..start];
+            let line_start = before.rfind('\n').map_or(0, |pos| pos + 1);
+            Error::Parse {
+                path: path.to_path_buf(),
+                line: before.matches('\n').count() + 1,
+                column: before[line_start..].chars().count() + 1,
+                message: error.to_string(),
+            }
+        })?;
+        if let Some(name) = name {
+            rename(&mut statements, name);
+        }
+
+        self.loading.push((canonical.clone(), path.to_path_buf()));
+        self.loaded.insert(canonical, name.map(str::to_string));
+        let directory = path.parent().unwrap_or(Path::new(""));
+        let mut program = Vec::new();
+        for statement in statements {
+            let (target, alias) = match *statement {
+                Statement::Import { path, alias } => (directory.join(path), alias),
+                _ => {
+                    program.push((statement, path.to_path_buf()));
+                    continue;
+                }
+            };
+            let name = alias.unwrap_or_else(|| {
+                let stem = target.file_stem().unwrap_or_default();
+                stem.to_string_lossy().into_owned()
+            });
+            let canonical = fs::canonicalize(&target).map_err(|error| Error::Io {
+                path: target.clone(),
+                error,
+            })?;
+            match self.names.get(&name) {
+                Some(first) if *first != canonical => {
+                    return Err(Error::Namespace {
+                        name,
+                        first: first.clone(),
+                        second: target,
+                    })
+                }
+                _ => {}
+            }
+            // 循环导入时文件仍在加载中，交给file报告
+            if !self.loading.iter().any(|(file, _)| *file == canonical) {
+                match self.loaded.get(&canonical) {
+                    Some(Some(first)) if *first != name => {
+                        return Err(Error::Alias {
+                            path: target,
+                            first: first.clone(),
+                            second: name,
+                        })
+                    }
+                    Some(_) => continue,
+                    None => {}
+                }
+            }
+            self.names.insert(name.clone(), canonical);
+            program.extend(self.file(&target, Some(&name))?);
+        }
+        self.loading.pop();
+        Ok(program)
+    }
+}
+
+/*
+ * 模块中声明的全局变量与对话状态加上模块名作为前缀
+ * 全局变量可以在代码块、循环与对话状态中声明，同样改名；
+ * 同一个名字在模块中的所有出现(包括同名的局部变量)一起改名，不改变模块自身的语义；
+ * 已经带有前缀的名字来自模块再导入的模块，保持不变
+ */
+fn rename(statements: &mut [Box<Statement>], module: &str) {
+    let mut names = HashSet::new();
+    for statement in statements.iter() {
+        declarations(statement, &mut names);
+    }
+    let renamer = Renamer { module, names };
+    for statement in statements {
+        renamer.statement(statement);
+    }
+}
+
+/* 语句中任意一层声明的全局变量与对话状态 */
+fn declarations(statement: &Statement, names: &mut HashSet<String>) {
+    if let Statement::Var { name, .. } | Statement::State { name, .. } = statement {
+        names.insert(name.clone());
+    }
+    for child in statement.children() {
+        declarations(child, names);
+    }
+}
+
+struct Renamer<'a> {
+    module: &'a str,
+    names: HashSet<String>,
+}
+
+impl Renamer<'_> {
+    fn name(&self, name: &mut String) {
+        if self.names.contains(name.as_str()) {
+            *name = format!("{}.{}", self.module, name);
+        }
+    }
+
+    /*
+     * 命名分组匹配成功时写入同名的变量，与全局变量同名的分组同样改名，
+     * 例如 (?P<id>...) 改为 (?P<模块名.id>...)
+     */
+    fn regex(&self, regex: &mut Regex) {
+        let source = regex.as_str();
+        let mut renamed = String::with_capacity(source.len());
+        let mut rest = source;
+        // 转义字符与字符类中的括号不是分组
+        let mut class = false;
+        while let Some(c) = rest.chars().next() {
+            let group = ["(?P<", "(?<"]
+                .into_iter()
+                .find(|open| rest.starts_with(*open));
+            match (c, group) {
+                ('\\', _) => {
+                    let escaped = rest[1..].chars().next().map_or(0, char::len_utf8);
+                    renamed.push_str(&rest[..1 + escaped]);
+                    rest = &rest[1 + escaped..];
+                    continue;
+                }
+                ('[', _) => class = true,
+                (']', _) => class = false,
+                ('(', Some(open)) if !class => {
+                    let end = rest.find('>').unwrap_or(rest.len());
+                    let mut name = rest[open.len()..end].to_string();
+                    self.name(&mut name);
+                    renamed.push_str(open);
+                    renamed.push_str(&name);
+                    rest = &rest[end..];
+                    continue;
+                }
+                _ => {}
+            }
+            renamed.push(c);
+            rest = &rest[c.len_utf8()..];
+        }
+        if renamed != source {
+            *regex = Regex::new(&renamed).expect("分组改名不改变正则的结构");
+        }
+    }
+
+    fn statement(&self, statement: &mut Statement) {
+        match statement {
+            Statement::Block { statements } => {
+                for statement in statements {
+                    self.statement(statement);
+                }
+            }
+            Statement::Expression { expression } | Statement::Speak { expression } => {
+                self.expr(expression)
+            }
+            Statement::SpeakOneOf { options } => self.expr(options),
+            Statement::Branch { condition, then } => {
+                self.expr(condition);
+                self.statement(then);
+            }
+            Statement::Loop { body } => self.statement(body),
+            Statement::Input { input } => self.name(input),
+            Statement::Var { name, init } | Statement::Local { name, init } => {
+                self.name(name);
+                self.expr(init);
+            }
+            Statement::Store { init, .. } => self.expr(init),
+            Statement::Locale { locale } => self.expr(locale),
+            Statement::Transfer { reason } => self.expr(reason),
+            Statement::Exit | Statement::Import { .. } => {}
+            Statement::Match {
+                subject,
+                arms,
+                default,
+                ..
+            } => {
+                if let Some(subject) = subject {
+                    self.expr(subject);
+                }
+                for arm in arms {
+                    self.statement(&mut arm.body);
+                }
+                if let Some(default) = default {
+                    self.statement(default);
+                }
+            }
+            Statement::TypedInput {
+                name,
+                prompt,
+                retry,
+                fallback,
+                ..
+            } => {
+                self.name(name);
+                for expr in [prompt, retry].into_iter().flatten() {
+                    self.expr(expr);
+                }
+                if let Some(fallback) = fallback {
+                    self.statement(fallback);
+                }
+            }
+            Statement::Form { slots, confirm, .. } => {
+                // 用户修改槽位时仍然写原来的名字
+                for slot in slots {
+                    if self.names.contains(&slot.name) {
+                        slot.variable = Some(format!("{}.{}", self.module, slot.name));
+                    }
+                    self.expr(&mut slot.prompt);
+                    if let Some(retry) = &mut slot.retry {
+                        self.expr(retry);
+                    }
+                }
+                if let Some(confirm) = confirm {
+                    self.expr(confirm);
+                }
+            }
+            Statement::State {
+                name,
+                enter,
+                handler,
+            } => {
+                self.name(name);
+                if let Some(enter) = enter {
+                    self.statement(enter);
+                }
+                if let Some(handler) = handler {
+                    self.name(&mut handler.variable);
+                    self.statement(&mut handler.body);
+                }
+            }
+            Statement::Goto { state } => self.name(state),
+            Statement::For {
+                variable,
+                iterable,
+                body,
+            } => {
+                self.name(variable);
+                self.expr(iterable);
+                self.statement(body);
+            }
+        }
+    }
+
+    fn expr(&self, expr: &mut Expr) {
+        match expr {
+            Expr::Assign { name, value } => {
+                self.name(name);
+                self.expr(value);
+            }
+            Expr::Binary { left, right, .. } => {
+                self.expr(left);
+                self.expr(right);
+            }
+            Expr::Literal { .. } | Expr::Store { .. } => {}
+            Expr::Variable { name } => self.name(name),
+            Expr::RegexMatch { subject, regex } => {
+                self.expr(subject);
+                self.regex(regex);
+            }
+            Expr::Call { args: items, .. } | Expr::List { items } => {
+                for item in items {
+                    self.expr(item);
+                }
+            }
+            Expr::Map { entries } => {
+                for (_, value) in entries {
+                    self.expr(value);
+                }
+            }
+            Expr::Index { target, index } => {
+                self.expr(target);
+                self.expr(index);
+            }
+            Expr::StoreAssign { value, .. } => self.expr(value),
+            // 参数名是消息中的占位符，不是变量，不需要改名
+            Expr::Message { args, .. } => {
+                for (_, value) in args {
+                    self.expr(value);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::compile;
+    use crate::interpreter::{Interpreter, ScriptedIo};
+    use crate::vm::Vm;
+
+    /* 在临时目录中写入一组脚本，返回目录 */
+    fn scripts(name: &str, files: &[(&str, &str)]) -> PathBuf {
+        let directory =
+            std::env::temp_dir().join(format!("robot_dsl_{}_{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&directory);
+        for (path, source) in files {
+            let path = directory.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, source).unwrap();
+        }
+        directory
+    }
+
+    fn run(path: &Path, inputs: &[&str]) -> Vec<String> {
+        let mut io = ScriptedIo::new(inputs);
+        Interpreter::new(load(path).unwrap()).interpret_with(&mut io);
+        io.output
+    }
+
+    #[test]
+    fn test_import_namespaces() {
+        let directory = scripts(
+            "namespaces",
+            &[
+                (
+                    "main.dsl",
+                    "import \"common/greetings.dsl\";\nimport \"common/faq.dsl\" as help;\n\
+                     global shop = \"总店\";\nspeak greetings.shop + \"/\" + shop;\n\
+                     input x;\ngoto help.menu;",
+                ),
+                (
+                    "common/greetings.dsl",
+                    "global shop = \"分店\";\nspeak \"欢迎光临\" + shop;",
+                ),
+                (
+                    "common/faq.dsl",
+                    "import \"greetings.dsl\";\nglobal count = 0;\n\
+                     state menu { on enter { speak \"问题:\" + greetings.shop; } \
+                     on input q { count = to_number(count) + 1; speak count; if (q == \"e\") { exit; }; } }",
+                ),
+            ],
+        );
+        let output = run(&directory.join("main.dsl"), &["hi", "a", "e"]);
+        // greetings.dsl被导入两次，只执行一次
+        assert_eq!(
+            output,
+            vec!["欢迎光临分店", "分店/总店", "问题:分店", "1", "2"]
+        );
+        let program = load(&directory.join("main.dsl")).unwrap();
+        assert!(program.iter().any(|statement| matches!(
+            &**statement,
+            Statement::State { name, .. } if name == "help.menu"
+        )));
+        fs::remove_dir_all(directory).unwrap();
+
+        // 代码块与对话状态中声明的全局变量同样改名
+        let directory = scripts(
+            "nested",
+            &[
+                (
+                    "main.dsl",
+                    "import \"counter.dsl\";\nglobal total = \"main\";\n\
+                     speak counter.total + \"/\" + total;\ngoto counter.tick;",
+                ),
+                (
+                    "counter.dsl",
+                    "if (1 == 1) { global total = \"module\"; };\n\
+                     state tick { on enter { global seen = \"yes\"; speak seen + total; } }",
+                ),
+            ],
+        );
+        let output = run(&directory.join("main.dsl"), &[]);
+        assert_eq!(output, vec!["module/main", "yesmodule"]);
+        fs::remove_dir_all(directory).unwrap();
+
+        let output = run(Path::new("examples/shop.dsl"), &["北京", "e"]);
+        assert_eq!(output.last().unwrap(), "感谢您使用示例银行，再见!");
+    }
+
+    /* 分别用解释器与虚拟机执行，两者的输出应当相同 */
+    fn run_both(path: &Path, inputs: &[&str]) -> Vec<String> {
+        let output = run(path, inputs);
+        let chunk = compile(&load(path).unwrap()).unwrap();
+        let mut io = ScriptedIo::new(inputs);
+        Vm::new(&chunk).run(&mut io).unwrap();
+        assert_eq!(io.output, output);
+        output
+    }
+
+    #[test]
+    fn test_import_captures() {
+        let directory = scripts(
+            "captures",
+            &[
+                (
+                    "main.dsl",
+                    "import \"lib.dsl\";\nglobal id = \"main\";\nspeak lib.id + \"/\" + id;",
+                ),
+                (
+                    "lib.dsl",
+                    "global id = \"none\";\n\
+                     if (\"(订单12\" ~ \"[(]订单(?P<id>\\d+)\\(?\") { speak \"id=\" + id; };",
+                ),
+            ],
+        );
+        let output = run_both(&directory.join("main.dsl"), &[]);
+        assert_eq!(output, vec!["id=12", "12/main"]);
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn test_import_slots() {
+        // 槽位写入改名后的变量，用户修改槽位时仍然写原来的名字
+        let directory = scripts(
+            "slots",
+            &[
+                (
+                    "main.dsl",
+                    "import \"lib.dsl\";\nspeak \"main:\" + lib.account;",
+                ),
+                (
+                    "lib.dsl",
+                    "form open { slot account prompt \"账号?\"; slot owner prompt \"户名?\"; }\n\
+                     speak account + \"/\" + owner;\n\
+                     if (account == \"0\") { global account = \"none\"; };",
+                ),
+            ],
+        );
+        let output = run_both(&directory.join("main.dsl"), &["123", "account=456", "张三"]);
+        assert_eq!(
+            output,
+            vec!["账号?", "户名?", "户名?", "456/张三", "main:456"]
+        );
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn test_import_sources() {
+        let directory = scripts(
+            "sources",
+            &[
+                (
+                    "main.dsl",
+                    "speak \"start\";\nimport \"lib.dsl\";\nstate done { on enter { speak \"done\"; } }",
+                ),
+                ("lib.dsl", "global missing = store.missing;\ngoto done;"),
+            ],
+        );
+        let (program, sources) = load_sources(&directory.join("main.dsl")).unwrap();
+        assert_eq!(program.len(), sources.len());
+        let names: Vec<_> = sources
+            .iter()
+            .map(|path| path.file_name().unwrap())
+            .collect();
+        assert_eq!(names, ["main.dsl", "lib.dsl", "lib.dsl", "main.dsl"]);
+        // 运行时错误报告在出错的模块中
+        let chunk = compile(&program).unwrap();
+        let error = Vm::new(&chunk).run(&mut ScriptedIo::default()).unwrap_err();
+        let index = chunk.origin(error.pc).unwrap();
+        assert!(sources[index].ends_with("lib.dsl"));
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn test_import_errors() {
+        let directory = scripts(
+            "errors",
+            &[
+                ("cycle.dsl", "import \"a/b.dsl\";"),
+                ("a/b.dsl", "import \"../cycle.dsl\";"),
+                ("broken.dsl", "speak \"ok\";\nimport \"common/bad.dsl\";"),
+                ("common/bad.dsl", "global x = 1;\nspeak x +;"),
+                ("x/b.dsl", "global y = 1;"),
+                ("y/b.dsl", "global z = 2;"),
+                ("twice.dsl", "import \"x/b.dsl\";\nimport \"y/b.dsl\";"),
+                ("missing.dsl", "import \"nothing.dsl\";"),
+                (
+                    "alias.dsl",
+                    "import \"x/b.dsl\";\nimport \"x/b.dsl\" as other;",
+                ),
+            ],
+        );
+        let error = |name: &str| load(&directory.join(name)).unwrap_err();
+        match error("cycle.dsl") {
+            Error::Cycle(chain) => {
+                let names: Vec<_> = chain.iter().map(|path| path.file_name().unwrap()).collect();
+                assert_eq!(names, ["cycle.dsl", "b.dsl", "cycle.dsl"]);
+            }
+            other => panic!("{}", other),
+        }
+        // 语法错误报告在被导入的文件中
+        match error("broken.dsl") {
+            Error::Parse {
+                path, line, column, ..
+            } => {
+                assert!(path.ends_with("common/bad.dsl"));
+                assert_eq!((line, column), (2, 10));
+            }
+            other => panic!("{}", other),
+        }
+        assert!(matches!(error("twice.dsl"), Error::Namespace { name, .. } if name == "b"));
+        assert!(matches!(
+            error("alias.dsl"),
+            Error::Alias { first, second, .. } if first == "b" && second == "other"
+        ));
+        assert!(
+            matches!(error("missing.dsl"), Error::Io { path, .. } if path.ends_with("nothing.dsl"))
+        );
+        fs::remove_dir_all(directory).unwrap();
+    }
+}
@@ -0,0 +1,60 @@
+use crate::ast::Statement;
+use crate::lexer::{Lexer, LexicalError};
+use crate::tokens::Token;
+use lalrpop_util::{lalrpop_mod, ParseError};
+use logos::Logos;
+
+/*
+ * 由lalrpop根据grammar.lalrpop生成的parser
+ * 放在库中以便formatter等工具复用
+ */
+lalrpop_mod!(#[allow(clippy::all)] pub grammar);
+
+/* 解析错误类型 */
+pub type Error = ParseError<usize, Token, LexicalError>;
+
+/* 将源码解析为语法树 */
+pub fn parse_program(input: &str) -> Result<Vec<Box<Statement>>, Error> {
+    grammar::ProgramParser::new().parse(Lexer::new(input))
+}
+
+/*
+ * 跟在名字或其他关键字之后、后面是固定种类token的词，例如 threshold 0.8 中的 threshold，
+ * 不是保留字，由文法按标识符的文本识别，不区分大小写
+ */
+pub fn expect_word(word: &str, expected: &str, start: usize, end: usize) -> Result<(), Error> {
+    if word.eq_ignore_ascii_case(expected) {
+        return Ok(());
+    }
+    Err(ParseError::User {
+        error: LexicalError::InvalidLiteral {
+            start,
+            end,
+            message: format!("Unexpected `{}`, expected `{}`.", word, expected),
+        },
+    })
+}
+
+/* 错误在源码中的位置(字节偏移量)，无法识别的字符由词法分析重新找出 */
+pub fn span(input: &str, error: &Error) -> (usize, usize) {
+    match error {
+        ParseError::InvalidToken { location } => (*location, *location),
+        ParseError::UnrecognizedEof { location, .. } => (*location, *location),
+        ParseError::UnrecognizedToken {
+            token: (start, _, end),
+            ..
+        }
+        | ParseError::ExtraToken {
+            token: (start, _, end),
+        } => (*start, *end),
+        ParseError::User {
+            error: LexicalError::InvalidLiteral { start, end, .. },
+        } => (*start, *end),
+        ParseError::User {
+            error: LexicalError::InvalidToken,
+        } => Token::lexer(input)
+            .spanned()
+            .find(|(token, _)| *token == Token::Error)
+            .map_or((0, 0), |(_, span)| (span.start, span.end)),
+    }
+}
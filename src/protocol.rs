@@ -0,0 +1,393 @@
+use crate::handoff::{Handback, Handoff};
+use crate::interpreter::{Interpreter, Io, Session, State, Turn};
+use crate::vm::Vm;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::io::{self, BufRead, Write};
+use std::panic::{self, AssertUnwindSafe};
+
+/*
+ * 供其他进程嵌入使用的JSON Lines协议
+ * 父进程通过子进程的stdin/stdout驱动机器人，每行一个JSON对象：
+ * 输出  {"type":"say","text":"..."}        speak语句
+ *       {"type":"ask","var":"..."}         执行到input语句，等待用户消息(match input时没有var)
+ *       {"type":"transfer","reason":"...","transcript":[...],"variables":{...}}
+ *                                          执行到transfer语句，转给人工客服
+ *       {"type":"end","reason":"..."}      对话结束
+ *       {"type":"error","message":"..."}   无法识别的用户消息，对话继续等待
+ * 输入  {"text":"..."}                     用户消息
+ *       {"action":"resume","variables":{...}} 或 {"action":"end"}
+ *                                          transfer之后，人工客服交还控制
+ * end的reason为 finished(exit或程序执行完毕)、closed(输入结束)、
+ * agent(人工客服结束了对话) 或 error(脚本运行出错，附带message)。
+ * 解释器逐轮推进会话；虚拟机不能挂起，改为在读取输入与转接时通过Io收发事件，两者的事件相同。
+ */
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    Say {
+        text: String,
+    },
+    Ask {
+        /* match input 不写入变量，此时省略 */
+        #[serde(skip_serializing_if = "Option::is_none", default)]
+        var: Option<String>,
+    },
+    Transfer(Handoff),
+    End {
+        reason: EndReason,
+        #[serde(skip_serializing_if = "Option::is_none", default)]
+        message: Option<String>,
+    },
+    Error {
+        message: String,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EndReason {
+    Finished,
+    Closed,
+    Agent,
+    Error,
+}
+
+#[derive(Deserialize)]
+struct UserMessage {
+    text: String,
+}
+
+/* 按协议执行一次完整的对话 */
+pub fn run_jsonl(
+    interpreter: &Interpreter,
+    input: impl BufRead,
+    mut output: impl Write,
+) -> io::Result<()> {
+    let mut lines = input.lines();
+    let mut session = None;
+    let mut result = guard(|| {
+        let (started, turn) = interpreter.start();
+        session = Some(started);
+        turn
+    });
+    loop {
+        let turn = match result {
+            Ok(turn) => turn,
+            Err(message) => {
+                let end = Event::End {
+                    reason: EndReason::Error,
+                    message: Some(message),
+                };
+                return emit(&mut output, &end);
+            }
+        };
+        let session: &mut Session = session.as_mut().expect("会话已经开始");
+        for text in turn.output {
+            emit(&mut output, &Event::Say { text })?;
+        }
+        match turn.state {
+            State::Finished => return end(&mut output, EndReason::Finished),
+            State::Transferred => {
+                let handoff = session.handoff().expect("转接中的会话有转接事件").clone();
+                emit(&mut output, &Event::Transfer(handoff))?;
+                let handback = match read::<Handback>(&mut lines, &mut output, "handback")? {
+                    Some(handback) => handback,
+                    None => return end(&mut output, EndReason::Closed),
+                };
+                if handback == Handback::End {
+                    return end(&mut output, EndReason::Agent);
+                }
+                result = guard(|| interpreter.hand_back(session, handback));
+            }
+            State::NeedsInput => {
+                let var = session.waiting().map(str::to_string);
+                emit(&mut output, &Event::Ask { var })?;
+                let message = match read::<UserMessage>(&mut lines, &mut output, "message")? {
+                    Some(message) => message,
+                    None => return end(&mut output, EndReason::Closed),
+                };
+                result = guard(|| interpreter.resume(session, &message.text));
+            }
+        }
+    }
+}
+
+/* 按协议用虚拟机执行一次完整的对话 */
+pub fn run_jsonl_vm(vm: &mut Vm, input: impl BufRead, output: impl Write) -> io::Result<()> {
+    let mut io = JsonlIo {
+        lines: input.lines(),
+        output,
+        end: EndReason::Finished,
+        error: None,
+    };
+    let result = vm.run(&mut io);
+    if let Some(e) = io.error {
+        return Err(e);
+    }
+    match result {
+        Ok(()) => end(&mut io.output, io.end),
+        Err(e) => {
+            let end = Event::End {
+                reason: EndReason::Error,
+                message: Some(e.message),
+            };
+            emit(&mut io.output, &end)
+        }
+    }
+}
+
+/* 虚拟机使用的Io，读写出错时记录错误并结束对话 */
+struct JsonlIo<L, W> {
+    lines: L,
+    output: W,
+    /* 虚拟机执行完毕时对话结束的原因 */
+    end: EndReason,
+    error: Option<io::Error>,
+}
+
+impl<L: Iterator<Item = io::Result<String>>, W: Write> JsonlIo<L, W> {
+    fn emit(&mut self, event: &Event) {
+        if self.error.is_none() {
+            self.error = emit(&mut self.output, event).err();
+        }
+    }
+
+    fn read<T: DeserializeOwned>(&mut self, kind: &str) -> Option<T> {
+        if self.error.is_some() {
+            return None;
+        }
+        match read(&mut self.lines, &mut self.output, kind) {
+            Ok(Some(value)) => Some(value),
+            Ok(None) => {
+                self.end = EndReason::Closed;
+                None
+            }
+            Err(e) => {
+                self.error = Some(e);
+                None
+            }
+        }
+    }
+}
+
+impl<L: Iterator<Item = io::Result<String>>, W: Write> Io for JsonlIo<L, W> {
+    fn speak(&mut self, text: &str) {
+        let text = text.to_string();
+        self.emit(&Event::Say { text });
+    }
+
+    fn input(&mut self, var: Option<&str>) -> Option<String> {
+        let var = var.map(str::to_string);
+        self.emit(&Event::Ask { var });
+        self.read::<UserMessage>("message")
+            .map(|message| message.text)
+    }
+
+    fn transfer(&mut self, handoff: &Handoff) -> Handback {
+        self.emit(&Event::Transfer(handoff.clone()));
+        match self.read::<Handback>("handback") {
+            Some(Handback::End) => {
+                self.end = EndReason::Agent;
+                Handback::End
+            }
+            Some(handback) => handback,
+            None => Handback::End,
+        }
+    }
+}
+
+/* 读取下一条合法的输入，空行直接忽略，输入结束时返回None */
+fn read<T: DeserializeOwned>(
+    lines: &mut impl Iterator<Item = io::Result<String>>,
+    output: &mut impl Write,
+    kind: &str,
+) -> io::Result<Option<T>> {
+    for line in lines {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str(&line) {
+            Ok(value) => return Ok(Some(value)),
+            Err(e) => {
+                let message = format!("Invalid {}: {}", kind, e);
+                emit(output, &Event::Error { message })?;
+            }
+        }
+    }
+    Ok(None)
+}
+
+/* 脚本运行出错时解释器会panic，这里将其转换为错误信息 */
+fn guard(run: impl FnOnce() -> Turn) -> Result<Turn, String> {
+    panic::catch_unwind(AssertUnwindSafe(run)).map_err(|payload| {
+        match (
+            payload.downcast_ref::<&str>(),
+            payload.downcast_ref::<String>(),
+        ) {
+            (Some(message), _) => message.to_string(),
+            (_, Some(message)) => message.clone(),
+            _ => "The script failed to run.".to_string(),
+        }
+    })
+}
+
+fn end(output: &mut impl Write, reason: EndReason) -> io::Result<()> {
+    emit(
+        output,
+        &Event::End {
+            reason,
+            message: None,
+        },
+    )
+}
+
+fn emit(output: &mut impl Write, event: &Event) -> io::Result<()> {
+    let line = serde_json::to_string(event).expect("事件总是可以序列化");
+    writeln!(output, "{}", line)?;
+    // 每个事件立即发出，父进程不需要等待缓冲区写满
+    output.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::compile;
+    use crate::parser::parse_program;
+
+    fn events(output: Vec<u8>) -> Vec<Event> {
+        String::from_utf8(output)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    /* 分别用解释器与虚拟机按协议执行，两者的事件除出错信息外应当相同 */
+    fn run(source: &str, input: &str) -> Vec<Event> {
+        let ast = parse_program(source).unwrap();
+        let mut output = Vec::new();
+        run_jsonl(
+            &Interpreter::new(ast.clone()),
+            input.as_bytes(),
+            &mut output,
+        )
+        .unwrap();
+        let interpreted = events(output);
+
+        let chunk = compile(&ast).unwrap();
+        let mut output = Vec::new();
+        run_jsonl_vm(&mut Vm::new(&chunk), input.as_bytes(), &mut output).unwrap();
+        let without_message = |events: &[Event]| -> Vec<Event> {
+            events
+                .iter()
+                .map(|event| match event {
+                    Event::End { reason, .. } => Event::End {
+                        reason: *reason,
+                        message: None,
+                    },
+                    event => event.clone(),
+                })
+                .collect()
+        };
+        assert_eq!(
+            without_message(&events(output)),
+            without_message(&interpreted)
+        );
+        interpreted
+    }
+
+    fn say(text: &str) -> Event {
+        Event::Say {
+            text: text.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_conversation() {
+        let events = run(
+            "speak \"name?\"; input name; speak \"hi \" + name; exit; speak \"never\";",
+            "not json\n\n{\"text\": \"Tom\"}\n",
+        );
+        assert_eq!(
+            events,
+            vec![
+                say("name?"),
+                Event::Ask {
+                    var: Some("name".to_string())
+                },
+                Event::Error {
+                    message: "Invalid message: expected ident at line 1 column 2".to_string()
+                },
+                say("hi Tom"),
+                Event::End {
+                    reason: EndReason::Finished,
+                    message: None
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_transfer() {
+        let source = "input issue; transfer \"agent\"; speak \"order \" + order;";
+        let input = "{\"text\": \"refund\"}\n{\"text\": \"hello?\"}\n{\"action\": \"resume\", \"variables\": {\"order\": \"A12\"}}\n";
+        let events = run(source, input);
+        assert!(matches!(
+            &events[1],
+            Event::Transfer(handoff) if handoff.reason == "agent" && handoff.transcript.len() == 1
+        ));
+        // 转接期间只接受交还控制的消息
+        assert!(
+            matches!(&events[2], Event::Error { message } if message.starts_with("Invalid handback"))
+        );
+        assert_eq!(events[3], say("order A12"));
+
+        let events = run(source, "{\"text\": \"refund\"}\n{\"action\": \"end\"}\n");
+        assert_eq!(
+            events.last(),
+            Some(&Event::End {
+                reason: EndReason::Agent,
+                message: None
+            })
+        );
+    }
+
+    #[test]
+    fn test_ask_variables() {
+        let source = "input n as number; form f { slot a prompt \"a?\"; } \
+                      match input { intent \"yes\": [\"是\"] => { exit; } }";
+        let input = "{\"text\": \"1\"}\n{\"text\": \"x\"}\n{\"text\": \"是\"}\n";
+        let asks: Vec<Event> = run(source, input)
+            .into_iter()
+            .filter(|event| matches!(event, Event::Ask { .. }))
+            .collect();
+        let ask = |var: Option<&str>| Event::Ask {
+            var: var.map(str::to_string),
+        };
+        assert_eq!(asks, vec![ask(Some("n")), ask(Some("a")), ask(None)]);
+    }
+
+    #[test]
+    fn test_end_reasons() {
+        let events = run("loop { input x; }", "{\"text\": \"a\"}\n");
+        assert_eq!(
+            events.last(),
+            Some(&Event::End {
+                reason: EndReason::Closed,
+                message: None
+            })
+        );
+
+        let events = run("input x; speak x - 1;", "{\"text\": \"a\"}\n");
+        assert!(matches!(
+            events.last(),
+            Some(Event::End {
+                reason: EndReason::Error,
+                message: Some(_)
+            })
+        ));
+    }
+}
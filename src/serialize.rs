@@ -0,0 +1,393 @@
+use crate::ast::{Expr, InputType, Statement};
+use crate::builtins::Builtin;
+use crate::tokens::Token;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/*
+ * 语法树与JSON之间的相互转换
+ * 编译好的机器人可以保存为JSON，供其他工具查看，
+ * 其他生成器产出的JSON也可以直接交给解释器执行，不需要经过lalrpop的parser。
+ * 顶层文档格式如下：
+ * {
+ *   "format": "robot_dsl.ast",
+ *   "version": 2,
+ *   "program": [ { "kind": "var", "name": "x", "init": { ... } }, ... ]
+ * }
+ * 语句与表达式通过"kind"字段区分，字面量形如 {"type": "number", "value": "42"}，
+ * 二元表达式中的运算符保存为源码写法，例如 "+"、"=="。
+ * 加载时同样检查parser保证的约束，解释器与虚拟机可以假定这些约束成立。
+ */
+
+pub const FORMAT: &str = "robot_dsl.ast";
+
+/*
+ * 当前的格式版本，JSON结构发生不兼容的变化时递增
+ * 2: 数字字面量由JSON数字改为字符串
+ */
+pub const VERSION: u32 = 2;
+
+#[derive(Debug)]
+pub enum Error {
+    /* JSON本身不合法，或者结构与语法树不符 */
+    Json(serde_json::Error),
+    /* 不是语法树文档 */
+    UnknownFormat(String),
+    /* 由更新版本的工具生成的文档 */
+    UnsupportedVersion(u32),
+    /* 结构与语法树相符，但不是parser能够产生的语法树，例如未知的函数 */
+    Invalid(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Json(e) => write!(f, "Invalid AST JSON: {}", e),
+            Error::UnknownFormat(format) => write!(f, "Unknown document format `{}`.", format),
+            Error::UnsupportedVersion(version) => write!(
+                f,
+                "Unsupported AST version {} (supported: {}).",
+                version, VERSION
+            ),
+            Error::Invalid(message) => write!(f, "Invalid AST: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Error::Json(e)
+    }
+}
+
+#[derive(Serialize)]
+struct Document<'a> {
+    format: &'a str,
+    version: u32,
+    program: &'a [Box<Statement>],
+}
+
+/* 先只读取文档头，确认版本后再解析语法树 */
+#[derive(Deserialize)]
+struct Header {
+    format: String,
+    version: u32,
+}
+
+#[derive(Deserialize)]
+struct OwnedDocument {
+    program: Vec<Statement>,
+}
+
+/* 将语法树序列化为带版本号的JSON */
+pub fn to_json(ast: &[Box<Statement>]) -> String {
+    let document = Document {
+        format: FORMAT,
+        version: VERSION,
+        program: ast,
+    };
+    serde_json::to_string_pretty(&document).expect("语法树总是可以序列化")
+}
+
+/* 从JSON中加载语法树 */
+pub fn from_json(json: &str) -> Result<Vec<Box<Statement>>, Error> {
+    let header: Header = serde_json::from_str(json)?;
+    if header.format != FORMAT {
+        return Err(Error::UnknownFormat(header.format));
+    }
+    if header.version != VERSION {
+        return Err(Error::UnsupportedVersion(header.version));
+    }
+    let document: OwnedDocument = serde_json::from_str(json)?;
+    for statement in &document.program {
+        validate(statement, true).map_err(Error::Invalid)?;
+    }
+    Ok(document.program.into_iter().map(Box::new).collect())
+}
+
+/*
+ * 检查parser保证的约束：
+ * - 不含import，导入在序列化之前已经由module::load展开
+ * - 对话状态只在顶层声明
+ * - 循环、分支、else、match的分支与对话状态的代码都是代码块
+ * - match的阈值在0到1之间，输入最多尝试的次数至少为1，选项与表单的槽位不为空，槽位不重名
+ * - 函数存在并且参数个数正确，赋值只作为单独的语句出现
+ */
+fn validate(statement: &Statement, top_level: bool) -> Result<(), String> {
+    match statement {
+        Statement::Import { path, .. } => {
+            return Err(format!(
+                "Import of \"{}\" must be resolved by loading the script from a file.",
+                path
+            ))
+        }
+        Statement::State { name, .. } if !top_level => {
+            return Err(format!(
+                "State '{}' must be declared at the top level.",
+                name
+            ))
+        }
+        Statement::Match {
+            threshold: Some(threshold),
+            ..
+        } if !(0.0..=1.0).contains(threshold) => {
+            return Err(format!("Threshold {} is not between 0 and 1.", threshold))
+        }
+        Statement::TypedInput { name, max, .. } if *max == Some(0) => {
+            return Err(format!("Input '{}' must allow at least 1 attempt.", name))
+        }
+        Statement::TypedInput {
+            name,
+            input_type: InputType::Choice(choices),
+            ..
+        } if choices.is_empty() => return Err(format!("Input '{}' has no choices.", name)),
+        Statement::Form { name, slots, .. } => {
+            if slots.is_empty() {
+                return Err(format!("Form '{}' has no slots.", name));
+            }
+            for (index, slot) in slots.iter().enumerate() {
+                if slots[..index].iter().any(|other| other.name == slot.name) {
+                    return Err(format!(
+                        "Duplicate slot `{}` in form `{}`.",
+                        slot.name, name
+                    ));
+                }
+            }
+        }
+        _ => {}
+    }
+    let expressions: Vec<&Expr> = match statement {
+        // 只有表达式语句本身可以是赋值
+        Statement::Expression { expression } => match &**expression {
+            Expr::Assign { value, .. } | Expr::StoreAssign { value, .. } => vec![value],
+            expression => vec![expression],
+        },
+        Statement::Speak { expression } => vec![expression],
+        Statement::SpeakOneOf { options } => vec![options],
+        Statement::Locale { locale } => vec![locale],
+        Statement::Transfer { reason } => vec![reason],
+        Statement::Var { init, .. }
+        | Statement::Local { init, .. }
+        | Statement::Store { init, .. } => vec![init],
+        Statement::Branch { condition, .. } => vec![condition],
+        Statement::For { iterable, .. } => vec![iterable],
+        Statement::Match { subject, .. } => subject.iter().map(|subject| &**subject).collect(),
+        Statement::TypedInput { prompt, retry, .. } => {
+            prompt.iter().chain(retry).map(|expr| &**expr).collect()
+        }
+        Statement::Form { slots, confirm, .. } => slots
+            .iter()
+            .flat_map(|slot| Some(&slot.prompt).into_iter().chain(&slot.retry))
+            .chain(confirm)
+            .map(|expr| &**expr)
+            .collect(),
+        _ => Vec::new(),
+    };
+    for expression in expressions {
+        validate_expr(expression)?;
+    }
+    for child in statement.children() {
+        if !matches!(statement, Statement::Block { .. })
+            && !matches!(child, Statement::Block { .. })
+        {
+            return Err(
+                "The body of a loop, branch, else, match arm or state must be a block.".to_string(),
+            );
+        }
+        validate(child, false)?;
+    }
+    Ok(())
+}
+
+fn validate_expr(expr: &Expr) -> Result<(), String> {
+    match expr {
+        Expr::Assign { name, .. } => Err(format!(
+            "Assignment to '{}' must be a statement of its own.",
+            name
+        )),
+        Expr::StoreAssign { key, .. } => Err(format!(
+            "Assignment to 'store.{}' must be a statement of its own.",
+            key
+        )),
+        Expr::Binary {
+            operator: Token::Assign,
+            ..
+        } => Err("Operator `=` is not a binary operator.".to_string()),
+        Expr::Binary { left, right, .. } => {
+            validate_expr(left)?;
+            validate_expr(right)
+        }
+        Expr::Call { name, args } => {
+            Builtin::resolve(name, args.len())?;
+            args.iter().try_for_each(|arg| validate_expr(arg))
+        }
+        Expr::List { items } => items.iter().try_for_each(|item| validate_expr(item)),
+        Expr::Map { entries: args } | Expr::Message { args, .. } => {
+            args.iter().try_for_each(|(_, value)| validate_expr(value))
+        }
+        Expr::RegexMatch { subject, .. } => validate_expr(subject),
+        Expr::Index { target, index } => {
+            validate_expr(target)?;
+            validate_expr(index)
+        }
+        Expr::Literal { .. } | Expr::Variable { .. } | Expr::Store { .. } => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::LiteralValue;
+
+    #[test]
+    fn test_json_schema() {
+        let ast = vec![Box::new(Statement::Speak {
+            expression: Box::new(Expr::Binary {
+                left: Box::new(Expr::Variable {
+                    name: "bill".to_string(),
+                }),
+                operator: Token::OperatorAdd,
+                right: Box::new(Expr::Literal {
+                    value: LiteralValue::Number(1.into()),
+                }),
+            }),
+        })];
+        let value: serde_json::Value = serde_json::from_str(&to_json(&ast)).unwrap();
+        assert_eq!(
+            value,
+            serde_json::json!({
+                "format": "robot_dsl.ast",
+                "version": 2,
+                "program": [{
+                    "kind": "speak",
+                    "expression": {
+                        "kind": "binary",
+                        "left": { "kind": "variable", "name": "bill" },
+                        "operator": "+",
+                        "right": { "kind": "literal", "value": { "type": "number", "value": "1" } }
+                    }
+                }]
+            })
+        );
+    }
+
+    #[test]
+    fn test_json_round_trip() {
+        let ast = vec![
+            Box::new(Statement::Var {
+                name: "name".to_string(),
+                init: Box::new(Expr::Literal {
+                    value: LiteralValue::String("Tom".to_string()),
+                }),
+            }),
+            // 超出f64精度的数字原样保留
+            Box::new(Statement::Var {
+                name: "total".to_string(),
+                init: Box::new(Expr::Literal {
+                    value: LiteralValue::Number("12345678901234567890.10".parse().unwrap()),
+                }),
+            }),
+            Box::new(Statement::Loop {
+                body: Box::new(Statement::Block {
+                    statements: vec![
+                        Box::new(Statement::Input {
+                            input: "str".to_string(),
+                        }),
+                        Box::new(Statement::Branch {
+                            condition: Box::new(Expr::Binary {
+                                left: Box::new(Expr::Variable {
+                                    name: "str".to_string(),
+                                }),
+                                operator: Token::WEqual,
+                                right: Box::new(Expr::Literal {
+                                    value: LiteralValue::String("e".to_string()),
+                                }),
+                            }),
+                            then: Box::new(Statement::Block {
+                                statements: vec![Box::new(Statement::Exit)],
+                            }),
+                        }),
+                    ],
+                }),
+            }),
+        ];
+        assert_eq!(from_json(&to_json(&ast)).unwrap(), ast);
+    }
+
+    #[test]
+    fn test_json_version_check() {
+        let json = r#"{"format": "robot_dsl.ast", "version": 1, "program": []}"#;
+        assert!(matches!(from_json(json), Err(Error::UnsupportedVersion(1))));
+        let json = r#"{"format": "other", "version": 1, "program": []}"#;
+        assert!(matches!(from_json(json), Err(Error::UnknownFormat(_))));
+    }
+
+    #[test]
+    fn test_json_validation() {
+        use serde_json::{json, Value};
+
+        let load = |program: Value| {
+            let document = json!({
+                "format": "robot_dsl.ast",
+                "version": 2,
+                "program": program,
+            });
+            from_json(&document.to_string())
+        };
+        let number = json!({ "kind": "literal", "value": { "type": "number", "value": "1" } });
+        let speak = |expression: Value| json!({ "kind": "speak", "expression": expression });
+        let block = |statement: Value| json!({ "kind": "block", "statements": [statement] });
+        let call =
+            |name: &str, args: Vec<Value>| json!({ "kind": "call", "name": name, "args": args });
+        let input = |max: u32| {
+            json!({
+                "kind": "typed_input", "name": "n", "input_type": "number",
+                "prompt": null, "retry": null, "max": max, "fallback": null,
+            })
+        };
+        let matching = |threshold: f64| {
+            json!({
+                "kind": "match", "subject": null, "threshold": threshold, "arms": [],
+                "default": block(speak(number.clone())),
+            })
+        };
+        let state = json!({
+            "kind": "state", "name": "s", "enter": block(speak(number.clone())), "handler": null,
+        });
+        let rejected = [
+            json!({ "kind": "import", "path": "common/greetings.dsl", "alias": null }),
+            speak(call("nope", vec![number.clone()])),
+            speak(call("len", vec![])),
+            // 循环体不是代码块
+            json!({
+                "kind": "for", "variable": "x", "iterable": number, "body": speak(number.clone()),
+            }),
+            json!({ "kind": "loop", "body": state.clone() }),
+            // 对话状态不在顶层
+            block(state.clone()),
+            matching(1.5),
+            input(0),
+            speak(json!({ "kind": "assign", "name": "x", "value": number })),
+        ];
+        for statement in rejected {
+            let error = load(json!([statement])).unwrap_err();
+            assert!(matches!(error, Error::Invalid(_)), "{}", statement);
+        }
+        let accepted = [
+            speak(call("len", vec![number.clone()])),
+            json!({ "kind": "loop", "body": block(speak(number.clone())) }),
+            state,
+            matching(0.5),
+            input(1),
+            json!({
+                "kind": "expression",
+                "expression": { "kind": "assign", "name": "x", "value": number },
+            }),
+        ];
+        for statement in accepted {
+            assert!(load(json!([statement.clone()])).is_ok(), "{}", statement);
+        }
+    }
+}
@@ -0,0 +1,190 @@
+use crate::interpreter::{Interpreter, Session, State, Turn};
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::thread;
+
+/*
+ * 基于TCP的多会话聊天服务
+ * 每个连接对应一个新的会话，客户端发来的每一行作为一次input，
+ * 每条speak输出作为一行发回客户端。
+ * 会话遇到exit或程序执行完毕时服务端关闭连接；
+ * 按行收发的连接上没有人工客服的通道，执行到transfer时
+ * 先将转接事件作为一行JSON发回客户端，再关闭连接；
+ * 客户端断开时直接丢弃会话，不影响其他连接。
+ * 所有连接共用同一个解释器，语法树只保存一份；
+ * store中的值默认按解释器的用户(--user，缺省为store::DEFAULT_USER)保存，
+ * trusted为true时客户端连接后发送的第一行是用户id，空行表示默认用户，
+ * 只有可信的客户端(例如同一台机器上的后端服务)才应当能够连接这样的服务。
+ */
+
+pub fn serve(
+    interpreter: Arc<Interpreter>,
+    listener: TcpListener,
+    trusted: bool,
+) -> io::Result<()> {
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            // 单个连接建立失败不影响继续接受其他连接
+            Err(e) => {
+                eprintln!("接受连接失败: {}", e);
+                continue;
+            }
+        };
+        let interpreter = Arc::clone(&interpreter);
+        thread::spawn(move || {
+            let peer = stream.peer_addr().ok();
+            if let Err(e) = handle(&interpreter, stream, trusted) {
+                eprintln!("连接 {:?} 异常断开: {}", peer, e);
+            }
+        });
+    }
+    Ok(())
+}
+
+/* 在一个连接上完成整个对话 */
+fn handle(interpreter: &Interpreter, stream: TcpStream, trusted: bool) -> io::Result<()> {
+    let mut writer = stream.try_clone()?;
+    let mut lines = BufReader::new(stream).lines();
+    // 可信的客户端先发送用户id
+    let (mut session, mut turn) = if trusted {
+        match lines.next() {
+            Some(line) => match line?.trim() {
+                "" => interpreter.start(),
+                user => interpreter.start_for(user),
+            },
+            None => return Ok(()),
+        }
+    } else {
+        interpreter.start()
+    };
+    loop {
+        send(&mut writer, &turn, &session)?;
+        if turn.state != State::NeedsInput {
+            break;
+        }
+        match lines.next() {
+            Some(line) => {
+                let line = line?;
+                turn = interpreter.resume(&mut session, line.trim_end_matches('\r'));
+            }
+            // 客户端关闭了连接
+            None => break,
+        }
+    }
+    writer
+        .shutdown(std::net::Shutdown::Both)
+        .or_else(|e| match e.kind() {
+            io::ErrorKind::NotConnected => Ok(()),
+            _ => Err(e),
+        })
+}
+
+fn send(writer: &mut TcpStream, turn: &Turn, session: &Session) -> io::Result<()> {
+    for line in &turn.output {
+        writeln!(writer, "{}", line)?;
+    }
+    if let Some(handoff) = session.handoff() {
+        let event = serde_json::to_string(handoff).expect("转接事件总是可以序列化");
+        writeln!(writer, "{}", event)?;
+    }
+    writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_program;
+    use std::io::Read;
+
+    fn start_server(source: &str, trusted: bool) -> std::net::SocketAddr {
+        let interpreter = Arc::new(Interpreter::new(parse_program(source).unwrap()));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || serve(interpreter, listener, trusted));
+        addr
+    }
+
+    #[test]
+    fn test_concurrent_sessions() {
+        let addr = start_server(
+            "speak \"hi\"; loop { input name; if (name == \"bye\") { exit; }; speak \"hello \" + name; }",
+            false,
+        );
+        let mut first = TcpStream::connect(addr).unwrap();
+        let mut second = TcpStream::connect(addr).unwrap();
+        let mut first_lines = BufReader::new(first.try_clone().unwrap()).lines();
+        let mut second_lines = BufReader::new(second.try_clone().unwrap()).lines();
+        assert_eq!(first_lines.next().unwrap().unwrap(), "hi");
+        assert_eq!(second_lines.next().unwrap().unwrap(), "hi");
+
+        writeln!(second, "Bob").unwrap();
+        writeln!(first, "Alice\r").unwrap();
+        assert_eq!(first_lines.next().unwrap().unwrap(), "hello Alice");
+        assert_eq!(second_lines.next().unwrap().unwrap(), "hello Bob");
+
+        // exit后服务端关闭连接
+        writeln!(first, "bye").unwrap();
+        assert!(first_lines.next().is_none());
+        // 另一个会话不受影响
+        writeln!(second, "Carol").unwrap();
+        assert_eq!(second_lines.next().unwrap().unwrap(), "hello Carol");
+    }
+
+    #[test]
+    fn test_store_per_user() {
+        let source =
+            "store visits = 0; store.visits = to_number(store.visits) + 1; speak store.visits;";
+        let visit = |addr, handshake: Option<&str>| {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            if let Some(user) = handshake {
+                writeln!(stream, "{}", user).unwrap();
+            }
+            let mut reply = String::new();
+            stream.read_to_string(&mut reply).unwrap();
+            reply
+        };
+        // 默认所有连接都是同一个用户，再次连接时接着计数
+        let addr = start_server(source, false);
+        assert_eq!(visit(addr, None), "1\n");
+        assert_eq!(visit(addr, None), "2\n");
+
+        // 可信的客户端用第一行指定用户
+        let addr = start_server(source, true);
+        assert_eq!(visit(addr, Some("alice")), "1\n");
+        assert_eq!(visit(addr, Some("alice\r")), "2\n");
+        assert_eq!(visit(addr, Some("bob")), "1\n");
+        assert_eq!(visit(addr, Some("")), "1\n");
+    }
+
+    #[test]
+    fn test_transfer_closes_with_handoff() {
+        let addr = start_server(
+            "input issue; transfer \"complaint\"; speak \"back\";",
+            false,
+        );
+        let mut stream = TcpStream::connect(addr).unwrap();
+        writeln!(stream, "broken").unwrap();
+        let mut reply = String::new();
+        stream.read_to_string(&mut reply).unwrap();
+        let handoff: crate::handoff::Handoff = serde_json::from_str(reply.trim_end()).unwrap();
+        assert_eq!(handoff.reason, "complaint");
+        assert_eq!(
+            handoff.transcript,
+            vec![crate::handoff::Line::user("broken")]
+        );
+    }
+
+    #[test]
+    fn test_client_disconnect() {
+        let addr = start_server("input x; speak x;", false);
+        // 客户端不发送任何内容直接断开，服务端继续接受新的连接
+        drop(TcpStream::connect(addr).unwrap());
+        let mut stream = TcpStream::connect(addr).unwrap();
+        writeln!(stream, "echo").unwrap();
+        let mut reply = String::new();
+        stream.read_to_string(&mut reply).unwrap();
+        assert_eq!(reply, "echo\n");
+    }
+}
@@ -0,0 +1,187 @@
+use crate::ast::Statement;
+use crate::interpreter::Session;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/*
+ * 会话快照
+ * 将挂起中的会话(执行位置、作用域栈与变量)保存为JSON，
+ * 之后可以在另一个进程中恢复，服务重启后对话仍能继续。
+ * 文档格式如下：
+ * {
+ *   "format": "robot_dsl.session",
+ *   "version": 1,
+ *   "program": "9c1f0e0a5d3b7e21",
+ *   "session": { "env": {...}, "frames": [...], "waiting": "str" }
+ * }
+ * 执行位置以语法树中的路径表示，只对生成快照时的程序有意义，
+ * 因此快照中记录了程序的指纹，恢复时程序不一致会被拒绝。
+ */
+
+pub const FORMAT: &str = "robot_dsl.session";
+
+/* 当前的格式版本，JSON结构发生不兼容的变化时递增 */
+pub const VERSION: u32 = 1;
+
+#[derive(Debug)]
+pub enum Error {
+    /* JSON本身不合法，或者结构与会话不符 */
+    Json(serde_json::Error),
+    /* 不是会话快照 */
+    UnknownFormat(String),
+    UnsupportedVersion(u32),
+    /* 快照由另一个程序生成 */
+    ProgramMismatch { expected: String, found: String },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Json(e) => write!(f, "Invalid session snapshot: {}", e),
+            Error::UnknownFormat(format) => write!(f, "Unknown document format `{}`.", format),
+            Error::UnsupportedVersion(version) => write!(
+                f,
+                "Unsupported snapshot version {} (supported: {}).",
+                version, VERSION
+            ),
+            Error::ProgramMismatch { expected, found } => write!(
+                f,
+                "Snapshot was taken from program {}, but the loaded program is {}.",
+                found, expected
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Error::Json(e)
+    }
+}
+
+#[derive(Serialize)]
+struct Document<'a> {
+    format: &'a str,
+    version: u32,
+    program: String,
+    session: &'a Session,
+}
+
+#[derive(Deserialize)]
+struct Header {
+    format: String,
+    version: u32,
+    program: String,
+}
+
+#[derive(Deserialize)]
+struct OwnedDocument {
+    session: Session,
+}
+
+/* 程序的指纹：语法树JSON的64位FNV-1a散列，与进程和平台无关 */
+pub fn fingerprint(ast: &[Box<Statement>]) -> String {
+    let json = serde_json::to_string(ast).expect("语法树总是可以序列化");
+    let hash = json.bytes().fold(0xcbf29ce484222325u64, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    });
+    format!("{:016x}", hash)
+}
+
+/* 将会话保存为JSON */
+pub fn to_json(ast: &[Box<Statement>], session: &Session) -> String {
+    let document = Document {
+        format: FORMAT,
+        version: VERSION,
+        program: fingerprint(ast),
+        session,
+    };
+    serde_json::to_string(&document).expect("会话总是可以序列化")
+}
+
+pub fn to_bytes(ast: &[Box<Statement>], session: &Session) -> Vec<u8> {
+    to_json(ast, session).into_bytes()
+}
+
+/* 从JSON中恢复会话，ast必须是生成快照时的程序 */
+pub fn from_json(ast: &[Box<Statement>], json: &str) -> Result<Session, Error> {
+    from_bytes(ast, json.as_bytes())
+}
+
+pub fn from_bytes(ast: &[Box<Statement>], bytes: &[u8]) -> Result<Session, Error> {
+    let header: Header = serde_json::from_slice(bytes)?;
+    if header.format != FORMAT {
+        return Err(Error::UnknownFormat(header.format));
+    }
+    if header.version != VERSION {
+        return Err(Error::UnsupportedVersion(header.version));
+    }
+    let expected = fingerprint(ast);
+    if header.program != expected {
+        return Err(Error::ProgramMismatch {
+            expected,
+            found: header.program,
+        });
+    }
+    let document: OwnedDocument = serde_json::from_slice(bytes)?;
+    Ok(document.session)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interpreter::{Interpreter, State};
+    use crate::parser::parse_program;
+    use crate::store::{MemoryStore, Storage};
+    use std::sync::Arc;
+
+    fn bank(store: Arc<dyn Storage>) -> Interpreter {
+        let source = std::fs::read_to_string("examples/bank.dsl").unwrap();
+        Interpreter::new(parse_program(&source).unwrap()).with_store(store)
+    }
+
+    #[test]
+    fn test_restore_in_new_interpreter() {
+        // store中的值不在快照里，重启前后使用同一个存储
+        let store: Arc<dyn Storage> = Arc::new(MemoryStore::new());
+        let interpreter = bank(store.clone());
+        let (mut session, _) = interpreter.start();
+        interpreter.resume(&mut session, "r");
+        let bytes = to_bytes(&interpreter.ast, &session);
+        drop(session);
+
+        // 模拟重启：重新解析脚本，从快照继续对话
+        let restarted = bank(store);
+        let mut session = from_bytes(&restarted.ast, &bytes).unwrap();
+        assert_eq!(session.state(), State::NeedsInput);
+        let turn = restarted.resume(&mut session, "100");
+        assert_eq!(turn.output[0], "充值成功!");
+        let turn = restarted.resume(&mut session, "b");
+        assert_eq!(turn.output[0], "你的账户余额为:100");
+
+        // 快照可以反复生成，结果一致
+        let json = to_json(&restarted.ast, &session);
+        let restored = from_json(&restarted.ast, &json).unwrap();
+        assert_eq!(restored, session);
+    }
+
+    #[test]
+    fn test_reject_other_program() {
+        let interpreter = bank(Arc::new(MemoryStore::new()));
+        let (session, _) = interpreter.start();
+        let json = to_json(&interpreter.ast, &session);
+
+        let other = parse_program("input x; speak x;").unwrap();
+        assert!(matches!(
+            from_json(&other, &json),
+            Err(Error::ProgramMismatch { .. })
+        ));
+        let json = json.replace(FORMAT, "robot_dsl.ast");
+        assert!(matches!(
+            from_json(&interpreter.ast, &json),
+            Err(Error::UnknownFormat(_))
+        ));
+    }
+}
@@ -0,0 +1,292 @@
+use crate::value::{Map, Value};
+use rusqlite::OptionalExtension;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/*
+ * store 命名空间背后的持久化存储
+ * 普通变量只属于一次会话，store.balance 这样的变量按用户保存，
+ * 同一个用户之后的对话仍能读到上一次写入的值，例如充值后的余额。
+ * 存储只需要按 (用户, 键) 读写一个值，实现了Storage的类型都可以使用：
+ * - MemoryStore  保存在进程内，进程退出后丢失，未指定存储文件时使用
+ * - JsonStore    整个存储为一个JSON文件 {"用户": {"键": 值}}，每次写入后整体写回
+ * - SqliteStore  嵌入式SQLite数据库文件，表 store(user, key, value)，值以JSON保存
+ * 存储文件由扩展名决定使用哪一种，见open。
+ */
+
+pub trait Storage: Send + Sync {
+    /* 用户保存的值，没有写入过时返回None */
+    fn get(&self, user: &str, key: &str) -> Result<Option<Value>, Error>;
+    fn set(&self, user: &str, key: &str, value: &Value) -> Result<(), Error>;
+}
+
+/* 用户没有指定时使用的用户名 */
+pub const DEFAULT_USER: &str = "default";
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    /* 存储文件的内容不合法 */
+    Json(serde_json::Error),
+    Sqlite(rusqlite::Error),
+    /* 无法由扩展名判断存储的类型 */
+    UnknownKind(PathBuf),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "Store I/O failed: {}", e),
+            Error::Json(e) => write!(f, "Invalid store file: {}", e),
+            Error::Sqlite(e) => write!(f, "Store database failed: {}", e),
+            Error::UnknownKind(path) => write!(
+                f,
+                "Unknown store file `{}` (expected .json, .db, .sqlite or .sqlite3).",
+                path.display()
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Error::Json(e)
+    }
+}
+
+impl From<rusqlite::Error> for Error {
+    fn from(e: rusqlite::Error) -> Self {
+        Error::Sqlite(e)
+    }
+}
+
+/* 按扩展名打开存储文件，文件不存在时创建 */
+pub fn open(path: &Path) -> Result<Arc<dyn Storage>, Error> {
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(str::to_ascii_lowercase);
+    match extension.as_deref() {
+        Some("json") => Ok(Arc::new(JsonStore::open(path)?)),
+        Some("db" | "sqlite" | "sqlite3") => Ok(Arc::new(SqliteStore::open(path)?)),
+        _ => Err(Error::UnknownKind(path.to_path_buf())),
+    }
+}
+
+#[derive(Default)]
+pub struct MemoryStore {
+    users: Mutex<HashMap<String, Map>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Storage for MemoryStore {
+    fn get(&self, user: &str, key: &str) -> Result<Option<Value>, Error> {
+        let users = self.users.lock().unwrap();
+        Ok(users.get(user).and_then(|values| values.get(key)).cloned())
+    }
+
+    fn set(&self, user: &str, key: &str, value: &Value) -> Result<(), Error> {
+        let mut users = self.users.lock().unwrap();
+        let values = users.entry(user.to_string()).or_default();
+        values.insert(key.to_string(), value.clone());
+        Ok(())
+    }
+}
+
+pub struct JsonStore {
+    path: PathBuf,
+    users: Mutex<HashMap<String, Map>>,
+}
+
+impl JsonStore {
+    pub fn open(path: &Path) -> Result<Self, Error> {
+        let users = match fs::read_to_string(path) {
+            Ok(text) => serde_json::from_str(&text)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Self {
+            path: path.to_path_buf(),
+            users: Mutex::new(users),
+        })
+    }
+
+    /* 先写入临时文件再改名，写到一半时进程退出也不会破坏原来的文件 */
+    fn save(&self, users: &HashMap<String, Map>) -> Result<(), Error> {
+        let temp = self.path.with_extension("json.tmp");
+        fs::write(&temp, serde_json::to_string_pretty(users)?)?;
+        fs::rename(&temp, &self.path)?;
+        Ok(())
+    }
+}
+
+impl Storage for JsonStore {
+    fn get(&self, user: &str, key: &str) -> Result<Option<Value>, Error> {
+        let users = self.users.lock().unwrap();
+        Ok(users.get(user).and_then(|values| values.get(key)).cloned())
+    }
+
+    fn set(&self, user: &str, key: &str, value: &Value) -> Result<(), Error> {
+        let mut users = self.users.lock().unwrap();
+        let values = users.entry(user.to_string()).or_default();
+        let previous = values.insert(key.to_string(), value.clone());
+        let saved = self.save(&users);
+        // 写入失败时撤销修改，内存中的值与文件保持一致
+        if saved.is_err() {
+            let values = users.get_mut(user).expect("刚刚写入过该用户");
+            match previous {
+                Some(previous) => {
+                    values.insert(key.to_string(), previous);
+                }
+                None => {
+                    values.shift_remove(key);
+                    if values.is_empty() {
+                        users.remove(user);
+                    }
+                }
+            }
+        }
+        saved
+    }
+}
+
+pub struct SqliteStore {
+    connection: Mutex<rusqlite::Connection>,
+}
+
+impl SqliteStore {
+    pub fn open(path: &Path) -> Result<Self, Error> {
+        let connection = rusqlite::Connection::open(path)?;
+        connection.execute(
+            "CREATE TABLE IF NOT EXISTS store (
+                user TEXT NOT NULL,
+                key TEXT NOT NULL,
+                value TEXT NOT NULL,
+                PRIMARY KEY (user, key)
+            )",
+            [],
+        )?;
+        Ok(Self {
+            connection: Mutex::new(connection),
+        })
+    }
+}
+
+impl Storage for SqliteStore {
+    fn get(&self, user: &str, key: &str) -> Result<Option<Value>, Error> {
+        let connection = self.connection.lock().unwrap();
+        let json: Option<String> = connection
+            .query_row(
+                "SELECT value FROM store WHERE user = ?1 AND key = ?2",
+                [user, key],
+                |row| row.get(0),
+            )
+            .optional()?;
+        match json {
+            Some(json) => Ok(Some(serde_json::from_str(&json)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn set(&self, user: &str, key: &str, value: &Value) -> Result<(), Error> {
+        let json = serde_json::to_string(value)?;
+        let connection = self.connection.lock().unwrap();
+        connection.execute(
+            "INSERT INTO store (user, key, value) VALUES (?1, ?2, ?3)
+             ON CONFLICT (user, key) DO UPDATE SET value = excluded.value",
+            [user, key, json.as_str()],
+        )?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("robot_dsl_{}_{}", std::process::id(), name));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    fn check(store: &dyn Storage) {
+        assert_eq!(store.get("alice", "balance").unwrap(), None);
+        store.set("alice", "balance", &Value::from("100")).unwrap();
+        store
+            .set("alice", "history", &Value::List(vec![Value::from("100")]))
+            .unwrap();
+        store.set("bob", "balance", &Value::from("5")).unwrap();
+        store.set("alice", "balance", &Value::from("150")).unwrap();
+        assert_eq!(
+            store.get("alice", "balance").unwrap(),
+            Some(Value::from("150"))
+        );
+        assert_eq!(store.get("bob", "balance").unwrap(), Some(Value::from("5")));
+        assert_eq!(
+            store.get("alice", "history").unwrap(),
+            Some(Value::List(vec![Value::from("100")]))
+        );
+    }
+
+    #[test]
+    fn test_memory_store() {
+        check(&MemoryStore::new());
+    }
+
+    #[test]
+    fn test_file_stores_persist() {
+        for name in ["store.json", "store.db"] {
+            let path = temp_path(name);
+            check(&*open(&path).unwrap());
+            // 重新打开之后仍能读到之前写入的值
+            let reopened = open(&path).unwrap();
+            assert_eq!(
+                reopened.get("alice", "balance").unwrap(),
+                Some(Value::from("150"))
+            );
+            fs::remove_file(&path).unwrap();
+        }
+        assert!(matches!(
+            open(Path::new("store.txt")),
+            Err(Error::UnknownKind(_))
+        ));
+    }
+
+    #[test]
+    fn test_json_store_write_failure() {
+        let path = temp_path("failing.json");
+        fs::write(&path, r#"{"alice": {"balance": "1"}}"#).unwrap();
+        // 临时文件的位置被目录占用，写入失败
+        let temp = path.with_extension("json.tmp");
+        fs::create_dir_all(&temp).unwrap();
+        let store = JsonStore::open(&path).unwrap();
+        assert!(store.set("alice", "balance", &Value::from("2")).is_err());
+        assert!(store.set("bob", "balance", &Value::from("3")).is_err());
+        // 内存中的值没有被修改
+        assert_eq!(
+            store.get("alice", "balance").unwrap(),
+            Some(Value::from("1"))
+        );
+        assert!(!store.users.lock().unwrap().contains_key("bob"));
+        fs::remove_dir(&temp).unwrap();
+        fs::remove_file(&path).unwrap();
+    }
+}
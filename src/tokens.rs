@@ -1,5 +1,6 @@
 use std::fmt;  // to implement the Display trait
 use logos::Logos;
+use rust_decimal::Decimal;
 
 /*
  * 对词素类型进行枚举定义
@@ -24,6 +25,25 @@ pub enum Token {
     KeywordExit,
     #[regex(r"(?i)loop")]
     KeywordLoop,
+    // 以下关键字不是保留字，由lexer根据上下文识别
+    KeywordLocal,
+    KeywordMatch,
+    KeywordIntent,
+    KeywordDefault,
+    KeywordPrompt,
+    KeywordRetry,
+    KeywordElse,
+    KeywordForm,
+    KeywordSlot,
+    KeywordConfirm,
+    KeywordState,
+    KeywordOn,
+    KeywordGoto,
+    KeywordFor,
+    KeywordStore,
+    KeywordImport,
+    KeywordLocale,
+    KeywordTransfer,
 
     //signle token class
     #[token("(")]
@@ -36,6 +56,20 @@ pub enum Token {
     LBracket,
     #[token("}")]
     RBracket,
+    #[token("[")]
+    LSquare,
+    #[token("]")]
+    RSquare,
+    #[token(",")]
+    Comma,
+    #[token(":")]
+    Colon,
+    #[token("=>")]
+    FatArrow,
+    #[token(".")]
+    Dot,
+    #[token("@")]
+    At,
 
     //operator
     #[token("+")]
@@ -46,18 +80,24 @@ pub enum Token {
     OperatorMul,
     #[token("/")]
     OperatorDiv,
+    #[token("%")]
+    OperatorMod,
+    #[token("^")]
+    OperatorPow,
     #[token("==")]
     WEqual,
     #[token("=")]
     Assign,
     #[token("!=")]
     BangEqual,
+    #[token("~")]
+    Tilde,
 
     //literals
     #[regex("[_a-zA-Z][_0-9a-zA-Z]*", |lex| lex.slice().parse())]
     Identifier(String),
-    #[regex(r"[0-9]+(\.[0-9]+)?", |lex| lex.slice().parse::<f64>())]
-    Number(f64),
+    #[regex(r"[0-9]+(\.[0-9]+)?", |lex| lex.slice().parse::<Decimal>())]
+    Number(Decimal),
     #[regex(r#""[^"]*""#, |lex| lex.slice()[1..lex.slice().len()-1].to_string())]
     StringContent(String),
 
@@ -0,0 +1,217 @@
+use crate::ast::{InputType, Slot};
+use crate::interpreter::parse_number;
+use rust_decimal::Decimal;
+
+/*
+ * 带类型的input语句对用户输入的校验
+ * 输入合法时返回规范化后写入变量的值，否则返回None，解释器与虚拟机共用：
+ * - number   数字，例如 100、-2.5
+ * - integer  整数
+ * - yesno    是/否，写入 True 或 False
+ * - choice   给定选项之一(不区分大小写)，也可以输入选项的序号，写入选项原文
+ * - date     2024-03-05、2024/3/5、2024.3.5 或 2024年3月5日，写入 2024-03-05
+ * - phone    大陆手机号，可以带空格、横线以及 +86 前缀，写入11位数字
+ * 表单的槽位在类型之外还可以要求匹配正则，没有类型时接受任意非空输入。
+ */
+
+const YES: [&str; 10] = [
+    "yes", "y", "ok", "true", "是", "是的", "对", "好", "好的", "确认",
+];
+const NO: [&str; 8] = ["no", "n", "false", "否", "不", "不是", "不要", "取消"];
+
+pub fn validate(input_type: &InputType, text: &str) -> Option<String> {
+    let text = text.trim();
+    match input_type {
+        InputType::Number => number(text).map(|_| text.to_string()),
+        InputType::Integer => number(text)
+            .filter(|n| !text.contains('.') && n.fract().is_zero())
+            .map(|_| text.to_string()),
+        InputType::YesNo => {
+            let answer = text.to_lowercase();
+            if YES.contains(&answer.as_str()) {
+                Some("True".to_string())
+            } else if NO.contains(&answer.as_str()) {
+                Some("False".to_string())
+            } else {
+                None
+            }
+        }
+        InputType::Choice(choices) => choices
+            .iter()
+            .find(|choice| choice.to_lowercase() == text.to_lowercase())
+            .or_else(|| {
+                let index = text.parse::<usize>().ok()?;
+                choices.get(index.checked_sub(1)?)
+            })
+            .cloned(),
+        InputType::Date => date(text),
+        InputType::Phone => phone(text),
+    }
+}
+
+/* 表单槽位的校验：先按类型规范化，再匹配槽位的正则 */
+pub fn validate_slot(slot: &Slot, text: &str) -> Option<String> {
+    let value = match &slot.input_type {
+        Some(input_type) => validate(input_type, text)?,
+        None => Some(text.trim())
+            .filter(|text| !text.is_empty())?
+            .to_string(),
+    };
+    match &slot.regex {
+        Some(regex) if !regex.is_match(&value) => None,
+        _ => Some(value),
+    }
+}
+
+/* 填写表单时用 "槽位名=新的值" 修改任意槽位，返回槽位的序号与新的值 */
+pub fn correction<'t>(slots: &[Slot], text: &'t str) -> Option<(usize, &'t str)> {
+    let (name, value) = text.split_once('=')?;
+    let index = slots
+        .iter()
+        .position(|slot| slot.name.eq_ignore_ascii_case(name.trim()))?;
+    Some((index, value))
+}
+
+/*
+ * 只接受普通的十进制写法，不接受 inf、NaN 与指数形式，
+ * 能通过校验的输入在运算时一定能解析为数字
+ */
+fn number(text: &str) -> Option<Decimal> {
+    let digits = text.strip_prefix(['+', '-']).unwrap_or(text);
+    if !digits.starts_with(|c: char| c.is_ascii_digit())
+        || !digits.chars().all(|c| c.is_ascii_digit() || c == '.')
+    {
+        return None;
+    }
+    parse_number(text)
+}
+
+fn date(text: &str) -> Option<String> {
+    let normalized = text
+        .trim_end_matches('日')
+        .replace(['年', '月', '/', '.'], "-");
+    let parts: Vec<&str> = normalized.split('-').collect();
+    let [year, month, day] = parts[..] else {
+        return None;
+    };
+    if year.len() != 4
+        || [year, month, day]
+            .iter()
+            .any(|part| part.is_empty() || !part.bytes().all(|b| b.is_ascii_digit()))
+    {
+        return None;
+    }
+    let (year, month, day): (u32, u32, u32) =
+        (year.parse().ok()?, month.parse().ok()?, day.parse().ok()?);
+    let leap = year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+    let days = match month {
+        1 | 3 | 5 | 7 | 8 | 10 | 12 => 31,
+        4 | 6 | 9 | 11 => 30,
+        2 if leap => 29,
+        2 => 28,
+        _ => return None,
+    };
+    if day == 0 || day > days {
+        return None;
+    }
+    Some(format!("{:04}-{:02}-{:02}", year, month, day))
+}
+
+fn phone(text: &str) -> Option<String> {
+    let digits: String = text
+        .chars()
+        .filter(|c| !matches!(c, ' ' | '-' | '(' | ')'))
+        .collect();
+    let digits = digits
+        .strip_prefix("+86")
+        .or_else(|| digits.strip_prefix("0086"))
+        .unwrap_or(&digits);
+    let bytes = digits.as_bytes();
+    let valid = bytes.len() == 11
+        && bytes.iter().all(u8::is_ascii_digit)
+        && bytes[0] == b'1'
+        && (b'3'..=b'9').contains(&bytes[1]);
+    valid.then(|| digits.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_numbers_and_answers() {
+        assert_eq!(
+            validate(&InputType::Number, " 100.5 "),
+            Some("100.5".to_string())
+        );
+        assert_eq!(validate(&InputType::Number, "-3"), Some("-3".to_string()));
+        assert_eq!(validate(&InputType::Number, "abc"), None);
+        assert_eq!(validate(&InputType::Number, "inf"), None);
+        assert_eq!(validate(&InputType::Number, "1e5"), None);
+        // 与运算时的解析一致，超出十进制数范围的输入在运算时无法使用，校验时也不接受
+        let big = "123456789012345678901234567890123";
+        assert_eq!(validate(&InputType::Number, big), None);
+        assert_eq!(validate(&InputType::Integer, big), None);
+        let long = "12345678901234567890";
+        assert_eq!(validate(&InputType::Integer, long), Some(long.to_string()));
+        assert_eq!(validate(&InputType::Integer, "42"), Some("42".to_string()));
+        assert_eq!(validate(&InputType::Integer, "4.2"), None);
+        assert_eq!(validate(&InputType::YesNo, "Yes"), Some("True".to_string()));
+        assert_eq!(
+            validate(&InputType::YesNo, "不要"),
+            Some("False".to_string())
+        );
+        assert_eq!(validate(&InputType::YesNo, "也许"), None);
+
+        let choice = InputType::Choice(vec!["Card".to_string(), "现金".to_string()]);
+        assert_eq!(validate(&choice, "card"), Some("Card".to_string()));
+        assert_eq!(validate(&choice, "2"), Some("现金".to_string()));
+        assert_eq!(validate(&choice, "3"), None);
+        assert_eq!(validate(&choice, "0"), None);
+    }
+
+    #[test]
+    fn test_dates_and_phones() {
+        assert_eq!(
+            validate(&InputType::Date, "2024/2/29"),
+            Some("2024-02-29".to_string())
+        );
+        assert_eq!(
+            validate(&InputType::Date, "2024年3月5日"),
+            Some("2024-03-05".to_string())
+        );
+        assert_eq!(validate(&InputType::Date, "2023-02-29"), None);
+        assert_eq!(validate(&InputType::Date, "24-01-01"), None);
+        assert_eq!(validate(&InputType::Date, "tomorrow"), None);
+        assert_eq!(
+            validate(&InputType::Phone, "+86 138-0013-8000"),
+            Some("13800138000".to_string())
+        );
+        assert_eq!(validate(&InputType::Phone, "12800138000"), None);
+        assert_eq!(validate(&InputType::Phone, "1380013800"), None);
+    }
+
+    #[test]
+    fn test_slots_and_corrections() {
+        let program = crate::parser::parse_program(
+            "form f { slot account as integer regex \"^[0-9]{8}$\" prompt \"?\"; slot note prompt \"?\"; }",
+        )
+        .unwrap();
+        let slots = match &*program[0] {
+            crate::ast::Statement::Form { slots, .. } => slots,
+            _ => unreachable!(),
+        };
+        assert_eq!(
+            validate_slot(&slots[0], " 12345678 "),
+            Some("12345678".to_string())
+        );
+        assert_eq!(validate_slot(&slots[0], "1234567"), None);
+        assert_eq!(validate_slot(&slots[1], "  "), None);
+        assert_eq!(
+            correction(slots, "Account = 87654321"),
+            Some((0, " 87654321"))
+        );
+        assert_eq!(correction(slots, "a=b"), None);
+        assert_eq!(correction(slots, "hello"), None);
+    }
+}
@@ -0,0 +1,107 @@
+use indexmap::IndexMap;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/*
+ * 变量中保存的值
+ * 语言中的数字与布尔值都以字符串保存，列表与映射中的元素也是如此；
+ * 映射按插入的顺序保存，keys与for循环按声明的顺序给出键，
+ * 例如用映射描述菜单时，选项的顺序与源码中一致。
+ * 序列化时字符串仍为JSON字符串，与之前保存的会话兼容，
+ * 列表与映射分别为JSON数组与对象。
+ */
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Value {
+    String(String),
+    List(Vec<Value>),
+    Map(Map),
+}
+
+pub type Map = IndexMap<String, Value>;
+
+impl Value {
+    /* 字符串的内容，列表与映射返回None */
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    /* 列表与映射中的字符串带引号输出 */
+    fn fmt_nested(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::String(s) => write!(f, "\"{}\"", s),
+            _ => write!(f, "{}", self),
+        }
+    }
+}
+
+impl From<String> for Value {
+    fn from(s: String) -> Self {
+        Value::String(s)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(s: &str) -> Self {
+        Value::String(s.to_string())
+    }
+}
+
+/*
+ * 输出时的形式：字符串原样输出，
+ * 列表与映射还原为字面量的写法，例如 ["a", "b"]、{"k": "v"}
+ */
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::String(s) => write!(f, "{}", s),
+            Value::List(items) => {
+                write!(f, "[")?;
+                for (index, item) in items.iter().enumerate() {
+                    if index > 0 {
+                        write!(f, ", ")?;
+                    }
+                    item.fmt_nested(f)?;
+                }
+                write!(f, "]")
+            }
+            Value::Map(entries) => {
+                write!(f, "{{")?;
+                for (index, (key, value)) in entries.iter().enumerate() {
+                    if index > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "\"{}\": ", key)?;
+                    value.fmt_nested(f)?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_display_and_serde() {
+        let mut menu = Map::new();
+        menu.insert("b".to_string(), Value::from("余额"));
+        menu.insert("a".to_string(), Value::List(vec![Value::from("1")]));
+        let value = Value::Map(menu);
+        assert_eq!(value.to_string(), r#"{"b": "余额", "a": ["1"]}"#);
+        let json = serde_json::to_string(&value).unwrap();
+        assert_eq!(json, r#"{"b":"余额","a":["1"]}"#);
+        assert_eq!(serde_json::from_str::<Value>(&json).unwrap(), value);
+        // 之前保存的会话中变量的值都是字符串
+        assert_eq!(
+            serde_json::from_str::<Value>("\"42\"").unwrap(),
+            Value::from("42")
+        );
+    }
+}
@@ -0,0 +1,1080 @@
+use crate::ast::{number_to_string, InputType, Pattern};
+use crate::bytecode::{Chunk, Constant, Instruction};
+use crate::catalog::Catalog;
+use crate::clock::{Clock, SystemClock};
+use crate::handoff::{Handback, Handoff, Line};
+use crate::intent;
+use crate::interpreter::{
+    eval_binary, eval_index, load, match_regex, pick_one, Io, Operand, Outcome,
+};
+use crate::random::Rng;
+use crate::store::{self, MemoryStore, Storage};
+use crate::validation;
+use crate::value::{self, Map};
+use rust_decimal::prelude::ToPrimitive;
+use rust_decimal::Decimal;
+use std::fmt;
+use std::sync::Arc;
+
+/*
+ * 执行字节码的栈式虚拟机
+ * 运行过程中只维护程序计数器、操作数栈与变量槽位，
+ * 循环的每一轮都不会复制语法树或环境；
+ * 常量与变量中的字符串以引用计数共享，读取时不复制内容。
+ * 运算语义与interpreter.rs共用eval_binary，
+ * 树遍历解释器作为参考实现，两者的输出应完全一致。
+ */
+
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    Number(Decimal),
+    String(Arc<str>),
+    List(Arc<Vec<value::Value>>),
+    Map(Arc<Map>),
+}
+
+impl Value {
+    fn operand(&self) -> Operand<'_> {
+        match self {
+            Value::Number(n) => Operand::Number(*n),
+            Value::String(s) => Operand::String(s),
+            Value::List(items) => Operand::List(items),
+            Value::Map(entries) => Operand::Map(entries),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RuntimeError {
+    /* 出错指令的位置 */
+    pub pc: usize,
+    pub message: String,
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} (at instruction {})", self.message, self.pc)
+    }
+}
+
+impl std::error::Error for RuntimeError {}
+
+pub struct Vm<'a> {
+    chunk: &'a Chunk,
+    pc: usize,
+    stack: Vec<Value>,
+    /* 变量槽位，None表示尚未赋值，变量中的数字以字符串保存 */
+    slots: Vec<Option<Value>>,
+    /* 当前所处的对话状态，为Chunk::states中的下标 */
+    state: Option<usize>,
+    true_value: Arc<str>,
+    false_value: Arc<str>,
+    clock: Arc<dyn Clock>,
+    rng: Rng,
+    store: Arc<dyn Storage>,
+    user: String,
+    catalog: Arc<Catalog>,
+    /* locale指令切换到的语言，None时使用消息目录的默认语言 */
+    locale: Option<String>,
+    /* 到目前为止的对话记录，转接时交给人工客服 */
+    transcript: Vec<Line>,
+    /* 程序中有transfer指令时才记录对话记录 */
+    transcribe: bool,
+}
+
+impl<'a> Vm<'a> {
+    pub fn new(chunk: &'a Chunk) -> Self {
+        Self {
+            chunk,
+            pc: 0,
+            stack: Vec::with_capacity(16),
+            slots: vec![None; chunk.names.len()],
+            state: None,
+            true_value: Arc::from("True"),
+            false_value: Arc::from("False"),
+            clock: Arc::new(SystemClock),
+            rng: Rng::from_entropy(),
+            store: Arc::new(MemoryStore::new()),
+            user: store::DEFAULT_USER.to_string(),
+            catalog: Arc::new(Catalog::default()),
+            locale: None,
+            transcript: Vec::new(),
+            transcribe: chunk.code.contains(&Instruction::Transfer),
+        }
+    }
+
+    /* 替换日期函数读取的时钟 */
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /* 固定随机数种子，与解释器使用同一个种子时随机选出的结果也相同 */
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.rng = Rng::seeded(seed);
+        self
+    }
+
+    /* 替换store使用的存储，并指定值按哪个用户读写 */
+    pub fn with_store(mut self, store: Arc<dyn Storage>, user: &str) -> Self {
+        self.store = store;
+        self.user = user.to_string();
+        self
+    }
+
+    /* 使用给定的消息目录 */
+    pub fn with_catalog(mut self, catalog: Arc<Catalog>) -> Self {
+        self.catalog = catalog;
+        self
+    }
+
+    /* 当前使用的语言 */
+    pub fn locale(&self) -> Option<&str> {
+        self.locale.as_deref()
+    }
+
+    /* 到目前为止的对话记录 */
+    pub fn transcript(&self) -> &[Line] {
+        &self.transcript
+    }
+
+    /* 全局变量当前的值 */
+    pub fn variable(&self, name: &str) -> Option<value::Value> {
+        let slot = self.chunk.globals.get(name)?;
+        self.slots[*slot]
+            .as_ref()
+            .map(|value| value.operand().into())
+    }
+
+    /* 当前所处的对话状态 */
+    pub fn current_state(&self) -> Option<&str> {
+        let index = self.state?;
+        Some(&self.chunk.states[index].name)
+    }
+
+    /* 执行程序，直到exit、输入结束或程序执行完毕 */
+    pub fn run(&mut self, io: &mut dyn Io) -> Result<(), RuntimeError> {
+        while let Some(instruction) = self.chunk.code.get(self.pc) {
+            self.pc += 1;
+            match *instruction {
+                Instruction::Constant(index) => {
+                    let value = match &self.chunk.constants[index] {
+                        Constant::Number(n) => Value::Number(*n),
+                        Constant::String(s) => Value::String(s.clone()),
+                        Constant::List(items) => Value::List(items.clone()),
+                        Constant::Map(entries) => Value::Map(entries.clone()),
+                    };
+                    self.stack.push(value);
+                }
+                Instruction::Load(slot) => match &self.slots[slot] {
+                    Some(value) => self.stack.push(value.clone()),
+                    None => {
+                        let name = &self.chunk.names[slot];
+                        return Err(self.error(format!("Variable '{}' cannot be found", name)));
+                    }
+                },
+                Instruction::LoadAny(index) => {
+                    let candidates = &self.chunk.lookups[index];
+                    match candidates
+                        .iter()
+                        .find_map(|slot| self.slots[*slot].as_ref())
+                    {
+                        Some(value) => self.stack.push(value.clone()),
+                        None => {
+                            let name = &self.chunk.names[candidates[0]];
+                            return Err(self.error(format!("Variable '{}' cannot be found", name)));
+                        }
+                    }
+                }
+                Instruction::Store(slot) => {
+                    let value = self.pop_stored();
+                    self.slots[slot] = Some(value);
+                }
+                Instruction::StoreAny(index) => {
+                    let value = self.pop_stored();
+                    let slot = self.target(index);
+                    self.slots[slot] = Some(value);
+                }
+                Instruction::Binary(op) => {
+                    let right = self.pop();
+                    let left = self.pop();
+                    let outcome = eval_binary(&op.token(), left.operand(), right.operand())
+                        .map_err(|message| self.error(message))?;
+                    let value = self.value(outcome);
+                    self.stack.push(value);
+                }
+                Instruction::Call(builtin, count) => {
+                    let args = self.stack.split_off(self.stack.len() - count);
+                    let operands: Vec<Operand> = args.iter().map(Value::operand).collect();
+                    let outcome = builtin
+                        .call(&operands, &*self.clock, &mut self.rng)
+                        .map_err(|message| self.error(message))?;
+                    let value = self.value(outcome);
+                    self.stack.push(value);
+                }
+                Instruction::List(count) => {
+                    let items = self.stack.split_off(self.stack.len() - count);
+                    let items = items.iter().map(|item| item.operand().into()).collect();
+                    self.stack.push(Value::List(Arc::new(items)));
+                }
+                Instruction::Map(count) => {
+                    let entries = self.stack.split_off(self.stack.len() - 2 * count);
+                    let entries = entries
+                        .chunks(2)
+                        .map(|entry| (entry[0].operand().to_string(), entry[1].operand().into()))
+                        .collect();
+                    self.stack.push(Value::Map(Arc::new(entries)));
+                }
+                Instruction::Index => {
+                    let index = self.pop();
+                    let target = self.pop();
+                    let outcome = eval_index(target.operand(), index.operand())
+                        .map_err(|message| self.error(message))?;
+                    let value = self.value(outcome);
+                    self.stack.push(value);
+                }
+                Instruction::Next(end) => {
+                    let position = match self.pop() {
+                        Value::Number(n) => n.to_usize().expect("下一项的序号"),
+                        _ => unreachable!("Next之前压入的是下一项的序号"),
+                    };
+                    let item = match self.stack.last().expect("遍历的列表或映射") {
+                        Value::List(items) => items.get(position).cloned(),
+                        Value::Map(entries) => entries
+                            .get_index(position)
+                            .map(|(key, _)| value::Value::from(key.as_str())),
+                        other => {
+                            let message = format!(
+                                "Only lists and maps can be iterated, got '{}'",
+                                other.operand()
+                            );
+                            return Err(self.error(message));
+                        }
+                    };
+                    match item {
+                        Some(item) => {
+                            self.stack.push(Value::Number(Decimal::from(position + 1)));
+                            let item = self.value(item.into());
+                            self.stack.push(item);
+                        }
+                        None => {
+                            self.pop();
+                            self.pc = end;
+                        }
+                    }
+                }
+                Instruction::Pop => {
+                    self.pop();
+                }
+                Instruction::Speak => {
+                    let text = self.pop_string();
+                    self.speak(io, &text);
+                }
+                Instruction::SpeakOneOf => {
+                    let options = self.pop();
+                    let text = pick_one(options.operand(), &mut self.rng)
+                        .map_err(|message| self.error(message))?
+                        .to_string();
+                    self.speak(io, &text);
+                }
+                Instruction::Input(slot) => match self.read(io, Some(&self.chunk.names[slot])) {
+                    Some(line) => self.slots[slot] = Some(Value::String(Arc::from(line))),
+                    None => return Ok(()),
+                },
+                Instruction::InputAny(index) => match self.read(io, Some(self.name(index))) {
+                    Some(line) => {
+                        let slot = self.target(index);
+                        self.slots[slot] = Some(Value::String(Arc::from(line)));
+                    }
+                    None => return Ok(()),
+                },
+                Instruction::Read => match self.read(io, None) {
+                    Some(line) => self.stack.push(Value::String(Arc::from(line))),
+                    None => return Ok(()),
+                },
+                Instruction::Match(index) => {
+                    let text = self.pop_string();
+                    let table = &self.chunk.matches[index];
+                    let intents: Vec<&[Pattern]> =
+                        table.intents.iter().map(|patterns| &patterns[..]).collect();
+                    let arm =
+                        intent::classify(&intents, table.threshold, &text).unwrap_or(intents.len());
+                    self.pc = table.targets[arm];
+                }
+                Instruction::RegexMatch(index) => {
+                    let text = self.pop_string();
+                    let table = &self.chunk.regexes[index];
+                    let value = match match_regex(&table.regex, &text) {
+                        Some(captures) => {
+                            // 分组按名字的顺序给出，与bindings一一对应
+                            for ((_, lookup), (_, capture)) in table.bindings.iter().zip(captures) {
+                                let slot = self.target(*lookup);
+                                self.slots[slot] = Some(Value::String(Arc::from(capture)));
+                            }
+                            self.true_value.clone()
+                        }
+                        None => self.false_value.clone(),
+                    };
+                    self.stack.push(Value::String(value));
+                }
+                Instruction::Ask(index) => {
+                    let attempts = match self.pop() {
+                        Value::Number(n) => n,
+                        _ => unreachable!("Ask之前压入的是尝试次数"),
+                    };
+                    let table = &self.chunk.asks[index];
+                    let line = match self.read(io, Some(self.name(table.target))) {
+                        Some(line) => line,
+                        None => return Ok(()),
+                    };
+                    match validation::validate(&table.input_type, &line) {
+                        Some(value) => {
+                            let slot = self.target(table.target);
+                            self.slots[slot] = Some(Value::String(Arc::from(value)));
+                            self.pc = table.end;
+                        }
+                        None => {
+                            let attempts = attempts + Decimal::ONE;
+                            if table.max.is_some_and(|max| attempts >= Decimal::from(max)) {
+                                self.pc = table.fallback;
+                            } else {
+                                self.stack.push(Value::Number(attempts));
+                            }
+                        }
+                    }
+                }
+                Instruction::FormNext(index) => {
+                    let table = &self.chunk.forms[index];
+                    let missing = table
+                        .targets
+                        .iter()
+                        .position(|lookup| self.defined(*lookup).is_none());
+                    self.pc = match missing {
+                        Some(position) => table.prompts[position],
+                        None => table.confirm.unwrap_or(table.end),
+                    };
+                }
+                Instruction::FormInput(index) => {
+                    let position = match self.pop() {
+                        Value::Number(n) => n.to_usize().expect("槽位序号"),
+                        _ => unreachable!("FormInput之前压入的是槽位序号"),
+                    };
+                    let table = &self.chunk.forms[index];
+                    let line = match self.read(io, Some(table.slots[position].variable())) {
+                        Some(line) => line,
+                        None => return Ok(()),
+                    };
+                    self.pc = match validation::correction(&table.slots, &line) {
+                        Some((corrected, value)) => self.fill_slot(index, corrected, value),
+                        None => self.fill_slot(index, position, &line),
+                    };
+                }
+                Instruction::FormConfirm(index) => {
+                    let line = match self.read(io, None) {
+                        Some(line) => line,
+                        None => return Ok(()),
+                    };
+                    let table = &self.chunk.forms[index];
+                    if let Some((corrected, value)) = validation::correction(&table.slots, &line) {
+                        self.pc = self.fill_slot(index, corrected, value);
+                        continue;
+                    }
+                    let answer = validation::validate(&InputType::YesNo, &line);
+                    self.pc = match answer.as_deref() {
+                        Some("True") => table.end,
+                        // 清空所有槽位，从头重新填写
+                        Some(_) => {
+                            for lookup in &table.targets {
+                                if let Some(slot) = self.defined(*lookup) {
+                                    self.slots[slot] = None;
+                                }
+                            }
+                            table.next
+                        }
+                        None => table.confirm.expect("FormConfirm之前是确认提示"),
+                    };
+                }
+                Instruction::EnterScope(index) => {
+                    for slot in &self.chunk.scopes[index] {
+                        self.slots[*slot] = None;
+                    }
+                }
+                Instruction::StoreGet(key) => {
+                    let value = load(&*self.store, &self.user, self.key(key))
+                        .map_err(|message| self.error(message))?;
+                    let value = self.value(value.into());
+                    self.stack.push(value);
+                }
+                Instruction::StoreSet(key) => {
+                    let value = self.pop().operand().into();
+                    self.store
+                        .set(&self.user, self.key(key), &value)
+                        .map_err(|e| self.error(e.to_string()))?;
+                }
+                Instruction::JumpIfStored(key, target) => {
+                    let saved = self
+                        .store
+                        .get(&self.user, self.key(key))
+                        .map_err(|e| self.error(e.to_string()))?;
+                    if saved.is_some() {
+                        self.pc = target;
+                    }
+                }
+                Instruction::Message(index) => {
+                    let table = &self.chunk.messages[index];
+                    let count = table.names.len();
+                    let mut values = Vec::with_capacity(count);
+                    for _ in 0..count {
+                        values.push(self.pop_string());
+                    }
+                    let args: Vec<(&str, String)> = table
+                        .names
+                        .iter()
+                        .zip(values.iter().rev())
+                        .map(|(name, value)| (name.as_str(), value.to_string()))
+                        .collect();
+                    let text = self
+                        .catalog
+                        .format(self.locale.as_deref(), &table.key, &args)
+                        .map_err(|message| self.error(message))?;
+                    self.stack.push(Value::String(Arc::from(text)));
+                }
+                Instruction::Transfer => {
+                    let reason = self.pop_string().to_string();
+                    let handoff = Handoff {
+                        reason,
+                        transcript: self.transcript.clone(),
+                        variables: self.globals(),
+                    };
+                    match io.transfer(&handoff) {
+                        Handback::Resume { variables } => {
+                            for (name, value) in variables {
+                                // 只能写入脚本中出现过的全局变量
+                                if let Some(slot) = self.chunk.globals.get(&name) {
+                                    self.slots[*slot] = Some(self.value(value.into()));
+                                }
+                            }
+                        }
+                        Handback::End => return Ok(()),
+                    }
+                }
+                Instruction::Locale => {
+                    let locale = self.pop_string().to_string();
+                    self.catalog
+                        .check_locale(&locale)
+                        .map_err(|message| self.error(message))?;
+                    self.locale = Some(locale);
+                }
+                Instruction::JumpIfFalse(target) => match self.pop() {
+                    Value::String(s) if *s == *"True" => {}
+                    Value::String(s) if *s == *"False" => self.pc = target,
+                    _ => return Err(self.error("结果非布尔值！".to_string())),
+                },
+                Instruction::Jump(target) => self.pc = target,
+                Instruction::Goto(index) => {
+                    // 离开所有正在执行的语句，包括for循环留在栈上的列表与序号
+                    self.stack.clear();
+                    self.state = Some(index);
+                    self.pc = self.chunk.states[index].entry;
+                }
+                Instruction::Exit => return Ok(()),
+            }
+        }
+        Ok(())
+    }
+
+    /* 输出一行，程序中有transfer指令时同时记入对话记录 */
+    fn speak(&mut self, io: &mut dyn Io, text: &str) {
+        if self.transcribe {
+            self.transcript.push(Line::bot(text));
+        }
+        io.speak(text);
+    }
+
+    /* 读取一行输入，程序中有transfer指令时同时记入对话记录 */
+    fn read(&mut self, io: &mut dyn Io, var: Option<&str>) -> Option<String> {
+        let line = io.input(var)?;
+        if self.transcribe {
+            self.transcript.push(Line::user(&line));
+        }
+        Some(line)
+    }
+
+    /* 已经赋值的全局变量，按名字排序 */
+    fn globals(&self) -> value::Map {
+        let mut names: Vec<&String> = self.chunk.globals.keys().collect();
+        names.sort();
+        names
+            .into_iter()
+            .filter_map(|name| Some((name.clone(), self.variable(name)?)))
+            .collect()
+    }
+
+    /* 常量池中store的键 */
+    fn key(&self, index: usize) -> &str {
+        match &self.chunk.constants[index] {
+            Constant::String(key) => key,
+            _ => unreachable!("store的键总是字符串常量"),
+        }
+    }
+
+    fn pop(&mut self) -> Value {
+        self.stack.pop().expect("编译器保证操作数栈不会下溢")
+    }
+
+    /* 运算结果对应的栈上的值 */
+    fn value(&self, outcome: Outcome) -> Value {
+        match outcome {
+            Outcome::Number(n) => Value::Number(n),
+            Outcome::String(s) => Value::String(Arc::from(s)),
+            Outcome::Bool(true) => Value::String(self.true_value.clone()),
+            Outcome::Bool(false) => Value::String(self.false_value.clone()),
+            Outcome::List(items) => Value::List(Arc::new(items)),
+            Outcome::Map(entries) => Value::Map(Arc::new(entries)),
+        }
+    }
+
+    /* 弹出栈顶并转为字符串，列表与映射取输出时的形式 */
+    fn pop_string(&mut self) -> Arc<str> {
+        match self.pop() {
+            Value::Number(n) => Arc::from(number_to_string(n)),
+            Value::String(s) => s,
+            other => Arc::from(other.operand().to_string()),
+        }
+    }
+
+    /* 弹出栈顶作为变量的值，数字转为字符串 */
+    fn pop_stored(&mut self) -> Value {
+        match self.pop() {
+            Value::Number(n) => Value::String(Arc::from(number_to_string(n))),
+            other => other,
+        }
+    }
+
+    /* 赋值的目标槽位：第一个已定义的候选，否则为当前作用域 */
+    fn target(&self, index: usize) -> usize {
+        self.defined(index).unwrap_or(self.chunk.lookups[index][0])
+    }
+
+    /* 第一个已定义的候选槽位 */
+    fn defined(&self, index: usize) -> Option<usize> {
+        self.chunk.lookups[index]
+            .iter()
+            .copied()
+            .find(|slot| self.slots[*slot].is_some())
+    }
+
+    /* 填写表单的一个槽位，返回接下来执行的位置：
+     * 合法时回到FormNext，否则为该槽位的重新提示 */
+    fn fill_slot(&mut self, index: usize, position: usize, text: &str) -> usize {
+        let table = &self.chunk.forms[index];
+        match validation::validate_slot(&table.slots[position], text) {
+            Some(value) => {
+                let slot = self.target(table.targets[position]);
+                self.slots[slot] = Some(Value::String(Arc::from(value)));
+                table.next
+            }
+            None => table.retries[position],
+        }
+    }
+
+    /* Chunk::lookups中的候选槽位对应的变量名 */
+    fn name(&self, lookup: usize) -> &'a str {
+        let chunk = self.chunk;
+        &chunk.names[chunk.lookups[lookup][0]]
+    }
+
+    fn error(&self, message: String) -> RuntimeError {
+        RuntimeError {
+            pc: self.pc - 1,
+            message,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::compile;
+    use crate::clock::FixedClock;
+    use crate::interpreter::{Interpreter, ScriptedIo};
+    use crate::parser::parse_program;
+
+    /* 分别用树遍历解释器与虚拟机执行同一段对话，返回两者的输出，
+     * 两者的时钟停在同一时刻，随机数种子也相同 */
+    fn run_both(source: &str, inputs: &[&str]) -> (Vec<String>, Vec<String>) {
+        let ast = parse_program(source).unwrap();
+        let clock: Arc<dyn Clock> = Arc::new(FixedClock::parse("2024-03-09 20:30").unwrap());
+
+        let mut reference = ScriptedIo::new(inputs);
+        Interpreter::new(ast.clone())
+            .with_clock(clock.clone())
+            .with_seed(7)
+            .interpret_with(&mut reference);
+
+        let chunk = compile(&ast).unwrap();
+        let mut io = ScriptedIo::new(inputs);
+        Vm::new(&chunk)
+            .with_clock(clock)
+            .with_seed(7)
+            .run(&mut io)
+            .unwrap();
+        (reference.output, io.output)
+    }
+
+    #[test]
+    fn test_differential_bank() {
+        let source = std::fs::read_to_string("examples/bank.dsl").unwrap();
+        let inputs = [
+            "b", "r", "100", "b", "c", "很好", "x", "r", "0.5", "b", "e", "b",
+        ];
+        let (expected, actual) = run_both(&source, &inputs);
+        assert_eq!(actual, expected);
+        assert!(expected.contains(&"你的账户余额为:100.5".to_string()));
+    }
+
+    #[test]
+    fn test_differential_intent() {
+        let source = std::fs::read_to_string("examples/intent.dsl").unwrap();
+        let inputs = [
+            "看看余额",
+            "top-up",
+            "12",
+            "订单 654321",
+            "hmm",
+            "查看",
+            "bye",
+        ];
+        let (expected, actual) = run_both(&source, &inputs);
+        assert_eq!(actual, expected);
+        assert!(expected.contains(&"你的账户余额为:12".to_string()));
+
+        // 对表达式的匹配，以及没有default分支的情况
+        let source = r#"
+            global word = "recharge";
+            match word threshold 0.9 { intent "r": ["rechage"] => { speak "r"; } }
+            match word { intent "r": ["rechage"] => { local x = 1; speak x; } }
+            speak "end";
+        "#;
+        let (expected, actual) = run_both(source, &[]);
+        assert_eq!(actual, expected);
+        assert_eq!(expected, vec!["1", "end"]);
+    }
+
+    #[test]
+    fn test_differential_examples() {
+        for path in ["examples/simple.dsl", "examples/lexerTest.dsl"] {
+            let source = std::fs::read_to_string(path).unwrap();
+            let (expected, actual) = run_both(&source, &[]);
+            assert_eq!(actual, expected, "{}", path);
+        }
+    }
+
+    #[test]
+    fn test_differential_operators() {
+        let source = r#"
+            global a = 6;
+            global b = "4";
+            speak a + b;
+            speak a - b;
+            speak a * b / 3;
+            speak "a" + a;
+            speak a + "b";
+            speak "x" + "y";
+            speak a == 6;
+            speak a == "6";
+            speak (a == b) == "False";
+            c = (a - b) * (a + b);
+            speak c;
+            input d;
+            if (d == 2.5) { speak "half"; };
+            if (d == "2.5") { speak "string"; };
+        "#;
+        let (expected, actual) = run_both(source, &["2.5"]);
+        assert_eq!(actual, expected);
+        assert_eq!(expected.len(), 12);
+    }
+
+    #[test]
+    fn test_differential_regex() {
+        let source = r#"
+            loop {
+                input text;
+                if (text == "e") { exit; };
+                if (text ~ "^1[0-9]{10}$") { speak "phone"; };
+                if ((text ~ "^1[0-9]{10}$") == "False") { speak "not a phone"; };
+                if (text ~ "order (?P<id>[0-9]{6})( (?P<note>.+))?") {
+                    speak "order " + id + " [" + note + "]";
+                };
+            }
+        "#;
+        let inputs = [
+            "13800138000",
+            "1380013800",
+            "order 123456 urgent",
+            "order 654321",
+            "e",
+        ];
+        let (expected, actual) = run_both(source, &inputs);
+        assert_eq!(actual, expected);
+        assert_eq!(
+            expected,
+            vec![
+                "phone",
+                "not a phone",
+                "not a phone",
+                "order 123456 [urgent]",
+                "not a phone",
+                "order 654321 []",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_differential_typed_input() {
+        let source = r#"
+            global total = 0;
+            loop {
+                input amount as number prompt "金额:" retry "请输入数字:" max 3 else {
+                    speak "次数用完";
+                    exit;
+                };
+                total = total + amount;
+                input more as yesno prompt "继续?";
+                if (more == "False") {
+                    input pay as choice ["card", "cash"] prompt "支付方式?";
+                    input day as date;
+                    input phone as phone retry "手机号不正确";
+                    speak pay + " " + day + " " + phone + " " + total;
+                    exit;
+                };
+            }
+        "#;
+        let inputs = [
+            "abc",
+            "10",
+            "maybe",
+            "y",
+            "x",
+            "2.5",
+            "no",
+            "3",
+            "CASH",
+            "2024/2/30",
+            "2024/2/29",
+            "123",
+            "138 0013 8000",
+        ];
+        let (expected, actual) = run_both(source, &inputs);
+        assert_eq!(actual, expected);
+        assert_eq!(expected.last().unwrap(), "cash 2024-02-29 13800138000 12.5");
+
+        let inputs = ["a", "b", "c", "never read"];
+        let (expected, actual) = run_both(source, &inputs);
+        assert_eq!(actual, expected);
+        assert_eq!(
+            expected,
+            vec!["金额:", "请输入数字:", "请输入数字:", "次数用完"]
+        );
+    }
+
+    #[test]
+    fn test_differential_form() {
+        let source = std::fs::read_to_string("examples/form.dsl").unwrap();
+        let inputs = [
+            "1234",
+            "amount=50",
+            "12345678",
+            "3",
+            "2",
+            "amount=80",
+            "maybe",
+            "不",
+            "Jerry",
+            "87654321",
+            "100",
+            "网银",
+            "amount=abc",
+            "100.5",
+            "是",
+        ];
+        let (expected, actual) = run_both(&source, &inputs);
+        assert_eq!(actual, expected);
+        assert!(expected.contains(&"请问您的姓名?".to_string()));
+        assert_eq!(expected.last().unwrap(), "转账已提交，谢谢Jerry!");
+
+        // 表单在循环中每一轮重新填写，输入结束时停止
+        let source = "loop { form f { slot x prompt \"x?\"; } speak x; }";
+        let (expected, actual) = run_both(source, &["1", "", "2"]);
+        assert_eq!(actual, expected);
+        assert_eq!(expected, vec!["x?", "1", "x?", "x?", "2", "x?"]);
+    }
+
+    #[test]
+    fn test_differential_builtins() {
+        let source = "input s; speak len(s); speak upper(substr(s, 2)) + \"|\" + trim(replace(s, \"好\", \" \")); if (starts_with(lower(s), \"你好\")) { speak split(s, \"好\", 1); }; speak to_number(\"0\" + len(s)) * 2; speak to_string(contains(s, \"x\"));";
+        let (expected, actual) = run_both(source, &["你好abc"]);
+        assert_eq!(actual, expected);
+        assert_eq!(expected, vec!["5", "ABC|你 abc", "abc", "10", "False"]);
+    }
+
+    #[test]
+    fn test_differential_numbers() {
+        let source = "global bill = 0; loop { input x; if (x == \"e\") { exit; }; bill = bill + x; speak bill; speak round(bill * 1.005, 2) + \" \" + bill % 0.25 + \" \" + 2 ^ 3 ^ 2; speak max(floor(bill), ceil(bill / 3), abs(0 - bill)); }";
+        let (expected, actual) = run_both(source, &["0.1", "0.2", "10", "e"]);
+        assert_eq!(actual, expected);
+        assert_eq!(expected[3], "0.3");
+        assert_eq!(expected[4], "0.3 0.05 512");
+        assert_eq!(expected[7], "10.35 0.05 512");
+        assert_eq!(expected[8], "10.3");
+
+        // 变量中保存的数字与字面量相加
+        let source =
+            "global bill = 0; bill = bill + 0.1 + 0.2; speak bill; speak \"合计:\" + bill;";
+        let (expected, actual) = run_both(source, &[]);
+        assert_eq!(actual, expected);
+        assert_eq!(expected, vec!["0.3", "合计:0.3"]);
+    }
+
+    #[test]
+    fn test_differential_states() {
+        let source = std::fs::read_to_string("examples/states.dsl").unwrap();
+        let inputs = [
+            "x", "a", "b", "r", "q", "r", "abc", "50", "b", "q", "c", "太慢", "e",
+        ];
+        let (expected, actual) = run_both(&source, &inputs);
+        assert_eq!(actual, expected);
+        assert!(expected.contains(&"你的账户余额为:50".to_string()));
+        assert_eq!(expected.last().unwrap(), "再见!");
+
+        // 没有 on input 的状态执行完 on enter 即结束，goto之后的语句不会执行
+        let source = "local n = 1; { goto a; speak \"never\"; } state a { on enter { speak n; goto b; } } state b { on enter { n = n + \"1\"; speak n; } } speak \"never\";";
+        let (expected, actual) = run_both(source, &[]);
+        assert_eq!(actual, expected);
+        assert_eq!(expected, vec!["1", "2"]);
+
+        let ast = parse_program("goto nowhere;").unwrap();
+        assert_eq!(
+            compile(&ast),
+            Err(crate::bytecode::CompileError::UnknownState(
+                "nowhere".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_differential_collections() {
+        let source = std::fs::read_to_string("examples/faq.dsl").unwrap();
+        let inputs = ["b", "f", "客服电话", "x", "f", "天气", "e"];
+        let (expected, actual) = run_both(&source, &inputs);
+        assert_eq!(actual, expected);
+        assert!(expected.contains(&"95555".to_string()));
+        assert_eq!(
+            expected[expected.len() - 2],
+            r#"本次共办理了4项业务: ["查看账户余额", "常见问题", "常见问题", "退出程序"]"#
+        );
+
+        let source = "global xs = split(\"a,b\", \",\"); for x in push(xs, [1]) { speak x; speak has(xs, x); } speak {\"k\": xs}[\"k\"][0] + len(keys({\"a\": 1}));";
+        let (expected, actual) = run_both(source, &[]);
+        assert_eq!(actual, expected);
+        assert_eq!(
+            expected,
+            vec!["a", "True", "b", "True", "[\"1\"]", "False", "a1"]
+        );
+    }
+
+    #[test]
+    fn test_differential_dates() {
+        let source = std::fs::read_to_string("examples/hours.dsl").unwrap();
+        let (expected, actual) = run_both(&source, &["x", "2024/3/1"]);
+        assert_eq!(actual, expected);
+        assert_eq!(
+            expected,
+            vec![
+                "晚上好!",
+                "现在是2024年03月09日 20:30",
+                "现在是非工作时间，人工客服的工作时间为周一至周五 9:00-18:00",
+                "请输入交易日期(例如 2024-03-05):",
+                "日期格式不正确，请重新输入:",
+                "该笔交易发生在8天前",
+                "退款申请的截止时间为03月08日",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_differential_random() {
+        let source = "loop { speak one of [\"a\", \"b\", \"c\"]; speak random(10) + random(0 - 5, 5) + random(); input x; if (x == \"e\") { exit; }; }";
+        let inputs = ["1", "2", "3", "4", "e"];
+        let (expected, actual) = run_both(source, &inputs);
+        assert_eq!(actual, expected);
+        // 同一个种子在五轮中不会总是选出同一项
+        let picks: Vec<&String> = expected.iter().step_by(2).collect();
+        assert!(picks.iter().any(|pick| *pick != picks[0]));
+
+        let chunk = compile(&parse_program("speak one of [];").unwrap()).unwrap();
+        let error = Vm::new(&chunk).run(&mut ScriptedIo::default()).unwrap_err();
+        assert_eq!(
+            error.message,
+            "`speak one of` expects a non-empty list, got '[]'!"
+        );
+    }
+
+    #[test]
+    fn test_differential_store() {
+        let source = r#"
+            store bill = 0;
+            store history = [];
+            loop {
+                input amount;
+                if (amount == "e") { exit; };
+                store.bill = store.bill + amount;
+                store.history = push(store.history, amount);
+                speak store.bill;
+            }
+        "#;
+        let (expected, actual) = run_both(source, &["100", "0.5", "e"]);
+        assert_eq!(actual, expected);
+        assert_eq!(expected, vec!["100", "100.5"]);
+
+        // 第二次运行接着同一个用户保存的值
+        let chunk = compile(&parse_program(source).unwrap()).unwrap();
+        let store: Arc<dyn Storage> = Arc::new(MemoryStore::new());
+        for _ in 0..2 {
+            let mut io = ScriptedIo::new(&["10", "e"]);
+            Vm::new(&chunk)
+                .with_store(store.clone(), "alice")
+                .run(&mut io)
+                .unwrap();
+        }
+        assert_eq!(
+            store.get("alice", "history").unwrap(),
+            Some(value::Value::List(vec!["10".into(), "10".into()]))
+        );
+        assert_eq!(store.get("alice", "bill").unwrap(), Some("20".into()));
+
+        let chunk = compile(&parse_program("speak store.missing;").unwrap()).unwrap();
+        let error = Vm::new(&chunk).run(&mut ScriptedIo::default()).unwrap_err();
+        assert_eq!(error.message, "Key 'missing' cannot be found in the store");
+    }
+
+    #[test]
+    fn test_differential_messages() {
+        let mut catalog = Catalog::default();
+        catalog
+            .add(
+                "zh",
+                "greeting = \"你好，{name}！\"\n[menu]\ntitle = \"菜单\"",
+            )
+            .unwrap();
+        catalog.add("en", "greeting = \"Hello, {name}!\"").unwrap();
+        let catalog = Arc::new(catalog.with_default("zh"));
+        let source = r#"
+            loop {
+                input a;
+                if (a == "e") { exit; };
+                locale a;
+                speak @greeting(name: a + "!");
+                speak @menu.title;
+            }
+        "#;
+        let inputs = ["en", "zh", "e"];
+        let ast = parse_program(source).unwrap();
+        let mut reference = ScriptedIo::new(&inputs);
+        Interpreter::new(ast.clone())
+            .with_catalog(catalog.clone())
+            .interpret_with(&mut reference);
+        let chunk = compile(&ast).unwrap();
+        let mut io = ScriptedIo::new(&inputs);
+        let mut vm = Vm::new(&chunk).with_catalog(catalog.clone());
+        vm.run(&mut io).unwrap();
+        assert_eq!(io.output, reference.output);
+        assert_eq!(
+            io.output,
+            vec!["Hello, en!!", "菜单", "你好，zh!！", "菜单"]
+        );
+        assert_eq!(vm.locale(), Some("zh"));
+
+        let mut io = ScriptedIo::new(&["fr"]);
+        let error = Vm::new(&chunk)
+            .with_catalog(catalog)
+            .run(&mut io)
+            .unwrap_err();
+        assert_eq!(error.message, "Unknown locale 'fr' (available: en, zh)");
+    }
+
+    #[test]
+    fn test_differential_transfer() {
+        let source = r#"
+            global order = "";
+            loop {
+                input issue;
+                if (issue == "e") { exit; };
+                if (issue == "人工") {
+                    transfer "转人工：" + issue;
+                    speak "订单" + order;
+                };
+            }
+        "#;
+        let inputs = ["退款", "人工", "resume", "人工", "end", "e"];
+        let ast = parse_program(source).unwrap();
+        let mut reference = ScriptedIo::new(&inputs);
+        Interpreter::new(ast.clone()).interpret_with(&mut reference);
+        let chunk = compile(&ast).unwrap();
+        let mut io = ScriptedIo::new(&inputs);
+        let mut vm = Vm::new(&chunk);
+        vm.run(&mut io).unwrap();
+        assert_eq!(io.output, reference.output);
+        assert_eq!(io.output, vec!["订单"]);
+        assert_eq!(io.handoffs.len(), 2);
+        for (actual, expected) in io.handoffs.iter().zip(&reference.handoffs) {
+            assert_eq!(actual.reason, expected.reason);
+            assert_eq!(actual.transcript, expected.transcript);
+            assert_eq!(actual.variables["order"], expected.variables["order"]);
+        }
+        assert_eq!(io.handoffs[1].transcript.len(), 4);
+        assert_eq!(vm.transcript().len(), 4);
+    }
+
+    #[test]
+    fn test_differential_scoping() {
+        let source = r#"
+            global x = "outer";
+            loop {
+                speak x;
+                input a;
+                if (a == "local") { local x = "shadow"; speak x; x = a; speak x; };
+                if (a == "assign") { x = a; };
+                if (a == "global") { global y = a; };
+                if (a == "y") { speak y; };
+                if (a == "e") { exit; };
+                b = a;
+                speak b;
+            }
+        "#;
+        let inputs = ["local", "assign", "global", "y", "x", "e"];
+        let (expected, actual) = run_both(source, &inputs);
+        assert_eq!(actual, expected);
+        assert!(expected.contains(&"shadow".to_string()));
+
+        // 内层代码块的局部变量在下一次进入时已被清空
+        let source = "loop { input a; { if (a == \"2\") { speak b; }; b = a; } }";
+        let chunk = compile(&parse_program(source).unwrap()).unwrap();
+        let mut io = ScriptedIo::new(&["1", "2"]);
+        let error = Vm::new(&chunk).run(&mut io).unwrap_err();
+        assert_eq!(error.message, "Variable 'b' cannot be found");
+        let mut reference = ScriptedIo::new(&["1", "2"]);
+        let result = std::panic::catch_unwind(move || {
+            Interpreter::new(parse_program(source).unwrap()).interpret_with(&mut reference)
+        });
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_runtime_error() {
+        let chunk = compile(&parse_program("speak missing;").unwrap()).unwrap();
+        let error = Vm::new(&chunk).run(&mut ScriptedIo::default()).unwrap_err();
+        assert_eq!(error.message, "Variable 'missing' cannot be found");
+        assert_eq!(error.pc, 0);
+    }
+}